@@ -0,0 +1,71 @@
+// FADT is Fixed ACPI Description Table
+// see https://uefi.org/sites/default/files/resources/ACPI_Spec_6_4_Jan22.pdf#page=173
+
+use core::mem::{offset_of, size_of};
+
+use static_assertions::const_assert_eq;
+
+use super::{AcpiGenericAddress, sdt::SdtHeader};
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    __: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_int: u16,
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_cnt: u8,
+    pub pm1a_evt_blk: u32,
+    pub pm1b_evt_blk: u32,
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    pub pm2_cnt_blk: u32,
+    pub pm_tmr_blk: u32,
+    pub gpe0_blk: u32,
+    pub gpe1_blk: u32,
+    pub pm1_evt_len: u8,
+    pub pm1_cnt_len: u8,
+    pub pm2_cnt_len: u8,
+    pub pm_tmr_len: u8,
+    pub gpe0_blk_len: u8,
+    pub gpe1_blk_len: u8,
+    pub gpe1_base: u8,
+    pub cst_cnt: u8,
+    pub p_lvl2_lat: u16,
+    pub p_lvl3_lat: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alrm: u8,
+    pub mon_alrm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    ___: u8,
+    pub flags: u32,
+    pub reset_reg: AcpiGenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_arch: u16,
+    pub fadt_minor_version: u8,
+}
+
+const_assert_eq!(offset_of!(Fadt, arm_boot_arch), 129);
+
+impl Fadt {
+    pub const ARM_PSCI_COMPLIANT: u16 = 1 << 0;
+    pub const ARM_PSCI_USE_HVC: u16 = 1 << 1;
+
+    /// Return the ARM boot flags, 0 if the table is too old to have them.
+    pub fn arm_boot_flags(&self) -> u16 {
+        if (self.header.length as usize) < size_of::<Self>() {
+            return 0;
+        }
+        self.arm_boot_arch
+    }
+}
@@ -1,3 +1,4 @@
+pub mod fadt;
 pub mod madt;
 mod rsdp;
 pub mod sdt;
@@ -1,11 +1,11 @@
-use core::{fmt::Display, panic::PanicInfo};
+use core::{arch::asm, fmt::Display, ops::Range, panic::PanicInfo};
 
 use aarch64_cpu::{asm::wfi, registers::MPIDR_EL1};
 use log::error;
 use static_assertions::assert_eq_size;
 use tock_registers::interfaces::Readable;
 
-use crate::interrupts::exceptions::disable_exceptions;
+use crate::{interrupts::exceptions::disable_exceptions, memory::VirtualAddress, scheduler::Cpu};
 
 #[panic_handler]
 pub fn panic_handler(info: &PanicInfo) -> ! {
@@ -21,6 +21,10 @@ pub fn panic_handler(info: &PanicInfo) -> ! {
         );
     }
 
+    if cfg!(feature = "poweroff_on_exit") {
+        // skip the shutdown hooks, the kernel state may be inconsistent
+        let _ = crate::psci::system_off();
+    }
     halt();
 }
 
@@ -36,6 +40,53 @@ pub fn id() -> u32 {
     id as u32
 }
 
+/// Range of the stack holding `sp`, among the stacks of the current thread and CPU.
+fn current_stack(sp: VirtualAddress) -> Option<Range<VirtualAddress>> {
+    let cpu = Cpu::current();
+    cpu.try_current_thread()
+        .and_then(|thread| thread.stack_containing(sp))
+        .or_else(|| cpu.emergency_stack().filter(|stack| stack.contains(&sp)))
+}
+
+/// Fill `frames` with the return addresses of the callers by following the frame records,
+/// and return how many were found.
+///
+/// The walk stops at the first frame record outside the current stack, and finds nothing on the
+/// boot stacks. The frame records are only complete with the `lockdep` feature, which compiles
+/// the kernel with frame pointers.
+#[inline(always)]
+pub fn backtrace(frames: &mut [usize]) -> usize {
+    let (mut fp, sp): (usize, usize);
+    unsafe {
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    let Some(stack) = current_stack(VirtualAddress::new(sp)) else {
+        return 0;
+    };
+
+    let mut count = 0;
+    // a frame record is the previous frame pointer followed by the return address
+    while count < frames.len()
+        && fp.is_multiple_of(16)
+        && fp >= sp
+        && fp + 2 * size_of::<usize>() <= stack.end.addr()
+    {
+        let [next, lr] = unsafe { *(fp as *const [usize; 2]) };
+        if lr == 0 {
+            break;
+        }
+        frames[count] = lr;
+        count += 1;
+        // the caller frame must be higher on the same stack
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    count
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct InterruptFrame {
@@ -3,6 +3,8 @@ use core::fmt::Debug;
 use alloc::string::String;
 use thiserror::Error;
 
+use crate::psci::PsciError;
+
 #[derive(Error, Clone)]
 pub enum Error {
     #[error("{0}")]
@@ -20,6 +22,9 @@ pub enum Error {
     #[error("Memory error: {0}")]
     Memory(MemoryError),
 
+    #[error("PSCI error: {0}")]
+    Psci(PsciError),
+
     #[error("IO error")]
     IoError,
 }
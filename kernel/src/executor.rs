@@ -0,0 +1,179 @@
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake};
+use core::{
+    future::Future,
+    pin::{Pin, pin},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use log::trace;
+use spin::{Mutex, Once};
+
+use crate::{
+    error::Error,
+    scheduler::{current_thread, kthread, park, thread::ThreadRef},
+    sync::no_irq_locks::NoIrqMutex,
+};
+
+type BoxedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Wake up the thread running `block_on`.
+struct ThreadWaker(ThreadRef);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread, which sleeps while the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(current_thread().clone())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        park();
+    }
+}
+
+/// Return a future letting the other tasks of the executor run before completing.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+#[derive(Debug)]
+#[must_use = "futures do nothing unless awaited"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+struct Task {
+    /// `None` once the future completed.
+    future: Mutex<Option<BoxedFuture>>,
+    executor: &'static Executor,
+    /// The task is in the ready queue of its executor.
+    queued: AtomicBool,
+}
+
+impl Task {
+    fn schedule(self: Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let executor = self.executor;
+        executor.ready.lock().push_back(self);
+        if let Some(thread) = executor.thread.get() {
+            thread.unpark();
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        Arc::clone(self).schedule();
+    }
+}
+
+/// Run many futures on a single kernel thread, each one is polled again once its waker is called.
+pub struct Executor {
+    name: &'static str,
+    ready: NoIrqMutex<VecDeque<Arc<Task>>>,
+    /// The thread polling the tasks, set once it started.
+    thread: Once<ThreadRef>,
+    tasks: AtomicUsize,
+}
+
+impl Executor {
+    /// Create an executor and the kernel thread running it.
+    pub fn new(name: &'static str) -> Result<&'static Self, Error> {
+        // the tasks keep a reference to the executor, so it is never freed
+        let executor: &'static Self = Box::leak(Box::new(Self {
+            name,
+            ready: NoIrqMutex::new(VecDeque::new()),
+            thread: Once::new(),
+            tasks: AtomicUsize::new(0),
+        }));
+        kthread::spawn(name, || executor.run())?;
+        Ok(executor)
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Count of tasks not completed yet.
+    #[inline]
+    pub fn task_count(&self) -> usize {
+        self.tasks.load(Ordering::Relaxed)
+    }
+
+    /// Add `future` to the tasks of the executor. Can be called from any thread.
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&'static self, future: F) {
+        trace!(target: "executor", "Spawn task on {}", self.name);
+        self.tasks.fetch_add(1, Ordering::Relaxed);
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            executor: self,
+            queued: AtomicBool::new(false),
+        });
+        task.schedule();
+    }
+
+    fn run(&'static self) -> ! {
+        self.thread.call_once(|| current_thread().clone());
+        loop {
+            let task = self.ready.lock().pop_front();
+            let Some(task) = task else {
+                park();
+                continue;
+            };
+
+            // cleared before polling so a wake up during the poll queues the task again
+            task.queued.store(false, Ordering::Release);
+            let waker = Waker::from(Arc::clone(&task));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = task.future.lock();
+            if let Some(fut) = future.as_mut()
+                && fut.as_mut().poll(&mut cx).is_ready()
+            {
+                // also drops the wakers the future holds to its own task
+                *future = None;
+                self.tasks.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl core::fmt::Debug for Executor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Executor")
+            .field("name", &self.name)
+            .field("tasks", &self.task_count())
+            .finish()
+    }
+}
@@ -1,20 +1,35 @@
 use core::{
+    alloc::Layout,
     fmt::Debug,
+    future::Future,
     marker::PhantomData,
-    mem::{self, size_of},
+    mem::{self, MaybeUninit, size_of},
     ops::{Deref, DerefMut},
-    ptr::NonNull,
+    pin::Pin,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
 };
 
-use alloc::{boxed::Box, string::String};
+use alloc::{boxed::Box, string::String, vec::Vec};
 use hashbrown::HashMap;
-use spin::lock_api::RwLock;
+use log::{error, trace};
 
 use crate::{
     create_fs_node,
-    error::Error,
+    error::{Error, MemoryError},
     fs::node::{Block, FsNodeInfos},
-    utils::{buffer::Buffer, smart_ptr::SmartPtrResizableBuff},
+    memory::{PAGE_SIZE, PMM_PAGE_ALLOCATOR, slab::SlabCache},
+    power,
+    scheduler::{kthread, sleep},
+    sync::{
+        lockdep::{LockClass, TrackedRwLock, rwlock_in_class},
+        no_irq_locks::NoIrqMutex,
+    },
+    utils::{
+        buffer::Buffer,
+        smart_ptr::{SmartPtr, SmartPtrResizableBuff},
+    },
 };
 
 use super::{
@@ -25,7 +40,94 @@ use super::{
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BlockIndex(pub usize);
 
+static BLOCK_CACHE_CLASS: LockClass = LockClass::new("block_cache");
+static CACHED_BLOCK_CLASS: LockClass = LockClass::new("cached_block");
+
+/// Period of the writeback of the dirty blocks.
+const WRITEBACK_PERIOD: Duration = Duration::from_secs(5);
+/// Clean blocks evicted at once when the caches are full.
+const EVICT_BATCH: usize = 16;
+
 static BLOCK_DEVICES: SmartPtrResizableBuff<FsNode<BlockDevice>> = SmartPtrResizableBuff::new();
+/// The registered devices, for the writeback.
+static DEVICES: NoIrqMutex<Vec<SmartPtr<FsNode<BlockDevice>>>> = NoIrqMutex::new(Vec::new());
+
+const fn block_data_cache(name: &'static str, size: usize) -> SlabCache {
+    // naturally aligned, so a block doesn't straddle two pages
+    SlabCache::new(name, unsafe {
+        Layout::from_size_align_unchecked(size, size)
+    })
+}
+
+/// Caches of the data of the cached blocks by block size, shared by the devices.
+///
+/// Not registered as named caches, so the heap doesn't use them for the other allocations of
+/// the same layout.
+static BLOCK_DATA_CACHES: [SlabCache; 4] = [
+    block_data_cache("block-512", 512),
+    block_data_cache("block-1024", 1024),
+    block_data_cache("block-2048", 2048),
+    block_data_cache("block-4096", 4096),
+];
+
+#[inline]
+fn block_data_cache_for(size: usize) -> Option<&'static SlabCache> {
+    BLOCK_DATA_CACHES
+        .iter()
+        .find(|cache| cache.layout().size() == size)
+}
+
+/// Layout of the heap allocation of the data of a block without a cache of its size.
+#[inline]
+fn block_data_layout(size: usize) -> Layout {
+    Layout::from_size_align(size, PAGE_SIZE).unwrap()
+}
+
+/// Bytes of block data in the caches of all the devices.
+static CACHED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Incremented on each access to a cached block, to find the least recently used ones.
+static ACCESS_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Return the size in bytes of the cached blocks of all the devices.
+pub fn cache_size() -> usize {
+    CACHED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Maximum size in bytes of the caches of all the devices, an eighth of the memory.
+#[inline]
+fn max_cache_size() -> usize {
+    PMM_PAGE_ALLOCATOR.get().unwrap().usable_pages() * PAGE_SIZE / 8
+}
+
+/// Start the thread writing back the dirty blocks, and write them back on shutdown.
+pub(crate) fn init() {
+    if let Err(e) = kthread::spawn("block_flush", flusher) {
+        error!(target: "block", "Failed to start the writeback thread: {}", e);
+    }
+    power::register_shutdown_hook("block_cache", || {
+        let _ = sync_all();
+    });
+}
+
+fn flusher() {
+    loop {
+        sleep(WRITEBACK_PERIOD);
+        let _ = sync_all();
+    }
+}
+
+/// Write back the dirty blocks of all the devices.
+pub fn sync_all() -> Result<(), Error> {
+    let devices = DEVICES.lock().clone();
+    let mut result = Ok(());
+    for device in devices {
+        if let Err(e) = device.sync() {
+            error!(target: "block", "Failed to write back {}: {}", device.dev.infos().name, e);
+            result = Err(e);
+        }
+    }
+    result
+}
 
 pub fn register_device(device: Box<dyn BlockDev>) {
     let device = BlockDevice::new(device);
@@ -39,6 +141,7 @@ pub fn register_device(device: Box<dyn BlockDev>) {
         file: dyn File
     );
     let device = BLOCK_DEVICES.insert(node);
+    DEVICES.lock().push(device.clone());
     devfs::add_device(name, FsNodeRef::new(device));
 }
 
@@ -46,7 +149,7 @@ pub fn register_device(device: Box<dyn BlockDev>) {
 pub struct BlockDevice {
     dev: Box<dyn BlockDev>,
     block_size: usize,
-    cache: RwLock<HashMap<BlockIndex, CachedBlock>>,
+    cache: TrackedRwLock<HashMap<BlockIndex, CachedBlock>>,
 }
 
 unsafe impl Block for BlockDevice {
@@ -66,8 +169,7 @@ unsafe impl Block for BlockDevice {
             }
             let cached_block = self.read_block(block)?;
             let block_ref = cached_block.get_ref(self, block);
-            let r = cache.insert(block, cached_block);
-            debug_assert!(r.is_none());
+            self.insert_block(&mut cache, block, cached_block);
             Ok(block_ref)
         }
     }
@@ -84,8 +186,7 @@ unsafe impl Block for BlockDevice {
             }
             let cached_block = self.read_block(block)?;
             let block_mut = cached_block.get_mut(self, block);
-            let r = cache.insert(block, cached_block);
-            debug_assert!(r.is_none());
+            self.insert_block(&mut cache, block, cached_block);
             Ok(block_mut)
         }
     }
@@ -103,19 +204,41 @@ unsafe impl Block for BlockDevice {
                 block.copy_from_slice(buff);
                 return Ok(());
             }
-            let mut buffer = Buffer::new_boxed(self.block_size);
-            buffer.write(0, buff);
-            let cached_block = CachedBlock::new(CacheState::Modified, buffer);
-            cache.insert(block, cached_block);
+            let mut cached_block = CachedBlock::new(CacheState::Modified, self.block_size)?;
+            cached_block.buffer_mut().write(0, buff);
+            self.insert_block(&mut cache, block, cached_block);
             Ok(())
         }
     }
+
+    fn read_direct(&self, block: BlockIndex, buff: &mut Buffer) -> Result<(), Error> {
+        assert_eq!(buff.len() % self.block_size, 0);
+        for i in 0..buff.len() / self.block_size {
+            let data = buff.slice_mut(i * self.block_size..(i + 1) * self.block_size);
+            self.dev.read(BlockIndex(block.0 + i), data)?;
+        }
+        Ok(())
+    }
+
+    fn write_direct(&self, block: BlockIndex, buff: &Buffer) -> Result<(), Error> {
+        assert_eq!(buff.len() % self.block_size, 0);
+        for i in 0..buff.len() / self.block_size {
+            let data = buff.slice(i * self.block_size..(i + 1) * self.block_size);
+            self.dev.write(BlockIndex(block.0 + i), data)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn sync(&self) -> Result<(), Error> {
+        BlockDevice::sync(self)
+    }
 }
 
 impl BlockDevice {
     pub fn new(dev: Box<dyn BlockDev>) -> Self {
         let block_size = dev.infos().block_size;
-        let cache = RwLock::new(HashMap::new());
+        let cache = rwlock_in_class(&BLOCK_CACHE_CLASS, HashMap::new());
         Self {
             dev,
             block_size,
@@ -128,8 +251,116 @@ impl BlockDevice {
         infos.block_count * infos.block_size
     }
 
+    /// Return the async interface of the device, if it has one.
+    #[inline]
+    pub fn as_async(&self) -> Option<&dyn AsyncBlockDev> {
+        self.dev.as_async()
+    }
+
+    /// Write back the dirty blocks through `BlockDev::write`.
+    ///
+    /// The blocks borrowed mutably are skipped, they are written back by a later sync.
+    pub fn sync(&self) -> Result<(), Error> {
+        // copied under the locks, then written without them, the blocks can't be evicted until
+        // written or the cache could read them again from the disk before the write
+        let dirty: Vec<_> = self
+            .cache
+            .read()
+            .iter()
+            .filter_map(|(index, block)| {
+                let mut inner = block.inner.try_write()?;
+                if !matches!(inner.state, CacheState::Modified) {
+                    return None;
+                }
+                inner.state = CacheState::Writeback;
+                let mut buff = Buffer::new_boxed(self.block_size);
+                buff.write(0, unsafe { inner.data.as_ref() });
+                Some((*index, buff))
+            })
+            .collect();
+        if !dirty.is_empty() {
+            trace!(target: "block", "Write back {} blocks of {}", dirty.len(), self.dev.infos().name);
+        }
+
+        let mut result = Ok(());
+        for (index, buff) in dirty {
+            let r = self.dev.write(index, &buff);
+            self.end_writeback(index, r.is_ok());
+            if let Err(e) = r {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Write back the dirty blocks and drop the unused blocks from the cache.
     pub fn flush(&self) -> Result<(), Error> {
-        todo!()
+        self.sync()?;
+        self.cache.write().retain(|_, block| !block.evictable());
+        Ok(())
+    }
+
+    /// Mark the block clean once written back, or dirty again if the write failed.
+    ///
+    /// A block modified during the write stays dirty.
+    fn end_writeback(&self, index: BlockIndex, written: bool) {
+        let cache = self.cache.write();
+        // blocks in writeback aren't evictable
+        let block = cache.get(&index).unwrap();
+        // Safety: the cache is locked, so no new reference to the block can be made, and the state
+        // is only changed with the cache locked or by the only `BlockMut`.
+        let inner = unsafe { &mut *block.inner.data_ptr() };
+        if matches!(inner.state, CacheState::Writeback) {
+            inner.state = if written {
+                CacheState::Clean
+            } else {
+                CacheState::Modified
+            };
+        }
+    }
+
+    /// Insert a block in the cache, evicting the least recently used clean blocks if the caches are
+    /// full, those of the device first and then those of the others.
+    fn insert_block(
+        &self,
+        cache: &mut HashMap<BlockIndex, CachedBlock>,
+        index: BlockIndex,
+        block: CachedBlock,
+    ) {
+        let max = max_cache_size();
+        let cached = CACHED_BYTES.load(Ordering::Relaxed);
+        if cached > max {
+            let excess = cached - max;
+            let count = excess.div_ceil(self.block_size).max(EVICT_BATCH);
+            let freed = evict_lru(cache, count) * self.block_size;
+            if freed < excess {
+                self.evict_others(excess - freed);
+            }
+        }
+        let r = cache.insert(index, block);
+        debug_assert!(r.is_none());
+    }
+
+    /// Evict the least recently used clean blocks of the other devices to free `bytes`.
+    ///
+    /// The devices with their cache locked are skipped, waiting for them could deadlock with a
+    /// thread evicting the blocks of this device.
+    fn evict_others(&self, mut bytes: usize) {
+        let devices = DEVICES.lock().clone();
+        for device in devices {
+            let device: &BlockDevice = &device;
+            if ptr::eq(device, self) {
+                continue;
+            }
+            let Some(mut cache) = device.cache.try_write() else {
+                continue;
+            };
+            let evicted = evict_lru(&mut cache, bytes.div_ceil(device.block_size));
+            bytes = bytes.saturating_sub(evicted * device.block_size);
+            if bytes == 0 {
+                break;
+            }
+        }
     }
 
     fn get_block_ref(
@@ -138,7 +369,10 @@ impl BlockDevice {
         block: BlockIndex,
     ) -> Option<BlockRef<'_>> {
         let cached_block = cache.get(&block);
-        cached_block.map(|cached_block| cached_block.get_ref(self, block))
+        cached_block.map(|cached_block| {
+            cached_block.touch();
+            cached_block.get_ref(self, block)
+        })
     }
 
     fn get_block_ref_mut(
@@ -147,51 +381,101 @@ impl BlockDevice {
         block: BlockIndex,
     ) -> Option<BlockMut<'_>> {
         let cached_block = cache.get(&block);
-        cached_block.map(|cached_block| cached_block.get_mut(self, block))
+        cached_block.map(|cached_block| {
+            cached_block.touch();
+            cached_block.get_mut(self, block)
+        })
     }
 
     fn read_block(&self, block: BlockIndex) -> Result<CachedBlock, Error> {
-        // TODO: bypass heap allocator and use instead the vmm
-        let mut buff = Buffer::new_boxed(self.block_size);
-        self.dev.read(block, &mut buff)?;
-
-        let block = CachedBlock::new(CacheState::Clean, buff);
+        let mut cached_block = CachedBlock::new(CacheState::Clean, self.block_size)?;
+        self.dev.read(block, cached_block.buffer_mut())?;
+        Ok(cached_block)
+    }
+}
 
-        Ok(block)
+/// Evict up to `count` of the least recently used clean blocks of `cache`. Return the count of
+/// blocks evicted.
+fn evict_lru(cache: &mut HashMap<BlockIndex, CachedBlock>, count: usize) -> usize {
+    let mut candidates: Vec<_> = cache
+        .iter()
+        .filter(|(_, block)| block.evictable())
+        .map(|(index, block)| (block.last_access.load(Ordering::Relaxed), *index))
+        .collect();
+    let count = count.min(candidates.len());
+    if count > 0 && count < candidates.len() {
+        candidates.select_nth_unstable(count - 1);
+    }
+    for (_, index) in &candidates[..count] {
+        cache.remove(index);
     }
+    count
 }
 
 #[derive(Debug)]
 struct CachedBlock {
-    inner: RwLock<CachedBlockInner>,
+    inner: TrackedRwLock<CachedBlockInner>,
+    /// Value of `ACCESS_TICK` at the last access.
+    last_access: AtomicU64,
 }
 
 #[derive(Debug)]
 struct CachedBlockInner {
     state: CacheState,
-    data: NonNull<u8>,
+    data: NonNull<[u8]>,
 }
 
 unsafe impl Send for CachedBlockInner {}
 unsafe impl Sync for CachedBlockInner {}
 
 impl CachedBlock {
-    fn new(state: CacheState, buff: Box<Buffer>) -> Self {
-        let ptr = buff.as_ptr() as *mut _;
-        mem::forget(buff);
-        Self {
-            inner: RwLock::new(CachedBlockInner {
-                state,
-                data: NonNull::new(ptr).unwrap(),
-            }),
+    /// Allocate a block of `size` bytes, uninitialized.
+    fn new(state: CacheState, size: usize) -> Result<Self, Error> {
+        let ptr = match block_data_cache_for(size) {
+            Some(cache) => cache.alloc(),
+            None => NonNull::new(unsafe { alloc::alloc::alloc(block_data_layout(size)) }),
         }
+        .ok_or(Error::Memory(MemoryError::OutOfPhysicalMemory))?;
+        CACHED_BYTES.fetch_add(size, Ordering::Relaxed);
+        Ok(Self {
+            inner: rwlock_in_class(
+                &CACHED_BLOCK_CLASS,
+                CachedBlockInner {
+                    state,
+                    data: NonNull::slice_from_raw_parts(ptr, size),
+                },
+            ),
+            last_access: AtomicU64::new(ACCESS_TICK.fetch_add(1, Ordering::Relaxed)),
+        })
+    }
+
+    /// Return the data of a block not inserted in a cache yet.
+    #[inline]
+    fn buffer_mut(&mut self) -> &mut Buffer {
+        let data = self.inner.get_mut().data;
+        unsafe { &mut *Buffer::from_slice_ptr_mut(data.as_ptr() as *mut [MaybeUninit<u8>]) }
+    }
+
+    #[inline]
+    fn touch(&self) {
+        let tick = ACCESS_TICK.fetch_add(1, Ordering::Relaxed);
+        self.last_access.store(tick, Ordering::Relaxed);
+    }
+
+    /// Return true if the block is clean and not borrowed, must be called with the cache locked.
+    fn evictable(&self) -> bool {
+        // a borrowed block holds its lock
+        let Some(inner) = self.inner.try_write() else {
+            return false;
+        };
+        matches!(inner.state, CacheState::Clean)
     }
 
     fn get_ref<'a>(&self, device: &'a BlockDevice, block: BlockIndex) -> BlockRef<'a> {
         let guard = self.inner.read();
-        let ptr = guard.data;
+        let data = guard.data;
         mem::forget(guard);
-        let data = NonNull::slice_from_raw_parts(ptr, device.block_size);
+        debug_assert_eq!(data.len(), device.block_size);
         BlockRef {
             block,
             data,
@@ -201,9 +485,9 @@ impl CachedBlock {
 
     fn get_mut<'a>(&self, device: &'a BlockDevice, block: BlockIndex) -> BlockMut<'a> {
         let guard = self.inner.write();
-        let ptr = guard.data;
+        let data = guard.data;
         mem::forget(guard);
-        let data = NonNull::slice_from_raw_parts(ptr, device.block_size);
+        debug_assert_eq!(data.len(), device.block_size);
         BlockMut {
             block,
             data,
@@ -212,10 +496,26 @@ impl CachedBlock {
     }
 }
 
+impl Drop for CachedBlock {
+    fn drop(&mut self) {
+        let data = self.inner.get_mut().data;
+        CACHED_BYTES.fetch_sub(data.len(), Ordering::Relaxed);
+        // Safety: `data` was allocated by `new`, and the block isn't borrowed anymore.
+        match block_data_cache_for(data.len()) {
+            Some(cache) => unsafe { cache.free(data.cast()) },
+            None => unsafe {
+                alloc::alloc::dealloc(data.as_ptr().cast(), block_data_layout(data.len()))
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 enum CacheState {
     Clean,
     Modified,
+    /// Being written back by `sync`, not evictable until written.
+    Writeback,
 }
 
 #[derive(Debug)]
@@ -301,6 +601,21 @@ pub trait BlockDev: Debug + Send + Sync {
 
     fn read(&self, block: BlockIndex, buff: &mut Buffer) -> Result<(), Error>;
     fn write(&self, block: BlockIndex, buff: &Buffer) -> Result<(), Error>;
+
+    /// Return the async interface of the device if it can have several requests in flight.
+    fn as_async(&self) -> Option<&dyn AsyncBlockDev> {
+        None
+    }
+}
+
+/// Future of an async block device request.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+/// Same as `BlockDev` but the requests complete asynchronously,
+/// so a single thread can drive many of them with an `Executor`.
+pub trait AsyncBlockDev: BlockDev {
+    fn read_async<'a>(&'a self, block: BlockIndex, buff: &'a mut Buffer) -> BlockFuture<'a>;
+    fn write_async<'a>(&'a self, block: BlockIndex, buff: &'a Buffer) -> BlockFuture<'a>;
 }
 
 unsafe impl File for BlockDevice {
@@ -1,9 +1,12 @@
 use alloc::{string::String, vec::Vec};
 use hashbrown::HashMap;
 use log::error;
-use spin::{lazy::Lazy, lock_api::RwLock};
+use spin::lazy::Lazy;
 
-use crate::{error::Error, fs::node::FsNodeInfos, create_fs_node, utils::smart_ptr::SmartPtr};
+use crate::{
+    create_fs_node, error::Error, fs::node::FsNodeInfos, sync::lockdep::TrackedRwLock,
+    utils::smart_ptr::SmartPtr,
+};
 
 use super::{
     mount_node,
@@ -28,13 +31,13 @@ static DEVFS: Lazy<SmartPtr<FsNode<DevFs>>> = Lazy::new(|| {
 
 #[derive(Debug)]
 struct DevFs {
-    nodes: RwLock<HashMap<String, FsNodeRef>>,
+    nodes: TrackedRwLock<HashMap<String, FsNodeRef>>,
 }
 
 impl DevFs {
     fn new() -> Self {
         Self {
-            nodes: RwLock::new(HashMap::new()),
+            nodes: TrackedRwLock::new(HashMap::new()),
         }
     }
 
@@ -1,10 +1,9 @@
 use core::fmt::Debug;
 
-use hashbrown::HashMap;
 use log::trace;
-use spin::{lazy::Lazy, lock_api::RwLock};
+use spin::lazy::Lazy;
 
-use crate::error::Error;
+use crate::{error::Error, sync::rcu::RcuMap};
 
 use super::node::FsNodeRef;
 
@@ -15,15 +14,15 @@ pub trait Driver: Send + Sync + Debug {
     fn get_root_node(&self, device: &FsNodeRef) -> Result<FsNodeRef, Error>;
 }
 
-static DRIVERS: Lazy<RwLock<HashMap<&'static str, &'static dyn Driver>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+static DRIVERS: Lazy<RcuMap<&'static str, &'static dyn Driver>> = Lazy::new(Default::default);
 
 pub fn register_driver(driver: &'static dyn Driver) {
     trace!(target: "fs", "Registering driver for {}", driver.fs_type());
-    let mut drivers = DRIVERS.write();
-    drivers.insert(driver.fs_type(), driver);
+    DRIVERS.modify(|drivers| {
+        drivers.insert(driver.fs_type(), driver);
+    });
 }
 
 pub fn get_driver_for_type(fs_type: &str) -> Option<&dyn Driver> {
-    DRIVERS.read().get(fs_type).copied()
+    DRIVERS.get().get(fs_type).copied()
 }
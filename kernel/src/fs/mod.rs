@@ -4,7 +4,9 @@ pub mod block;
 pub(crate) mod devfs;
 mod drivers;
 mod initrd;
+pub mod page_cache;
 pub mod path;
+pub mod procfs;
 mod utils;
 mod vfs;
 
@@ -0,0 +1,548 @@
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, sync::Weak, vec::Vec};
+use core::{
+    fmt::Debug,
+    mem::MaybeUninit,
+    ptr, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use log::{error, trace};
+use spin::{Lazy, Once};
+
+use crate::{
+    error::{Error, FsError, MemoryError},
+    fs::node::{FsNode, FsNodeRef},
+    memory::{PAGE_SIZE, PMM_PAGE_ALLOCATOR, PageAllocator, PhysicalAddress},
+    power,
+    scheduler::{kthread, sleep},
+    sync::{no_irq_locks::NoIrqMutex, wait_map::WaitMap},
+    utils::buffer::Buffer,
+    workqueue,
+};
+
+/// Pages read ahead after a sequential read.
+const READAHEAD_PAGES: usize = 8;
+/// Period of the writeback of the dirty pages.
+const WRITEBACK_PERIOD: Duration = Duration::from_secs(5);
+/// Clean pages evicted at once when the cache is full.
+const EVICT_BATCH: usize = 32;
+
+/// Identifier of a file in the page cache, unique for the lifetime of the kernel.
+pub type FileId = usize;
+
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+static PAGES: NoIrqMutex<CacheState> = NoIrqMutex::new(CacheState {
+    pages: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    tick: 0,
+});
+/// Wakes the threads waiting for a page being read.
+static LOADED: Lazy<WaitMap<PageKey>> = Lazy::new(WaitMap::new);
+/// The files with a cache, for the writeback.
+static FILES: NoIrqMutex<BTreeMap<FileId, Weak<FileInner>>> = NoIrqMutex::new(BTreeMap::new());
+static FLUSHER: Once = Once::new();
+
+/// Where the pages of a file are read from and written back to, implemented by the filesystems.
+///
+/// The file data is already cached here, so it should be transferred straight to the device, bypassing the
+/// block cache.
+pub trait PageIo: Debug + Send + Sync {
+    /// Fill `buff`, a page, with the page `index` of the file. The bytes after the end of the file must be zeroed.
+    fn read_page(&self, index: usize, buff: &mut Buffer) -> Result<(), Error>;
+
+    /// Return true if `write_page` is supported.
+    fn writable(&self) -> bool {
+        false
+    }
+
+    /// Write back the page `index` of the file from `buff`, `size` is the size of the file.
+    #[allow(unused_variables)]
+    fn write_page(&self, index: usize, buff: &Buffer, size: usize) -> Result<(), Error> {
+        Err(Error::Fs(FsError::ReadOnly))
+    }
+
+    /// Return the device the file is stored on, to write back only its files when unmounted.
+    fn device(&self) -> Option<&FsNodeRef> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct PageKey {
+    file: FileId,
+    index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    /// Being read, the threads needing it wait on `LOADED`.
+    Loading,
+    Clean,
+    Dirty,
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    page: PhysicalAddress,
+    state: PageState,
+    /// Users of the page, a pinned page isn't evicted.
+    pins: usize,
+    last_access: u64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    pages: BTreeMap<PageKey, CachedPage>,
+    /// The pages by last access.
+    lru: BTreeMap<u64, PageKey>,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: PageKey) {
+        let tick = self.tick;
+        self.tick += 1;
+        let page = self.pages.get_mut(&key).unwrap();
+        self.lru.remove(&page.last_access);
+        page.last_access = tick;
+        self.lru.insert(tick, key);
+    }
+
+    fn insert(&mut self, key: PageKey, page: PhysicalAddress) {
+        let tick = self.tick;
+        self.tick += 1;
+        let page = CachedPage {
+            page,
+            state: PageState::Loading,
+            pins: 1,
+            last_access: tick,
+        };
+        self.pages.insert(key, page);
+        self.lru.insert(tick, key);
+    }
+
+    fn remove(&mut self, key: PageKey) -> PhysicalAddress {
+        let page = self.pages.remove(&key).unwrap();
+        self.lru.remove(&page.last_access);
+        page.page
+    }
+
+    /// Remove the least recently used clean unused page.
+    fn evict_one(&mut self) -> Option<PhysicalAddress> {
+        let key = self
+            .lru
+            .values()
+            .find(|key| {
+                let page = &self.pages[key];
+                page.pins == 0 && page.state == PageState::Clean
+            })
+            .copied()?;
+        Some(self.remove(key))
+    }
+
+    /// Remove up to `count` clean unused pages, the least recently used first.
+    fn evict(&mut self, count: usize) -> Vec<PhysicalAddress> {
+        let keys: Vec<_> = self
+            .lru
+            .values()
+            .filter(|key| {
+                let page = &self.pages[key];
+                page.pins == 0 && page.state == PageState::Clean
+            })
+            .take(count)
+            .copied()
+            .collect();
+        keys.into_iter().map(|key| self.remove(key)).collect()
+    }
+}
+
+#[inline]
+fn free_pages(pages: Vec<PhysicalAddress>) {
+    let pmm = PMM_PAGE_ALLOCATOR.get().unwrap();
+    for page in pages {
+        unsafe { pmm.dealloc(page, 1) };
+    }
+}
+
+/// Access the cached `page` through the linear mapping.
+#[inline]
+unsafe fn page_buffer(page: PhysicalAddress) -> &'static mut Buffer {
+    let ptr = page.to_virt().as_ptr::<MaybeUninit<u8>>();
+    Buffer::from_slice_mut(unsafe { slice::from_raw_parts_mut(ptr, PAGE_SIZE) })
+}
+
+/// Maximum count of cached pages, a quarter of the memory.
+#[inline]
+fn max_pages() -> usize {
+    PMM_PAGE_ALLOCATOR.get().unwrap().usable_pages() / 4
+}
+
+/// Return the count of pages in the page cache.
+pub fn cached_pages() -> usize {
+    PAGES.lock().pages.len()
+}
+
+/// Free up to `count` clean unused pages of the cache, for the memory reclaim. Return the count of pages freed.
+pub fn shrink(count: usize) -> usize {
+    let pages = PAGES.lock().evict(count);
+    let freed = pages.len();
+    free_pages(pages);
+    freed
+}
+
+/// Remove the least recently used clean unused page of the cache and return it instead of freeing it.
+///
+/// For the physical allocator once out of pages: it doesn't allocate and gives up if the cache is locked.
+pub fn try_evict() -> Option<PhysicalAddress> {
+    PAGES.try_lock()?.evict_one()
+}
+
+/// Write back the dirty pages of all the files.
+pub fn sync_all() -> Result<(), Error> {
+    sync_files(|_| true)
+}
+
+/// Write back the dirty pages of the files stored on `device`.
+pub fn sync_device(device: &FsNodeRef) -> Result<(), Error> {
+    sync_files(|file| {
+        file.io
+            .device()
+            .is_some_and(|d| ptr::eq::<FsNode<()>>(&**d, &**device))
+    })
+}
+
+fn sync_files<F: Fn(&FileInner) -> bool>(filter: F) -> Result<(), Error> {
+    let files: Vec<_> = FILES
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .filter(|file| filter(file))
+        .collect();
+    let mut result = Ok(());
+    for file in files {
+        if let Err(e) = file.sync() {
+            error!(target: "page_cache", "Failed to write back file {}: {}", file.id, e);
+            result = Err(e);
+        }
+    }
+    result
+}
+
+fn flusher() {
+    loop {
+        sleep(WRITEBACK_PERIOD);
+        let _ = sync_all();
+    }
+}
+
+/// The cached content of a file, shared by its reads, writes and mappings.
+///
+/// The pages are written back by a background thread, on `sync` and once the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    inner: Arc<FileInner>,
+}
+
+#[derive(Debug)]
+struct FileInner {
+    id: FileId,
+    io: Box<dyn PageIo>,
+    size: AtomicUsize,
+    /// Page following the last read, a read starting there is sequential.
+    next_read: AtomicUsize,
+}
+
+impl FileCache {
+    /// Create the cache of a file of `size` bytes, with its pages read and written by `io`.
+    pub fn new(io: Box<dyn PageIo>, size: usize) -> Self {
+        FLUSHER.call_once(|| {
+            if let Err(e) = kthread::spawn("page_flush", flusher) {
+                error!(target: "page_cache", "Failed to start the writeback thread: {}", e);
+            }
+            power::register_shutdown_hook("page_cache", || {
+                let _ = sync_all();
+            });
+        });
+        let inner = Arc::new(FileInner {
+            id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+            io,
+            size: AtomicUsize::new(size),
+            next_read: AtomicUsize::new(0),
+        });
+        FILES.lock().insert(inner.id, Arc::downgrade(&inner));
+        Self { inner }
+    }
+
+    #[inline]
+    pub fn id(&self) -> FileId {
+        self.inner.id
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.inner.size.load(Ordering::Relaxed)
+    }
+
+    /// Return whether no other clone or pinned page references the file, dropping it frees the cache.
+    #[inline]
+    pub fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.inner) == 1
+    }
+
+    /// Read from `offset` to fill `buff`, stop at the end of the file. Return the count of bytes read.
+    pub fn read(&self, offset: usize, buff: &mut Buffer) -> Result<usize, Error> {
+        let size = self.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buff.len().min(size - offset);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let in_page = pos % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - in_page).min(len - done);
+            let page = self.inner.get_page(pos / PAGE_SIZE, true)?;
+            let data = unsafe { page_buffer(page.page) };
+            buff.write(done, data.read(in_page, chunk));
+            done += chunk;
+        }
+
+        let first = offset / PAGE_SIZE;
+        let end = (offset + len).div_ceil(PAGE_SIZE);
+        let sequential = self.inner.next_read.swap(end, Ordering::Relaxed) == first;
+        if sequential {
+            self.readahead(end, READAHEAD_PAGES);
+        }
+        Ok(len)
+    }
+
+    /// Write `buff` at `offset`, extending the file if needed. Return the count of bytes written.
+    pub fn write(&self, offset: usize, buff: &Buffer) -> Result<usize, Error> {
+        if !self.inner.io.writable() {
+            return Err(Error::Fs(FsError::ReadOnly));
+        }
+        let size = self.size();
+        let mut done = 0;
+        while done < buff.len() {
+            let pos = offset + done;
+            let in_page = pos % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - in_page).min(buff.len() - done);
+            // the pages overwritten up to the end of the file aren't read
+            let whole = in_page == 0 && (chunk == PAGE_SIZE || pos + chunk >= size);
+            let page = self.inner.get_page(pos / PAGE_SIZE, !whole)?;
+            let data = unsafe { page_buffer(page.page) };
+            data.write(in_page, buff.read(done, chunk));
+            page.mark_dirty();
+            done += chunk;
+        }
+        self.inner.size.fetch_max(offset + done, Ordering::Relaxed);
+        Ok(done)
+    }
+
+    /// Read the `count` pages from `index` in the background.
+    pub fn readahead(&self, index: usize, count: usize) {
+        let end = (index + count).min(self.size().div_ceil(PAGE_SIZE));
+        if index >= end {
+            return;
+        }
+        let inner = self.inner.clone();
+        workqueue::system_unbound().queue(move || {
+            for index in index..end {
+                if let Err(e) = inner.get_page(index, true) {
+                    trace!(target: "page_cache", "Readahead of page {} of file {} failed: {}", index, inner.id, e);
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Write back the dirty pages of the file.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.inner.sync()
+    }
+}
+
+impl FileInner {
+    /// Return the page `index` pinned, read by `io` if `fill` or zeroed otherwise when it isn't cached.
+    fn get_page(self: &Arc<Self>, index: usize, fill: bool) -> Result<PageRef, Error> {
+        let key = PageKey {
+            file: self.id,
+            index,
+        };
+        let pmm = PMM_PAGE_ALLOCATOR.get().unwrap();
+        let page = loop {
+            let mut state = PAGES.lock();
+            if let Some(page) = state.pages.get_mut(&key) {
+                if page.state == PageState::Loading {
+                    LOADED.wait_drop(key, state);
+                    continue;
+                }
+                page.pins += 1;
+                let page = page.page;
+                state.touch(key);
+                return Ok(PageRef::new(self.clone(), index, page));
+            }
+
+            let evicted = if state.pages.len() >= max_pages() {
+                state.evict(EVICT_BATCH)
+            } else {
+                Vec::new()
+            };
+            let page = match pmm.alloc(1) {
+                Some(page) => page,
+                None => {
+                    drop(state);
+                    free_pages(evicted);
+                    if shrink(EVICT_BATCH) == 0 {
+                        return Err(Error::Memory(MemoryError::OutOfPhysicalMemory));
+                    }
+                    continue;
+                }
+            };
+            state.insert(key, page);
+            drop(state);
+            free_pages(evicted);
+            break page;
+        };
+
+        let buff = unsafe { page_buffer(page) };
+        let r = if fill {
+            self.io.read_page(index, buff)
+        } else {
+            buff.inner_mut().fill(MaybeUninit::new(0));
+            Ok(())
+        };
+
+        let mut state = PAGES.lock();
+        if r.is_ok() {
+            state.pages.get_mut(&key).unwrap().state = PageState::Clean;
+        } else {
+            state.remove(key);
+        }
+        drop(state);
+        LOADED.send(key);
+        match r {
+            Ok(()) => Ok(PageRef::new(self.clone(), index, page)),
+            Err(e) => {
+                unsafe { pmm.dealloc(page, 1) };
+                Err(e)
+            }
+        }
+    }
+
+    fn unpin(&self, index: usize) {
+        let key = PageKey {
+            file: self.id,
+            index,
+        };
+        PAGES.lock().pages.get_mut(&key).unwrap().pins -= 1;
+    }
+
+    fn sync(self: &Arc<Self>) -> Result<(), Error> {
+        let range = PageKey {
+            file: self.id,
+            index: 0,
+        }..=PageKey {
+            file: self.id,
+            index: usize::MAX,
+        };
+        // cleaned before the write, a write during it makes the page dirty again
+        let dirty: Vec<_> = {
+            let mut state = PAGES.lock();
+            state
+                .pages
+                .range_mut(range)
+                .filter(|(_, page)| page.state == PageState::Dirty)
+                .map(|(key, page)| {
+                    page.state = PageState::Clean;
+                    page.pins += 1;
+                    PageRef::new(self.clone(), key.index, page.page)
+                })
+                .collect()
+        };
+
+        let size = self.size.load(Ordering::Relaxed);
+        let mut result = Ok(());
+        for page in dirty {
+            let buff = unsafe { page_buffer(page.page) };
+            if let Err(e) = self.io.write_page(page.index, buff, size) {
+                page.mark_dirty();
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl Drop for FileInner {
+    fn drop(&mut self) {
+        FILES.lock().remove(&self.id);
+        // not referenced by any `PageRef` anymore, so no page is pinned
+        let mut state = PAGES.lock();
+        let keys: Vec<_> = state
+            .pages
+            .range(
+                PageKey {
+                    file: self.id,
+                    index: 0,
+                }..=PageKey {
+                    file: self.id,
+                    index: usize::MAX,
+                },
+            )
+            .map(|(key, page)| (*key, page.state))
+            .collect();
+        let mut dirty = Vec::new();
+        let mut pages = Vec::new();
+        for (key, page_state) in keys {
+            let page = state.remove(key);
+            if page_state == PageState::Dirty {
+                dirty.push((key.index, page));
+            } else {
+                pages.push(page);
+            }
+        }
+        drop(state);
+
+        let size = self.size.load(Ordering::Relaxed);
+        for (index, page) in dirty {
+            let buff = unsafe { page_buffer(page) };
+            if let Err(e) = self.io.write_page(index, buff, size) {
+                error!(target: "page_cache", "Failed to write back page {} of file {}: {}", index, self.id, e);
+            }
+            pages.push(page);
+        }
+        free_pages(pages);
+    }
+}
+
+/// A page of a file pinned in the cache.
+#[derive(Debug)]
+struct PageRef {
+    file: Arc<FileInner>,
+    index: usize,
+    page: PhysicalAddress,
+}
+
+impl PageRef {
+    #[inline]
+    fn new(file: Arc<FileInner>, index: usize, page: PhysicalAddress) -> Self {
+        Self { file, index, page }
+    }
+
+    /// Mark the page to be written back.
+    fn mark_dirty(&self) {
+        let key = PageKey {
+            file: self.file.id,
+            index: self.index,
+        };
+        PAGES.lock().pages.get_mut(&key).unwrap().state = PageState::Dirty;
+    }
+}
+
+impl Drop for PageRef {
+    fn drop(&mut self) {
+        self.file.unpin(self.index);
+    }
+}
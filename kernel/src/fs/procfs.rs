@@ -0,0 +1,112 @@
+use alloc::{string::String, vec::Vec};
+use core::alloc::Layout;
+use hashbrown::HashMap;
+use log::error;
+use spin::lazy::Lazy;
+
+use crate::{
+    create_fs_node,
+    error::Error,
+    fs::node::FsNodeInfos,
+    memory::slab::{self, SlabCache},
+    sync::lockdep::TrackedRwLock,
+    utils::{
+        buffer::Buffer,
+        smart_ptr::{SmartPtr, SmartPtrInner},
+    },
+};
+
+use super::{
+    mount_node,
+    node::{Directory, File, FsNode, FsNodeRef},
+};
+
+/// Generate the content of a proc file, called on each read.
+pub type ProcGenerator = fn() -> String;
+
+/// Named heap cache of the proc file nodes.
+static FS_NODE_CACHE: SlabCache =
+    SlabCache::new("fs_node", Layout::new::<SmartPtrInner<FsNode<ProcFile>>>());
+
+static PROCFS: Lazy<SmartPtr<FsNode<ProcFs>>> = Lazy::new(|| {
+    if let Err(e) = slab::register_named(&FS_NODE_CACHE) {
+        error!("Failed to register the fs node cache: {}", e);
+    }
+    let proc = ProcFs::new();
+    let node = create_fs_node!(
+        proc,
+        FsNodeInfos { size: proc.size() },
+        directory: dyn Directory
+    );
+    let ptr = SmartPtr::new_boxed(node);
+    let node = FsNodeRef::new(SmartPtr::clone(&ptr));
+    let r = mount_node("/proc", node);
+    if let Err(e) = r {
+        error!("Failed to mount procfs: {}", e);
+    }
+    ptr
+});
+
+#[derive(Debug)]
+struct ProcFs {
+    nodes: TrackedRwLock<HashMap<String, FsNodeRef>>,
+}
+
+impl ProcFs {
+    fn new() -> Self {
+        Self {
+            nodes: TrackedRwLock::new(HashMap::new()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        let nodes = self.nodes.read();
+        nodes.len()
+    }
+}
+
+unsafe impl Directory for ProcFs {
+    fn find(&self, name: &str) -> Result<Option<FsNodeRef>, Error> {
+        let nodes = self.nodes.read();
+        Ok(nodes.get(name).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let nodes = self.nodes.read();
+        let keys = nodes.keys().cloned().collect();
+        Ok(keys)
+    }
+}
+
+#[derive(Debug)]
+struct ProcFile {
+    generate: ProcGenerator,
+}
+
+unsafe impl File for ProcFile {
+    fn read(&self, offset: usize, buff: &mut Buffer) -> Result<usize, Error> {
+        let content = (self.generate)();
+        let bytes = content.as_bytes().get(offset..).unwrap_or_default();
+        let len = bytes.len().min(buff.len());
+        Ok(buff.write(0, &bytes[..len]))
+    }
+
+    // the size isn't known before generating the content
+    fn read_to_end_vec(&self, offset: usize) -> Result<Vec<u8>, Error> {
+        let content = (self.generate)();
+        Ok(content.as_bytes().get(offset..).unwrap_or_default().to_vec())
+    }
+}
+
+/// Add a file `name` into the procfs, its content is generated by `generate`.
+///
+/// Panic if a file with the same `name` already exist.
+pub fn add_file<S: Into<String>>(name: S, generate: ProcGenerator) {
+    let file = ProcFile { generate };
+    let node = create_fs_node!(file, FsNodeInfos { size: 0 }, file: dyn File);
+    let node = FsNodeRef::new(SmartPtr::new_boxed(node));
+
+    let mut nodes = PROCFS.nodes.write();
+    let r = nodes.insert(name.into(), node);
+    assert!(r.is_none(), "Proc file already exist");
+}
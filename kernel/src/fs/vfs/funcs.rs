@@ -1,12 +1,13 @@
-use alloc::{format, vec::Vec};
-use spin::lock_api::RwLock;
+use alloc::format;
+use spin::Lazy;
 
 use crate::{
     error::{
         Error,
         FsError::{self, Custom, CustomStr, NotFound},
     },
-    fs::{drivers::get_driver_for_type, path::Path},
+    fs::{drivers::get_driver_for_type, page_cache, path::Path},
+    sync::rcu::RcuList,
 };
 
 use super::node::FsNodeRef;
@@ -46,9 +47,11 @@ where
 pub struct MountPoint {
     pub path: &'static Path,
     pub root_node: FsNodeRef,
+    /// The device the filesystem was mounted from, if any.
+    pub device: Option<FsNodeRef>,
 }
 
-static MOUNTPOINTS: RwLock<Vec<MountPoint>> = RwLock::new(Vec::new());
+static MOUNTPOINTS: Lazy<RcuList<MountPoint>> = Lazy::new(Default::default);
 
 pub fn mount_device<S>(path: S, device: FsNodeRef, fs_type: &str) -> Result<(), Error>
 where
@@ -64,30 +67,56 @@ where
         }
     };
     let root_node = driver.get_root_node(&device)?;
-    mount_node(path, root_node)?;
-    Ok(())
+    add_mountpoint(MountPoint {
+        path: path.into(),
+        root_node,
+        device: Some(device),
+    })
 }
 
 pub fn mount_node<S>(path: S, node: FsNodeRef) -> Result<(), Error>
 where
     S: Into<&'static Path>,
 {
-    let mountpoint = MountPoint {
+    add_mountpoint(MountPoint {
         path: path.into(),
         root_node: node,
-    };
-    let mut mountpoints = MOUNTPOINTS.write();
-    let i = match mountpoints.binary_search_by(|m| m.path.cmp(mountpoint.path)) {
-        Ok(_) => return Err(Error::Fs(CustomStr("Already mounted"))),
-        Err(i) => i,
-    };
-    mountpoints.insert(i, mountpoint);
+        device: None,
+    })
+}
+
+fn add_mountpoint(mountpoint: MountPoint) -> Result<(), Error> {
+    MOUNTPOINTS.modify(|mountpoints| {
+        let i = match mountpoints.binary_search_by(|m| m.path.cmp(mountpoint.path)) {
+            Ok(_) => return Err(Error::Fs(CustomStr("Already mounted"))),
+            Err(i) => i,
+        };
+        mountpoints.insert(i, mountpoint);
+        Ok(())
+    })
+}
+
+/// Unmount the filesystem mounted at `path`, after writing back its cached data.
+pub fn unmount(path: &str) -> Result<(), Error> {
+    let mountpoint = MOUNTPOINTS.modify(|mountpoints| {
+        let i = mountpoints
+            .binary_search_by(|m| m.path.as_str().cmp(path))
+            .map_err(|_| Error::Fs(NotFound))?;
+        Ok::<_, Error>(mountpoints.remove(i))
+    })?;
+
+    if let Some(device) = mountpoint.device {
+        page_cache::sync_device(&device)?;
+        if let Some(block) = device.as_block() {
+            block.sync()?;
+        }
+    }
     Ok(())
 }
 
 /// Find in which filesytem the path is.
 pub fn get_mountpoint(path: &str) -> Option<MountPoint> {
-    let mountpoints = MOUNTPOINTS.read();
+    let mountpoints = MOUNTPOINTS.get();
 
     let mut best: Option<&MountPoint> = None;
     for mountpoint in mountpoints.iter() {
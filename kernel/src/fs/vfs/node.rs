@@ -214,4 +214,15 @@ pub unsafe trait Block: Debug + Send + Sync {
     ///
     /// `buff.len` should equal to the block size.
     fn write_block(&self, block: BlockIndex, buff: &Buffer) -> Result<(), Error>;
+
+    /// Read `buff.len / block_size` blocks from `block` straight from the device, bypassing the cache.
+    ///
+    /// The blocks shouldn't be accessed through the cache, it isn't updated.
+    fn read_direct(&self, block: BlockIndex, buff: &mut Buffer) -> Result<(), Error>;
+
+    /// Write `buff.len / block_size` blocks from `block` straight to the device, bypassing the cache.
+    fn write_direct(&self, block: BlockIndex, buff: &Buffer) -> Result<(), Error>;
+
+    /// Write back the dirty cached blocks to the device.
+    fn sync(&self) -> Result<(), Error>;
 }
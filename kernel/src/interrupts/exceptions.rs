@@ -1,4 +1,11 @@
-use core::{arch::global_asm, fmt::Display, num::NonZeroU8, sync::atomic::Ordering};
+use core::{
+    arch::{asm, global_asm},
+    fmt::Display,
+    mem::size_of,
+    num::NonZeroU8,
+    ptr,
+    sync::atomic::Ordering,
+};
 
 use aarch64_cpu::registers::{DAIF, ESR_EL1, FAR_EL1, VBAR_EL1};
 use log::{error, info, trace};
@@ -6,7 +13,11 @@ use tock_registers::interfaces::{Readable, Writeable};
 
 use crate::{
     cpu::{self, InterruptFrame},
-    scheduler::Cpu,
+    memory::{
+        VirtualAddress,
+        swap::{self, FaultResolution},
+    },
+    scheduler::{CPU_EMERGENCY_STACK_OFFSET, Cpu, current_process, current_thread, preempt},
 };
 
 #[derive(Debug)]
@@ -23,6 +34,7 @@ enum CpuException {
     StackAlignment,
     FloatingPointException,
     SError,
+    BrkInstruction(u16),
 }
 
 impl CpuException {
@@ -43,6 +55,7 @@ impl CpuException {
             0x26 => CpuException::StackAlignment,
             0x28 | 0x2C => CpuException::FloatingPointException,
             0x2F => CpuException::SError,
+            0x3C => CpuException::BrkInstruction(esr as u16),
             _ => CpuException::NotImplemented(esr),
         }
     }
@@ -105,6 +118,7 @@ impl Display for CpuException {
             CpuException::StackAlignment => write!(f, "Stack Alignment Exception"),
             CpuException::FloatingPointException => write!(f, "Floating Point Exception"),
             CpuException::SError => write!(f, "SError Exception"),
+            CpuException::BrkInstruction(imm) => write!(f, "BRK Instruction #{imm:#X}"),
             CpuException::NotImplemented(esr) => {
                 let ec = esr >> 26;
                 let iss = esr & 0x1FFFFFF;
@@ -117,7 +131,10 @@ impl Display for CpuException {
     }
 }
 
-global_asm!(include_str!("asm.S"));
+global_asm!(
+    include_str!("asm.S"),
+    CPU_EMERGENCY_STACK = const CPU_EMERGENCY_STACK_OFFSET
+);
 
 unsafe extern "C" {
     #[allow(improper_ctypes)]
@@ -131,12 +148,118 @@ pub fn init() {
     info!("Exceptions initialized");
 }
 
+/// Fault status codes of the aborts which may be resolved by the swap, for any level.
+const ABORT_TRANSLATION_FAULT: u32 = 0b0001;
+const ABORT_ACCESS_FLAG_FAULT: u32 = 0b0010;
+const ABORT_PERMISSION_FAULT: u32 = 0b0011;
+/// Mode field of PSTATE for EL1 using SP_EL0, the one of the threads.
+const SPSR_EL1T: usize = 0b0100;
+/// Immediate of the `brk` ending `swap_in_trampoline`.
+const SWAP_IN_RESUME_BRK: u16 = 0x5A;
+
+/// Handler of the synchronous exceptions of the threads, taken on SP_EL0, and of EL0.
+///
+/// The faults on the user pages not accessed since the last scan of the swap or swapped out are resolved, the other exceptions are fatal.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn thread_exception_handler(frame: *mut InterruptFrame) -> *mut InterruptFrame {
+    let exception = CpuException::from_esr(ESR_EL1.get() as u32);
+    match exception {
+        CpuException::DataAbort(far, esr) | CpuException::InstructionAbort(far, esr)
+            if matches!(
+                (esr >> 2) & 0b1111,
+                ABORT_TRANSLATION_FAULT | ABORT_ACCESS_FLAG_FAULT | ABORT_PERMISSION_FAULT
+            ) =>
+        {
+            if let Some(thread) = Cpu::current().try_current_thread() {
+                let far = VirtualAddress::new(far as usize);
+                match swap::handle_fault(far, thread.process().get_addr_space()) {
+                    FaultResolution::Resolved => return frame,
+                    FaultResolution::SwapIn if can_sleep(unsafe { &*frame }) => {
+                        unsafe { redirect_to_swap_in(&mut *frame, far) };
+                        return frame;
+                    }
+                    FaultResolution::SwapIn => {
+                        error!(target: "panic", "Swapped out page {} accessed with IRQs or preemption disabled", far);
+                    }
+                    FaultResolution::Invalid => {}
+                }
+            }
+        }
+        CpuException::BrkInstruction(SWAP_IN_RESUME_BRK) => {
+            // restore the context saved by `redirect_to_swap_in`
+            unsafe {
+                let saved = (*frame).x0 as *const InterruptFrame;
+                ptr::copy_nonoverlapping(saved, frame, 1);
+            }
+            return frame;
+        }
+        _ => {}
+    }
+    unsafe { exception_handler(frame) }
+}
+
+/// Return true if the context of `frame` may sleep.
+#[inline]
+fn can_sleep(frame: &InterruptFrame) -> bool {
+    let cpu = Cpu::current();
+    frame.pstate & (1 << 7) == 0
+        && cpu.irqs_depth.load(Ordering::Relaxed) == 0
+        && preempt::preempt_count() == 0
+}
+
+/// Make the faulting thread of `frame` run `swap_in_trampoline` to read back the page at `addr`.
+///
+/// The swap in sleeps, so it can't be done by the exception handler. The context of the
+/// thread is saved on its stack and restored by the `brk` ending the trampoline.
+unsafe fn redirect_to_swap_in(frame: &mut InterruptFrame, addr: VirtualAddress) {
+    let saved = (frame.sp - size_of::<InterruptFrame>()) & !0xF;
+    unsafe { ptr::copy_nonoverlapping(frame as *const InterruptFrame, saved as *mut _, 1) };
+    frame.x0 = addr.addr();
+    frame.x1 = saved;
+    // end of the frame chain for the backtraces
+    frame.x29 = 0;
+    frame.x30 = 0;
+    frame.sp = saved;
+    frame.pc = swap_in_trampoline as *const () as usize;
+    // the trampoline runs in the kernel even for a fault of EL0, the saved PSTATE returns to EL0 after
+    frame.pstate = (frame.pstate & !0b1111) | SPSR_EL1T;
+}
+
+extern "C" fn swap_in_trampoline(addr: usize, saved: *const InterruptFrame) -> ! {
+    let addr = VirtualAddress::new(addr);
+    if let Err(e) = swap::swap_in(addr, current_process().get_addr_space()) {
+        panic!(
+            "Failed to swap in {} in thread {}: {}",
+            addr,
+            current_thread().id(),
+            e
+        );
+    }
+    unsafe {
+        asm!("brk #{}", const SWAP_IN_RESUME_BRK, in("x0") saved, options(noreturn));
+    }
+}
+
 #[unsafe(no_mangle)]
-unsafe extern "C" fn exception_handler(frame: *mut InterruptFrame) {
+unsafe extern "C" fn exception_handler(frame: *mut InterruptFrame) -> ! {
     let frame = unsafe { frame.as_mut() }.unwrap();
     error!(target: "panic", "Exception in CPU {}", cpu::id());
     error!(target: "panic", "{}", frame);
-    panic!("{}", CpuException::from_esr(ESR_EL1.get() as u32));
+    let exception = CpuException::from_esr(ESR_EL1.get() as u32);
+    if let CpuException::DataAbort(far, _) = exception
+        && let Some(thread) = Cpu::current().try_current_thread()
+    {
+        let far = VirtualAddress::new(far as usize);
+        if thread.kernel_stack_guard().contains(&far) {
+            error!(target: "panic", "{}", exception);
+            panic!("kernel stack overflow in thread {}", thread.id());
+        }
+        if thread.stack_guard().contains(&far) {
+            error!(target: "panic", "{}", exception);
+            panic!("stack overflow in thread {}", thread.id());
+        }
+    }
+    panic!("{}", exception);
 }
 
 #[unsafe(no_mangle)]
@@ -0,0 +1,137 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    hint, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use log::trace;
+
+use crate::{
+    cpu::InterruptFrame,
+    interrupts::exceptions::{disable_exceptions_depth, restore_exceptions_depth},
+    per_cpu,
+    scheduler::{Cpu, SCHEDULER},
+    sync::no_irq_locks::NoIrqMutex,
+};
+
+use super::CoreSelection;
+
+/// SGI sent to make a CPU reschedule.
+pub const RESCHEDULE_SGI: u8 = 0;
+/// SGI sent to make a CPU run its queued function calls.
+pub const CALL_FUNCTION_SGI: u8 = 1;
+
+/// CPUs a function call is sent to. Offline CPUs are always skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallTarget {
+    Cpu(u32),
+    Others,
+    All,
+}
+
+#[derive(Debug)]
+struct Call {
+    func: fn(usize),
+    arg: usize,
+    /// Count of CPUs that didn't run the function yet.
+    pending: AtomicUsize,
+}
+
+per_cpu! {
+    static QUEUE: NoIrqMutex<VecDeque<Arc<Call>>> = NoIrqMutex::new(VecDeque::new());
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Called by the scheduler once the CPUs are registered.
+pub(crate) fn init() {
+    super::set_irq_handler(CALL_FUNCTION_SGI as u32, interrupt_handler, 0);
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+#[inline]
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Make the CPU `cpu_id` reschedule.
+#[inline]
+pub fn send_reschedule(cpu_id: u32) {
+    super::chip().send_sgi(CoreSelection::Mask(1 << cpu_id), RESCHEDULE_SGI);
+}
+
+/// Run `func(arg)` on the CPUs selected by `target`, with interrupts disabled.
+///
+/// If `wait` is true, return once all the CPUs ran the function. Waiting with
+/// IRQs disabled isn't allowed since the CPUs could wait for each other.
+pub fn call_function(target: CallTarget, func: fn(usize), arg: usize, wait: bool) {
+    assert!(is_initialized(), "IPIs not initialized");
+    let current = Cpu::current();
+    debug_assert!(
+        !wait || current.irqs_depth.load(Ordering::Relaxed) == 0,
+        "Waiting for a cross-CPU call with IRQs disabled"
+    );
+
+    let run_locally = match target {
+        CallTarget::Cpu(id) => id == current.id && current.is_online(),
+        CallTarget::Others => false,
+        CallTarget::All => true,
+    };
+    let remotes = SCHEDULER.cpus().iter().filter(|cpu| {
+        !ptr::eq(*cpu, current)
+            && match target {
+                CallTarget::Cpu(id) => cpu.id == id,
+                CallTarget::Others | CallTarget::All => true,
+            }
+    });
+
+    let call = Arc::new(Call {
+        func,
+        arg,
+        pending: AtomicUsize::new(0),
+    });
+
+    let mut mask = 0u8;
+    for cpu in remotes {
+        let mut queue = QUEUE.get(cpu.index).unwrap().lock();
+        // checked with the queue locked, a CPU going offline runs its queue after clearing the flag
+        if !cpu.is_online() {
+            continue;
+        }
+        call.pending.fetch_add(1, Ordering::Relaxed);
+        queue.push_back(Arc::clone(&call));
+        mask |= 1 << cpu.id;
+    }
+    if mask != 0 {
+        trace!(target: "interrupts", "Send call function IPI to CPUs {:#b}", mask);
+        super::chip().send_sgi(CoreSelection::Mask(mask), CALL_FUNCTION_SGI);
+    }
+
+    if run_locally {
+        disable_exceptions_depth();
+        func(arg);
+        restore_exceptions_depth();
+    }
+
+    if wait {
+        while call.pending.load(Ordering::Acquire) != 0 {
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Run the calls queued for the current CPU.
+pub(crate) fn handle_calls() {
+    loop {
+        let Some(call) = QUEUE.with(|queue| queue.lock().pop_front()) else {
+            break;
+        };
+        (call.func)(call.arg);
+        call.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+fn interrupt_handler(_id: u32, frame: *mut InterruptFrame, _: usize) -> *mut InterruptFrame {
+    handle_calls();
+    frame
+}
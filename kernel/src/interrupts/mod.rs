@@ -9,6 +9,8 @@ use spin::lock_api::RwLock;
 use crate::{cpu::InterruptFrame, interrupts::exceptions::get_exception_state, scheduler::Cpu};
 
 pub mod exceptions;
+pub mod ipi;
+pub mod softirq;
 
 pub trait InterruptsChip: Sync + Send {
     fn init_ap(&self);
@@ -102,7 +104,11 @@ unsafe extern "C" fn interrupt_handler(frame: *mut InterruptFrame) -> *mut Inter
         get_exception_state(),
         Cpu::current().irqs_depth.load(Ordering::Relaxed),
     );
+    #[cfg(feature = "lockdep")]
+    crate::sync::lockdep::irq_enter();
     let r = handler(id, frame, val);
+    #[cfg(feature = "lockdep")]
+    crate::sync::lockdep::irq_exit();
     let after = (
         get_exception_state(),
         Cpu::current().irqs_depth.load(Ordering::Relaxed),
@@ -118,7 +124,7 @@ unsafe extern "C" fn interrupt_handler(frame: *mut InterruptFrame) -> *mut Inter
     r
 }
 
-/// Handler will be run with interrupt disabled
+/// Handler will be run with interrupt disabled, slow work should be deferred with [softirq::raise].
 pub fn set_irq_handler(id: u32, handler: Handler, val: usize) {
     assert!(id < 1020);
     let ptr = handler as usize;
@@ -0,0 +1,97 @@
+use alloc::{collections::VecDeque, format};
+use core::mem;
+use log::trace;
+
+use crate::{
+    interrupts::{CoreSelection, ipi::RESCHEDULE_SGI},
+    per_cpu,
+    scheduler::{
+        SCHEDULER, block_thread_drop, current_thread, kthread,
+        thread::{Priority, ThreadRef},
+        wake_thread,
+    },
+    sync::no_irq_locks::NoIrqMutex,
+    utils::sync_once_cell::SyncOnceCell,
+};
+
+/// A function deferred by an interrupt handler. Shouldn't sleep.
+pub type BottomHalf = fn(usize);
+
+#[derive(Debug)]
+struct Pending {
+    queue: VecDeque<(BottomHalf, usize)>,
+    /// The softirq thread is blocked waiting for work.
+    idle: bool,
+}
+
+#[derive(Debug)]
+struct SoftirqCpu {
+    pending: NoIrqMutex<Pending>,
+    thread: SyncOnceCell<ThreadRef>,
+}
+
+per_cpu! {
+    static CPUS: SoftirqCpu = SoftirqCpu {
+        pending: NoIrqMutex::new(Pending {
+            queue: VecDeque::new(),
+            idle: false,
+        }),
+        thread: SyncOnceCell::new(),
+    };
+}
+
+/// Create a softirq thread for each CPU, called once the boot CPU runs its first thread.
+pub(crate) fn init() {
+    for cpu in SCHEDULER.cpus() {
+        let index = cpu.index;
+        let handle = kthread::spawn_on(cpu.id, format!("softirq/{}", cpu.id), move || {
+            softirq_thread(index)
+        })
+        .expect("Failed to create softirq thread");
+        handle.thread().set_priority(Priority::MAX);
+        unsafe { softirq_cpu(index).thread.set(handle.thread().clone()).unwrap() };
+    }
+}
+
+#[inline]
+fn softirq_cpu(index: usize) -> &'static SoftirqCpu {
+    CPUS.get(index).expect("Invalid CPU index")
+}
+
+/// Defer `handler(data)` to the softirq thread of the current CPU.
+///
+/// Meant to be called from interrupt context: the handler will run as soon as
+/// the interrupt returns, with interrupts enabled.
+pub fn raise(handler: BottomHalf, data: usize) {
+    CPUS.with(|cpu| {
+        let mut pending = cpu.pending.lock();
+        pending.queue.push_back((handler, data));
+        if pending.idle {
+            pending.idle = false;
+            drop(pending);
+            let thread = cpu.thread.get().expect("No softirq thread");
+            assert!(wake_thread(thread));
+            // reschedule right after the interrupt so the softirq thread preempts the current one
+            super::chip().send_sgi(CoreSelection::Me, RESCHEDULE_SGI);
+        }
+    });
+}
+
+fn softirq_thread(index: usize) -> ! {
+    let cpu = softirq_cpu(index);
+    loop {
+        let mut pending = cpu.pending.lock();
+        if pending.queue.is_empty() {
+            pending.idle = true;
+            block_thread_drop(pending);
+            continue;
+        }
+        let queue = mem::take(&mut pending.queue);
+        drop(pending);
+
+        trace!(target: "interrupts", "Run {} bottom halves on thread {}", queue.len(), current_thread().id());
+        for (handler, data) in queue {
+            handler(data);
+        }
+    }
+}
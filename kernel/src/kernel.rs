@@ -22,17 +22,20 @@ pub mod cpu;
 pub mod device_tree;
 pub mod devices;
 pub mod error;
+pub mod executor;
 pub mod fs;
 pub mod interrupts;
 pub mod logger;
 pub mod memory;
 pub mod modules;
+pub mod power;
 pub mod psci;
 pub mod scheduler;
 pub mod symbols;
 pub mod sync;
 pub mod timer;
 pub mod utils;
+pub mod workqueue;
 
 extern crate alloc;
 
@@ -123,6 +126,7 @@ extern "C" fn main(
 
     memory::init(memory_map);
     unsafe { fs::init(initrd_ptr, initrd_len) };
+    memory::stats::init();
     symbols::init();
     psci::init();
     gic_v2::init();
@@ -135,9 +139,19 @@ extern "C" fn main(
 }
 
 fn later_main() -> ! {
+    // the kernel threads can only be spawned once the boot CPU runs one
+    interrupts::softirq::init();
+    workqueue::init();
+    sync::rcu::init();
+    fs::block::init();
     pcie::init();
 
     modules::load("/initrd/ext2.kmod").unwrap();
+    // once the drivers of the swap device are loaded
+    memory::swap::swapon_from_bootargs();
 
+    if cfg!(feature = "poweroff_on_exit") {
+        power::power_off();
+    }
     exit(0);
 }
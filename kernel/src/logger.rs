@@ -36,6 +36,8 @@ const TARGET_BLACKLIST_TRACE: &[&str] = &[
     "fs",
     "exceptions",
     "devices",
+    "workqueue",
+    "rcu",
 ];
 
 const MODULES_BLACKLIST: &[&str] = &["nvme"];
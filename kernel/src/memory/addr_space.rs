@@ -14,15 +14,20 @@ use super::{
 
 #[derive(Debug)]
 pub struct VirtualAddressSpace {
-    pub ptr: *mut TableEntry, // the first table
-    pub is_low: bool,         // TTBR0 or TTBR1 (before or after hole)
+    pub ptr: *mut TableEntry,         // the first table
+    pub is_low: bool,                 // TTBR0 or TTBR1 (before or after hole)
+    pub(super) resident_pages: usize, // pages allocated by the vmm
 }
 
 impl VirtualAddressSpace {
     pub unsafe fn new(addr: PhysicalAddress, is_low: bool) -> Self {
         debug_assert!(addr.addr() != 0);
         let ptr = addr.to_virt().as_ptr::<TableEntry>();
-        Self { ptr, is_low }
+        Self {
+            ptr,
+            is_low,
+            resident_pages: 0,
+        }
     }
 
     // return None if out of memory
@@ -35,6 +40,12 @@ impl VirtualAddressSpace {
         Some(unsafe { Self::new(l1, true) })
     }
 
+    /// Count of pages allocated by the vmm in this address space.
+    #[inline]
+    pub fn resident_pages(&self) -> usize {
+        self.resident_pages
+    }
+
     #[inline]
     pub fn get_table(&self) -> &'static [TableEntry] {
         unsafe { slice::from_raw_parts(self.ptr, ENTRIES_IN_TABLE) }
@@ -58,7 +69,10 @@ impl Display for VirtualAddressSpace {
 
 impl Drop for VirtualAddressSpace {
     fn drop(&mut self) {
-        // TODO
+        // the kernel address space lives forever
+        if self.is_low {
+            vmm::vmm().destroy_addr_space(self);
+        }
     }
 }
 
@@ -103,6 +117,15 @@ impl<'a> AddrSpaceSelector<'a> {
         Self::Locked(vmm::get_kernel_addr_space())
     }
 
+    /// Borrow the selector for a shorter lifetime, to lock it more than once.
+    #[inline]
+    pub fn reborrow(&mut self) -> AddrSpaceSelector<'_> {
+        match self {
+            Self::Locked(lock) => AddrSpaceSelector::Locked(lock),
+            Self::Unlocked(addr_space) => AddrSpaceSelector::Unlocked(addr_space),
+        }
+    }
+
     pub fn lock(self) -> Guard<'a> {
         match self {
             Self::Locked(lock) => Guard {
@@ -0,0 +1,57 @@
+use core::arch::asm;
+
+use super::VirtualAddress;
+
+/// Size in bytes of the smallest data cache line of the CPUs.
+#[inline]
+pub fn dcache_line_size() -> usize {
+    let ctr: usize;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags)) };
+    // DminLine: log2 of the count of words
+    4 << ((ctr >> 16) & 0xF)
+}
+
+/// Start addresses of the cache lines holding the `len` bytes from `addr`.
+#[inline]
+fn lines(addr: VirtualAddress, len: usize) -> impl Iterator<Item = usize> {
+    let line = dcache_line_size();
+    (addr.addr() & !(line - 1)..addr.addr() + len).step_by(line)
+}
+
+/// Wait for the cache maintenance to be visible to the devices.
+#[inline]
+fn barrier() {
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// Write back the `len` bytes from `addr` to the memory, so a device reads them.
+pub fn clean_range(addr: VirtualAddress, len: usize) {
+    for line in lines(addr, len) {
+        unsafe { asm!("dc cvac, {}", in(reg) line, options(nostack, preserves_flags)) };
+    }
+    barrier();
+}
+
+/// Write back and discard the `len` bytes from `addr`.
+pub fn clean_invalidate_range(addr: VirtualAddress, len: usize) {
+    for line in lines(addr, len) {
+        unsafe { asm!("dc civac, {}", in(reg) line, options(nostack, preserves_flags)) };
+    }
+    barrier();
+}
+
+/// Discard the `len` bytes from `addr`, so the CPU reads what a device wrote.
+///
+/// The lines partially in the range are written back first, to keep the bytes around.
+pub fn invalidate_range(addr: VirtualAddress, len: usize) {
+    let line_size = dcache_line_size();
+    let end = addr.addr() + len;
+    for line in lines(addr, len) {
+        if line < addr.addr() || line + line_size > end {
+            unsafe { asm!("dc civac, {}", in(reg) line, options(nostack, preserves_flags)) };
+        } else {
+            unsafe { asm!("dc ivac, {}", in(reg) line, options(nostack, preserves_flags)) };
+        }
+    }
+    barrier();
+}
@@ -5,6 +5,8 @@ use super::VirtualAddress;
 pub const PAGE_SHIFT: usize = 12;
 pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT; // 4 KB
 pub const ENTRIES_IN_TABLE: usize = PAGE_SIZE / size_of::<usize>();
+/// Pages in a 2 MB block.
+pub const HUGE_PAGE_PAGES: usize = ENTRIES_IN_TABLE;
 
 pub const INVALID_VIRT_ADDRESS_RANGE: Range<usize> = 0x8000000000..0xFFFF000000000000;
 
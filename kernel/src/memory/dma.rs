@@ -1,24 +1,198 @@
 use core::{
-    mem::size_of,
+    alloc::Layout,
+    marker::PhantomData,
+    mem::{align_of, size_of},
     ops::{Deref, DerefMut},
-    ptr::NonNull,
+    ptr::{self, NonNull},
     slice,
 };
 
-use crate::error::Error;
+use alloc::vec::Vec;
+
+use crate::{
+    error::{Error, MemoryError::*},
+    sync::no_irq_locks::NoIrqMutex,
+    utils::buffer::Buffer,
+};
 
 use super::{
-    vmm::{vmm, MapFlags},
-    AddrSpaceSelector, MemoryUsage, PhysicalAddress, VirtualAddress, PAGE_SIZE,
+    AddrSpaceSelector, MemoryUsage, PAGE_SIZE, PhysicalAddress, VirtualAddress, cache,
+    vmm::{MapFlags, vmm},
 };
 
+/// Size of the objects of the smallest pool, the pools hold powers of 2 up to `POOL_MAX_SIZE`.
+const POOL_MIN_SIZE: usize = 64;
+const POOL_MAX_SIZE: usize = PAGE_SIZE / 2;
+
+/// The pools of the small coherent allocations reaching all the memory.
+static POOLS: [DmaPool; 6] = [
+    DmaPool::new(64, DmaMask::ALL),
+    DmaPool::new(128, DmaMask::ALL),
+    DmaPool::new(256, DmaMask::ALL),
+    DmaPool::new(512, DmaMask::ALL),
+    DmaPool::new(1024, DmaMask::ALL),
+    DmaPool::new(2048, DmaMask::ALL),
+];
+
+/// Normal non cacheable memory, so the CPU and the devices always see the same data.
+#[inline]
+fn coherent_flags() -> MapFlags {
+    MapFlags::new(false, false, 0b11, 0, false)
+}
+
+/// Allocate `count` contiguous coherent pages reachable with `mask`.
+fn alloc_coherent_pages(
+    count: usize,
+    mask: DmaMask,
+) -> Result<(VirtualAddress, PhysicalAddress), Error> {
+    let vaddr = if mask == DmaMask::ALL {
+        vmm().alloc_pages(
+            count,
+            MemoryUsage::KernelData,
+            coherent_flags(),
+            AddrSpaceSelector::kernel(),
+        )?
+    } else {
+        vmm().alloc_pages_below(
+            count,
+            mask.limit(),
+            MemoryUsage::KernelData,
+            coherent_flags(),
+            AddrSpaceSelector::kernel(),
+        )?
+    };
+    let phys = vaddr.to_phys().unwrap();
+    // the pages may still be in the caches through the linear mapping
+    cache::clean_invalidate_range(phys.to_virt(), count * PAGE_SIZE);
+    Ok((vaddr, phys))
+}
+
+/// The physical addresses a device can reach, from 0 to a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaMask(usize);
+
+impl DmaMask {
+    /// The devices reaching all the memory.
+    pub const ALL: Self = Self(usize::MAX);
+
+    /// The devices reaching the addresses of `bits` bits.
+    #[inline]
+    pub const fn bits(bits: u32) -> Self {
+        if bits >= usize::BITS {
+            Self::ALL
+        } else {
+            Self(1 << bits)
+        }
+    }
+
+    /// The end of the reachable addresses.
+    #[inline]
+    pub const fn limit(self) -> usize {
+        self.0
+    }
+
+    /// Return true if the device reaches the `len` bytes from `addr`.
+    #[inline]
+    pub fn contains(self, addr: PhysicalAddress, len: usize) -> bool {
+        addr.addr()
+            .checked_add(len)
+            .is_some_and(|end| end <= self.0)
+    }
+}
+
+impl Default for DmaMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// Coherent blocks of a power of 2 bytes carved from pages, for the DMA allocations smaller than a page.
+///
+/// The blocks are aligned on their size and never cross a page. The pages are freed with the pool.
+#[derive(Debug)]
+pub struct DmaPool {
+    size: usize,
+    mask: DmaMask,
+    inner: NoIrqMutex<PoolInner>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    pages: Vec<VirtualAddress>,
+    free: Vec<(VirtualAddress, PhysicalAddress)>,
+}
+
+impl DmaPool {
+    pub const fn new(size: usize, mask: DmaMask) -> Self {
+        assert!(size.is_power_of_two() && size <= PAGE_SIZE);
+        Self {
+            size,
+            mask,
+            inner: NoIrqMutex::new(PoolInner {
+                pages: Vec::new(),
+                free: Vec::new(),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take a block, carving a new page if none is free.
+    pub fn alloc(&self) -> Result<(VirtualAddress, PhysicalAddress), Error> {
+        let mut inner = self.inner.lock();
+        if let Some(block) = inner.free.pop() {
+            return Ok(block);
+        }
+        let (vaddr, phys) = alloc_coherent_pages(1, self.mask)?;
+        inner.pages.push(vaddr);
+        inner.free.extend(
+            (self.size..PAGE_SIZE)
+                .step_by(self.size)
+                .map(|off| (vaddr + off, phys + off)),
+        );
+        Ok((vaddr, phys))
+    }
+
+    /// Give back a block of `alloc`.
+    ///
+    /// # Safety
+    /// The block must come from this pool and not be used anymore, by the CPU or a device.
+    pub unsafe fn free(&self, addr: VirtualAddress, phys: PhysicalAddress) {
+        debug_assert!(addr.is_aligned_to(self.size));
+        self.inner.lock().free.push((addr, phys));
+    }
+}
+
+impl Drop for DmaPool {
+    fn drop(&mut self) {
+        for page in self.inner.get_mut().pages.drain(..) {
+            vmm()
+                .dealloc_pages(page, 1, AddrSpaceSelector::kernel())
+                .unwrap();
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Backing {
+    Pages(usize),
+    Pool(&'static DmaPool),
+}
+
+/// Coherent memory shared with a device, the small allocations come from pools.
 #[derive(Debug)]
 pub struct Dma<T: ?Sized> {
     phys: PhysicalAddress,
-    page_count: usize,
     ptr: NonNull<T>,
+    backing: Backing,
 }
 
+unsafe impl<T: ?Sized + Send> Send for Dma<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for Dma<T> {}
+
 impl<T: ?Sized> Deref for Dma<T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
@@ -34,13 +208,13 @@ impl<T: ?Sized> DerefMut for Dma<T> {
 
 impl<T: ?Sized> Drop for Dma<T> {
     fn drop(&mut self) {
-        vmm()
-            .dealloc_pages(
-                VirtualAddress::new(self.ptr.as_ptr() as *const () as usize),
-                self.page_count,
-                AddrSpaceSelector::kernel(),
-            )
-            .unwrap();
+        let addr = VirtualAddress::from_ptr(self.ptr.as_ptr());
+        match self.backing {
+            Backing::Pages(page_count) => vmm()
+                .dealloc_pages(addr, page_count, AddrSpaceSelector::kernel())
+                .unwrap(),
+            Backing::Pool(pool) => unsafe { pool.free(addr, self.phys) },
+        }
     }
 }
 
@@ -54,45 +228,218 @@ impl<T: ?Sized> Dma<T> {
     pub fn ptr(&self) -> *mut T {
         self.ptr.as_ptr()
     }
+
+    /// Allocate coherent memory for `layout`, reachable with `mask`. The alignment is at most a page.
+    fn alloc(
+        layout: Layout,
+        mask: DmaMask,
+    ) -> Result<(VirtualAddress, PhysicalAddress, Backing), Error> {
+        if layout.align() > PAGE_SIZE {
+            return Err(Error::CustomStr("DMA alignment above a page"));
+        }
+        let size = layout.pad_to_align().size().max(POOL_MIN_SIZE);
+        if mask == DmaMask::ALL && size <= POOL_MAX_SIZE {
+            let index = (size.next_power_of_two() / POOL_MIN_SIZE).trailing_zeros();
+            let pool = &POOLS[index as usize];
+            let (vaddr, phys) = pool.alloc()?;
+            return Ok((vaddr, phys, Backing::Pool(pool)));
+        }
+        let page_count = size.div_ceil(PAGE_SIZE);
+        let (vaddr, phys) = alloc_coherent_pages(page_count, mask)?;
+        Ok((vaddr, phys, Backing::Pages(page_count)))
+    }
 }
 
 impl<T> Dma<T> {
     pub unsafe fn new() -> Result<Self, Error> {
-        let size = size_of::<T>();
-        let page_count = size.div_ceil(PAGE_SIZE);
-        let vaddr = vmm().alloc_pages(
-            page_count,
-            MemoryUsage::KernelData,
-            MapFlags::new(false, false, 0, 2, false),
-            AddrSpaceSelector::kernel(),
-        )?;
-        let phys = vaddr.to_phys().unwrap();
+        unsafe { Self::new_in(DmaMask::ALL, 1) }
+    }
+
+    /// Allocate coherent memory for a `T` reachable with `mask` and aligned on at least `align` bytes.
+    ///
+    /// # Safety
+    /// The memory isn't initialized, `T` must be valid for any bit pattern, including the ones
+    /// written by the device.
+    pub unsafe fn new_in(mask: DmaMask, align: usize) -> Result<Self, Error> {
+        let layout = Layout::from_size_align(size_of::<T>(), align.max(align_of::<T>()))
+            .map_err(|_| Error::CustomStr("Invalid DMA alignment"))?;
+        let (vaddr, phys, backing) = Self::alloc(layout, mask)?;
 
         Ok(Self {
             phys,
-            page_count,
             ptr: unsafe { NonNull::new_unchecked(vaddr.as_ptr()) },
+            backing,
         })
     }
 }
 
 impl<T> Dma<[T]> {
     pub unsafe fn new_slice(len: usize) -> Result<Self, Error> {
-        let size = size_of::<T>() * len;
-        let page_count = size.div_ceil(PAGE_SIZE);
-        let vaddr = vmm().alloc_pages(
-            page_count,
-            MemoryUsage::KernelData,
-            MapFlags::new(false, false, 0, 2, false),
-            AddrSpaceSelector::kernel(),
-        )?;
-        let phys = vaddr.to_phys().unwrap();
+        unsafe { Self::new_slice_in(len, DmaMask::ALL, 1) }
+    }
+
+    /// Allocate coherent memory for `len` `T` reachable with `mask` and aligned on at least `align` bytes.
+    ///
+    /// # Safety
+    /// The memory isn't initialized, `T` must be valid for any bit pattern, including the ones
+    /// written by the device.
+    pub unsafe fn new_slice_in(len: usize, mask: DmaMask, align: usize) -> Result<Self, Error> {
+        let layout = Layout::array::<T>(len)
+            .and_then(|layout| layout.align_to(align))
+            .map_err(|_| Error::CustomStr("Invalid DMA layout"))?;
+        let (vaddr, phys, backing) = Self::alloc(layout, mask)?;
         let slice = unsafe { slice::from_raw_parts_mut(vaddr.as_ptr::<T>(), len) };
 
         Ok(Self {
             phys,
-            page_count,
             ptr: unsafe { NonNull::new_unchecked(&mut *slice) },
+            backing,
         })
     }
 }
+
+/// Direction of the data of a streaming DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    Bidirectional,
+}
+
+/// A physically contiguous part of a streaming DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaSegment {
+    pub addr: PhysicalAddress,
+    pub len: usize,
+}
+
+/// A `Buffer` mapped for a transfer of a device, as the list of its physically contiguous segments.
+///
+/// The caches are maintained for the direction when mapping and unmapping, the CPU must not access the buffer
+/// in between. The buffers a device can't reach, and the buffers written by the device not aligned on the cache
+/// lines, are transferred through a coherent bounce buffer.
+#[derive(Debug)]
+pub struct DmaMapping<'a> {
+    buff: NonNull<Buffer>,
+    direction: DmaDirection,
+    segments: Vec<DmaSegment>,
+    bounce: Option<Dma<[u8]>>,
+    _marker: PhantomData<&'a mut Buffer>,
+}
+
+unsafe impl Send for DmaMapping<'_> {}
+unsafe impl Sync for DmaMapping<'_> {}
+
+impl<'a> DmaMapping<'a> {
+    /// Map `buff` for a device reading it.
+    pub fn to_device(buff: &'a Buffer, mask: DmaMask) -> Result<Self, Error> {
+        // never written with this direction
+        unsafe { Self::map(NonNull::from(buff), DmaDirection::ToDevice, mask) }
+    }
+
+    /// Map `buff` for a device accessing it in `direction`.
+    pub fn new(
+        buff: &'a mut Buffer,
+        direction: DmaDirection,
+        mask: DmaMask,
+    ) -> Result<Self, Error> {
+        unsafe { Self::map(NonNull::from(buff), direction, mask) }
+    }
+
+    unsafe fn map(
+        buff: NonNull<Buffer>,
+        direction: DmaDirection,
+        mask: DmaMask,
+    ) -> Result<Self, Error> {
+        let buffer = unsafe { buff.as_ref() };
+        let addr = VirtualAddress::from_ptr(buffer.inner().as_ptr());
+        let mut segments = Self::segments_of(buffer)?;
+
+        // a line shared with other data could be written back over what the device wrote
+        let line_size = cache::dcache_line_size();
+        let aligned = direction == DmaDirection::ToDevice
+            || (addr.addr().is_multiple_of(line_size) && buffer.len().is_multiple_of(line_size));
+        let bounce = if aligned && segments.iter().all(|s| mask.contains(s.addr, s.len)) {
+            match direction {
+                DmaDirection::ToDevice => cache::clean_range(addr, buffer.len()),
+                DmaDirection::FromDevice | DmaDirection::Bidirectional => {
+                    cache::clean_invalidate_range(addr, buffer.len())
+                }
+            }
+            None
+        } else {
+            let mut bounce = unsafe { Dma::<[u8]>::new_slice_in(buffer.len(), mask, 1)? };
+            if direction != DmaDirection::FromDevice {
+                bounce.copy_from_slice(buffer);
+            }
+            segments.clear();
+            segments.push(DmaSegment {
+                addr: bounce.phys(),
+                len: buffer.len(),
+            });
+            Some(bounce)
+        };
+
+        Ok(Self {
+            buff,
+            direction,
+            segments,
+            bounce,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Split `buff` where its pages aren't physically contiguous.
+    fn segments_of(buff: &Buffer) -> Result<Vec<DmaSegment>, Error> {
+        let start = VirtualAddress::from_ptr(buff.inner().as_ptr());
+        let mut segments: Vec<DmaSegment> = Vec::new();
+        let mut offset = 0;
+        while offset < buff.len() {
+            let addr = start + offset;
+            let len = (PAGE_SIZE - addr.addr() % PAGE_SIZE).min(buff.len() - offset);
+            let phys = addr.to_phys().ok_or(Error::Memory(NotMapped))?;
+            match segments.last_mut() {
+                Some(last) if last.addr + last.len == phys => last.len += len,
+                _ => segments.push(DmaSegment { addr: phys, len }),
+            }
+            offset += len;
+        }
+        Ok(segments)
+    }
+
+    #[inline]
+    pub fn direction(&self) -> DmaDirection {
+        self.direction
+    }
+
+    #[inline]
+    pub fn segments(&self) -> &[DmaSegment] {
+        &self.segments
+    }
+
+    /// Return true if the buffer is transferred through a bounce buffer.
+    #[inline]
+    pub fn is_bounced(&self) -> bool {
+        self.bounce.is_some()
+    }
+}
+
+impl Drop for DmaMapping<'_> {
+    fn drop(&mut self) {
+        if self.direction == DmaDirection::ToDevice {
+            return;
+        }
+        // the buffer is borrowed mutably for the other directions
+        let buff = unsafe { self.buff.as_mut() };
+        match &self.bounce {
+            Some(bounce) => unsafe {
+                ptr::copy_nonoverlapping(bounce.ptr() as *const u8, buff.as_mut_ptr(), buff.len())
+            },
+            None => {
+                cache::invalidate_range(VirtualAddress::from_ptr(buff.inner().as_ptr()), buff.len())
+            }
+        }
+    }
+}
@@ -1,9 +1,10 @@
 #![allow(unsafe_op_in_unsafe_fn)]
 
 use core::{
-    alloc::GlobalAlloc,
+    alloc::{GlobalAlloc, Layout},
     mem::size_of,
     ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use log::trace;
@@ -15,13 +16,45 @@ use crate::{
     utils::{byte_size::ByteSize, sync_once_cell::SyncOnceCell},
 };
 
-use super::{address::Virtual, constants::PAGE_SIZE, PageAllocator};
+#[cfg(feature = "heap_debug")]
+use super::heap_debug;
+use super::{PageAllocator, address::Virtual, constants::PAGE_SIZE, slab};
 
 const MIN_PAGE_COUNT: usize = 16; // minimum page count to alloc from page allocator
+/// Allocations from this size are pages allocated directly from the page allocator.
+const LARGE_SIZE: usize = 4 * PAGE_SIZE;
 
 pub struct Allocator<'a> {
     page_allocator: SyncOnceCell<&'a dyn PageAllocator<Virtual>>,
     head: NoIrqMutex<*mut ChunkHeader>,
+    /// Bytes requested by the allocations in use.
+    allocated: AtomicUsize,
+    /// Pages of the large allocations in use.
+    large_pages: AtomicUsize,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapStats {
+    /// Bytes requested by the allocations in use, from the slabs, the chunks and the pages.
+    pub allocated: usize,
+    pub chunks: usize,
+    pub chunk_pages: usize,
+    /// Free bytes in the chunks.
+    pub chunk_free: usize,
+    /// Size of the largest allocation a chunk can hold without allocating another.
+    pub largest_free: usize,
+    pub large_pages: usize,
+}
+
+impl HeapStats {
+    /// Percentage of the free chunk memory outside of the largest hole.
+    pub fn fragmentation(&self) -> usize {
+        if self.chunk_free == 0 {
+            0
+        } else {
+            100 - self.largest_free * 100 / self.chunk_free
+        }
+    }
 }
 
 unsafe impl<'a> Sync for Allocator<'a> {}
@@ -32,6 +65,8 @@ impl<'a> Allocator<'a> {
         Self {
             page_allocator: SyncOnceCell::new(),
             head: NoIrqMutex::new(ptr::null_mut()),
+            allocated: AtomicUsize::new(0),
+            large_pages: AtomicUsize::new(0),
         }
     }
 
@@ -87,8 +122,115 @@ impl<'a> Allocator<'a> {
 }
 
 unsafe impl<'a> GlobalAlloc for Allocator<'a> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         trace!(target: "kernel_heap", "Alloc {}", ByteSize(layout.size()));
+        assert!(layout.size() > 0);
+        let ptr = {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "heap_debug")] {
+                    heap_debug::alloc(self, layout)
+                } else {
+                    self.alloc_raw(layout)
+                }
+            }
+        };
+        if !ptr.is_null() {
+            self.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        trace!(target: "kernel_heap", "Dealloc {} at {:p}", ByteSize(layout.size()), ptr);
+        assert!(!ptr.is_null(), "Dealloc with null ptr");
+        self.allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "heap_debug")] {
+                heap_debug::dealloc(self, ptr, layout)
+            } else {
+                self.dealloc_raw(ptr, layout)
+            }
+        }
+    }
+}
+
+impl<'a> Allocator<'a> {
+    /// Allocate from a slab cache, the pages or a chunk, without the debug checks.
+    pub(super) unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        if let Some(cache) = slab::cache_for(layout) {
+            return cache.alloc().map_or(ptr::null_mut(), NonNull::as_ptr);
+        }
+        if Self::is_large(layout) {
+            assert!(
+                layout.align() <= PAGE_SIZE,
+                "Alignment should not be more than a page"
+            );
+            let count = layout.size().div_ceil(PAGE_SIZE);
+            let Some(addr) = self.page_allocator().alloc(count) else {
+                return ptr::null_mut();
+            };
+            self.large_pages.fetch_add(count, Ordering::Relaxed);
+            return addr.as_ptr();
+        }
+        self.alloc_block(layout)
+    }
+
+    /// Free an allocation of `alloc_raw`.
+    pub(super) unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        if slab::is_slab_object(ptr) {
+            slab::free(NonNull::new_unchecked(ptr));
+        } else if Self::is_large(layout) {
+            let count = layout.size().div_ceil(PAGE_SIZE);
+            self.page_allocator()
+                .dealloc(VirtualAddress::new(ptr.addr()), count);
+            self.large_pages.fetch_sub(count, Ordering::Relaxed);
+        } else {
+            self.dealloc_block(ptr, layout);
+        }
+    }
+
+    /// Return the statistics of the heap, walking all the chunks.
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            chunks: 0,
+            chunk_pages: 0,
+            chunk_free: 0,
+            largest_free: 0,
+            large_pages: self.large_pages.load(Ordering::Relaxed),
+        };
+
+        let head = self.head.lock();
+        let mut chunk = *head;
+        while let Some(chunk_ref) = unsafe { chunk.as_mut() } {
+            stats.chunks += 1;
+            stats.chunk_pages += chunk_ref.page_count;
+            stats.chunk_free += chunk_ref.free;
+
+            let mut block = chunk_ref.first();
+            while let Some(block_ref) = unsafe { block.as_ref() } {
+                // same space as searched by `alloc_block`
+                let free = if block_ref.allocated_size == 0 {
+                    block_ref.size
+                } else {
+                    (block_ref.size - block_ref.allocated_size)
+                        .saturating_sub(size_of::<BlockHeader>() as u32)
+                };
+                stats.largest_free = stats.largest_free.max(free as usize);
+                block = block_ref.next;
+            }
+            chunk = chunk_ref.next;
+        }
+        stats
+    }
+
+    /// The allocations not fitting in a slab cache nor in a chunk.
+    #[inline]
+    fn is_large(layout: Layout) -> bool {
+        layout.size() >= LARGE_SIZE || layout.align() > size_of::<usize>()
+    }
+
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
         assert!(
             layout.align() <= size_of::<usize>(),
             "Alignment should not be more than usize"
@@ -213,9 +355,7 @@ unsafe impl<'a> GlobalAlloc for Allocator<'a> {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        trace!(target: "kernel_heap", "Dealloc {} at {:p}", ByteSize(layout.size()), ptr);
-        assert!(!ptr.is_null(), "Dealloc with null ptr");
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
         let mut head = self.head.lock(); // lock
 
         let block: *mut BlockHeader = ptr.sub(size_of::<BlockHeader>()).cast();
@@ -0,0 +1,230 @@
+#![allow(unsafe_op_in_unsafe_fn)]
+
+use core::{
+    alloc::Layout,
+    fmt::Display,
+    mem::{align_of, size_of},
+    ptr::NonNull,
+};
+
+use log::error;
+
+use crate::{cpu, sync::no_irq_locks::NoIrqMutex};
+
+use super::heap::Allocator;
+
+/// Minimum size of the red zones before and after each allocation.
+const RED_ZONE_SIZE: usize = 16;
+/// Count of freed blocks kept poisoned before being returned to the heap.
+const QUARANTINE_LEN: usize = 256;
+const BACKTRACE_LEN: usize = 8;
+
+const ALLOCATED_MAGIC: u64 = 0xA110_CA7E_DB10_C000;
+const FREED_MAGIC: u64 = 0xF4EE_DB10_C000_DEAD;
+
+/// Fill of new allocations, to spot the reads of uninitialized memory.
+const POISON_INUSE: u8 = 0x5a;
+/// Fill of freed allocations, overwritten only by a use after free.
+const POISON_FREE: u8 = 0x6b;
+const RED_ZONE: u8 = 0xbb;
+
+#[derive(Debug, Clone, Copy)]
+struct Backtrace([usize; BACKTRACE_LEN]);
+
+impl Backtrace {
+    const EMPTY: Self = Self([0; BACKTRACE_LEN]);
+
+    #[inline(always)]
+    fn capture() -> Self {
+        let mut frames = [0; BACKTRACE_LEN];
+        cpu::backtrace(&mut frames);
+        Self(frames)
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for addr in self.0.iter().take_while(|a| **a != 0) {
+            write!(f, " {:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+// found before the front red zone of every allocation
+#[derive(Debug)]
+#[repr(C)]
+struct DebugHeader {
+    magic: u64,
+    size: usize,
+    align: usize,
+    alloc_site: Backtrace,
+    free_site: Backtrace,
+}
+
+/// Offset of the data from the start of the block: the header then the front red zone.
+#[inline]
+fn data_offset(align: usize) -> usize {
+    (size_of::<DebugHeader>() + RED_ZONE_SIZE)
+        .next_multiple_of(align.max(align_of::<DebugHeader>()))
+}
+
+/// Layout of the block allocated from the heap for `layout`.
+#[inline]
+fn block_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        data_offset(layout.align()) + layout.size() + RED_ZONE_SIZE,
+        layout.align().max(align_of::<DebugHeader>()),
+    )
+    .expect("Allocation too large for the heap debug mode")
+}
+
+#[inline]
+unsafe fn header_of(ptr: *mut u8, align: usize) -> *mut DebugHeader {
+    ptr.sub(data_offset(align)).cast()
+}
+
+/// Offset of the first byte of `len` bytes at `ptr` which isn't `value`.
+#[inline]
+unsafe fn find_not(ptr: *const u8, len: usize, value: u8) -> Option<usize> {
+    core::slice::from_raw_parts(ptr, len)
+        .iter()
+        .position(|b| *b != value)
+}
+
+#[derive(Debug)]
+enum Corruption {
+    InvalidHeader,
+    DoubleFree,
+    LayoutMismatch(Layout),
+    FrontRedZone(usize),
+    BackRedZone(usize),
+    UseAfterFree(usize),
+}
+
+impl Corruption {
+    /// Print the corruption of the allocation at `ptr` and its history, then panic.
+    unsafe fn report(self, ptr: *mut u8, header: *const DebugHeader) -> ! {
+        match self {
+            Self::InvalidHeader => {
+                error!(target: "heap_debug", "Invalid header for {:p}, freed a pointer not allocated by the heap or overwritten by an underflow", ptr);
+                panic!("Heap corruption detected at {:p}", ptr);
+            }
+            Self::DoubleFree => {
+                error!(target: "heap_debug", "Double free of {:p}", ptr);
+            }
+            Self::LayoutMismatch(layout) => {
+                error!(
+                    target: "heap_debug",
+                    "Free of {:p} with {} bytes aligned to {}, allocated with {} bytes aligned to {}",
+                    ptr,
+                    layout.size(),
+                    layout.align(),
+                    (*header).size,
+                    (*header).align
+                );
+            }
+            Self::FrontRedZone(offset) => {
+                let distance = ptr.addr() - (header.addr() + size_of::<DebugHeader>() + offset);
+                error!(target: "heap_debug", "Buffer underflow: {:p} overwritten {} bytes before the data", ptr, distance);
+            }
+            Self::BackRedZone(offset) => {
+                error!(target: "heap_debug", "Buffer overflow: {:p} overwritten {} bytes after the data", ptr, offset);
+            }
+            Self::UseAfterFree(offset) => {
+                error!(target: "heap_debug", "Use after free: {:p} written at offset {} after its free", ptr, offset);
+            }
+        }
+        error!(target: "heap_debug", "  {} bytes allocated at{}", (*header).size, (*header).alloc_site);
+        if (*header).magic == FREED_MAGIC {
+            error!(target: "heap_debug", "  freed at{}", (*header).free_site);
+        }
+        panic!("Heap corruption detected at {:p}", ptr);
+    }
+}
+
+/// The recently freed blocks, still poisoned to catch the writes after their free.
+struct Quarantine {
+    blocks: [Option<(NonNull<u8>, Layout)>; QUARANTINE_LEN],
+    next: usize,
+}
+
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    /// Add a freed block, return the oldest one if the quarantine is full.
+    fn push(&mut self, ptr: NonNull<u8>, layout: Layout) -> Option<(NonNull<u8>, Layout)> {
+        let evicted = self.blocks[self.next].replace((ptr, layout));
+        self.next = (self.next + 1) % QUARANTINE_LEN;
+        evicted
+    }
+}
+
+static QUARANTINE: NoIrqMutex<Quarantine> = NoIrqMutex::new(Quarantine {
+    blocks: [None; QUARANTINE_LEN],
+    next: 0,
+});
+
+/// Allocate `layout` surrounded by red zones, with its data poisoned.
+pub unsafe fn alloc(heap: &Allocator, layout: Layout) -> *mut u8 {
+    let block = heap.alloc_raw(block_layout(layout));
+    if block.is_null() {
+        return block;
+    }
+    let ptr = block.add(data_offset(layout.align()));
+
+    block.cast::<DebugHeader>().write(DebugHeader {
+        magic: ALLOCATED_MAGIC,
+        size: layout.size(),
+        align: layout.align(),
+        alloc_site: Backtrace::capture(),
+        free_site: Backtrace::EMPTY,
+    });
+    let front = block.add(size_of::<DebugHeader>());
+    front.write_bytes(RED_ZONE, ptr.offset_from(front) as usize);
+    ptr.write_bytes(POISON_INUSE, layout.size());
+    ptr.add(layout.size()).write_bytes(RED_ZONE, RED_ZONE_SIZE);
+    ptr
+}
+
+/// Check the allocation at `ptr` then keep it poisoned in quarantine, releasing the oldest quarantined block.
+pub unsafe fn dealloc(heap: &Allocator, ptr: *mut u8, layout: Layout) {
+    let header = header_of(ptr, layout.align());
+    match (*header).magic {
+        ALLOCATED_MAGIC => {}
+        FREED_MAGIC => Corruption::DoubleFree.report(ptr, header),
+        _ => Corruption::InvalidHeader.report(ptr, header),
+    }
+    if (*header).size != layout.size() || (*header).align != layout.align() {
+        Corruption::LayoutMismatch(layout).report(ptr, header);
+    }
+    let front = header.add(1).cast::<u8>();
+    if let Some(offset) = find_not(front, ptr.offset_from(front) as usize, RED_ZONE) {
+        Corruption::FrontRedZone(offset).report(ptr, header);
+    }
+    if let Some(offset) = find_not(ptr.add(layout.size()), RED_ZONE_SIZE, RED_ZONE) {
+        Corruption::BackRedZone(offset).report(ptr, header);
+    }
+
+    (*header).magic = FREED_MAGIC;
+    (*header).free_site = Backtrace::capture();
+    ptr.write_bytes(POISON_FREE, layout.size());
+
+    // released outside of the lock, the report may allocate
+    let evicted = QUARANTINE.lock().push(NonNull::new_unchecked(ptr), layout);
+    if let Some((ptr, layout)) = evicted {
+        release(heap, ptr.as_ptr(), layout);
+    }
+}
+
+/// Check that the quarantined block at `ptr` wasn't written since its free, then return it to the heap.
+unsafe fn release(heap: &Allocator, ptr: *mut u8, layout: Layout) {
+    let header = header_of(ptr, layout.align());
+    if (*header).magic != FREED_MAGIC {
+        Corruption::InvalidHeader.report(ptr, header);
+    }
+    if let Some(offset) = find_not(ptr, layout.size(), POISON_FREE) {
+        Corruption::UseAfterFree(offset).report(ptr, header);
+    }
+    heap.dealloc_raw(header.cast(), block_layout(layout));
+}
@@ -1,13 +1,14 @@
 use crate::error::{Error, MemoryError::*};
-use crate::memory::{HIGH_ADDR_SPACE_RANGE, PAGE_SHIFT};
+use crate::memory::{HIGH_ADDR_SPACE_RANGE, LOW_ADDR_SPACE_RANGE, PAGE_SHIFT};
 
 use super::{
     PageAllocator, PhysicalAddress, VirtualAddress, VirtualAddressSpace,
     address::Physical,
     constants::{ENTRIES_IN_TABLE, PAGE_SIZE},
+    swap, tlb,
     vmm::{MapFlags, MapOptions, MapSize},
 };
-use core::{arch::asm, fmt::Debug, mem::discriminant, ops::Range, ptr, slice};
+use core::{fmt::Debug, mem::discriminant, ops::Range, ptr, slice};
 
 mod structs {
     #![allow(dead_code)]
@@ -80,6 +81,17 @@ mod structs {
 }
 use structs::*;
 
+/// An invalid descriptor so any access faults, marked with a software bit so the page isn't reused.
+const GUARD_ENTRY: u64 = 1 << 55;
+/// Software bit of the leaf descriptors mapping pages allocated by the vmm, freed with their address space.
+const OWNED_ENTRY: u64 = 1 << 56;
+/// Software bit of the leaf descriptors of anonymous user pages, which may be swapped out.
+const SWAPPABLE_ENTRY: u64 = 1 << 57;
+/// Software bit of the invalid descriptors of swapped out pages, their address field holds the swap slot.
+const SWAP_ENTRY: u64 = 1 << 58;
+/// Capacity of `FreedTables`, the tables spanning the pages unmapped at once by the vmm usually fit.
+const MAX_FREED_TABLES: usize = 8;
+
 #[derive(Clone, Copy)]
 #[allow(unused)]
 pub union TableEntry {
@@ -143,6 +155,99 @@ impl TableEntry {
         }
     }
 
+    #[inline]
+    fn is_guard(&self) -> bool {
+        unsafe { self.bits == GUARD_ENTRY }
+    }
+
+    #[inline]
+    fn is_owned(&self) -> bool {
+        unsafe { self.bits & OWNED_ENTRY != 0 }
+    }
+
+    #[inline]
+    fn set_owned(&mut self, owned: bool) {
+        if owned {
+            unsafe { self.bits |= OWNED_ENTRY }
+        }
+    }
+
+    #[inline]
+    fn is_swappable(&self) -> bool {
+        unsafe { self.bits & SWAPPABLE_ENTRY != 0 }
+    }
+
+    #[inline]
+    fn set_swappable(&mut self, swappable: bool) {
+        if swappable {
+            unsafe { self.bits |= SWAPPABLE_ENTRY }
+        }
+    }
+
+    #[inline]
+    fn is_swapped(&self) -> bool {
+        !self.is_present() && unsafe { self.bits & SWAP_ENTRY != 0 }
+    }
+
+    #[inline]
+    fn swap_slot(&self) -> usize {
+        debug_assert!(self.is_swapped());
+        unsafe { self.block_descriptor.address() as usize }
+    }
+
+    #[inline]
+    fn is_accessed(&self) -> bool {
+        unsafe { self.block_descriptor.lower_attributes().access_flag() == 1 }
+    }
+
+    #[inline]
+    fn set_accessed(&mut self, accessed: bool) {
+        unsafe {
+            let l_attrib = self
+                .block_descriptor
+                .lower_attributes()
+                .with_access_flag(accessed as u8);
+            self.block_descriptor.set_lower_attributes(l_attrib);
+        }
+    }
+
+    /// Replace the page by a swap entry for `slot`, the other bits are kept to map it back.
+    #[inline]
+    fn swap_out(&mut self, slot: usize) -> PhysicalAddress {
+        let addr = self.addr();
+        unsafe {
+            self.block_descriptor.set_present(false);
+            self.block_descriptor.set_address(slot as u64);
+            self.bits |= SWAP_ENTRY;
+        }
+        addr
+    }
+
+    /// Map `page` in place of the swap entry.
+    #[inline]
+    fn swap_in(&mut self, page: PhysicalAddress) {
+        debug_assert!(self.is_swapped());
+        unsafe {
+            self.bits &= !SWAP_ENTRY;
+            self.block_descriptor.set_present(true);
+            self.block_descriptor
+                .set_address(((page.addr() & 0xFFFF_FFFF_FFFF) >> PAGE_SHIFT) as u64);
+        }
+        self.set_accessed(true);
+    }
+
+    /// Return true if the leaf is writable and executable at the same or another exception level.
+    #[inline]
+    fn is_writable_exec(&self) -> bool {
+        let (l_attrib, u_attrib) = unsafe {
+            (
+                self.block_descriptor.lower_attributes(),
+                self.block_descriptor.upper_attributes(),
+            )
+        };
+        !l_attrib.readonly() && (!u_attrib.PXN() || (l_attrib.EL0_access() && !u_attrib.UXN()))
+    }
+
     #[inline]
     fn is_present(&self) -> bool {
         unsafe { self.block_descriptor.present() }
@@ -161,16 +266,12 @@ impl TableEntry {
         unsafe { self.block_descriptor.block_or_table() == 0 }
     }
 
-    #[inline]
-    fn set_present(&mut self, present: bool) {
-        unsafe { self.block_descriptor.set_present(present) }
-    }
-
     #[inline]
     fn unmap(&mut self) -> PhysicalAddress {
         assert!(self.is_present());
         let addr = self.addr();
-        self.set_present(false);
+        // cleared so the tables left with only invalid entries can be freed
+        *self = TableEntry { bits: 0 };
         addr
     }
 }
@@ -182,31 +283,28 @@ impl Debug for TableEntry {
 }
 
 #[inline]
-fn invalidate_addr(addr: VirtualAddress) {
-    unsafe {
-        let v = addr.addr() >> PAGE_SHIFT;
-        asm!(
-            "dsb ishst",
-            "tlbi vaae1is, {}",
-            "dsb ish",
-            "isb",
-            in(reg) v,
-            options(preserves_flags)
-        );
-    }
+fn descriptor_attributes(flags: MapFlags) -> LowerDescriptorAttributes {
+    LowerDescriptorAttributes::new()
+        .with_attr_index(flags.attr_index())
+        .with_shareability(flags.shareability())
+        .with_EL0_access(flags.el0_access())
+        .with_readonly(flags.read_only())
+        .with_access_flag(1)
 }
 
+/// The execute permissions of `flags`, the pages are execute never unless asked.
 #[inline]
-pub fn invalidate_tlb_all() {
-    unsafe {
-        asm!(
-            "dsb ishst",
-            "tlbi vmalle1",
-            "dsb ish",
-            "isb",
-            options(preserves_flags)
-        )
-    }
+fn upper_attributes(flags: MapFlags) -> UpperDescriptorAttributes {
+    UpperDescriptorAttributes::new()
+        .with_PXN(!flags.exec())
+        .with_UXN(!flags.user_exec())
+}
+
+/// Return true if no entry of the table at `addr` is used, guards included.
+#[inline]
+fn is_table_empty(addr: PhysicalAddress) -> bool {
+    let table = unsafe { get_table(addr.to_virt().as_ptr()) };
+    table.iter().all(|entry| unsafe { entry.bits } == 0)
 }
 
 #[inline]
@@ -250,6 +348,37 @@ impl From<usize> for PageLevel {
     }
 }
 
+/// The tables unlinked by `Mmu::take_empty_tables`, to free once the TLBs are flushed.
+#[derive(Debug)]
+pub struct FreedTables {
+    tables: [PhysicalAddress; MAX_FREED_TABLES],
+    len: usize,
+}
+
+impl FreedTables {
+    pub fn new() -> Self {
+        Self {
+            tables: [PhysicalAddress::new(0); MAX_FREED_TABLES],
+            len: 0,
+        }
+    }
+
+    /// Return false if full.
+    fn push(&mut self, table: PhysicalAddress) -> bool {
+        if self.len == MAX_FREED_TABLES {
+            return false;
+        }
+        self.tables[self.len] = table;
+        self.len += 1;
+        true
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[PhysicalAddress] {
+        &self.tables[..self.len]
+    }
+}
+
 pub struct Mmu<'a> {
     page_allocator: &'a dyn PageAllocator<Physical>,
 }
@@ -346,16 +475,19 @@ impl<'a> Mmu<'a> {
 
         let l3_entry = &mut l3[get_page_level_index(from, PageLevel::L3)];
 
-        if l3_entry.is_present() && !flags.force_remap() {
+        let was_present = l3_entry.is_present();
+        if was_present && !flags.force_remap() {
             return Err(Error::Memory(AlreadyMapped));
         }
 
-        let l_attrib = LowerDescriptorAttributes::new()
-            .with_attr_index(1)
-            .with_shareability(0b11)
-            .with_access_flag(1);
-        let u_attrib = UpperDescriptorAttributes::new();
+        let l_attrib = descriptor_attributes(flags);
+        let u_attrib = upper_attributes(flags);
         *l3_entry = TableEntry::create_page_descriptor(to, l_attrib, u_attrib);
+        l3_entry.set_owned(flags.owned());
+        l3_entry.set_swappable(flags.swappable());
+        if was_present {
+            tlb::flush_page_broadcast(from);
+        }
 
         Ok(())
     }
@@ -389,16 +521,18 @@ impl<'a> Mmu<'a> {
 
         let l2_entry = &mut l2[get_page_level_index(from, PageLevel::L2)];
 
-        if l2_entry.is_present() && !flags.force_remap() {
+        let was_present = l2_entry.is_present();
+        if was_present && !flags.force_remap() {
             return Err(Error::Memory(AlreadyMapped));
         }
 
-        let l_attrib = LowerDescriptorAttributes::new()
-            .with_attr_index(1)
-            .with_shareability(0b11)
-            .with_access_flag(1);
-        let u_attrib = UpperDescriptorAttributes::new();
+        let l_attrib = descriptor_attributes(flags);
+        let u_attrib = upper_attributes(flags);
         *l2_entry = TableEntry::create_block_descriptor(to, l_attrib, u_attrib);
+        l2_entry.set_owned(flags.owned());
+        if was_present {
+            tlb::flush_all_broadcast();
+        }
 
         Ok(())
     }
@@ -426,18 +560,18 @@ impl<'a> Mmu<'a> {
 
         let l1_entry = &mut l1[get_page_level_index(from, PageLevel::L1)];
 
-        if l1_entry.is_present() && !flags.force_remap() {
+        let was_present = l1_entry.is_present();
+        if was_present && !flags.force_remap() {
             return Err(Error::Memory(AlreadyMapped));
         }
 
-        let l_attrib = LowerDescriptorAttributes::new()
-            .with_attr_index(flags.attr_index())
-            .with_shareability(flags.shareability())
-            .with_EL0_access(flags.el0_access())
-            .with_readonly(flags.read_only())
-            .with_access_flag(1);
-        let u_attrib = UpperDescriptorAttributes::new();
+        let l_attrib = descriptor_attributes(flags);
+        let u_attrib = upper_attributes(flags);
         *l1_entry = TableEntry::create_block_descriptor(to, l_attrib, u_attrib);
+        l1_entry.set_owned(flags.owned());
+        if was_present {
+            tlb::flush_all_broadcast();
+        }
 
         Ok(())
     }
@@ -500,6 +634,88 @@ impl<'a> Mmu<'a> {
         Ok(())
     }
 
+    /// Return the leaf entry mapping `addr` and the count of pages it maps.
+    ///
+    /// The blocks which don't start at `addr` or span beyond the `count` pages from `addr` are split.
+    fn get_leaf_entry(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Result<(&'static mut TableEntry, usize), Error> {
+        assert!(addr.is_aligned_to(PAGE_SIZE));
+        let l1 = if addr_space.is_low {
+            addr_space.get_table_mut()
+        } else {
+            let l0 = addr_space.get_table_mut();
+            self.get_table(&l0[get_page_level_index(addr, PageLevel::L0)])?
+        };
+        let entry = &mut l1[get_page_level_index(addr, PageLevel::L1)];
+        if entry.is_present() && entry.is_block() {
+            if addr.is_aligned_to(0x40000000) && count >= 512 * 512 {
+                return Ok((entry, 512 * 512));
+            }
+            self.remap_block(entry, MapSize::Size1GB)?;
+        }
+        let l2 = self.get_table(entry)?;
+        let entry = &mut l2[get_page_level_index(addr, PageLevel::L2)];
+        if entry.is_present() && entry.is_block() {
+            if addr.is_aligned_to(0x200000) && count >= 512 {
+                return Ok((entry, 512));
+            }
+            self.remap_block(entry, MapSize::Size2MB)?;
+        }
+        let l3 = self.get_table(entry)?;
+        let entry = &mut l3[get_page_level_index(addr, PageLevel::L3)];
+
+        if !entry.is_present() {
+            return Err(Error::Memory(NotMapped));
+        }
+        Ok((entry, 1))
+    }
+
+    /// Change the access and execute permissions of the page or the block at `addr` to the ones of `flags`,
+    /// splitting the block if it isn't inside the `count` pages from `addr`.
+    ///
+    /// Return the count of pages changed. The TLBs aren't invalidated.
+    pub fn protect_leaf(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        flags: MapFlags,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Result<usize, Error> {
+        let (entry, pages) = self.get_leaf_entry(addr, count, addr_space)?;
+        unsafe {
+            let l_attrib = entry
+                .block_descriptor
+                .lower_attributes()
+                .with_EL0_access(flags.el0_access())
+                .with_readonly(flags.read_only());
+            entry.block_descriptor.set_lower_attributes(l_attrib);
+            let u_attrib = entry
+                .block_descriptor
+                .upper_attributes()
+                .with_PXN(!flags.exec())
+                .with_UXN(!flags.user_exec());
+            entry.block_descriptor.set_upper_attributes(u_attrib);
+        }
+        Ok(pages)
+    }
+
+    /// Unmap the page or the block at `addr`, splitting the block if it isn't inside the `count` pages from `addr`.
+    ///
+    /// Return its physical address and the count of pages unmapped. The TLBs aren't invalidated.
+    pub fn unmap_leaf(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Result<(PhysicalAddress, usize), Error> {
+        let (entry, pages) = self.get_leaf_entry(addr, count, addr_space)?;
+        Ok((entry.unmap(), pages))
+    }
+
     #[inline]
     pub fn unmap(
         &self,
@@ -533,10 +749,55 @@ impl<'a> Mmu<'a> {
             return Err(Error::Memory(NotMapped));
         }
 
-        let r_addr = entry.unmap();
-        invalidate_addr(addr);
+        Ok(entry.unmap())
+    }
+
+    /// Reserve the page at `addr` without mapping it, any access to it faults.
+    pub fn set_guard_page(
+        &self,
+        addr: VirtualAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Result<(), Error> {
+        assert!(addr.is_aligned_to(PAGE_SIZE));
+        let l1 = if addr_space.is_low {
+            addr_space.get_table_mut()
+        } else {
+            let l0 = addr_space.get_table_mut();
+            self.create_next_table(&mut l0[get_page_level_index(addr, PageLevel::L0)])?
+        };
+        let l2 = self.create_next_table(&mut l1[get_page_level_index(addr, PageLevel::L1)])?;
+        let l3 = self.create_next_table(&mut l2[get_page_level_index(addr, PageLevel::L2)])?;
+        let entry = &mut l3[get_page_level_index(addr, PageLevel::L3)];
+
+        if entry.is_present() || entry.is_guard() {
+            return Err(Error::Memory(AlreadyMapped));
+        }
+        // never cached by the TLB since it is invalid
+        *entry = TableEntry { bits: GUARD_ENTRY };
+        Ok(())
+    }
+
+    /// Release a page reserved by `set_guard_page`.
+    pub fn clear_guard_page(
+        &self,
+        addr: VirtualAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Result<(), Error> {
+        let l1 = if addr_space.is_low {
+            addr_space.get_table_mut()
+        } else {
+            let l0 = addr_space.get_table_mut();
+            self.get_table(&l0[get_page_level_index(addr, PageLevel::L0)])?
+        };
+        let l2 = self.get_table(&l1[get_page_level_index(addr, PageLevel::L1)])?;
+        let l3 = self.get_table(&l2[get_page_level_index(addr, PageLevel::L2)])?;
+        let entry = &mut l3[get_page_level_index(addr, PageLevel::L3)];
 
-        Ok(r_addr)
+        if !entry.is_guard() {
+            return Err(Error::Memory(NotMapped));
+        }
+        *entry = TableEntry { bits: 0 };
+        Ok(())
     }
 
     fn unmap_2m(
@@ -557,10 +818,7 @@ impl<'a> Mmu<'a> {
             return Err(Error::Memory(NotMapped));
         }
 
-        let r_addr = entry.unmap();
-        invalidate_addr(addr);
-
-        Ok(r_addr)
+        Ok(entry.unmap())
     }
 
     fn unmap_1g(
@@ -580,10 +838,242 @@ impl<'a> Mmu<'a> {
             return Err(Error::Memory(NotMapped));
         }
 
-        let r_addr = entry.unmap();
-        invalidate_addr(addr);
+        Ok(entry.unmap())
+    }
+
+    /// Return the entry of the table of `level` for `addr`, None if a table above isn't present.
+    fn get_entry(
+        &self,
+        addr: VirtualAddress,
+        level: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<&'static mut TableEntry> {
+        // the low address spaces start at L1
+        let root_level = if addr_space.is_low { 1 } else { 0 };
+        if level < root_level {
+            return None;
+        }
+        let mut table = addr_space.get_table_mut();
+        for l in root_level..level {
+            table = self
+                .get_table(&table[get_page_level_index(addr, l.into())])
+                .ok()?;
+        }
+        Some(&mut table[get_page_level_index(addr, level.into())])
+    }
+
+    /// Unlink the tables left empty after unmapping `count` pages from `addr`, and add them to `freed`.
+    ///
+    /// Return false if `freed` is full and empty tables are left, call it again once they are freed.
+    /// The root table is kept. The TLBs must be flushed before freeing the tables, their walk caches may hold them.
+    pub fn take_empty_tables(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        addr_space: &mut VirtualAddressSpace,
+        freed: &mut FreedTables,
+    ) -> bool {
+        let mut complete = true;
+        let end = addr.addr() + count * PAGE_SIZE;
+        // from the lowest tables, so their parents may be empty once they are unlinked
+        for (level, span) in [(2, 0x200000), (1, 0x40000000), (0, 0x8000000000)] {
+            let mut vaddr = addr.addr() & !(span - 1);
+            while vaddr < end {
+                if let Some(entry) = self.get_entry(VirtualAddress::new(vaddr), level, addr_space)
+                    && entry.is_present()
+                    && !entry.is_block()
+                    && is_table_empty(entry.addr())
+                {
+                    if freed.push(entry.addr()) {
+                        *entry = TableEntry { bits: 0 };
+                    } else {
+                        complete = false;
+                    }
+                }
+                let Some(next) = vaddr.checked_add(span) else {
+                    break;
+                };
+                vaddr = next;
+            }
+        }
+        complete
+    }
+
+    /// Free all the tables of the low `addr_space`, its root included, and the pages it owns.
+    ///
+    /// Return the count of owned pages freed. No CPU may use the address space anymore.
+    pub unsafe fn destroy(&self, addr_space: &mut VirtualAddressSpace) -> usize {
+        assert!(addr_space.is_low);
+        let root = VirtualAddress::from_ptr(addr_space.ptr)
+            .to_phys()
+            .expect("Address space root table not mapped");
+        unsafe { self.free_table(root, 1) }
+    }
+
+    unsafe fn free_table(&self, table: PhysicalAddress, level: usize) -> usize {
+        let mut freed = 0;
+        for entry in unsafe { get_table(table.to_virt().as_ptr()) } {
+            if level == 3 && entry.is_swapped() {
+                swap::discard(entry.swap_slot());
+            }
+            if !entry.is_present() {
+                continue;
+            }
+            // the L3 entries are pages even with the table bit
+            if level < 3 && !entry.is_block() {
+                freed += unsafe { self.free_table(entry.addr(), level + 1) };
+            } else if entry.is_owned() {
+                let count = 1 << (9 * (3 - level));
+                unsafe { self.page_allocator.dealloc(entry.addr(), count) };
+                freed += count;
+            }
+        }
+        unsafe { self.page_allocator.dealloc(table, 1) };
+        freed
+    }
+
+    /// Replace the swappable page at `addr` by a swap entry for `slot`, if it wasn't accessed since its access flag was cleared.
+    ///
+    /// Return its physical page, which may be freed once the TLBs are flushed.
+    pub fn swap_out(
+        &self,
+        addr: VirtualAddress,
+        slot: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<PhysicalAddress> {
+        let entry = self.get_entry(addr, 3, addr_space)?;
+        if !entry.is_present() || !entry.is_swappable() || entry.is_accessed() {
+            return None;
+        }
+        Some(entry.swap_out(slot))
+    }
+
+    /// Map `page` at `addr` in place of the swap entry for `slot`. Return false if the entry changed.
+    pub fn swap_in(
+        &self,
+        addr: VirtualAddress,
+        slot: usize,
+        page: PhysicalAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> bool {
+        match self.get_entry(addr, 3, addr_space) {
+            Some(entry) if entry.is_swapped() && entry.swap_slot() == slot => {
+                // never cached by the TLB since it was invalid
+                entry.swap_in(page);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return the swap slot of the page at `addr`, None if it isn't swapped out.
+    pub fn swap_slot(
+        &self,
+        addr: VirtualAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<usize> {
+        self.get_entry(addr, 3, addr_space)
+            .filter(|entry| entry.is_swapped())
+            .map(|entry| entry.swap_slot())
+    }
+
+    /// Clear the swap entry at `addr` and return its slot, None if the page isn't swapped out.
+    pub fn take_swap_entry(
+        &self,
+        addr: VirtualAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<usize> {
+        let entry = self.get_entry(addr, 3, addr_space)?;
+        if !entry.is_swapped() {
+            return None;
+        }
+        let slot = entry.swap_slot();
+        *entry = TableEntry { bits: 0 };
+        Some(slot)
+    }
+
+    /// Set the access flag of the page at `addr`, after an access flag fault.
+    ///
+    /// Return false if no page is mapped at `addr`.
+    pub fn set_accessed(&self, addr: VirtualAddress, addr_space: &mut VirtualAddressSpace) -> bool {
+        match self.get_entry(addr, 3, addr_space) {
+            Some(entry) if entry.is_present() => {
+                // the entries without the access flag aren't cached by the TLB
+                entry.set_accessed(true);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return the first swappable page of `range` not accessed since the previous scan, clearing the access flags of the pages passed.
+    ///
+    /// The cleared flags are effective once the TLBs are flushed.
+    pub fn find_swap_victim(
+        &self,
+        range: Range<VirtualAddress>,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<VirtualAddress> {
+        assert!(addr_space.is_low);
+        let mut addr = range.start.addr();
+        while addr < range.end.addr() {
+            let vaddr = VirtualAddress::new(addr);
+            let l1 = addr_space.get_table_mut();
+            let Ok(l2) = self.get_table(&l1[get_page_level_index(vaddr, PageLevel::L1)]) else {
+                addr = (addr + 1).next_multiple_of(0x40000000);
+                continue;
+            };
+            let Ok(l3) = self.get_table(&l2[get_page_level_index(vaddr, PageLevel::L2)]) else {
+                addr = (addr + 1).next_multiple_of(0x200000);
+                continue;
+            };
+            let entry = &mut l3[get_page_level_index(vaddr, PageLevel::L3)];
+            if entry.is_present() && entry.is_swappable() {
+                if !entry.is_accessed() {
+                    return Some(vaddr);
+                }
+                // second chance
+                entry.set_accessed(false);
+            }
+            addr += PAGE_SIZE;
+        }
+        None
+    }
+
+    /// Call `f` with the address and the count of pages of each leaf of `addr_space` both writable and executable.
+    pub fn find_wx_mappings(
+        &self,
+        addr_space: &VirtualAddressSpace,
+        f: &mut dyn FnMut(VirtualAddress, usize),
+    ) {
+        let (level, base) = if addr_space.is_low {
+            (1, LOW_ADDR_SPACE_RANGE.start)
+        } else {
+            (0, HIGH_ADDR_SPACE_RANGE.start)
+        };
+        unsafe { Self::find_wx_in_table(addr_space.get_table(), level, base.addr(), f) }
+    }
 
-        Ok(r_addr)
+    unsafe fn find_wx_in_table(
+        table: &[TableEntry],
+        level: usize,
+        base: usize,
+        f: &mut dyn FnMut(VirtualAddress, usize),
+    ) {
+        let span = PAGE_SIZE << (9 * (3 - level));
+        for (i, entry) in table.iter().enumerate() {
+            if !entry.is_present() {
+                continue;
+            }
+            let addr = base + i * span;
+            // the L3 entries are pages even with the table bit
+            if level < 3 && !entry.is_block() {
+                let table = unsafe { get_table(entry.addr().to_virt().as_ptr()) };
+                unsafe { Self::find_wx_in_table(table, level + 1, addr, f) };
+            } else if entry.is_writable_exec() {
+                f(VirtualAddress::new(addr), span / PAGE_SIZE);
+            }
+        }
     }
 
     fn get_table(&self, entry: &TableEntry) -> Result<&'static mut [TableEntry], Error> {
@@ -600,15 +1090,19 @@ impl<'a> Mmu<'a> {
     }
 
     // TODO: rewrite this to allow 4KB aligned and bigger than 512GB searchs.
-    /// Find free memory space of size "count * PAGE_SIZE" in `range`.
+    /// Find free memory space of size "count * PAGE_SIZE" in `range`, aligned on `align` pages.
+    ///
+    /// `align` is a power of 2 up to the pages of a 2 MB block.
     #[allow(clippy::needless_range_loop)]
     pub fn find_free_pages(
         &self,
         count: usize,
+        align: usize,
         range: Range<VirtualAddress>,
         addr_space: &VirtualAddressSpace,
     ) -> Result<VirtualAddress, Error> {
         assert!(count > 0);
+        assert!(align.is_power_of_two() && align <= ENTRIES_IN_TABLE);
         assert!(!range.is_empty());
         assert!((count << PAGE_SHIFT) <= (range.end.addr() - range.start.addr()));
         assert!(range.start.is_aligned_to(PAGE_SIZE));
@@ -629,10 +1123,7 @@ impl<'a> Mmu<'a> {
 
         let l0_index = get_page_level_index(range.start, PageLevel::L0);
         let min_l1_index = get_page_level_index(range.start, PageLevel::L1);
-        let mut max_l1_index = get_page_level_index(range.end, PageLevel::L1);
-        if max_l1_index == 0 {
-            max_l1_index = 511;
-        }
+        let max_l1_index = get_page_level_index(range.end - 1, PageLevel::L1);
 
         let l1 = if addr_space.is_low {
             addr_space.get_table()
@@ -675,15 +1166,22 @@ impl<'a> Mmu<'a> {
                                 let l3 = unsafe { get_table(l2_entry.addr().to_virt().as_ptr()) };
                                 for l3_index in 0..ENTRIES_IN_TABLE {
                                     let l3_entry = &l3[l3_index];
-                                    if l3_entry.is_present() {
+                                    if l3_entry.is_present()
+                                        || l3_entry.is_guard()
+                                        || l3_entry.is_swapped()
+                                    {
                                         found_pages = 0;
                                         start_page = None;
                                     } else {
-                                        start_page.get_or_insert(
-                                            ((l0_index * 512 + l1_index) * 512 + l2_index) * 512
-                                                + l3_index
-                                                + page_off,
-                                        );
+                                        let page = ((l0_index * 512 + l1_index) * 512 + l2_index)
+                                            * 512
+                                            + l3_index
+                                            + page_off;
+                                        // a free space starts only at an aligned page
+                                        if start_page.is_none() && !page.is_multiple_of(align) {
+                                            continue;
+                                        }
+                                        start_page.get_or_insert(page);
                                         found_pages += 1;
                                         if found_pages >= count {
                                             return Ok(VirtualAddress::new(
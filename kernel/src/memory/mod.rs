@@ -1,6 +1,6 @@
 use core::fmt::Debug;
 
-use crate::{memory::mmu::invalidate_tlb_all, utils::sync_once_cell::SyncOnceCell};
+use crate::utils::sync_once_cell::SyncOnceCell;
 use aarch64_cpu::registers::TTBR0_EL1;
 use log::info;
 use tock_registers::interfaces::Writeable;
@@ -8,17 +8,25 @@ use uefi::mem::memory_map::MemoryMapRef;
 
 mod addr_space;
 mod address;
+pub mod cache;
 mod constants;
 mod dma;
 mod heap;
+#[cfg(feature = "heap_debug")]
+mod heap_debug;
 mod mmu;
 mod pmm;
+pub mod slab;
+pub mod stats;
+pub mod swap;
+pub mod tlb;
 pub mod vmm;
 
 pub use addr_space::*;
 pub use address::{PhysicalAddress, VirtualAddress};
 pub use constants::*;
 pub use dma::*;
+pub use heap::HeapStats;
 pub use vmm::{MemoryUsage, vmm};
 
 use self::{
@@ -30,6 +38,11 @@ use self::{
 static ALLOCATOR: heap::Allocator = heap::Allocator::new();
 pub static PMM_PAGE_ALLOCATOR: SyncOnceCell<PmmPageAllocator> = SyncOnceCell::new();
 
+/// Return the statistics of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
 pub fn init(memory_map: MemoryMapRef<'static>) {
     unsafe {
         pmm::init(&memory_map);
@@ -46,8 +59,13 @@ pub fn init(memory_map: MemoryMapRef<'static>) {
         ALLOCATOR.init(vmm());
 
         TTBR0_EL1.set(0); // clear
-        invalidate_tlb_all();
+        tlb::flush_all_local();
     }
+    vmm()
+        .protect_kernel_sections()
+        .expect("Failed to protect the kernel sections");
+    let wx = vmm().check_wx();
+    assert_eq!(wx, 0, "Mappings writable and executable");
     info!(target: "memory", "Memory initialized");
 }
 
@@ -66,4 +84,30 @@ pub enum CustomMemoryTypes {
 pub trait PageAllocator<K: MemoryKind>: Sync + Debug {
     fn alloc(&self, count: usize) -> Option<Address<K>>;
     unsafe fn dealloc(&self, ptr: Address<K>, count: usize);
+
+    /// Allocate `count` pages aligned on `align` pages, a power of 2.
+    ///
+    /// The default only succeeds if `alloc` happens to return an aligned address.
+    fn alloc_aligned(&self, count: usize, align: usize) -> Option<Address<K>> {
+        let addr = self.alloc(count)?;
+        if addr.is_aligned_to(align * PAGE_SIZE) {
+            Some(addr)
+        } else {
+            unsafe { self.dealloc(addr, count) };
+            None
+        }
+    }
+
+    /// Allocate `count` pages ending at or before the byte `limit`, for the devices which can't reach all the memory.
+    ///
+    /// The default only succeeds if `alloc` happens to return pages below `limit`.
+    fn alloc_below(&self, count: usize, limit: usize) -> Option<Address<K>> {
+        let addr = self.alloc(count)?;
+        if addr.addr() + count * PAGE_SIZE <= limit {
+            Some(addr)
+        } else {
+            unsafe { self.dealloc(addr, count) };
+            None
+        }
+    }
 }
@@ -1,6 +1,8 @@
 use crate::{
     error::{Error, MemoryError::*},
+    fs::page_cache,
     memory::PAGE_SHIFT,
+    scheduler::Cpu,
     sync::no_irq_locks::NoIrqMutex,
     utils::byte_size::ByteSize,
 };
@@ -8,41 +10,69 @@ use crate::{
 use super::{
     CustomMemoryTypes, PageAllocator, PhysicalAddress, address::Physical, constants::PAGE_SIZE,
 };
-use core::{fmt::Debug, slice};
+use core::{fmt::Debug, ops::Range, slice};
 use log::trace;
 use uefi::{
     boot::MemoryType,
     mem::memory_map::{MemoryMap, MemoryMapRef},
 };
 
+/// Blocks are at most 2^MAX_ORDER pages (1 GB), the virtual allocations are physically contiguous.
+const MAX_ORDER: usize = 18;
+const NO_PAGE: usize = usize::MAX;
+/// Set in `page_states` for the first page of a free block, with the order of the block in the low bits.
+const FREE_HEAD: u8 = 0x80;
+
+/// Count of single pages kept by each per-CPU cache.
+const PAGE_CACHE_SIZE: usize = 64;
+/// Count of pages moved at once between a per-CPU cache and the buddy allocator.
+const PAGE_CACHE_BATCH: usize = 16;
+/// CPUs beyond this count share the caches.
+const PAGE_CACHE_SLOTS: usize = 8;
+
 pub static mut PHYSICAL_MANAGER: Option<NoIrqMutex<PhysicalMemoryManager>> = None;
 
 pub fn init(memory_map: &MemoryMapRef<'static>) {
     unsafe { PHYSICAL_MANAGER = Some(NoIrqMutex::new(PhysicalMemoryManager::new(memory_map))) };
 }
 
+/// Node of the free lists, stored in the first page of each free block.
+#[repr(C)]
+struct FreeNode {
+    prev: usize,
+    next: usize,
+}
+
+/// A buddy allocator: free blocks of 2^order pages, aligned on their size, are kept in a list per order.
 pub struct PhysicalMemoryManager {
-    bitmap: &'static mut [u8],
+    /// `FREE_HEAD | order` for the first page of a free block, else 0.
+    page_states: &'static mut [u8],
+    free_lists: [usize; MAX_ORDER + 1],
+    free_pages: usize,
+    usable_pages: usize,
 }
 
 impl PhysicalMemoryManager {
     pub fn new(memory_map: &MemoryMapRef) -> Self {
-        let max_address = Self::get_max_address(&memory_map);
-        let bitmap_size = max_address.addr() / PAGE_SIZE / 8;
-        let bitmap_page_count = bitmap_size / PAGE_SIZE + (bitmap_size % PAGE_SIZE != 0) as usize;
-        let bitmap_ptr = Self::get_free_space(&memory_map, bitmap_page_count)
-            .expect("Cannot find free space for pmm bitmap");
-        let bitmap = unsafe {
-            slice::from_raw_parts_mut(
-                bitmap_ptr.to_virt().as_ptr::<u8>(),
-                bitmap_page_count * PAGE_SIZE,
-            )
+        let max_address = Self::get_max_address(memory_map);
+        let page_count = max_address.addr() / PAGE_SIZE;
+        let states_page_count = page_count.div_ceil(PAGE_SIZE);
+        let states_ptr = Self::get_free_space(memory_map, states_page_count)
+            .expect("Cannot find free space for pmm page states");
+        let page_states =
+            unsafe { slice::from_raw_parts_mut(states_ptr.to_virt().as_ptr::<u8>(), page_count) };
+        page_states.fill(0); // all used
+
+        let mut s = Self {
+            page_states,
+            free_lists: [NO_PAGE; MAX_ORDER + 1],
+            free_pages: 0,
+            usable_pages: 0,
         };
-
-        let mut s = Self { bitmap };
-        s.init_bitmap(&memory_map);
-        s.set_used_range(bitmap_ptr.addr() / PAGE_SIZE, bitmap_page_count);
-        s.set_memory_map_usable(&memory_map);
+        let states_start = states_ptr.addr() / PAGE_SIZE;
+        s.free_usable_memory(memory_map, states_start..states_start + states_page_count);
+        s.set_memory_map_usable(memory_map);
+        s.usable_pages = s.free_pages;
         s
     }
 
@@ -70,7 +100,7 @@ impl PhysicalMemoryManager {
         )
     }
 
-    // find free space in memory map (used to find where to put the bitmap)
+    // find free space in memory map (used to find where to put the page states)
     fn get_free_space(memory_map: &MemoryMapRef, page_count: usize) -> Option<PhysicalAddress> {
         for desc in memory_map.entries() {
             if Self::is_memory_type_usable(desc.ty) && desc.page_count as usize >= page_count {
@@ -80,9 +110,9 @@ impl PhysicalMemoryManager {
         None
     }
 
-    fn init_bitmap(&mut self, memory_map: &MemoryMapRef) {
-        trace!(target: "pmm", "Init bitmap");
-        self.bitmap.fill(0xFF); // all used
+    /// Free the usable memory except the pages in `reserved`.
+    fn free_usable_memory(&mut self, memory_map: &MemoryMapRef, reserved: Range<usize>) {
+        trace!(target: "pmm", "Init free lists");
         for desc in memory_map.entries() {
             if Self::is_memory_type_usable(desc.ty) {
                 assert!(desc.phys_start as usize % PAGE_SIZE == 0);
@@ -93,229 +123,247 @@ impl PhysicalMemoryManager {
                     ByteSize(desc.page_count as usize * PAGE_SIZE),
                 );
 
-                let start_page = desc.phys_start as usize / PAGE_SIZE;
-                let end_page = start_page + desc.page_count as usize;
-                let is_start_aligned = start_page % 8 == 0;
-                let is_end_aligned = end_page % 8 == 0;
-
-                let byte_start = if is_start_aligned {
-                    start_page / 8
-                } else {
-                    self.set_free_range(start_page, 8 - (start_page % 8));
-                    start_page / 8 + 1
-                };
-
-                let byte_end = if is_end_aligned {
-                    end_page / 8
-                } else {
-                    self.set_free_range(end_page & !7, end_page % 8);
-                    end_page / 8
-                };
-
-                if byte_start <= byte_end {
-                    self.bitmap[byte_start..byte_end].fill(0);
+                let start = desc.phys_start as usize / PAGE_SIZE;
+                let end = start + desc.page_count as usize;
+                let before_reserved = start..end.min(reserved.start);
+                let after_reserved = start.max(reserved.end)..end;
+                for range in [before_reserved, after_reserved] {
+                    if !range.is_empty() {
+                        self.free_range(range.start, range.len());
+                    }
                 }
             }
         }
     }
 
+    // once the memory map isn't used anymore
     fn set_memory_map_usable(&mut self, memory_map: &MemoryMapRef) {
         let desc = memory_map
             .entries()
             .find(|desc| desc.ty.0 == CustomMemoryTypes::MemoryMap as u32)
             .expect("Memory map region not found");
         assert!(desc.phys_start as usize % PAGE_SIZE == 0);
-        self.set_free_range(
+        self.free_range(
             desc.phys_start as usize / PAGE_SIZE,
             desc.page_count as usize,
         );
     }
 
     #[inline]
-    pub fn set_free(&mut self, index: usize) {
-        self.bitmap[index / 8] &= !(0b10000000 >> (index % 8));
+    fn node(index: usize) -> *mut FreeNode {
+        PhysicalAddress::new(index << PAGE_SHIFT).to_virt().as_ptr()
     }
 
-    #[inline]
-    pub fn set_used(&mut self, index: usize) {
-        self.bitmap[index / 8] |= 0b10000000 >> (index % 8);
+    fn push_free(&mut self, index: usize, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            *Self::node(index) = FreeNode {
+                prev: NO_PAGE,
+                next: head,
+            };
+            if head != NO_PAGE {
+                (*Self::node(head)).prev = index;
+            }
+        }
+        self.free_lists[order] = index;
+        self.page_states[index] = FREE_HEAD | order as u8;
     }
 
-    #[inline]
-    pub fn set_free_range(&mut self, start: usize, length: usize) {
-        for i in start..(start + length) {
-            self.set_free(i);
+    fn remove_free(&mut self, index: usize, order: usize) {
+        debug_assert_eq!(self.page_states[index], FREE_HEAD | order as u8);
+        let FreeNode { prev, next } = unsafe { Self::node(index).read() };
+        if prev == NO_PAGE {
+            self.free_lists[order] = next;
+        } else {
+            unsafe { (*Self::node(prev)).next = next };
         }
+        if next != NO_PAGE {
+            unsafe { (*Self::node(next)).prev = prev };
+        }
+        self.page_states[index] = 0;
     }
 
-    #[inline]
-    pub fn set_used_range(&mut self, start: usize, length: usize) {
-        for i in start..(start + length) {
-            self.set_used(i);
+    /// Free the block at `index`, merged with its buddy as long as the buddy is free.
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        debug_assert!(index.is_multiple_of(1 << order));
+        debug_assert_eq!(self.page_states[index], 0, "Double free of page {index}");
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if self.page_states.get(buddy) != Some(&(FREE_HEAD | order as u8)) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            index = index.min(buddy);
+            order += 1;
         }
+        self.push_free(index, order);
     }
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn is_used(&self, index: usize) -> bool {
-        (self.bitmap[index / 8] & (0b10000000 >> (index % 8))) != 0
+    /// Free `count` pages from `start`, which don't have to be a block.
+    fn free_range(&mut self, start: usize, count: usize) {
+        let end = start + count;
+        let mut index = start;
+        while index < end {
+            // the biggest aligned block starting at `index` and fitting in the range
+            let mut order = (index.trailing_zeros() as usize).min(MAX_ORDER);
+            while index + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(index, order);
+            index += 1 << order;
+        }
+        self.free_pages += count;
     }
 
-    #[cfg(debug_assertions)]
-    #[allow(unused)]
-    pub fn print_bitmap(&self) {
-        use log::debug;
-
-        let len = self.bitmap.len() * 8;
-        debug!(
-            "Physical bitmap: {} pages {}",
-            len,
-            ByteSize(len * PAGE_SIZE)
-        );
-        let mut used = self.is_used(0);
-        let mut from = 0;
-        for i in 0..len {
-            let u = self.is_used(i);
-            if u != used {
-                debug!(
-                    "{} from {:p} to {:p} ({})",
-                    if used { "Used" } else { "Free" },
-                    (from * PAGE_SIZE) as *const (),
-                    (i * PAGE_SIZE) as *const (),
-                    ByteSize((i - from) * PAGE_SIZE)
-                );
-                used = u;
-                from = i;
+    /// Take a free block of `order` ending before the page `end`, splitting a bigger one if needed.
+    fn alloc_block(&mut self, order: usize, end: usize) -> Option<usize> {
+        let (index, mut block_order) = (order..=MAX_ORDER).find_map(|o| {
+            let mut index = self.free_lists[o];
+            // without limit the first block fits, the lists are only walked for the devices with one
+            while index != NO_PAGE {
+                if index + (1 << order) <= end {
+                    return Some((index, o));
+                }
+                index = unsafe { (*Self::node(index)).next };
             }
+            None
+        })?;
+        self.remove_free(index, block_order);
+        // give back the upper halves
+        while block_order > order {
+            block_order -= 1;
+            self.push_free(index + (1 << block_order), block_order);
         }
+        Some(index)
     }
 
-    /// Find the byte index of the first byte which contains at least one 0 bit in the bitmap.
+    /// Allocate `count` contiguous pages aligned on `align` pages, a power of 2.
     #[inline]
-    fn find_first_zero(bitmap: &[u8]) -> Option<usize> {
-        let (a, bitmap64, b) = unsafe { bitmap.align_to::<u64>() };
+    pub fn alloc_pages_aligned(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhysicalAddress, Error> {
+        self.alloc_pages_below(count, align, usize::MAX)
+    }
 
-        let mut index = 0;
-        for val in a {
-            if *val != u8::MAX {
-                return Some(index);
-            }
-            index += 1;
+    /// Allocate `count` contiguous pages aligned on `align` pages, a power of 2, ending at or before the byte `limit`.
+    pub fn alloc_pages_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: usize,
+    ) -> Result<PhysicalAddress, Error> {
+        debug_assert!(count > 0);
+        debug_assert!(align.is_power_of_two());
+        let order = (count.next_power_of_two().trailing_zeros() as usize)
+            .max(align.trailing_zeros() as usize);
+        if order > MAX_ORDER {
+            return Err(Error::Memory(OutOfPhysicalMemory));
         }
 
-        for val in bitmap64 {
-            if *val != u64::MAX {
-                return Some(index + (u64::from_be(*val).leading_ones() / u8::BITS) as usize);
-            }
-            index += 8;
-        }
-        for val in b {
-            if *val != u8::MAX {
-                return Some(index);
-            }
-            index += 1;
+        let index = self
+            .alloc_block(order, limit >> PAGE_SHIFT)
+            .ok_or(Error::Memory(OutOfPhysicalMemory))?;
+        self.free_pages -= 1 << order;
+        // the pages after `count` are given back, so any part of the allocation can be freed on its own
+        let block_len = 1 << order;
+        if block_len > count {
+            self.free_range(index + count, block_len - count);
         }
 
-        None
+        let addr = PhysicalAddress::new(index << PAGE_SHIFT);
+        trace!(target: "pmm", "Alloc {} page(s) at {}", count, addr);
+        Ok(addr)
     }
 
-    /// Check if `count` pages are allocable in `bitmap`.
-    /// The alloc is possible if there is `count` contigous 0 bits with at
-    /// least 1 free bit in the first byte of the bitmap.
-    ///
-    /// `bitmap[0]` should contains at least 1 hole (0 bit).
-    ///
-    /// `offset` is the bit offset in `bitmap` where to start looking for alloc.
-    ///
-    /// If the alloc is possible, return `Ok` with the bit offset where the alloc start.
-    /// Else, return `Err` with the count of bits which are checked to be unable
-    /// to contains the alloc.
     #[inline]
-    fn can_alloc(bitmap: &[u8], count: usize, offset: usize) -> Result<usize, usize> {
-        debug_assert!(bitmap[0] != u8::MAX);
-        debug_assert!(offset < u8::BITS as usize);
-        debug_assert!(count > 0);
-
-        let mask = !((1u16 << (u8::BITS as usize - offset)) - 1) as u8;
-        let bit_i = (bitmap[0] | mask).leading_ones() as usize;
-        if bit_i == u8::BITS as usize {
-            return Err(u8::BITS as usize - offset);
-        }
-        if count == 1 {
-            return Ok(bit_i);
-        }
-
-        let mask = !((1 << (u8::BITS as usize).saturating_sub(count)) - 1) >> bit_i;
-        let val = bitmap[0];
+    pub fn alloc_pages(&mut self, count: usize) -> Result<PhysicalAddress, Error> {
+        self.alloc_pages_aligned(count, 1)
+    }
 
-        if (val & mask) == 0 {
-            // if the alloc fit in the bitmap element (u8)
-            if count <= u8::BITS as usize - bit_i {
-                Ok(bit_i)
-            } else {
-                let remaining_count = count - (u8::BITS as usize - bit_i);
+    pub fn unalloc_pages(&mut self, addr: PhysicalAddress, count: usize) {
+        assert!(addr.is_aligned_to(PAGE_SIZE));
+        trace!(target: "pmm", "Dealloc {} page(s) at {}", count, addr);
+        self.free_range(addr.addr() / PAGE_SIZE, count);
+    }
 
-                // the count of elements that needs to be 0 for the alloc to succeed
-                let full_elements = remaining_count / u8::BITS as usize;
+    /// Count of free pages, not counting the per-CPU caches.
+    #[inline]
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
 
-                for (i, &v) in bitmap.iter().enumerate().skip(1).take(full_elements) {
-                    if v != 0 {
-                        return Err(i * u8::BITS as usize);
-                    }
-                }
+    /// Count of pages managed by the allocator.
+    #[inline]
+    pub fn usable_pages(&self) -> usize {
+        self.usable_pages
+    }
 
-                let remaining_bits = remaining_count % u8::BITS as usize;
-                let mask = !(1 << ((u8::BITS as usize - remaining_bits) - 1));
-                if bitmap[full_elements + 1] & mask == 0 {
-                    Ok(bit_i)
-                } else {
-                    Err((full_elements + 1) * u8::BITS as usize)
-                }
+    /// Count of free blocks of each order.
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut index = self.free_lists[order];
+            while index != NO_PAGE {
+                *count += 1;
+                index = unsafe { (*Self::node(index)).next };
             }
-        } else {
-            Err(bit_i + (val << bit_i).leading_zeros() as usize)
         }
+        counts
     }
 
-    fn find_pages(&self, count: usize) -> Result<usize, Error> {
-        debug_assert!(count > 0);
+    #[cfg(debug_assertions)]
+    #[allow(unused)]
+    pub fn print_free_lists(&self) {
+        use log::debug;
 
-        let mut index = 0;
-        let mut off = 0;
-
-        loop {
-            let bitmap = &self.bitmap[index..];
-            let first_free =
-                Self::find_first_zero(bitmap).ok_or(Error::Memory(OutOfPhysicalMemory))?;
-            match Self::can_alloc(&bitmap[first_free..], count, off) {
-                Ok(off) => return Ok((index + first_free) * u8::BITS as usize + off),
-                Err(e) => {
-                    index += e / u8::BITS as usize;
-                    off += e % u8::BITS as usize;
-                    if off >= u8::BITS as usize {
-                        off -= u8::BITS as usize;
-                        index += 1;
-                    }
-                }
+        debug!(
+            "Physical memory: {} free of {}",
+            ByteSize(self.free_pages * PAGE_SIZE),
+            ByteSize(self.usable_pages * PAGE_SIZE)
+        );
+        for (order, count) in self.free_blocks().iter().enumerate() {
+            if *count != 0 {
+                debug!(
+                    "{} free block(s) of {}",
+                    count,
+                    ByteSize(PAGE_SIZE << order)
+                );
             }
         }
     }
+}
 
-    pub fn alloc_pages(&mut self, count: usize) -> Result<PhysicalAddress, Error> {
-        let pages = self.find_pages(count)?;
-        self.set_used_range(pages, count);
-        let addr = PhysicalAddress::new(pages << PAGE_SHIFT);
-        trace!(target: "pmm", "Alloc {} page(s) at {}", count, addr);
-        Ok(addr)
-    }
+/// Single pages freed recently on a CPU, reused first by its next single page allocations.
+struct PageCache {
+    pages: [usize; PAGE_CACHE_SIZE],
+    len: usize,
+}
 
-    pub fn unalloc_pages(&mut self, addr: PhysicalAddress, count: usize) {
-        assert!(addr.is_aligned_to(PAGE_SIZE));
-        trace!(target: "pmm", "Dealloc {} page(s) at {}", count, addr);
-        self.set_free_range(addr.addr() / PAGE_SIZE, count);
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            pages: [0; PAGE_CACHE_SIZE],
+            len: 0,
+        }
     }
 }
 
+// locked because CPUs can share a slot, and a CPU may not have its `Cpu` set yet
+static PAGE_CACHES: [NoIrqMutex<PageCache>; PAGE_CACHE_SLOTS] =
+    [const { NoIrqMutex::new(PageCache::new()) }; PAGE_CACHE_SLOTS];
+
+#[inline]
+fn page_cache() -> &'static NoIrqMutex<PageCache> {
+    &PAGE_CACHES[Cpu::current().index % PAGE_CACHE_SLOTS]
+}
+
+/// Return the count of pages in the per-CPU caches.
+pub fn cached_pages() -> usize {
+    PAGE_CACHES.iter().map(|cache| cache.lock().len).sum()
+}
+
 pub struct PmmPageAllocator<'a> {
     pmm: &'a NoIrqMutex<PhysicalMemoryManager>,
 }
@@ -324,19 +372,126 @@ impl<'a> PmmPageAllocator<'a> {
     pub fn new(pmm: &'a NoIrqMutex<PhysicalMemoryManager>) -> Self {
         Self { pmm }
     }
+
+    /// Allocate `count` contiguous pages aligned on `align` pages, a power of 2.
+    pub fn alloc_aligned(&self, count: usize, align: usize) -> Option<PhysicalAddress> {
+        self.retry_after_shrink(count, || {
+            self.pmm.lock().alloc_pages_aligned(count, align).ok()
+        })
+    }
+
+    /// Allocate `count` contiguous pages ending at or before the byte `limit`.
+    pub fn alloc_below(&self, count: usize, limit: usize) -> Option<PhysicalAddress> {
+        self.retry_after_shrink(count, || {
+            self.pmm.lock().alloc_pages_below(count, 1, limit).ok()
+        })
+    }
+
+    /// Run `alloc` again if it failed, once the clean pages of the page cache are given back.
+    ///
+    /// The callers may hold any lock, so the pages aren't swapped out here, the VMM does it.
+    fn retry_after_shrink<F>(&self, count: usize, alloc: F) -> Option<PhysicalAddress>
+    where
+        F: Fn() -> Option<PhysicalAddress>,
+    {
+        if let Some(addr) = alloc() {
+            return Some(addr);
+        }
+        // directly to the buddy allocator, the per-CPU caches would hide them from the bigger allocations
+        let mut freed = 0;
+        while freed < count.max(PAGE_CACHE_BATCH)
+            && let Some(page) = page_cache::try_evict()
+        {
+            self.pmm.lock().unalloc_pages(page, 1);
+            freed += 1;
+        }
+        if freed == 0 {
+            return None;
+        }
+        trace!(target: "pmm", "Dropped {} cached file pages to allocate {} pages", freed, count);
+        alloc()
+    }
+
+    /// Count of free pages, including the per-CPU caches.
+    pub fn free_pages(&self) -> usize {
+        // the caches are locked before the PMM when allocating, don't hold both here
+        let free = self.pmm.lock().free_pages();
+        free + cached_pages()
+    }
+
+    /// Count of pages managed by the allocator.
+    pub fn usable_pages(&self) -> usize {
+        self.pmm.lock().usable_pages()
+    }
+
+    fn alloc_cached(&self) -> Option<PhysicalAddress> {
+        let mut cache = page_cache().lock();
+        if cache.len == 0 {
+            let mut pmm = self.pmm.lock();
+            while cache.len < PAGE_CACHE_BATCH {
+                let Ok(addr) = pmm.alloc_pages(1) else {
+                    break;
+                };
+                let len = cache.len;
+                cache.pages[len] = addr.addr();
+                cache.len += 1;
+            }
+        }
+        if cache.len == 0 {
+            return None;
+        }
+        cache.len -= 1;
+        Some(PhysicalAddress::new(cache.pages[cache.len]))
+    }
+
+    fn dealloc_cached(&self, addr: PhysicalAddress) {
+        let mut cache = page_cache().lock();
+        if cache.len == PAGE_CACHE_SIZE {
+            // give back the oldest pages, the newest ones are more likely to be in the CPU caches
+            let mut pmm = self.pmm.lock();
+            for page in &cache.pages[..PAGE_CACHE_BATCH] {
+                pmm.unalloc_pages(PhysicalAddress::new(*page), 1);
+            }
+            cache.pages.copy_within(PAGE_CACHE_BATCH.., 0);
+            cache.len -= PAGE_CACHE_BATCH;
+        }
+        let len = cache.len;
+        cache.pages[len] = addr.addr();
+        cache.len += 1;
+    }
 }
 
 impl<'a> PageAllocator<Physical> for PmmPageAllocator<'a> {
     fn alloc(&self, count: usize) -> Option<PhysicalAddress> {
-        match self.pmm.lock().alloc_pages(count) {
-            Ok(addr) => Some(addr),
-            Err(_) => None,
-        }
+        self.retry_after_shrink(count, || {
+            if count == 1 {
+                return self.alloc_cached();
+            }
+            match self.pmm.lock().alloc_pages(count) {
+                Ok(addr) => Some(addr),
+                Err(_) => None,
+            }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: PhysicalAddress, count: usize) {
+        if count == 1 {
+            assert!(ptr.is_aligned_to(PAGE_SIZE));
+            self.dealloc_cached(ptr);
+            return;
+        }
         self.pmm.lock().unalloc_pages(ptr, count)
     }
+
+    #[inline]
+    fn alloc_aligned(&self, count: usize, align: usize) -> Option<PhysicalAddress> {
+        PmmPageAllocator::alloc_aligned(self, count, align)
+    }
+
+    #[inline]
+    fn alloc_below(&self, count: usize, limit: usize) -> Option<PhysicalAddress> {
+        PmmPageAllocator::alloc_below(self, count, limit)
+    }
 }
 
 impl<'a> Debug for PmmPageAllocator<'a> {
@@ -0,0 +1,467 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    alloc::Layout,
+    fmt::Debug,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use log::trace;
+
+use crate::{error::Error, scheduler::Cpu, sync::no_irq_locks::NoIrqMutex};
+
+use super::{
+    PAGE_SIZE, PHYSICAL_LINEAR_MAPPING_RANGE, PMM_PAGE_ALLOCATOR, PageAllocator, PhysicalAddress,
+    VirtualAddress,
+};
+
+/// Every slab is this count of physically contiguous pages, aligned on their size
+/// so the header of a slab is found by masking the address of its objects.
+pub const SLAB_PAGES: usize = 8;
+const SLAB_SIZE: usize = SLAB_PAGES * PAGE_SIZE;
+/// Objects bigger than this aren't allocated from slabs.
+pub const MAX_OBJECT_SIZE: usize = SLAB_SIZE / 4;
+
+/// Count of free objects kept by each per-CPU magazine.
+const MAGAZINE_SIZE: usize = 32;
+/// Count of objects moved at once between a magazine and the slabs.
+const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+/// CPUs beyond this count share the magazines.
+const MAGAZINE_SLOTS: usize = 8;
+
+const MAX_NAMED_CACHES: usize = 16;
+
+/// Biggest size class, bigger allocations of the heap don't use the slabs.
+pub const MAX_SIZE_CLASS: usize = 2048;
+
+const fn size_class(name: &'static str, size: usize) -> SlabCache {
+    // naturally aligned, so any alignment up to the size is fine
+    SlabCache::new(name, unsafe {
+        Layout::from_size_align_unchecked(size, size)
+    })
+}
+
+static SIZE_CLASSES: [SlabCache; 9] = [
+    size_class("size-8", 8),
+    size_class("size-16", 16),
+    size_class("size-32", 32),
+    size_class("size-64", 64),
+    size_class("size-128", 128),
+    size_class("size-256", 256),
+    size_class("size-512", 512),
+    size_class("size-1024", 1024),
+    size_class("size-2048", 2048),
+];
+
+/// Caches used by the heap for the allocations with their exact layout.
+static NAMED_CACHES: [AtomicPtr<SlabCache>; MAX_NAMED_CACHES] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_NAMED_CACHES];
+
+/// All the caches which allocated a slab, linked by `SlabCache::next`.
+static ALL_CACHES: AtomicPtr<SlabCache> = AtomicPtr::new(ptr::null_mut());
+
+// at the start of every slab
+#[derive(Debug)]
+struct SlabHeader {
+    cache: *const SlabCache,
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+// in every free object
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SlabLists {
+    /// Slabs with at least one free object.
+    partial: *mut SlabHeader,
+    slabs: usize,
+    /// Slabs without any object in use, only one is kept.
+    free_slabs: usize,
+    /// Objects allocated from the slabs, including the ones in the magazines.
+    in_use: usize,
+}
+
+unsafe impl Send for SlabLists {}
+
+/// Free objects recently freed on a CPU, given first to its next allocations.
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [ptr::null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+}
+
+/// A cache of objects of the same layout, carved out of slabs of physical pages.
+pub struct SlabCache {
+    name: &'static str,
+    layout: Layout,
+    /// Distance between two objects.
+    object_size: usize,
+    /// Offset of the first object, after the header.
+    first_offset: usize,
+    objects_per_slab: usize,
+    lists: NoIrqMutex<SlabLists>,
+    magazines: [NoIrqMutex<Magazine>; MAGAZINE_SLOTS],
+    registered: AtomicBool,
+    next: AtomicPtr<SlabCache>,
+}
+
+unsafe impl Sync for SlabCache {}
+
+#[derive(Debug, Clone)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    /// Count of objects the slabs can hold.
+    pub objects: usize,
+    pub in_use: usize,
+    /// Count of free objects in the per-CPU magazines, counted as in use by the slabs.
+    pub cached: usize,
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, layout: Layout) -> Self {
+        let align = if layout.align() > align_of::<FreeObject>() {
+            layout.align()
+        } else {
+            align_of::<FreeObject>()
+        };
+        let size = if layout.size() > size_of::<FreeObject>() {
+            layout.size()
+        } else {
+            size_of::<FreeObject>()
+        };
+        let object_size = size.next_multiple_of(align);
+        assert!(
+            object_size <= MAX_OBJECT_SIZE,
+            "Object too big for a slab cache"
+        );
+        let first_offset = size_of::<SlabHeader>().next_multiple_of(align);
+
+        Self {
+            name,
+            layout,
+            object_size,
+            first_offset,
+            objects_per_slab: (SLAB_SIZE - first_offset) / object_size,
+            lists: NoIrqMutex::new(SlabLists {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                free_slabs: 0,
+                in_use: 0,
+            }),
+            magazines: [const { NoIrqMutex::new(Magazine::new()) }; MAGAZINE_SLOTS],
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    #[inline]
+    fn magazine(&self) -> &NoIrqMutex<Magazine> {
+        &self.magazines[Cpu::current().index % MAGAZINE_SLOTS]
+    }
+
+    /// Allocate an object, uninitialized.
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        let mut magazine = self.magazine().lock();
+        if magazine.len == 0 {
+            self.refill(&mut magazine);
+        }
+        if magazine.len == 0 {
+            return None;
+        }
+        magazine.len -= 1;
+        NonNull::new(magazine.objects[magazine.len])
+    }
+
+    /// # Safety
+    /// `ptr` should have been returned by `alloc` of this cache, and not be used anymore.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        debug_assert!(ptr::eq(unsafe { (*slab_of(ptr.as_ptr())).cache }, self));
+        let mut magazine = self.magazine().lock();
+        if magazine.len == MAGAZINE_SIZE {
+            // give back the oldest objects, the newest ones are more likely to be in the CPU caches
+            let mut lists = self.lists.lock();
+            for object in &magazine.objects[..MAGAZINE_BATCH] {
+                unsafe { self.free_object(&mut lists, *object) };
+            }
+            magazine.objects.copy_within(MAGAZINE_BATCH.., 0);
+            magazine.len -= MAGAZINE_BATCH;
+        }
+        let len = magazine.len;
+        magazine.objects[len] = ptr.as_ptr();
+        magazine.len += 1;
+    }
+
+    fn refill(&'static self, magazine: &mut Magazine) {
+        let mut lists = self.lists.lock();
+        while magazine.len < MAGAZINE_BATCH {
+            if lists.partial.is_null() && !self.grow(&mut lists) {
+                break;
+            }
+            let slab = unsafe { &mut *lists.partial };
+            let object = slab.free;
+            slab.free = unsafe { (*object).next };
+            if slab.in_use == 0 {
+                lists.free_slabs -= 1;
+            }
+            slab.in_use += 1;
+            lists.in_use += 1;
+            if slab.free.is_null() {
+                // full, out of the partial list until an object is freed
+                unsafe { Self::unlink(&mut lists, slab) };
+            }
+            let len = magazine.len;
+            magazine.objects[len] = object.cast();
+            magazine.len += 1;
+        }
+    }
+
+    /// Allocate a new slab, return false if there is no physical memory left.
+    fn grow(&'static self, lists: &mut SlabLists) -> bool {
+        let Some(phys) = PMM_PAGE_ALLOCATOR
+            .get()
+            .and_then(|pmm| pmm.alloc_aligned(SLAB_PAGES, SLAB_PAGES))
+        else {
+            return false;
+        };
+        let slab: *mut SlabHeader = phys.to_virt().as_ptr();
+        let base: *mut u8 = slab.cast();
+
+        // free list in address order
+        let mut free = ptr::null_mut();
+        for i in (0..self.objects_per_slab).rev() {
+            let object: *mut FreeObject =
+                unsafe { base.add(self.first_offset + i * self.object_size).cast() };
+            unsafe { *object = FreeObject { next: free } };
+            free = object;
+        }
+        unsafe {
+            *slab = SlabHeader {
+                cache: self,
+                prev: ptr::null_mut(),
+                next: lists.partial,
+                free,
+                in_use: 0,
+            };
+            if !lists.partial.is_null() {
+                (*lists.partial).prev = slab;
+            }
+        }
+        lists.partial = slab;
+        lists.slabs += 1;
+        lists.free_slabs += 1;
+        trace!(target: "slab", "New slab for {} at {}", self.name, phys);
+
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            let mut head = ALL_CACHES.load(Ordering::Relaxed);
+            loop {
+                self.next.store(head, Ordering::Relaxed);
+                let this = ptr::from_ref(self).cast_mut();
+                match ALL_CACHES.compare_exchange_weak(
+                    head,
+                    this,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => head = current,
+                }
+            }
+        }
+        true
+    }
+
+    unsafe fn free_object(&self, lists: &mut SlabLists, object: *mut u8) {
+        let slab = unsafe { &mut *slab_of(object) };
+        let was_full = slab.free.is_null();
+        let object: *mut FreeObject = object.cast();
+        unsafe { *object = FreeObject { next: slab.free } };
+        slab.free = object;
+        slab.in_use -= 1;
+        lists.in_use -= 1;
+        if was_full {
+            slab.prev = ptr::null_mut();
+            slab.next = lists.partial;
+            if !lists.partial.is_null() {
+                unsafe { (*lists.partial).prev = slab };
+            }
+            lists.partial = slab;
+        }
+
+        if slab.in_use == 0 {
+            lists.free_slabs += 1;
+            // keep one free slab so an object allocated and freed in a loop doesn't allocate a slab each time
+            if lists.free_slabs > 1 {
+                unsafe { Self::unlink(lists, slab) };
+                lists.slabs -= 1;
+                lists.free_slabs -= 1;
+                let phys = PhysicalAddress::new(
+                    ptr::from_mut(slab).addr() - PHYSICAL_LINEAR_MAPPING_RANGE.start.addr(),
+                );
+                trace!(target: "slab", "Free slab of {} at {}", self.name, phys);
+                unsafe { PMM_PAGE_ALLOCATOR.get().unwrap().dealloc(phys, SLAB_PAGES) };
+            }
+        }
+    }
+
+    /// Remove `slab` from the partial list.
+    unsafe fn unlink(lists: &mut SlabLists, slab: &mut SlabHeader) {
+        if slab.prev.is_null() {
+            lists.partial = slab.next;
+        } else {
+            unsafe { (*slab.prev).next = slab.next };
+        }
+        if !slab.next.is_null() {
+            unsafe { (*slab.next).prev = slab.prev };
+        }
+        slab.prev = ptr::null_mut();
+        slab.next = ptr::null_mut();
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let cached = self
+            .magazines
+            .iter()
+            .map(|magazine| magazine.lock().len)
+            .sum();
+        let lists = self.lists.lock();
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slabs: lists.slabs,
+            objects: lists.slabs * self.objects_per_slab,
+            in_use: lists.in_use.saturating_sub(cached),
+            cached,
+        }
+    }
+}
+
+impl Debug for SlabCache {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SlabCache")
+            .field("name", &self.name)
+            .field("object_size", &self.object_size)
+            .finish()
+    }
+}
+
+/// # Safety
+/// `ptr` should be an object of a slab.
+#[inline]
+unsafe fn slab_of(ptr: *mut u8) -> *mut SlabHeader {
+    ptr.map_addr(|addr| addr & !(SLAB_SIZE - 1)).cast()
+}
+
+/// Return true if `ptr` was allocated by a slab cache.
+#[inline]
+pub fn is_slab_object(ptr: *mut u8) -> bool {
+    // the other heap allocations are in the kernel heap range
+    PHYSICAL_LINEAR_MAPPING_RANGE.contains(&VirtualAddress::new(ptr.addr()))
+}
+
+/// Free an object of any slab cache.
+///
+/// # Safety
+/// `ptr` should have been allocated by a slab cache, and not be used anymore.
+pub unsafe fn free(ptr: NonNull<u8>) {
+    let cache = unsafe { &*(*slab_of(ptr.as_ptr())).cache };
+    unsafe { cache.free(ptr) };
+}
+
+/// Return the cache the heap uses for `layout`: a named cache of this exact layout, else a size class.
+pub fn cache_for(layout: Layout) -> Option<&'static SlabCache> {
+    for named in &NAMED_CACHES {
+        let cache = named.load(Ordering::Acquire);
+        if cache.is_null() {
+            break;
+        }
+        let cache = unsafe { &*cache };
+        if cache.layout == layout {
+            return Some(cache);
+        }
+    }
+
+    let size = layout.size().max(layout.align());
+    if size > MAX_SIZE_CLASS {
+        return None;
+    }
+    let index = size.next_power_of_two().trailing_zeros().saturating_sub(3) as usize;
+    Some(&SIZE_CLASSES[index])
+}
+
+/// Make the heap allocate the objects of the layout of `cache` from it, instead of a size class.
+///
+/// Does nothing if a named cache with the same layout is already registered.
+pub fn register_named(cache: &'static SlabCache) -> Result<(), Error> {
+    let this = ptr::from_ref(cache).cast_mut();
+    for named in &NAMED_CACHES {
+        match named.compare_exchange(ptr::null_mut(), this, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                trace!(target: "slab", "Register named cache {} ({:?})", cache.name, cache.layout);
+                return Ok(());
+            }
+            Err(other) if unsafe { (*other).layout } == cache.layout => return Ok(()),
+            Err(_) => {}
+        }
+    }
+    Err(Error::CustomStr("Too many named slab caches"))
+}
+
+/// Return the named cache for `layout`, created and registered if there is none.
+pub fn named_cache(name: &'static str, layout: Layout) -> Result<&'static SlabCache, Error> {
+    if let Some(cache) = NAMED_CACHES
+        .iter()
+        .map(|named| named.load(Ordering::Acquire))
+        .take_while(|cache| !cache.is_null())
+        .map(|cache| unsafe { &*cache })
+        .find(|cache| cache.layout == layout)
+    {
+        return Ok(cache);
+    }
+    if layout.size() > MAX_OBJECT_SIZE || layout.align() > PAGE_SIZE {
+        return Err(Error::CustomStr("Object too big for a slab cache"));
+    }
+    let cache: &'static SlabCache = Box::leak(Box::new(SlabCache::new(name, layout)));
+    register_named(cache)?;
+    // another cache with the same layout may have been registered in between
+    cache_for(layout).ok_or(Error::CustomStr("Object too big for a slab cache"))
+}
+
+/// Return the stats of all the caches which allocated memory.
+pub fn all_stats() -> Vec<SlabStats> {
+    let mut stats = Vec::new();
+    let mut cache = ALL_CACHES.load(Ordering::Acquire);
+    while !cache.is_null() {
+        let cache_ref = unsafe { &*cache };
+        let cache_stats = cache_ref.stats();
+        stats.push(cache_stats);
+        cache = cache_ref.next.load(Ordering::Relaxed);
+    }
+    stats
+}
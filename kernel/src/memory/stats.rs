@@ -0,0 +1,115 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    fs::{block, page_cache, procfs},
+    scheduler::process::{self, ProcessId},
+};
+
+use super::{
+    HeapStats, MemoryUsage, PAGE_SIZE, PMM_PAGE_ALLOCATOR, heap_stats, pmm,
+    slab::{self, SLAB_PAGES},
+    swap, vmm,
+};
+
+#[derive(Debug, Clone)]
+pub struct MemInfo {
+    /// Count of pages managed by the physical allocator.
+    pub total_pages: usize,
+    pub free_pages: usize,
+    /// Free pages in the per-CPU caches of the physical allocator, included in `free_pages`.
+    pub cached_pages: usize,
+    /// Pages allocated by the vmm, indexed by `MemoryUsage`.
+    pub usage_pages: [usize; MemoryUsage::ALL.len()],
+    pub heap: HeapStats,
+    pub slab_pages: usize,
+    /// Bytes of the slab objects in use, including the ones in the per-CPU magazines.
+    pub slab_in_use: usize,
+    /// Bytes of the cached blocks of the block devices.
+    pub block_cache: usize,
+    /// Pages of the page cache holding file contents.
+    pub file_pages: usize,
+    /// Slots of the swap area, 0 if the swap isn't enabled.
+    pub swap_pages: usize,
+    pub swap_used: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessMemStats {
+    pub id: ProcessId,
+    pub resident_pages: usize,
+}
+
+/// Return the memory usage of the whole system.
+pub fn meminfo() -> MemInfo {
+    let pmm = PMM_PAGE_ALLOCATOR
+        .get()
+        .expect("PMM_PAGE_ALLOCATOR not initialized");
+    let slabs = slab::all_stats();
+    let (swap_pages, swap_used) = swap::swap_usage().unwrap_or((0, 0));
+    MemInfo {
+        total_pages: pmm.usable_pages(),
+        free_pages: pmm.free_pages(),
+        cached_pages: pmm::cached_pages(),
+        usage_pages: MemoryUsage::ALL.map(vmm::usage_pages),
+        heap: heap_stats(),
+        slab_pages: slabs.iter().map(|s| s.slabs * SLAB_PAGES).sum(),
+        slab_in_use: slabs.iter().map(|s| s.in_use * s.object_size).sum(),
+        block_cache: block::cache_size(),
+        file_pages: page_cache::cached_pages(),
+        swap_pages,
+        swap_used,
+    }
+}
+
+/// Return the resident pages of all the processes, sorted by id.
+///
+/// The kernel threads share the kernel address space, so the kernel process counts all its pages.
+pub fn all_processes_stats() -> Vec<ProcessMemStats> {
+    let mut stats: Vec<_> = process::all_processes()
+        .iter()
+        .map(|process| ProcessMemStats {
+            id: process.id(),
+            resident_pages: process.resident_pages(),
+        })
+        .collect();
+    stats.sort_unstable_by_key(|s| s.id);
+    stats
+}
+
+/// Add `/proc/meminfo`.
+pub fn init() {
+    procfs::add_file("meminfo", proc_meminfo);
+}
+
+/// Content of `/proc/meminfo`, the sizes are in KB.
+fn proc_meminfo() -> String {
+    let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+    let info = meminfo();
+
+    let mut s = String::new();
+    writeln!(s, "MemTotal: {}", kb(info.total_pages)).unwrap();
+    writeln!(s, "MemFree: {}", kb(info.free_pages)).unwrap();
+    writeln!(s, "PageCaches: {}", kb(info.cached_pages)).unwrap();
+    for (usage, pages) in MemoryUsage::ALL.iter().zip(info.usage_pages) {
+        writeln!(s, "{:?}: {}", usage, kb(pages)).unwrap();
+    }
+    writeln!(s, "HeapAllocated: {}", info.heap.allocated / 1024).unwrap();
+    writeln!(s, "HeapChunks: {}", kb(info.heap.chunk_pages)).unwrap();
+    writeln!(s, "HeapChunksFree: {}", info.heap.chunk_free / 1024).unwrap();
+    writeln!(s, "HeapFragmentation: {}%", info.heap.fragmentation()).unwrap();
+    writeln!(s, "HeapLarge: {}", kb(info.heap.large_pages)).unwrap();
+    writeln!(s, "Slab: {}", kb(info.slab_pages)).unwrap();
+    writeln!(s, "SlabInUse: {}", info.slab_in_use / 1024).unwrap();
+    writeln!(s, "BlockCache: {}", info.block_cache / 1024).unwrap();
+    writeln!(s, "FileCache: {}", kb(info.file_pages)).unwrap();
+    writeln!(s, "SwapTotal: {}", kb(info.swap_pages)).unwrap();
+    writeln!(s, "SwapFree: {}", kb(info.swap_pages - info.swap_used)).unwrap();
+
+    writeln!(s).unwrap();
+    writeln!(s, "pid resident_kb").unwrap();
+    for process in all_processes_stats() {
+        writeln!(s, "{} {}", process.id, kb(process.resident_pages)).unwrap();
+    }
+    s
+}
@@ -0,0 +1,531 @@
+use core::{mem::MaybeUninit, slice, time::Duration};
+
+use alloc::{vec, vec::Vec};
+use hashbrown::HashMap;
+use log::{error, info, trace};
+use spin::Once;
+
+use crate::{
+    device_tree,
+    error::{Error, MemoryError::*},
+    fs::{block::BlockIndex, get_node, node::FsNodeRef, page_cache},
+    scheduler::{
+        Cpu, kthread,
+        preempt::{self, might_sleep},
+        process::{self, ProcessId},
+        sleep,
+    },
+    sync::{mutex::Mutex, no_irq_locks::NoIrqMutex},
+    utils::buffer::Buffer,
+};
+
+use super::{
+    AddrSpaceLock, PAGE_SIZE, PMM_PAGE_ALLOCATOR, PageAllocator, PhysicalAddress, USER_SPACE_RANGE,
+    VirtualAddress, VirtualAddressSpace, tlb, vmm::vmm,
+};
+
+/// Free pages under which `kswapd` starts to swap out pages.
+const LOW_WATERMARK: usize = 1024;
+/// Free pages up to which `kswapd` swaps out pages.
+const HIGH_WATERMARK: usize = 2048;
+/// Period of the checks of the free pages by `kswapd`.
+const KSWAPD_PERIOD: Duration = Duration::from_millis(100);
+/// Pages swapped out at least to make room when the memory is exhausted.
+const SWAP_IN_RECLAIM: usize = 32;
+
+static SWAP_AREA: Once<SwapArea> = Once::new();
+static SWAPON_LOCK: Mutex<()> = Mutex::new(());
+/// Position of the clock hand: the next page to scan and the process it belongs to.
+static CLOCK_HAND: Mutex<(ProcessId, VirtualAddress)> = Mutex::new((0, USER_SPACE_RANGE.start));
+
+/// State of a slot with an IO in progress.
+#[derive(Debug, Clone, Copy)]
+enum InFlight {
+    /// Being written, the page still holds its content.
+    Writing(PhysicalAddress),
+    /// Being read.
+    Reading,
+    /// Mapped back during its write, the slot is freed once the write ends.
+    Remapped,
+    /// Its entry was unmapped during the IO, the slot and the page of a write are freed once the IO ends.
+    Discarded(Option<PhysicalAddress>),
+}
+
+#[derive(Debug)]
+struct Slots {
+    bitmap: Vec<u64>,
+    count: usize,
+    used: usize,
+    /// Where to start the search of a free slot.
+    next: usize,
+}
+
+impl Slots {
+    fn alloc(&mut self) -> Option<usize> {
+        if self.used == self.count {
+            return None;
+        }
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let index = (self.next / 64 + i) % words;
+            let word = self.bitmap[index];
+            if word == u64::MAX {
+                continue;
+            }
+            let slot = index * 64 + word.trailing_ones() as usize;
+            if slot >= self.count {
+                continue;
+            }
+            self.bitmap[index] |= 1 << (slot % 64);
+            self.used += 1;
+            self.next = slot + 1;
+            return Some(slot);
+        }
+        None
+    }
+
+    fn free(&mut self, slot: usize) {
+        let bit = 1 << (slot % 64);
+        assert!(
+            self.bitmap[slot / 64] & bit != 0,
+            "Double free of swap slot {slot}"
+        );
+        self.bitmap[slot / 64] &= !bit;
+        self.used -= 1;
+    }
+}
+
+/// A device or a file holding the swapped out pages, a slot per page.
+#[derive(Debug)]
+struct SwapArea {
+    node: FsNodeRef,
+    slots: NoIrqMutex<Slots>,
+    in_flight: NoIrqMutex<HashMap<usize, InFlight>>,
+}
+
+impl SwapArea {
+    fn new(node: FsNodeRef) -> Result<Self, Error> {
+        if let Some(block) = node.as_block() {
+            if !PAGE_SIZE.is_multiple_of(block.block_size()) {
+                return Err(Error::CustomStr(
+                    "Swap device block size doesn't divide the page size",
+                ));
+            }
+        } else if node.as_file().is_none() {
+            return Err(Error::CustomStr(
+                "Swap area must be a block device or a file",
+            ));
+        }
+        let count = node.infos.size / PAGE_SIZE;
+        if count == 0 {
+            return Err(Error::CustomStr("Swap area smaller than a page"));
+        }
+        Ok(Self {
+            node,
+            slots: NoIrqMutex::new(Slots {
+                bitmap: vec![0; count.div_ceil(64)],
+                count,
+                used: 0,
+                next: 0,
+            }),
+            in_flight: NoIrqMutex::new(HashMap::new()),
+        })
+    }
+
+    #[inline]
+    fn alloc_slot(&self) -> Option<usize> {
+        self.slots.lock().alloc()
+    }
+
+    #[inline]
+    fn free_slot(&self, slot: usize) {
+        self.slots.lock().free(slot)
+    }
+
+    fn read_page(&self, slot: usize, page: PhysicalAddress) -> Result<(), Error> {
+        let buff = unsafe { page_buffer(page) };
+        if let Some(block) = self.node.as_block() {
+            block.read_direct(BlockIndex(slot * PAGE_SIZE / block.block_size()), buff)
+        } else if self.node.as_file().unwrap().read(slot * PAGE_SIZE, buff)? != PAGE_SIZE {
+            Err(Error::CustomStr("Swap file truncated"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_page(&self, slot: usize, page: PhysicalAddress) -> Result<(), Error> {
+        let buff = unsafe { page_buffer(page) };
+        if let Some(block) = self.node.as_block() {
+            block.write_direct(BlockIndex(slot * PAGE_SIZE / block.block_size()), buff)
+        } else if self.node.as_file().unwrap().write(slot * PAGE_SIZE, buff)? != PAGE_SIZE {
+            Err(Error::CustomStr("Swap file truncated"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Access the physical `page` through the linear mapping.
+///
+/// Safety: the page must be owned by the caller.
+#[inline]
+unsafe fn page_buffer(page: PhysicalAddress) -> &'static mut Buffer {
+    let ptr = page.to_virt().as_ptr::<MaybeUninit<u8>>();
+    Buffer::from_slice_mut(unsafe { slice::from_raw_parts_mut(ptr, PAGE_SIZE) })
+}
+
+/// Enable the swap to the block device or the file at `path`, and start `kswapd`.
+///
+/// The anonymous user pages may then be swapped out, the blocks of the device shouldn't be used by anything else.
+pub fn swapon(path: &str) -> Result<(), Error> {
+    let _guard = SWAPON_LOCK.lock();
+    if SWAP_AREA.is_completed() {
+        return Err(Error::CustomStr("A swap area is already enabled"));
+    }
+    let area = SwapArea::new(get_node(path)?)?;
+    let count = area.slots.lock().count;
+    SWAP_AREA.call_once(|| area);
+    kthread::spawn("kswapd", kswapd)?;
+    info!(target: "swap", "Swap enabled on {} ({} KB)", path, count * PAGE_SIZE / 1024);
+    Ok(())
+}
+
+/// Enable the swap on the path of the `swap=<path>` boot argument of the device tree, if there is one.
+pub fn swapon_from_bootargs() {
+    let path = device_tree::get_node("/chosen")
+        .and_then(|chosen| chosen.get_property("bootargs"))
+        .and_then(|bootargs| bootargs.buff().consume_str())
+        .and_then(|bootargs| {
+            bootargs
+                .split_whitespace()
+                .find_map(|arg| arg.strip_prefix("swap="))
+        });
+    if let Some(path) = path
+        && let Err(e) = swapon(path)
+    {
+        error!(target: "swap", "Failed to enable the swap on {}: {}", path, e);
+    }
+}
+
+/// Return the count of slots of the swap area and the count of used ones, None if the swap isn't enabled.
+pub fn swap_usage() -> Option<(usize, usize)> {
+    let slots = SWAP_AREA.get()?.slots.lock();
+    Some((slots.count, slots.used))
+}
+
+/// Keep the free pages above the watermarks by swapping out pages.
+fn kswapd() {
+    let pmm = PMM_PAGE_ALLOCATOR.get().unwrap();
+    loop {
+        let free = pmm.free_pages();
+        if free < LOW_WATERMARK {
+            // the clean file pages are cheaper to drop than swapping out pages
+            let dropped = page_cache::shrink(HIGH_WATERMARK - free);
+            let free = free + dropped;
+            if free >= LOW_WATERMARK {
+                trace!(target: "swap", "kswapd dropped {} cached file pages", dropped);
+            } else {
+                match reclaim(HIGH_WATERMARK - free) {
+                    Ok(count) => trace!(target: "swap", "kswapd swapped out {} pages", count),
+                    Err(e) => error!(target: "swap", "kswapd failed to swap out pages: {}", e),
+                }
+            }
+        }
+        sleep(KSWAPD_PERIOD);
+    }
+}
+
+/// Swap out up to `count` pages of the user processes with the clock algorithm, return the count of pages swapped out.
+///
+/// The pages accessed since the previous pass of the clock hand get a second chance.
+pub fn reclaim(count: usize) -> Result<usize, Error> {
+    might_sleep();
+    let area = SWAP_AREA
+        .get()
+        .ok_or(Error::CustomStr("Swap not enabled"))?;
+    reclaim_locked(area, &mut CLOCK_HAND.lock(), count)
+}
+
+/// Swap out pages after a failed allocation of `count` pages, return true if some were swapped out.
+///
+/// Does nothing if the context can't sleep or if a reclaim is in progress, which may be the one of the
+/// current thread allocating.
+pub fn reclaim_for_alloc(count: usize) -> bool {
+    let Some(area) = SWAP_AREA.get() else {
+        return false;
+    };
+    if Cpu::current().try_current_thread().is_none() || !preempt::preemptible() {
+        return false;
+    }
+    let Some(mut hand) = CLOCK_HAND.try_lock() else {
+        return false;
+    };
+    match reclaim_locked(area, &mut hand, count.max(SWAP_IN_RECLAIM)) {
+        Ok(count) => count > 0,
+        Err(e) => {
+            error!(target: "swap", "Failed to swap out pages for an allocation: {}", e);
+            false
+        }
+    }
+}
+
+fn reclaim_locked(
+    area: &SwapArea,
+    hand: &mut (ProcessId, VirtualAddress),
+    count: usize,
+) -> Result<usize, Error> {
+    let mut processes: Vec<_> = process::all_processes()
+        .into_iter()
+        .filter(|process| process.get_addr_space().is_low())
+        .collect();
+    processes.sort_unstable_by_key(|process| process.id());
+    if processes.is_empty() {
+        return Ok(0);
+    }
+    let start = processes
+        .iter()
+        .position(|process| process.id() >= hand.0)
+        .unwrap_or(0);
+    if processes[start].id() != hand.0 {
+        hand.1 = USER_SPACE_RANGE.start;
+    }
+
+    // two turns, the pages passed during the first one lost their access flag
+    let mut swapped = 0;
+    let mut result = Ok(());
+    'clock: for process in processes
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(2 * processes.len() + 1)
+    {
+        hand.0 = process.id();
+        let addr_space = process.get_addr_space();
+        while swapped < count {
+            let victim =
+                vmm().find_swap_victim(hand.1..USER_SPACE_RANGE.end, &mut addr_space.lock());
+            let Some(victim) = victim else {
+                // the cleared access flags must be effective before the next turn
+                tlb::flush_all_broadcast();
+                hand.1 = USER_SPACE_RANGE.start;
+                break;
+            };
+            hand.1 = victim + PAGE_SIZE;
+            match swap_out(area, addr_space, victim) {
+                Ok(true) => swapped += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break 'clock;
+                }
+            }
+        }
+        if swapped >= count {
+            break;
+        }
+    }
+    tlb::flush_all_broadcast();
+    result.map(|_| swapped)
+}
+
+/// Swap out the page at `addr` if it wasn't accessed since the last scan. Return false if it wasn't swapped out.
+fn swap_out(
+    area: &SwapArea,
+    addr_space: &AddrSpaceLock,
+    addr: VirtualAddress,
+) -> Result<bool, Error> {
+    let slot = area
+        .alloc_slot()
+        .ok_or(Error::CustomStr("Swap area full"))?;
+    let page = {
+        let mut lock = addr_space.lock();
+        let Some(page) = vmm().swap_out_page(addr, slot, &mut lock) else {
+            drop(lock);
+            area.free_slot(slot);
+            return Ok(false);
+        };
+        // the faults during the write find the page here
+        area.in_flight.lock().insert(slot, InFlight::Writing(page));
+        page
+    };
+    // no CPU can write the page anymore
+    tlb::flush_page_broadcast(addr);
+    let r = area.write_page(slot, page);
+
+    let pmm = PMM_PAGE_ALLOCATOR.get().unwrap();
+    let mut lock = addr_space.lock();
+    let state = area.in_flight.lock().remove(&slot);
+    match state {
+        Some(InFlight::Writing(page)) if r.is_ok() => {
+            drop(lock);
+            unsafe { pmm.dealloc(page, 1) };
+            Ok(true)
+        }
+        Some(InFlight::Writing(page)) => {
+            let mapped = vmm().swap_in_page(addr, slot, page, &mut lock);
+            debug_assert!(mapped);
+            drop(lock);
+            area.free_slot(slot);
+            r.map(|_| false)
+        }
+        Some(InFlight::Remapped) => {
+            drop(lock);
+            area.free_slot(slot);
+            Ok(false)
+        }
+        Some(InFlight::Discarded(page)) => {
+            drop(lock);
+            if let Some(page) = page {
+                unsafe { pmm.dealloc(page, 1) };
+            }
+            area.free_slot(slot);
+            Ok(false)
+        }
+        Some(InFlight::Reading) | None => unreachable!("Swap slot {slot} written and read"),
+    }
+}
+
+/// Release the swap slot of an unmapped swap entry. Called with the lock of its address space.
+pub(super) fn discard(slot: usize) {
+    let area = SWAP_AREA.get().expect("Swap entry without swap area");
+    let mut in_flight = area.in_flight.lock();
+    match in_flight.get(&slot).copied() {
+        // freed once the IO ends
+        Some(InFlight::Writing(page)) => {
+            in_flight.insert(slot, InFlight::Discarded(Some(page)));
+        }
+        Some(InFlight::Reading) => {
+            in_flight.insert(slot, InFlight::Discarded(None));
+        }
+        Some(InFlight::Remapped | InFlight::Discarded(_)) => {
+            unreachable!("Swap slot {slot} discarded twice")
+        }
+        None => {
+            drop(in_flight);
+            area.free_slot(slot);
+        }
+    }
+}
+
+/// Outcome of a fault on a user page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// The access can be retried.
+    Resolved,
+    /// The page must be read back by `swap_in`, in the context of the thread since it sleeps.
+    SwapIn,
+    /// No page is mapped at the address.
+    Invalid,
+}
+
+enum Resolve {
+    Resolved,
+    Swapped(usize),
+    /// Being read by another thread.
+    Busy,
+    Invalid,
+}
+
+/// Resolve the fault at the page `addr` without IO.
+fn resolve(addr: VirtualAddress, addr_space: &mut VirtualAddressSpace) -> Resolve {
+    if vmm().set_accessed(addr, addr_space) {
+        return Resolve::Resolved;
+    }
+    let Some(slot) = vmm().swap_slot(addr, addr_space) else {
+        return Resolve::Invalid;
+    };
+    let area = SWAP_AREA.get().expect("Swap entry without swap area");
+    let mut in_flight = area.in_flight.lock();
+    match in_flight.get(&slot).copied() {
+        None => Resolve::Swapped(slot),
+        Some(InFlight::Writing(page)) => {
+            // still in memory, the slot is freed once the write ends
+            in_flight.insert(slot, InFlight::Remapped);
+            let mapped = vmm().swap_in_page(addr, slot, page, addr_space);
+            debug_assert!(mapped);
+            Resolve::Resolved
+        }
+        Some(_) => Resolve::Busy,
+    }
+}
+
+/// Resolve an access flag or translation fault at `addr` in the low `addr_space`, without sleeping.
+pub fn handle_fault(addr: VirtualAddress, addr_space: &AddrSpaceLock) -> FaultResolution {
+    if !addr_space.is_low() || !USER_SPACE_RANGE.contains(&addr) {
+        return FaultResolution::Invalid;
+    }
+    let addr = VirtualAddress::new(addr.addr() & !(PAGE_SIZE - 1));
+    match resolve(addr, &mut addr_space.lock()) {
+        Resolve::Resolved => FaultResolution::Resolved,
+        Resolve::Swapped(_) | Resolve::Busy => FaultResolution::SwapIn,
+        Resolve::Invalid => FaultResolution::Invalid,
+    }
+}
+
+/// Read back the page at `addr` of the low `addr_space` from the swap.
+pub fn swap_in(addr: VirtualAddress, addr_space: &AddrSpaceLock) -> Result<(), Error> {
+    might_sleep();
+    let addr = VirtualAddress::new(addr.addr() & !(PAGE_SIZE - 1));
+    let pmm = PMM_PAGE_ALLOCATOR.get().unwrap();
+    loop {
+        let slot = {
+            let mut lock = addr_space.lock();
+            match resolve(addr, &mut lock) {
+                Resolve::Resolved => return Ok(()),
+                Resolve::Invalid => return Err(Error::Memory(NotMapped)),
+                Resolve::Busy => {
+                    drop(lock);
+                    sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Resolve::Swapped(slot) => slot,
+            }
+        };
+        let area = SWAP_AREA.get().unwrap();
+
+        let page = match pmm.alloc(1) {
+            Some(page) => page,
+            None => {
+                reclaim(SWAP_IN_RECLAIM)?;
+                pmm.alloc(1).ok_or(Error::Memory(OutOfPhysicalMemory))?
+            }
+        };
+        {
+            let mut lock = addr_space.lock();
+            // the entry may have changed during the allocation
+            if vmm().swap_slot(addr, &mut lock) != Some(slot) {
+                drop(lock);
+                unsafe { pmm.dealloc(page, 1) };
+                continue;
+            }
+            area.in_flight.lock().insert(slot, InFlight::Reading);
+        }
+        let r = area.read_page(slot, page);
+
+        let mut lock = addr_space.lock();
+        let state = area.in_flight.lock().remove(&slot);
+        match state {
+            Some(InFlight::Reading) if r.is_ok() => {
+                let mapped = vmm().swap_in_page(addr, slot, page, &mut lock);
+                debug_assert!(mapped);
+                drop(lock);
+                area.free_slot(slot);
+                trace!(target: "swap", "Swapped in {} from slot {}", addr, slot);
+                return Ok(());
+            }
+            Some(InFlight::Reading) => {
+                drop(lock);
+                unsafe { pmm.dealloc(page, 1) };
+                return r;
+            }
+            Some(InFlight::Discarded(None)) => {
+                drop(lock);
+                unsafe { pmm.dealloc(page, 1) };
+                area.free_slot(slot);
+                // unmapped meanwhile
+                r?;
+            }
+            _ => unreachable!("Swap slot {slot} read and written"),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use core::arch::asm;
+
+use super::{PAGE_SHIFT, PAGE_SIZE, VirtualAddress};
+
+/// Above this count of pages, the whole TLB is flushed instead.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+#[inline]
+pub fn flush_page_local(addr: VirtualAddress) {
+    unsafe {
+        let v = addr.addr() >> PAGE_SHIFT;
+        asm!(
+            "dsb nshst",
+            "tlbi vaae1, {}",
+            "dsb nsh",
+            "isb",
+            in(reg) v,
+            options(preserves_flags)
+        );
+    }
+}
+
+#[inline]
+pub fn flush_all_local() {
+    unsafe {
+        asm!(
+            "dsb nshst",
+            "tlbi vmalle1",
+            "dsb nsh",
+            "isb",
+            options(preserves_flags)
+        )
+    }
+}
+
+/// Invalidate `addr` in the TLBs of all the CPUs of the inner shareable domain.
+#[inline]
+pub fn flush_page_broadcast(addr: VirtualAddress) {
+    unsafe {
+        let v = addr.addr() >> PAGE_SHIFT;
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) v,
+            options(preserves_flags)
+        );
+    }
+}
+
+#[inline]
+pub fn flush_all_broadcast() {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            options(preserves_flags)
+        )
+    }
+}
+
+/// Invalidate `count` pages starting at `start` on all the online CPUs.
+///
+/// Should be called after the page table entries were changed, and before the
+/// physical pages are reused. The broadcast TLB maintenance reaches all the CPUs
+/// of the inner shareable domain, no IPI is needed and it can be done with IRQs
+/// disabled.
+pub fn flush_range(start: VirtualAddress, count: usize) {
+    if count > FLUSH_ALL_THRESHOLD {
+        flush_all_broadcast();
+    } else {
+        for i in 0..count {
+            flush_page_broadcast(start + i * PAGE_SIZE);
+        }
+    }
+}
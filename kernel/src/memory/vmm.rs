@@ -3,22 +3,43 @@ use super::{
     VirtualAddress,
     addr_space::VirtualAddressSpace,
     address::{Physical, Virtual},
-    mmu::Mmu,
+    mmu::{FreedTables, Mmu},
+    swap, tlb,
 };
 use crate::{
     error::{Error, MemoryError::*},
     memory::{
         KERNEL_DATA_RANGE, KERNEL_HEAP_RANGE, LOW_ADDR_SPACE_RANGE, USER_SPACE_RANGE,
-        constants::PAGE_SIZE,
+        constants::{HUGE_PAGE_PAGES, PAGE_SIZE},
     },
     utils::sync_once_cell::SyncOnceCell,
 };
 use aarch64_cpu::registers::TTBR1_EL1;
 use core::{
     fmt::Debug,
+    ops::Range,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use log::trace;
+use log::{error, trace};
+
+unsafe extern "C" {
+    // bounds of the kernel sections, defined by the linker script
+    #[allow(improper_ctypes)]
+    unsafe static __text_start: ();
+    #[allow(improper_ctypes)]
+    unsafe static __rodata_start: ();
+    #[allow(improper_ctypes)]
+    unsafe static __data_start: ();
+    #[allow(improper_ctypes)]
+    unsafe static __bss_end: ();
+}
+
+/// Count of pages or blocks unmapped by `dealloc_pages` before shooting down the TLBs.
+const DEALLOC_BATCH: usize = 64;
+
+/// Pages allocated by the vmm, indexed by `MemoryUsage`.
+static USAGE_PAGES: [AtomicUsize; MemoryUsage::ALL.len()] =
+    [const { AtomicUsize::new(0) }; MemoryUsage::ALL.len()];
 
 static mut KERNEL_ADDR_SPACE: Option<AddrSpaceLock> = None;
 pub static VIRTUAL_MANAGER: SyncOnceCell<VirtualMemoryManager> = SyncOnceCell::new();
@@ -63,7 +84,6 @@ pub fn get_kernel_addr_space() -> &'static AddrSpaceLock {
 pub struct VirtualMemoryManager<'a> {
     physical: &'a dyn PageAllocator<Physical>,
     mmu: Mmu<'a>,
-    modules_load_address: AtomicUsize,
 }
 
 impl<'a> VirtualMemoryManager<'a> {
@@ -71,7 +91,6 @@ impl<'a> VirtualMemoryManager<'a> {
         Self {
             physical,
             mmu: Mmu::new(physical),
-            modules_load_address: AtomicUsize::new(MODULES_SPACE_RANGE.start.addr()),
         }
     }
 
@@ -120,25 +139,135 @@ impl<'a> VirtualMemoryManager<'a> {
         &self,
         addr: VirtualAddress,
         size: MapSize,
-        addr_space: AddrSpaceSelector,
+        mut addr_space: AddrSpaceSelector,
     ) -> Result<PhysicalAddress, Error> {
         trace!(target: "vmm", "Unmap {}", addr );
 
-        let mut addr_space = addr_space.lock();
+        let mut lock = addr_space.reborrow().lock();
         {
             let is_low = LOW_ADDR_SPACE_RANGE.contains(&addr);
-            if addr_space.is_low != is_low {
+            if lock.is_low != is_low {
                 return Err(Error::Memory(InvalidAddrSpace));
             }
         }
-        self.mmu.unmap(addr, size, &mut addr_space)
+        let phys_addr = self.mmu.unmap(addr, size, &mut lock)?;
+        drop(lock);
+
+        let count = match size {
+            MapSize::Size4KB => 1,
+            MapSize::Size2MB => 512,
+            MapSize::Size1GB => 512 * 512,
+        };
+        tlb::flush_range(addr, count);
+
+        let mut lock = addr_space.lock();
+        self.free_empty_tables(addr, count, &mut lock);
+        Ok(phys_addr)
+    }
+
+    /// Change the access permissions of `count` pages from `addr` to the ones of `flags`.
+    ///
+    /// The blocks partially in the range are split.
+    pub fn protect(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        flags: MapFlags,
+        addr_space: AddrSpaceSelector,
+    ) -> Result<(), Error> {
+        trace!(target: "vmm", "Protect {} pages at {}", count, addr);
+        let flags = match MemoryUsage::of(addr) {
+            Some(usage) => flags.restrict_to(usage),
+            None => flags,
+        };
+
+        let mut lock = addr_space.lock();
+        if lock.is_low != LOW_ADDR_SPACE_RANGE.contains(&addr) {
+            return Err(Error::Memory(InvalidAddrSpace));
+        }
+        let mut done = 0;
+        let r = loop {
+            if done == count {
+                break Ok(());
+            }
+            match self
+                .mmu
+                .protect_leaf(addr + done * PAGE_SIZE, count - done, flags, &mut lock)
+            {
+                Ok(pages) => done += pages,
+                Err(e) => break Err(e),
+            }
+        };
+        drop(lock);
+
+        // some pages may have changed even on error
+        tlb::flush_range(addr, count);
+        r
+    }
+
+    /// Map the sections of the kernel with least privilege, the loader maps them writable and executable.
+    pub(super) fn protect_kernel_sections(&self) -> Result<(), Error> {
+        let sections = unsafe {
+            [
+                (
+                    &__text_start as *const (),
+                    &__rodata_start as *const (),
+                    MapFlags::default_rw(true).with_exec(),
+                ),
+                (
+                    &__rodata_start as *const (),
+                    &__data_start as *const (),
+                    MapFlags::default_rw(true),
+                ),
+                (
+                    &__data_start as *const (),
+                    &__bss_end as *const (),
+                    MapFlags::default(),
+                ),
+            ]
+        };
+        for (start, end, flags) in sections {
+            let count = (end.addr() - start.addr()).div_ceil(PAGE_SIZE);
+            self.protect(
+                VirtualAddress::from_ptr(start),
+                count,
+                flags,
+                AddrSpaceSelector::kernel(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Log the mappings of the kernel address space both writable and executable.
+    ///
+    /// Return their count.
+    pub fn check_wx(&self) -> usize {
+        let lock = AddrSpaceSelector::kernel().lock();
+        let mut count = 0;
+        self.mmu.find_wx_mappings(&lock, &mut |addr, pages| {
+            error!(target: "vmm", "{} pages at {} are writable and executable", pages, addr);
+            count += 1;
+        });
+        count
     }
 
+    #[inline]
     pub fn find_free_pages(
         &self,
         count: usize,
         usage: MemoryUsage,
         addr_space: AddrSpaceSelector,
+    ) -> Result<VirtualAddress, Error> {
+        self.find_free_pages_aligned(count, 1, usage, addr_space)
+    }
+
+    /// Same as `find_free_pages` but the space is aligned on `align` pages, up to the pages of a 2 MB block.
+    pub fn find_free_pages_aligned(
+        &self,
+        count: usize,
+        align: usize,
+        usage: MemoryUsage,
+        addr_space: AddrSpaceSelector,
     ) -> Result<VirtualAddress, Error> {
         trace!(target: "vmm", "Search {count} pages of {:?} virtual space", usage);
         let is_low_addr_space = match usage {
@@ -153,25 +282,14 @@ impl<'a> VirtualMemoryManager<'a> {
             return Err(Error::Memory(InvalidAddrSpace));
         }
 
-        if usage == MemoryUsage::ModuleSpace {
-            // module space alloc is special
-            let addr = self
-                .modules_load_address
-                .fetch_add(count * PAGE_SIZE, Ordering::Relaxed);
-            if addr + count * PAGE_SIZE >= MODULES_SPACE_RANGE.end {
-                return Err(Error::Memory(OutOfVirtualSpace));
-            }
-            return Ok(VirtualAddress::new(addr));
-        }
-
         let range = match usage {
             MemoryUsage::KernelHeap => KERNEL_HEAP_RANGE,
             MemoryUsage::KernelData => KERNEL_DATA_RANGE,
+            MemoryUsage::ModuleSpace => MODULES_SPACE_RANGE,
             MemoryUsage::UserData => USER_SPACE_RANGE,
-            MemoryUsage::ModuleSpace => unreachable!(),
         };
 
-        self.mmu.find_free_pages(count, range, &addr_space)
+        self.mmu.find_free_pages(count, align, range, &addr_space)
     }
 
     /// `find_free_pages` and then `map`.
@@ -198,6 +316,10 @@ impl<'a> VirtualMemoryManager<'a> {
         }
     }
 
+    /// Allocate and map `count` pages of `usage`.
+    ///
+    /// The allocations of at least 2 MB are mapped with 2 MB blocks when possible, the huge user pages
+    /// aren't swapped out.
     pub fn alloc_pages(
         &self,
         count: usize,
@@ -207,42 +329,196 @@ impl<'a> VirtualMemoryManager<'a> {
     ) -> Result<VirtualAddress, Error> {
         trace!(target: "vmm", "Alloc {} pages of {:?}", count, usage);
 
+        let align = if count >= HUGE_PAGE_PAGES {
+            HUGE_PAGE_PAGES
+        } else {
+            1
+        };
+        // before locking the address space, pages of the processes may be swapped out to make room
+        let paddr = self
+            .alloc_physical(count, align)
+            .ok_or(Error::Memory(OutOfPhysicalMemory))?;
+
+        let mut lock = addr_space.lock();
+        let virtual_addr = self
+            .find_free_pages_aligned(count, align, usage, AddrSpaceSelector::Unlocked(&mut lock))
+            .or_else(|_| self.find_free_pages(count, usage, AddrSpaceSelector::Unlocked(&mut lock)))
+            .inspect_err(|_| unsafe { self.physical.dealloc(paddr, count) })?;
+        let map_flags = if usage == MemoryUsage::UserData {
+            map_flags.with_owned().with_swappable()
+        } else {
+            map_flags.with_owned()
+        }
+        .restrict_to(usage);
+        unsafe {
+            self.map(
+                virtual_addr,
+                paddr,
+                count,
+                map_flags,
+                AddrSpaceSelector::Unlocked(&mut lock),
+            )?
+        };
+        account_alloc(&mut lock, virtual_addr, count);
+
+        Ok(virtual_addr)
+    }
+
+    /// Same as `alloc_pages` but the physical pages end at or before the byte `limit`, for the devices which
+    /// can't reach all the memory.
+    ///
+    /// The pages are never swapped out.
+    pub fn alloc_pages_below(
+        &self,
+        count: usize,
+        limit: usize,
+        usage: MemoryUsage,
+        map_flags: MapFlags,
+        addr_space: AddrSpaceSelector,
+    ) -> Result<VirtualAddress, Error> {
+        trace!(target: "vmm", "Alloc {} pages of {:?} below {:#x}", count, usage, limit);
+
         let mut lock = addr_space.lock();
         let virtual_addr =
             self.find_free_pages(count, usage, AddrSpaceSelector::Unlocked(&mut lock))?;
-
         let paddr = self
             .physical
-            .alloc(count)
+            .alloc_below(count, limit)
             .ok_or(Error::Memory(OutOfPhysicalMemory))?;
-        unsafe {
+        let r = unsafe {
             self.map(
                 virtual_addr,
                 paddr,
                 count,
-                map_flags,
+                map_flags.with_owned().restrict_to(usage),
                 AddrSpaceSelector::Unlocked(&mut lock),
-            )?
+            )
+        };
+        if let Err(e) = r {
+            self.unmap_failed_map(virtual_addr, count, &mut lock);
+            unsafe { self.physical.dealloc(paddr, count) };
+            return Err(e);
+        }
+        account_alloc(&mut lock, virtual_addr, count);
+
+        Ok(virtual_addr)
+    }
+
+    /// Same as `alloc_pages` but the page below the allocation is a guard page, so an overflow of a stack faults.
+    ///
+    /// The pages are never swapped out, the stacks are used with IRQs disabled.
+    pub fn alloc_guarded_pages(
+        &self,
+        count: usize,
+        usage: MemoryUsage,
+        map_flags: MapFlags,
+        addr_space: AddrSpaceSelector,
+    ) -> Result<VirtualAddress, Error> {
+        trace!(target: "vmm", "Alloc {} guarded pages of {:?}", count, usage);
+
+        let mut lock = addr_space.lock();
+        let guard_addr =
+            self.find_free_pages(count + 1, usage, AddrSpaceSelector::Unlocked(&mut lock))?;
+        self.mmu.set_guard_page(guard_addr, &mut lock)?;
+        let virtual_addr = guard_addr + PAGE_SIZE;
+
+        let Some(paddr) = self.physical.alloc(count) else {
+            self.mmu.clear_guard_page(guard_addr, &mut lock)?;
+            self.free_empty_tables(guard_addr, 1, &mut lock);
+            return Err(Error::Memory(OutOfPhysicalMemory));
         };
+        let r = unsafe {
+            self.map(
+                virtual_addr,
+                paddr,
+                count,
+                map_flags.with_owned().restrict_to(usage),
+                AddrSpaceSelector::Unlocked(&mut lock),
+            )
+        };
+        if let Err(e) = r {
+            self.unmap_failed_map(virtual_addr, count, &mut lock);
+            unsafe { self.physical.dealloc(paddr, count) };
+            self.mmu.clear_guard_page(guard_addr, &mut lock)?;
+            self.free_empty_tables(guard_addr, 1, &mut lock);
+            return Err(e);
+        }
+        account_alloc(&mut lock, virtual_addr, count);
 
         Ok(virtual_addr)
     }
 
+    /// Dealloc pages allocated by `alloc_guarded_pages` and release their guard page.
+    pub fn dealloc_guarded_pages(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        mut addr_space: AddrSpaceSelector,
+    ) -> Result<(), Error> {
+        self.dealloc_pages(addr, count, addr_space.reborrow())?;
+        let mut lock = addr_space.lock();
+        self.mmu.clear_guard_page(addr - PAGE_SIZE, &mut lock)?;
+        self.free_empty_tables(addr - PAGE_SIZE, 1, &mut lock);
+        Ok(())
+    }
+
+    /// Unmap and free `count` pages from `addr`, the blocks partially in the range are split.
     pub fn dealloc_pages(
         &self,
         addr: VirtualAddress,
         count: usize,
-        addr_space: AddrSpaceSelector,
+        mut addr_space: AddrSpaceSelector,
     ) -> Result<(), Error> {
         trace!(target: "vmm", "Dealloc {} pages at addr {}", count, addr);
-        let mut lock = addr_space.lock();
-        for i in 0..count {
-            let phys_addr = self.unmap_page(
-                addr + i * PAGE_SIZE,
-                MapSize::Size4KB,
-                AddrSpaceSelector::Unlocked(&mut lock),
-            )?;
-            unsafe { self.physical.dealloc(phys_addr, 1) };
+        if addr_space.reborrow().lock().is_low != LOW_ADDR_SPACE_RANGE.contains(&addr) {
+            return Err(Error::Memory(InvalidAddrSpace));
+        }
+
+        // the pages are freed only once no CPU can access them
+        let mut phys_addrs = [(PhysicalAddress::new(0), 0); DEALLOC_BATCH];
+        let mut done = 0;
+        while done < count {
+            let chunk_addr = addr + done * PAGE_SIZE;
+
+            let mut lock = addr_space.reborrow().lock();
+            let mut unmapped = 0;
+            let mut freed = 0;
+            let mut freed_pages = 0;
+            let r = loop {
+                if done + unmapped == count || freed == DEALLOC_BATCH {
+                    break Ok(());
+                }
+                let page_addr = chunk_addr + unmapped * PAGE_SIZE;
+                if let Some(slot) = self.mmu.take_swap_entry(page_addr, &mut lock) {
+                    swap::discard(slot);
+                    unmapped += 1;
+                    continue;
+                }
+                match self
+                    .mmu
+                    .unmap_leaf(page_addr, count - done - unmapped, &mut lock)
+                {
+                    Ok((phys_addr, pages)) => {
+                        phys_addrs[freed] = (phys_addr, pages);
+                        freed += 1;
+                        freed_pages += pages;
+                        unmapped += pages;
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+            account_dealloc(&mut lock, chunk_addr, freed_pages);
+            drop(lock);
+
+            tlb::flush_range(chunk_addr, unmapped);
+            for (phys_addr, pages) in &phys_addrs[..freed] {
+                unsafe { self.physical.dealloc(*phys_addr, *pages) };
+            }
+            let mut lock = addr_space.reborrow().lock();
+            self.free_empty_tables(chunk_addr, unmapped, &mut lock);
+            drop(lock);
+            r?;
+            done += unmapped;
         }
         Ok(())
     }
@@ -254,13 +530,164 @@ impl<'a> VirtualMemoryManager<'a> {
         flags: MapFlags,
         addr_space: AddrSpaceSelector,
     ) -> Result<VirtualAddress, Error> {
-        let mut addr_space = addr_space.lock();
+        // aligned like `addr` so the 2 MB blocks can be used
+        let align = if count >= HUGE_PAGE_PAGES && addr.is_aligned_to(HUGE_PAGE_PAGES * PAGE_SIZE) {
+            HUGE_PAGE_PAGES
+        } else {
+            1
+        };
+        // before locking the address space, pages of the processes may be swapped out to make room
         let phys_addr = self
-            .physical
-            .alloc(count)
+            .alloc_physical(count, align)
             .ok_or(Error::Memory(OutOfPhysicalMemory))?;
+        let mut addr_space = addr_space.lock();
+
+        let flags = match MemoryUsage::of(addr) {
+            Some(MemoryUsage::UserData) => flags
+                .with_owned()
+                .with_swappable()
+                .restrict_to(MemoryUsage::UserData),
+            Some(usage) => flags.with_owned().restrict_to(usage),
+            None => flags.with_owned(),
+        };
+        let addr = self
+            .mmu
+            .map(addr, phys_addr, count, flags, &mut addr_space)?;
+        account_alloc(&mut addr_space, addr, count);
+        Ok(addr)
+    }
+
+    /// Allocate `count` physical pages aligned to `align` pages if possible, for the 2 MB blocks.
+    ///
+    /// Once out of memory, pages are swapped out and the allocation is retried.
+    fn alloc_physical(&self, count: usize, align: usize) -> Option<PhysicalAddress> {
+        let alloc = || {
+            if align == 1 {
+                // the single pages come from the per-CPU caches
+                return self.physical.alloc(count);
+            }
+            // the memory may be too fragmented for an aligned range but not for the pages
+            self.physical
+                .alloc_aligned(count, align)
+                .or_else(|| self.physical.alloc(count))
+        };
+        alloc().or_else(|| swap::reclaim_for_alloc(count).then(alloc).flatten())
+    }
+
+    /// Unmap the pages mapped by a failed `map` of `count` free pages at `addr`, without freeing them.
+    fn unmap_failed_map(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) {
+        // the pages are mapped in order up to the failure
+        let mut unmapped = 0;
+        while unmapped < count {
+            let page_addr = addr + unmapped * PAGE_SIZE;
+            match self.mmu.unmap_leaf(page_addr, count - unmapped, addr_space) {
+                Ok((_, pages)) => unmapped += pages,
+                Err(_) => break,
+            }
+        }
+        tlb::flush_range(addr, unmapped);
+        self.free_empty_tables(addr, count, addr_space);
+    }
+
+    /// Free the page tables left empty by the unmap of `count` pages at `addr`.
+    fn free_empty_tables(
+        &self,
+        addr: VirtualAddress,
+        count: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) {
+        loop {
+            let mut tables = FreedTables::new();
+            let complete = self
+                .mmu
+                .take_empty_tables(addr, count, addr_space, &mut tables);
+            if !tables.as_slice().is_empty() {
+                // the walk caches may still hold the unlinked tables
+                tlb::flush_all_broadcast();
+                for table in tables.as_slice() {
+                    unsafe { self.physical.dealloc(*table, 1) };
+                }
+            }
+            if complete {
+                break;
+            }
+        }
+    }
+
+    /// Swap out the page at `addr` of the low `addr_space` to `slot`, if it is swappable and wasn't accessed since the last scan.
+    ///
+    /// Return its physical page, to free once the TLBs are flushed and its content is written to the slot.
+    pub(super) fn swap_out_page(
+        &self,
+        addr: VirtualAddress,
+        slot: usize,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<PhysicalAddress> {
+        let page = self.mmu.swap_out(addr, slot, addr_space)?;
+        account_dealloc(addr_space, addr, 1);
+        Some(page)
+    }
+
+    /// Map `page` at `addr` in place of the swap entry for `slot`. Return false if the entry changed.
+    pub(super) fn swap_in_page(
+        &self,
+        addr: VirtualAddress,
+        slot: usize,
+        page: PhysicalAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> bool {
+        let swapped_in = self.mmu.swap_in(addr, slot, page, addr_space);
+        if swapped_in {
+            account_alloc(addr_space, addr, 1);
+        }
+        swapped_in
+    }
+
+    /// Return the swap slot of the page at `addr`, None if it isn't swapped out.
+    #[inline]
+    pub(super) fn swap_slot(
+        &self,
+        addr: VirtualAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<usize> {
+        self.mmu.swap_slot(addr, addr_space)
+    }
 
-        self.mmu.map(addr, phys_addr, count, flags, &mut addr_space)
+    /// Set the access flag of the page at `addr`. Return false if no page is mapped at `addr`.
+    #[inline]
+    pub(super) fn set_accessed(
+        &self,
+        addr: VirtualAddress,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> bool {
+        self.mmu.set_accessed(addr, addr_space)
+    }
+
+    /// Return the next page of `range` to swap out with the clock algorithm.
+    #[inline]
+    pub(super) fn find_swap_victim(
+        &self,
+        range: Range<VirtualAddress>,
+        addr_space: &mut VirtualAddressSpace,
+    ) -> Option<VirtualAddress> {
+        self.mmu.find_swap_victim(range, addr_space)
+    }
+
+    /// Free the page tables of the low `addr_space` and the pages still allocated in it.
+    ///
+    /// Called when the address space is dropped, once no CPU uses it.
+    pub(super) fn destroy_addr_space(&self, addr_space: &mut VirtualAddressSpace) {
+        // without ASIDs the TLBs may still hold its entries
+        tlb::flush_all_broadcast();
+        let freed = unsafe { self.mmu.destroy(addr_space) };
+        trace!(target: "vmm", "Destroyed address space, {} pages freed", freed);
+        let resident = addr_space.resident_pages;
+        account_dealloc(addr_space, USER_SPACE_RANGE.start, resident);
     }
 }
 
@@ -298,13 +725,17 @@ pub enum MapSize {
     Size1GB,
 }
 
+// bit[11]: user exec (executable at EL0)
+// bit[10]: exec (executable at EL1)
+// bit[9]: swappable (anonymous user page)
+// bit[8]: owned (the pages are freed with the address space)
 // bit[7]: remap (force remap and doesn't return AlreadyMapped)
 // bits[6:4]: AttrIndx
 // bits[3:2]: shareability
 // bit[1]: EL0_access
 // bit[0]: RO
 #[derive(Debug, Clone, Copy)]
-pub struct MapFlags(u8);
+pub struct MapFlags(u16);
 
 impl MapFlags {
     #[inline]
@@ -318,14 +749,69 @@ impl MapFlags {
         assert!(shareability & 0b11 == shareability);
         assert!(attr_indx & 0b111 == attr_indx);
         Self(
-            read_only as u8
-                | (el0_access as u8) << 1
-                | shareability << 2
-                | attr_indx << 4
-                | (remap as u8) << 7,
+            read_only as u16
+                | (el0_access as u16) << 1
+                | (shareability as u16) << 2
+                | (attr_indx as u16) << 4
+                | (remap as u16) << 7,
         )
     }
 
+    /// Mark the pages as allocated by the vmm, so they are freed with their address space.
+    #[inline]
+    pub(super) fn with_owned(self) -> Self {
+        Self(self.0 | 0b100000000)
+    }
+
+    #[inline]
+    pub fn owned(self) -> bool {
+        self.0 & 0b100000000 != 0
+    }
+
+    /// Mark the pages as anonymous user memory, which may be swapped out.
+    #[inline]
+    pub(super) fn with_swappable(self) -> Self {
+        Self(self.0 | 0b1000000000)
+    }
+
+    #[inline]
+    pub fn swappable(self) -> bool {
+        self.0 & 0b1000000000 != 0
+    }
+
+    /// Make the pages executable by the kernel, they should be read only.
+    #[inline]
+    pub fn with_exec(self) -> Self {
+        Self(self.0 | 0b10000000000)
+    }
+
+    #[inline]
+    pub fn exec(self) -> bool {
+        self.0 & 0b10000000000 != 0
+    }
+
+    /// Make the pages executable by the users, they should be read only.
+    #[inline]
+    pub fn with_user_exec(self) -> Self {
+        Self(self.0 | 0b100000000000)
+    }
+
+    #[inline]
+    pub fn user_exec(self) -> bool {
+        self.0 & 0b100000000000 != 0
+    }
+
+    /// Remove the permissions which the pages of `usage` never get: the user pages aren't
+    /// executable by the kernel, the kernel pages aren't executable by the users.
+    #[inline]
+    fn restrict_to(self, usage: MemoryUsage) -> Self {
+        if usage == MemoryUsage::UserData {
+            Self(self.0 & !0b10000000000)
+        } else {
+            Self(self.0 & !0b100000000000)
+        }
+    }
+
     #[inline]
     pub fn default_rw(read_only: bool) -> Self {
         Self::new(read_only, false, 0b11, 1, false)
@@ -338,12 +824,12 @@ impl MapFlags {
 
     #[inline]
     pub fn attr_index(self) -> u8 {
-        (self.0 & 0b01110000) >> 5
+        ((self.0 & 0b01110000) >> 4) as u8
     }
 
     #[inline]
     pub fn shareability(self) -> u8 {
-        (self.0 & 0b00001100) >> 2
+        ((self.0 & 0b00001100) >> 2) as u8
     }
 
     #[inline]
@@ -359,7 +845,7 @@ impl MapFlags {
 
 impl Default for MapFlags {
     fn default() -> Self {
-        Self(0b00011100) // remap: 0 AttrIndx: 1 shareability: 0b11 L0_access: 0 RO: 0
+        Self(0b00011100) // exec: 0 remap: 0 AttrIndx: 1 shareability: 0b11 L0_access: 0 RO: 0
     }
 }
 
@@ -392,10 +878,56 @@ impl MapOptions {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryUsage {
     KernelHeap,
     KernelData,
     ModuleSpace,
     UserData,
 }
+
+impl MemoryUsage {
+    pub const ALL: [Self; 4] = [
+        Self::KernelHeap,
+        Self::KernelData,
+        Self::ModuleSpace,
+        Self::UserData,
+    ];
+
+    /// Return the usage of the virtual space containing `addr`.
+    pub fn of(addr: VirtualAddress) -> Option<Self> {
+        if KERNEL_HEAP_RANGE.contains(&addr) {
+            Some(Self::KernelHeap)
+        } else if KERNEL_DATA_RANGE.contains(&addr) {
+            Some(Self::KernelData)
+        } else if MODULES_SPACE_RANGE.contains(&addr) {
+            Some(Self::ModuleSpace)
+        } else if USER_SPACE_RANGE.contains(&addr) {
+            Some(Self::UserData)
+        } else {
+            None
+        }
+    }
+}
+
+/// Return the count of pages allocated by the vmm for `usage`.
+#[inline]
+pub fn usage_pages(usage: MemoryUsage) -> usize {
+    USAGE_PAGES[usage as usize].load(Ordering::Relaxed)
+}
+
+/// Count `count` pages allocated at `addr` in `addr_space`.
+fn account_alloc(addr_space: &mut VirtualAddressSpace, addr: VirtualAddress, count: usize) {
+    addr_space.resident_pages += count;
+    if let Some(usage) = MemoryUsage::of(addr) {
+        USAGE_PAGES[usage as usize].fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// Count `count` pages deallocated at `addr` in `addr_space`.
+fn account_dealloc(addr_space: &mut VirtualAddressSpace, addr: VirtualAddress, count: usize) {
+    addr_space.resident_pages -= count;
+    if let Some(usage) = MemoryUsage::of(addr) {
+        USAGE_PAGES[usage as usize].fetch_sub(count, Ordering::Relaxed);
+    }
+}
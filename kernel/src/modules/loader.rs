@@ -8,7 +8,8 @@ use elf::{
     abi::{
         R_AARCH64_ABS64, R_AARCH64_CALL26, R_AARCH64_GLOB_DAT, R_AARCH64_JUMP26,
         R_AARCH64_LD_PREL_LO19, R_AARCH64_MOVW_UABS_G0_NC, R_AARCH64_MOVW_UABS_G1_NC,
-        R_AARCH64_MOVW_UABS_G2_NC, R_AARCH64_MOVW_UABS_G3, SHF_ALLOC, SHT_RELA,
+        R_AARCH64_MOVW_UABS_G2_NC, R_AARCH64_MOVW_UABS_G3, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE,
+        SHT_NOBITS, SHT_RELA,
     },
     endian::LittleEndian,
     section::SectionHeader,
@@ -34,7 +35,10 @@ pub fn load(path: &str) -> Result<(), Error> {
 
     let buff = file.read_to_end_vec(0)?;
     let mut loader = Loader::new(&buff)?;
-    loader.load()?;
+    if let Err(e) = loader.load() {
+        loader.unload();
+        return Err(e);
+    }
 
     info!(
         "Module {} loaded at address {}",
@@ -52,10 +56,47 @@ impl From<elf::ParseError> for Error {
     }
 }
 
+/// The kinds of the loaded sections, each kind is in its own pages so they are mapped with least privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SectionKind {
+    Text,
+    ReadOnly,
+    Data,
+}
+
+impl SectionKind {
+    const ALL: [Self; 3] = [Self::Text, Self::ReadOnly, Self::Data];
+
+    /// Return the kind of `section`, or None if it isn't loaded.
+    fn of(section: &SectionHeader) -> Option<Self> {
+        if section.sh_flags & SHF_ALLOC as u64 == 0 {
+            None
+        } else if section.sh_flags & SHF_EXECINSTR as u64 != 0 {
+            Some(Self::Text)
+        } else if section.sh_flags & SHF_WRITE as u64 != 0 {
+            Some(Self::Data)
+        } else {
+            Some(Self::ReadOnly)
+        }
+    }
+
+    #[inline]
+    fn map_flags(self) -> MapFlags {
+        match self {
+            Self::Text => MapFlags::default_rw(true).with_exec(),
+            Self::ReadOnly => MapFlags::default_rw(true),
+            Self::Data => MapFlags::default(),
+        }
+    }
+}
+
 struct Loader<'a> {
     file: ElfBytes<'a, LittleEndian>,
     data: &'a [u8],
     load_address: Option<VirtualAddress>,
+    page_count: usize,
+    /// Pages of each `SectionKind`, in this order from `load_address`.
+    region_pages: [usize; SectionKind::ALL.len()],
 }
 
 impl<'a> Loader<'a> {
@@ -65,9 +106,20 @@ impl<'a> Loader<'a> {
             file,
             data,
             load_address: None,
+            page_count: 0,
+            region_pages: [0; SectionKind::ALL.len()],
         })
     }
 
+    /// Free the module space of a module which failed to load.
+    fn unload(&mut self) {
+        if let Some(addr) = self.load_address.take()
+            && let Err(e) = vmm().dealloc_pages(addr, self.page_count, AddrSpaceSelector::kernel())
+        {
+            warn!("Failed to free the module space at {}: {}", addr, e);
+        }
+    }
+
     fn load(&mut self) -> Result<(), Error> {
         let mut sections = {
             let sections_iter = self
@@ -186,6 +238,8 @@ impl<'a> Loader<'a> {
             }
         }
 
+        self.protect_sections()?;
+
         let init =
             unsafe { mem::transmute::<usize, fn() -> Result<(), Error>>(init.unwrap().addr()) };
         init().map_err(|e| Error::ModuleLoad(ModuleInitFailed(e.to_string())))?;
@@ -193,18 +247,22 @@ impl<'a> Loader<'a> {
         Ok(())
     }
 
-    /// Load all the sections marked with `SHF_ALLOC` into module space memory
+    /// Load all the sections marked with `SHF_ALLOC` into module space memory, grouped by `SectionKind`.
     /// Update each `sh_addr` to where the section is in memory.
+    ///
+    /// The pages are writable and not executable until `protect_sections`.
     fn load_sections(&mut self, sections: &mut [SectionHeader]) -> Result<(), Error> {
-        let mut alloc_size = 0usize;
+        let mut sizes = [0usize; SectionKind::ALL.len()];
         for section in sections.iter() {
-            if (section.sh_flags & SHF_ALLOC as u64) != 0 {
-                alloc_size = alloc_size.next_multiple_of(section.sh_addralign as usize);
-                alloc_size += section.sh_size as usize;
+            if let Some(kind) = SectionKind::of(section) {
+                let size = &mut sizes[kind as usize];
+                *size = size.next_multiple_of((section.sh_addralign as usize).max(1));
+                *size += section.sh_size as usize;
             }
         }
 
-        let page_count = alloc_size.next_multiple_of(PAGE_SIZE) >> PAGE_SHIFT;
+        self.region_pages = sizes.map(|size| size.next_multiple_of(PAGE_SIZE) >> PAGE_SHIFT);
+        let page_count = self.region_pages.iter().sum();
         let base_addr = vmm().alloc_pages(
             page_count,
             MemoryUsage::ModuleSpace,
@@ -213,28 +271,52 @@ impl<'a> Loader<'a> {
         )?;
 
         self.load_address = Some(base_addr);
+        self.page_count = page_count;
 
-        let mut current_offset = 0usize;
+        // each region starts on a page
+        let mut offsets = [0usize; SectionKind::ALL.len()];
+        let mut region_offset = 0;
+        for (offset, pages) in offsets.iter_mut().zip(self.region_pages) {
+            *offset = region_offset;
+            region_offset += pages << PAGE_SHIFT;
+        }
 
         for section in sections.iter_mut() {
-            if (section.sh_flags & SHF_ALLOC as u64) != 0 {
-                current_offset = current_offset.next_multiple_of(section.sh_addralign as usize);
+            if let Some(kind) = SectionKind::of(section) {
+                let current_offset = &mut offsets[kind as usize];
+                *current_offset =
+                    current_offset.next_multiple_of((section.sh_addralign as usize).max(1));
 
-                let file_off = section.sh_offset as usize;
                 let size = section.sh_size as usize;
-                let file_slice = &self.data[file_off..file_off + size];
-
-                let ptr = unsafe { base_addr.as_ptr::<u8>().add(current_offset) };
+                let ptr = unsafe { base_addr.as_ptr::<u8>().add(*current_offset) };
                 let slice = unsafe { slice::from_raw_parts_mut(ptr, size) };
-                slice.copy_from_slice(file_slice);
+                if section.sh_type == SHT_NOBITS {
+                    slice.fill(0);
+                } else {
+                    let file_off = section.sh_offset as usize;
+                    slice.copy_from_slice(&self.data[file_off..file_off + size]);
+                }
 
-                section.sh_addr = (base_addr.addr() + current_offset) as u64;
-                current_offset += size;
+                section.sh_addr = (base_addr.addr() + *current_offset) as u64;
+                *current_offset += size;
             }
         }
 
         Ok(())
     }
+
+    /// Map the regions of the loaded sections with the permissions of their `SectionKind`, once relocated.
+    fn protect_sections(&self) -> Result<(), Error> {
+        let mut addr = self.load_address.expect("No load address");
+        for (kind, pages) in SectionKind::ALL.into_iter().zip(self.region_pages) {
+            // the data is already mapped as allocated
+            if pages != 0 && kind != SectionKind::Data {
+                vmm().protect(addr, pages, kind.map_flags(), AddrSpaceSelector::kernel())?;
+            }
+            addr += pages * PAGE_SIZE;
+        }
+        Ok(())
+    }
 }
 
 /// Encode an immediate of `size` bits into `instruction`.
@@ -0,0 +1,61 @@
+use alloc::vec::Vec;
+use core::mem;
+
+use aarch64_cpu::asm;
+use log::{info, trace};
+use spin::Mutex;
+
+use crate::{
+    interrupts::{
+        exceptions::disable_exceptions_depth,
+        ipi::{self, CallTarget},
+    },
+    psci,
+};
+
+/// Called with interrupts enabled before the machine is powered off or reset, can sleep.
+pub type ShutdownHook = fn();
+
+static SHUTDOWN_HOOKS: Mutex<Vec<(&'static str, ShutdownHook)>> = Mutex::new(Vec::new());
+
+/// Register `hook` to be run on power off and reboot, in the reverse order of registration.
+pub fn register_shutdown_hook(name: &'static str, hook: ShutdownHook) {
+    SHUTDOWN_HOOKS.lock().push((name, hook));
+}
+
+/// Run the shutdown hooks then power off the machine.
+pub fn power_off() -> ! {
+    shutdown();
+    info!(target: "power", "Power off");
+    let Err(err) = psci::system_off();
+    panic!("Failed to power off: {err}");
+}
+
+/// Run the shutdown hooks then reset the machine.
+pub fn reboot() -> ! {
+    shutdown();
+    info!(target: "power", "Reboot");
+    let Err(err) = psci::system_reset();
+    panic!("Failed to reboot: {err}");
+}
+
+fn shutdown() {
+    // taken so the hooks run only once if several threads shut down the machine
+    let hooks = mem::take(&mut *SHUTDOWN_HOOKS.lock());
+    for (name, hook) in hooks.into_iter().rev() {
+        trace!(target: "power", "Run shutdown hook {name}");
+        hook();
+    }
+
+    // the other CPUs shouldn't use the devices anymore
+    if ipi::is_initialized() {
+        ipi::call_function(CallTarget::Others, stop_cpu, 0, false);
+    }
+    disable_exceptions_depth();
+}
+
+fn stop_cpu(_: usize) {
+    loop {
+        asm::wfe();
+    }
+}
@@ -1,68 +1,349 @@
+// PSCI is Power State Coordination Interface
+// see https://developer.arm.com/documentation/den0022/latest
+
 use core::{arch::global_asm, num::NonZeroU32};
 
-use log::warn;
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::{
+    acpi::{self, fadt::Fadt, sdt::Signature},
+    device_tree,
+    memory::PhysicalAddress,
+    utils::sync_once_cell::SyncOnceCell,
+};
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_SUSPEND: u32 = 0xC400_0001;
+const CPU_OFF: u32 = 0x8400_0002;
+const CPU_ON: u32 = 0xC400_0003;
+const AFFINITY_INFO: u32 = 0xC400_0004;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+const PSCI_FEATURES: u32 = 0x8400_000A;
+
+/// The instruction used to call the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    Hvc,
+    Smc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    CpuSuspend,
+    CpuOff,
+    CpuOn,
+    AffinityInfo,
+    SystemOff,
+    SystemReset,
+}
 
-use crate::{device_tree, memory::PhysicalAddress};
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    #[error("Not supported")]
+    NotSupported,
+    #[error("Invalid parameters")]
+    InvalidParameters,
+    #[error("Denied")]
+    Denied,
+    #[error("Already on")]
+    AlreadyOn,
+    #[error("On pending")]
+    OnPending,
+    #[error("Internal failure")]
+    InternalFailure,
+    #[error("Not present")]
+    NotPresent,
+    #[error("Disabled")]
+    Disabled,
+    #[error("Invalid address")]
+    InvalidAddress,
+    #[error("Unknown error {0}")]
+    Unknown(i32),
+}
 
+impl PsciError {
+    fn from_code(code: i32) -> Self {
+        match code {
+            -1 => Self::NotSupported,
+            -2 => Self::InvalidParameters,
+            -3 => Self::Denied,
+            -4 => Self::AlreadyOn,
+            -5 => Self::OnPending,
+            -6 => Self::InternalFailure,
+            -7 => Self::NotPresent,
+            -8 => Self::Disabled,
+            -9 => Self::InvalidAddress,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+/// State of a CPU as returned by `affinity_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityState {
+    On,
+    Off,
+    OnPending,
+}
+
+#[derive(Debug)]
 struct PsciInfos {
+    conduit: Conduit,
+    /// Major and minor version, 0.1 firmwares can't be asked for it.
+    version: (u16, u16),
+    cpu_suspend: Option<NonZeroU32>,
+    cpu_off: Option<NonZeroU32>,
     cpu_on: Option<NonZeroU32>,
+    affinity_info: Option<NonZeroU32>,
+    system_off: Option<NonZeroU32>,
+    system_reset: Option<NonZeroU32>,
 }
 
-static mut INFOS: Option<PsciInfos> = None;
+static INFOS: SyncOnceCell<PsciInfos> = SyncOnceCell::new();
 
+/// Find how to call the firmware, from the device tree or else from the ACPI FADT.
 pub fn init() {
-    let dt_node = match device_tree::get_node("/psci") {
-        Some(node) => node,
-        None => {
-            warn!(target: "psci", "Init failed: no psci node in the device tree");
-            return;
-        }
+    let infos = match device_tree::get_node("/psci") {
+        Some(node) => infos_from_device_tree(&node),
+        None => infos_from_acpi(),
     };
+    let Some(infos) = infos else {
+        warn!(target: "psci", "Init failed: no usable PSCI description");
+        return;
+    };
+
+    info!(target: "psci", "PSCI {}.{} via {:?}", infos.version.0, infos.version.1, infos.conduit);
+    unsafe { INFOS.set(infos).expect("Psci already init") };
+}
 
-    let method = match dt_node.get_property("method") {
-        Some(method) => method.buff().consume_str().unwrap(),
-        None => {
-            warn!(target: "psci", "Init failed: no method property in device tree");
-            return;
+fn infos_from_device_tree(node: &device_tree::Node) -> Option<PsciInfos> {
+    let conduit = match node.get_property("method")?.buff().consume_str()? {
+        "hvc" => Conduit::Hvc,
+        "smc" => Conduit::Smc,
+        method => {
+            warn!(target: "psci", "Unknown method {method}");
+            return None;
         }
     };
-    if method != "hvc" {
-        warn!(target: "psci", "Init failed: unknown method");
-        return;
+
+    let mut standard = false;
+    if let Some(compatible) = node.get_property("compatible") {
+        let mut buff = compatible.buff();
+        while let Some(name) = buff.consume_str() {
+            standard |= name != "arm,psci" && name.starts_with("arm,psci-");
+        }
+    }
+    if standard {
+        return Some(standard_infos(conduit));
     }
 
-    let cpu_on = dt_node.get_property("cpu_on").map_or_else(
-        || {
-            warn!(target: "psci", "No cpu_on function");
-            None
-        },
-        |p| {
-            let val = p.buff().consume_be_u32().unwrap();
-            Some(NonZeroU32::new(val).unwrap())
-        },
-    );
+    // PSCI 0.1 firmwares give the function IDs they implement
+    let func = |name: &str| {
+        node.get_property(name)
+            .and_then(|p| p.buff().consume_be_u32())
+            .and_then(NonZeroU32::new)
+    };
+    Some(PsciInfos {
+        conduit,
+        version: (0, 1),
+        cpu_suspend: func("cpu_suspend"),
+        cpu_off: func("cpu_off"),
+        cpu_on: func("cpu_on"),
+        affinity_info: None,
+        system_off: None,
+        system_reset: None,
+    })
+}
 
-    let infos = PsciInfos { cpu_on };
-    unsafe { INFOS = Some(infos) };
+fn infos_from_acpi() -> Option<PsciInfos> {
+    let fadt = unsafe { acpi::get_table::<Fadt>(Signature::FADT) }?;
+    let flags = fadt.arm_boot_flags();
+    if flags & Fadt::ARM_PSCI_COMPLIANT == 0 {
+        return None;
+    }
+    let conduit = if flags & Fadt::ARM_PSCI_USE_HVC != 0 {
+        Conduit::Hvc
+    } else {
+        Conduit::Smc
+    };
+    Some(standard_infos(conduit))
+}
+
+/// The function IDs are fixed since PSCI 0.2.
+fn standard_infos(conduit: Conduit) -> PsciInfos {
+    let version = unsafe { call(conduit, PSCI_VERSION, 0, 0, 0) } as u32;
+    PsciInfos {
+        conduit,
+        version: ((version >> 16) as u16, version as u16),
+        cpu_suspend: NonZeroU32::new(CPU_SUSPEND),
+        cpu_off: NonZeroU32::new(CPU_OFF),
+        cpu_on: NonZeroU32::new(CPU_ON),
+        affinity_info: NonZeroU32::new(AFFINITY_INFO),
+        system_off: NonZeroU32::new(SYSTEM_OFF),
+        system_reset: NonZeroU32::new(SYSTEM_RESET),
+    }
 }
 
 global_asm!(
     ".global hvc_call
      hvc_call:
      hvc #0
+     ret
+
+     .global smc_call
+     smc_call:
+     smc #0
      ret"
 );
 
 unsafe extern "C" {
-    unsafe fn hvc_call(func: u32, a: u64, b: u64, c: u64, d: u64) -> u64;
+    unsafe fn hvc_call(func: u32, a: u64, b: u64, c: u64) -> u64;
+    unsafe fn smc_call(func: u32, a: u64, b: u64, c: u64) -> u64;
+}
+
+#[inline]
+unsafe fn call(conduit: Conduit, func: u32, a: u64, b: u64, c: u64) -> i32 {
+    let r = match conduit {
+        Conduit::Hvc => unsafe { hvc_call(func, a, b, c) },
+        Conduit::Smc => unsafe { smc_call(func, a, b, c) },
+    };
+    // return values are 32 bits, even for SMC64 functions
+    r as i32
+}
+
+#[inline]
+fn infos() -> Result<&'static PsciInfos, PsciError> {
+    INFOS.get().ok_or(PsciError::NotSupported)
+}
+
+fn function_id(infos: &PsciInfos, function: Function) -> Option<NonZeroU32> {
+    match function {
+        Function::CpuSuspend => infos.cpu_suspend,
+        Function::CpuOff => infos.cpu_off,
+        Function::CpuOn => infos.cpu_on,
+        Function::AffinityInfo => infos.affinity_info,
+        Function::SystemOff => infos.system_off,
+        Function::SystemReset => infos.system_reset,
+    }
+}
+
+/// Call `function` and return its non-negative result.
+unsafe fn call_function(function: Function, a: u64, b: u64, c: u64) -> Result<i32, PsciError> {
+    let infos = infos()?;
+    let func = function_id(infos, function).ok_or(PsciError::NotSupported)?;
+    let r = unsafe { call(infos.conduit, func.get(), a, b, c) };
+    if r < 0 {
+        Err(PsciError::from_code(r))
+    } else {
+        Ok(r)
+    }
+}
+
+#[inline]
+pub fn is_available() -> bool {
+    INFOS.get().is_some()
+}
+
+#[inline]
+pub fn conduit() -> Option<Conduit> {
+    INFOS.get().map(|infos| infos.conduit)
+}
+
+/// Major and minor version of the firmware PSCI implementation.
+#[inline]
+pub fn version() -> Option<(u16, u16)> {
+    INFOS.get().map(|infos| infos.version)
+}
+
+/// Return the feature flags of `function`, or an error if it isn't implemented.
+///
+/// Firmwares older than PSCI 1.0 are assumed to implement all the functions they describe.
+pub fn features(function: Function) -> Result<u32, PsciError> {
+    let infos = infos()?;
+    let func = function_id(infos, function).ok_or(PsciError::NotSupported)?;
+    if infos.version < (1, 0) {
+        return Ok(0);
+    }
+    let r = unsafe { call(infos.conduit, PSCI_FEATURES, func.get() as u64, 0, 0) };
+    if r < 0 {
+        Err(PsciError::from_code(r))
+    } else {
+        Ok(r as u32)
+    }
+}
+
+#[inline]
+pub fn is_supported(function: Function) -> bool {
+    features(function).is_ok()
+}
+
+/// Start the CPU `cpu_id` at the physical address `entry` with `context` in x0.
+///
+/// # Safety
+/// - `entry` should be the code of a CPU entry point, which runs with the MMU off
+/// - `context` should be valid for the entry point until the CPU is started
+#[inline]
+pub unsafe fn cpu_on(cpu_id: u32, entry: PhysicalAddress, context: u64) -> Result<(), PsciError> {
+    unsafe { call_function(Function::CpuOn, cpu_id as u64, entry.addr() as u64, context) }
+        .map(|_| ())
+}
+
+/// Power down the current CPU. Only returns on failure.
+///
+/// The CPU shouldn't run any thread and its interrupts should be disabled.
+#[inline]
+pub fn cpu_off() -> Result<!, PsciError> {
+    unsafe { call_function(Function::CpuOff, 0, 0, 0) }?;
+    unreachable!("CPU_OFF returned")
+}
+
+/// Suspend the current CPU in `power_state`.
+///
+/// Return on wake up for standby states, else the CPU restarts at `entry` with `context` in x0.
+///
+/// # Safety
+/// - `entry` should be the code of a CPU entry point, which runs with the MMU off
+/// - for power down states, `context` should hold everything `entry` needs to restore the CPU,
+///   and should stay valid until the CPU is woken up
+#[inline]
+pub unsafe fn cpu_suspend(
+    power_state: u32,
+    entry: PhysicalAddress,
+    context: u64,
+) -> Result<(), PsciError> {
+    unsafe {
+        call_function(
+            Function::CpuSuspend,
+            power_state as u64,
+            entry.addr() as u64,
+            context,
+        )
+    }
+    .map(|_| ())
+}
+
+/// Return the power state of the CPU `cpu_id`.
+pub fn affinity_info(cpu_id: u32) -> Result<AffinityState, PsciError> {
+    match unsafe { call_function(Function::AffinityInfo, cpu_id as u64, 0, 0) }? {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        r => Err(PsciError::Unknown(r)),
+    }
+}
+
+/// Power off the machine. Only returns on failure.
+#[inline]
+pub fn system_off() -> Result<!, PsciError> {
+    unsafe { call_function(Function::SystemOff, 0, 0, 0) }?;
+    unreachable!("SYSTEM_OFF returned")
 }
 
+/// Reset the machine. Only returns on failure.
 #[inline]
-pub unsafe fn cpu_on(cpu_id: u32, entry: PhysicalAddress, context: u64) {
-    let func = unsafe { &*&raw const INFOS }
-        .as_ref()
-        .expect("Psci not init")
-        .cpu_on
-        .expect("No cpu_on func");
-    unsafe { hvc_call(func.get(), cpu_id as u64, entry.addr() as u64, context, 0) };
+pub fn system_reset() -> Result<!, PsciError> {
+    unsafe { call_function(Function::SystemReset, 0, 0, 0) }?;
+    unreachable!("SYSTEM_RESET returned")
 }
@@ -1,8 +1,10 @@
 use core::{
     arch::asm,
     cell::SyncUnsafeCell,
-    mem::MaybeUninit,
-    sync::atomic::{AtomicU32, Ordering},
+    mem::{MaybeUninit, offset_of},
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     time::Duration,
 };
 
@@ -10,7 +12,7 @@ use aarch64_cpu::{
     asm,
     registers::{DAIF, TPIDR_EL1},
 };
-use alloc::{collections::VecDeque, vec::Vec};
+use alloc::{collections::VecDeque, format, vec::Vec};
 use crossbeam_utils::atomic::AtomicCell;
 use log::{info, trace};
 use static_assertions::const_assert;
@@ -19,32 +21,50 @@ use tock_registers::interfaces::{Readable, Writeable};
 use crate::{
     cpu::{self, InterruptFrame},
     device_tree,
-    interrupts::{self, CoreSelection},
-    memory::{AddrSpaceLock, vmm},
+    fs::procfs,
+    interrupts::{
+        self, CoreSelection,
+        ipi::{self, RESCHEDULE_SGI},
+    },
+    memory::{
+        AddrSpaceLock, AddrSpaceSelector, PAGE_SIZE, VirtualAddress, slab, tlb,
+        vmm::{self, MapFlags, MemoryUsage},
+    },
     scheduler::{
         process::Process,
-        thread::{Thread, ThreadEntry},
+        thread::{THREAD_CACHE, Thread, ThreadEntry},
+    },
+    sync::{
+        no_irq_locks::{NoIrqMutex, NoIrqRwLock},
+        rcu,
     },
-    sync::no_irq_locks::{NoIrqMutex, NoIrqRwLock},
     timer,
 };
 
 use self::{
+    consts::EMERGENCY_STACK_PAGE_COUNT,
     process::ProcessRef,
-    thread::{ThreadRef, ThreadState},
+    stats::CpuCounters,
+    thread::{Priority, ThreadRef, ThreadState},
 };
 
 pub mod consts;
+pub mod deadline;
 mod funcs;
+pub mod kthread;
+pub mod preempt;
 pub mod process;
 mod smp;
+pub mod stats;
 pub mod sync_ref;
 pub mod thread;
 
 pub use funcs::*;
-pub use smp::register_cpus;
+pub use smp::{cpu_offline, cpu_online, register_cpus};
 
 const TIMESLICE: Duration = Duration::from_millis(100);
+/// A thread waiting for a CPU for this long runs before the more urgent threads, so they can't starve it.
+const STARVATION_DELAY: Duration = Duration::from_secs(1);
 
 unsafe extern "C" {
     unsafe fn exception_exit(frame: *mut InterruptFrame) -> !;
@@ -52,11 +72,20 @@ unsafe extern "C" {
 
 static DUMMY_CPU: Cpu = Cpu {
     id: 0,
+    index: 0,
     is_main_cpu: true,
     current_thread: SyncUnsafeCell::new(None),
+    switched_from: SyncUnsafeCell::new(None),
     idle_thread: None,
     threads: None,
     irqs_depth: AtomicU32::new(1),
+    preempt_count: AtomicU32::new(0),
+    need_resched: AtomicBool::new(false),
+    online: AtomicBool::new(false),
+    stopping: AtomicBool::new(false),
+    dl_bandwidth: AtomicU64::new(0),
+    counters: CpuCounters::new(),
+    emergency_stack: VirtualAddress::new(0),
 };
 
 pub static SCHEDULER: Scheduler = Scheduler::new();
@@ -70,7 +99,6 @@ pub struct Scheduler {
     thread_destroyer_of_threads: SyncUnsafeCell<Option<ThreadRef>>,
 
     waiting_threads: SyncUnsafeCell<MaybeUninit<NoIrqRwLock<VecDeque<ThreadRef>>>>,
-    blocked_threads: NoIrqMutex<Vec<ThreadRef>>,
 }
 
 unsafe impl Send for Scheduler {}
@@ -86,7 +114,6 @@ impl Scheduler {
             thread_destroyer_of_threads: SyncUnsafeCell::new(None),
 
             waiting_threads: SyncUnsafeCell::new(MaybeUninit::uninit()),
-            blocked_threads: NoIrqMutex::new(Vec::new()),
         }
     }
 
@@ -96,12 +123,15 @@ impl Scheduler {
 
         debug_assert!(kernel_process_.is_none());
 
+        slab::register_named(&THREAD_CACHE).expect("Failed to register the thread cache");
+
         let kernel_addr_space = AddrSpaceLock::Ref(vmm::get_kernel_addr_space());
         let kernel_process = Process::new(kernel_addr_space).into_ref();
         *kernel_process_ = Some(kernel_process);
 
         let thread_destroyer_of_threads = Thread::new(
             self.get_kernel_process(),
+            "thread_destroyer",
             Self::thread_destroyer_of_threads,
             false,
         )
@@ -111,8 +141,11 @@ impl Scheduler {
             *self.waiting_threads.get() = MaybeUninit::new(NoIrqRwLock::new(VecDeque::new()));
         }
 
-        interrupts::set_irq_handler(0, Self::interrupt_handler, 0);
+        interrupts::set_irq_handler(RESCHEDULE_SGI as u32, Self::interrupt_handler, 0);
         timer::init(Self::interrupt_handler);
+        ipi::init();
+
+        procfs::add_file("sched", stats::proc_sched);
 
         smp::start_cpus();
     }
@@ -129,7 +162,7 @@ impl Scheduler {
     }
 
     #[inline]
-    fn cpus(&self) -> &[Cpu] {
+    pub fn cpus(&self) -> &[Cpu] {
         // safety: cpus is writed only at initing by only one thread
         unsafe { self.cpus.get().as_ref().unwrap_unchecked() }
     }
@@ -145,8 +178,19 @@ impl Scheduler {
             assert!(!(is_main_cpu && cpu.is_main_cpu));
         }
 
-        let cpu = Cpu::new(id, is_main_cpu);
-        unsafe { self.cpus.get().as_mut().unwrap_unchecked() }.push(cpu);
+        let emergency_stack = vmm::vmm()
+            .alloc_guarded_pages(
+                EMERGENCY_STACK_PAGE_COUNT,
+                MemoryUsage::KernelHeap,
+                MapFlags::default(),
+                AddrSpaceSelector::kernel(),
+            )
+            .expect("Failed to allocate the emergency stack")
+            + EMERGENCY_STACK_PAGE_COUNT * PAGE_SIZE;
+
+        let cpus = unsafe { self.cpus.get().as_mut().unwrap_unchecked() };
+        let cpu = Cpu::new(id, cpus.len(), is_main_cpu, emergency_stack);
+        cpus.push(cpu);
     }
 
     // call this on each core
@@ -160,18 +204,37 @@ impl Scheduler {
                 .expect("Cpu not registered");
             unsafe { Cpu::set_current(cpu) };
 
-            let idle_thread = Thread::new(self.get_kernel_process(), idle_thread, true).unwrap();
-            unsafe {
+            let idle_thread = Thread::new(
+                self.get_kernel_process(),
+                format!("idle/{}", cpu.id),
+                idle_thread,
+                true,
+            )
+            .unwrap();
+            let previous_idle_thread = unsafe {
                 let cpu_mut = cpu as *const Cpu as *mut Cpu;
-                (*cpu_mut).idle_thread = Some(idle_thread);
+                (*cpu_mut).idle_thread.replace(idle_thread)
+            };
+            if let Some(thread) = previous_idle_thread {
+                // the CPU was taken offline by its idle thread, which never runs again
+                thread.atomic_state().store(ThreadState::Exited);
+                self.threads_to_destroy.lock().push(thread);
             }
 
-            let thread = Thread::new(SCHEDULER.get_kernel_process(), entry, false).unwrap();
+            let thread = Thread::new(
+                SCHEDULER.get_kernel_process(),
+                format!("main/{}", cpu.id),
+                entry,
+                false,
+            )
+            .unwrap();
             self.add_thread(thread.clone());
 
             timer::init_core();
 
             thread.atomic_state().store(ThreadState::Running);
+            thread.set_on_cpu(true);
+            thread.counters().switched_in(cpu.id, timer::uptime());
             cpu.set_current_thread(thread.clone());
 
             if cpu::id() == device_tree::get_boot_cpu_id() {
@@ -185,7 +248,11 @@ impl Scheduler {
                 info!(target: "scheduler", "Scheduler started");
             }
 
-            self.config_timer(1);
+            // the CPU missed the TLB shootdowns while it was offline, flush once it is marked online
+            cpu.online.store(true, Ordering::Release);
+            tlb::flush_all_local();
+
+            self.config_timer(1, None);
             thread
         };
 
@@ -207,34 +274,105 @@ impl Scheduler {
     // called by the timer and yield handlers
     // return the thread to run
     fn schedule(&self) -> ThreadRef {
+        // IRQs are disabled in RCU read-side critical sections, so the CPU isn't in one
+        rcu::note_quiescent_state();
+
         let cpu = Cpu::current();
         let current_thread = cpu.current_thread();
+        if cpu.preempt_count.load(Ordering::Relaxed) != 0
+            && current_thread.state() == ThreadState::Running
+        {
+            // switch once `preempt_enable` ends the non-preemptible section
+            cpu.need_resched.store(true, Ordering::Relaxed);
+            // the timer interrupt is masked, keep ticking in case `preempt_enable` can't yield
+            // because the IRQs are disabled
+            self.config_timer(1, deadline::budget_end(current_thread, timer::uptime()));
+            return current_thread.clone();
+        }
+        cpu.need_resched.store(false, Ordering::Relaxed);
+
+        let stopping = cpu.is_stopping();
+        let now = timer::uptime();
+        let preempted = current_thread.state() == ThreadState::Running;
+        let replenish_time = deadline::charge(current_thread, now);
         let can_rerun = {
-            if current_thread.state() == ThreadState::Running {
-                current_thread.atomic_state().store(ThreadState::Runnable);
-                !current_thread.is_idle_thread()
+            if preempted {
+                if let Some(time) = replenish_time {
+                    // the deadline thread used its runtime, throttle it until its next period
+                    self.add_waiting_thread(current_thread.clone(), time);
+                    false
+                } else {
+                    current_thread.atomic_state().store(ThreadState::Runnable);
+                    !current_thread.is_idle_thread()
+                }
             } else {
                 false
             }
         };
 
-        self.wake_up_waiting_threads();
+        if !stopping {
+            self.wake_up_waiting_threads();
+        }
+
+        // a thread pinned to another CPU while it was running moves there
+        let migrated = can_rerun && current_thread.affinity().is_some_and(|id| id != cpu.id);
 
         let mut threads = cpu.threads().lock();
 
-        if can_rerun {
+        if can_rerun && !migrated {
             threads.push_back(current_thread.clone());
         }
 
-        let next_thread = loop {
-            let thread = threads
-                .pop_front()
-                .unwrap_or_else(|| cpu.idle_thread().clone());
+        threads.retain(|t| t.state() == ThreadState::Runnable);
 
-            if thread.state() == ThreadState::Runnable {
-                break thread;
+        if stopping {
+            // only the threads pinned to the CPU stay, the idle thread will power it off
+            drop(threads);
+            self.migrate_threads(cpu);
+            threads = cpu.threads().lock();
+        }
+
+        // run the deadline thread with the earliest deadline, else the most urgent thread,
+        // round robin between threads of the same priority and the starving ones first
+        let mut next_deadline: Option<(usize, Duration)> = None;
+        let mut next_index: Option<(usize, (bool, Priority))> = None;
+        let mut skipped = false;
+        for (i, thread) in threads.iter().enumerate() {
+            // woken up before another CPU finished switching away from it
+            if thread.is_on_cpu() && thread != current_thread {
+                skipped = true;
+                continue;
             }
-        };
+            if let Some(abs_deadline) = deadline::abs_deadline(thread) {
+                if next_deadline.is_none_or(|(_, d)| abs_deadline < d) {
+                    next_deadline = Some((i, abs_deadline));
+                }
+                continue;
+            }
+            let starving =
+                thread != current_thread && thread.counters().waited(now) >= STARVATION_DELAY;
+            let key = (starving, thread.priority());
+            if next_index.is_none_or(|(_, k)| key > k) {
+                next_index = Some((i, key));
+            }
+        }
+        let next_thread = next_deadline
+            .map(|(i, _)| i)
+            .or(next_index.map(|(i, _)| i))
+            .filter(|_| !stopping)
+            .and_then(|i| threads.remove(i))
+            .unwrap_or_else(|| cpu.idle_thread().clone());
+
+        if next_thread != *current_thread {
+            current_thread.counters().switched_out(!preempted, now);
+            next_thread.counters().switched_in(cpu.id, now);
+            deadline::switched_in(&next_thread, now);
+            cpu.counters.context_switches.fetch_add(1, Ordering::Relaxed);
+
+            next_thread.set_on_cpu(true);
+            // cleared by `finish_switch` once the exception handler is done with its stack
+            unsafe { *cpu.switched_from.get() = Some(current_thread.clone()) };
+        }
 
         {
             next_thread.atomic_state().store(ThreadState::Running);
@@ -243,14 +381,23 @@ impl Scheduler {
 
         let threads_len = threads.len();
         drop(threads); // unlock threads
-        self.config_timer(threads_len);
+        if migrated {
+            trace!(target: "scheduler", "Migrate thread {} from CPU {}", current_thread.id(), cpu.id);
+            self.add_thread(current_thread.clone());
+        }
+        self.config_timer(threads_len, deadline::budget_end(&next_thread, now));
+        if skipped {
+            // try again right after the interrupt, the other CPU will be done soon
+            interrupts::chip().send_sgi(CoreSelection::Me, RESCHEDULE_SGI);
+        }
 
-        trace!(target: "scheduler", "Run thread {} of process {} on CPU {}", next_thread.id(), next_thread.process().id(), cpu.id);
+        trace!(target: "scheduler", "Run thread {} ({}) of process {} on CPU {}", next_thread.id(), next_thread.name(), next_thread.process().id(), cpu.id);
 
         next_thread
     }
 
-    fn config_timer(&self, runnable_threads_count: usize) {
+    /// Set the timer for the next timeslice, the next thread to wake up or `budget_end`, the first to come.
+    fn config_timer(&self, runnable_threads_count: usize, budget_end: Option<Duration>) {
         let lower_waiting_time =
             self.waiting_threads()
                 .read()
@@ -260,53 +407,169 @@ impl Scheduler {
                     _ => unreachable!(),
                 });
 
-        match (runnable_threads_count == 0, lower_waiting_time) {
-            (true, None) => {} // don't set the timer
-            (true, Some(duration)) => {
-                timer::tick_at(duration);
-            }
-            (false, None) => timer::tick_in(TIMESLICE),
+        let uptime = timer::uptime();
+        let next_tick = match (runnable_threads_count == 0, lower_waiting_time) {
+            (true, None) => None, // don't set the timer
+            (true, Some(duration)) => Some(duration),
+            (false, None) => Some(uptime + TIMESLICE),
             (false, Some(duration)) => {
-                let uptime = timer::uptime();
                 if uptime >= duration {
-                    timer::tick_in(TIMESLICE); // FIXME
-                    return;
-                }
-                let remaining_time = duration - uptime;
-                if remaining_time < TIMESLICE {
-                    timer::tick_at(duration);
+                    Some(uptime + TIMESLICE) // FIXME
                 } else {
-                    timer::tick_in(TIMESLICE);
+                    Some(duration.min(uptime + TIMESLICE))
                 }
             }
+        };
+        let next_tick = match (next_tick, budget_end) {
+            (Some(tick), Some(end)) => Some(tick.min(end)),
+            (tick, end) => tick.or(end),
+        };
+        if let Some(time) = next_tick {
+            timer::tick_at(time);
         }
     }
 
     fn wake_up_waiting_threads(&self) {
-        let mut runnable_threads = Cpu::current().threads().lock();
-        let mut waiting_threads = self.waiting_threads().write();
+        let cpu = Cpu::current();
         let uptime = timer::uptime();
-        while let Some(thread) = waiting_threads.front() {
-            let wake_up_time = match thread.state() {
-                ThreadState::Waiting(time) => time,
-                _ => unreachable!(),
-            };
-            if wake_up_time - Duration::from_micros(1) > uptime {
-                break;
+        let mut woken_up = Vec::new();
+        {
+            let mut waiting_threads = self.waiting_threads().write();
+            while let Some(thread) = waiting_threads.front() {
+                let wake_up_time = match thread.state() {
+                    ThreadState::Waiting(time) => time,
+                    _ => unreachable!(),
+                };
+                if wake_up_time - Duration::from_micros(1) > uptime {
+                    break;
+                }
+                woken_up.push(waiting_threads.pop_front().unwrap());
             }
+        }
 
-            let thread = waiting_threads.pop_front().unwrap(); // take it
+        let mut remote = Vec::new();
+        let mut runnable_threads = cpu.threads().lock();
+        for thread in woken_up {
             thread.atomic_state().store(ThreadState::Runnable);
-            runnable_threads.push_back(thread);
+            match thread.affinity() {
+                // pinned threads go back to their CPU
+                Some(id) if id != cpu.id => remote.push(thread),
+                _ => {
+                    thread.counters().queued(uptime);
+                    deadline::queued(&thread, uptime);
+                    runnable_threads.push_back(thread);
+                }
+            }
+        }
+        drop(runnable_threads);
+        for thread in remote {
+            self.add_thread(thread);
         }
     }
 
-    /// Add the thread in the current CPU threads queue.
+    /// Put `thread` to sleep until `time_point`, in the timer queue sorted by wake up time.
+    fn add_waiting_thread(&self, thread: ThreadRef, time_point: Duration) {
+        let mut threads = self.waiting_threads().write();
+        let r = threads.binary_search_by(|e| {
+            let time = match e.state() {
+                ThreadState::Waiting(time) => time,
+                _ => unreachable!(),
+            };
+            time.cmp(&time_point)
+        });
+        thread.atomic_state().store(ThreadState::Waiting(time_point));
+        match r {
+            Ok(i) => threads.insert(i, thread),
+            Err(i) => threads.insert(i, thread),
+        };
+    }
+
+    #[inline]
+    pub fn get_cpu(&self, id: u32) -> Option<&Cpu> {
+        self.cpus().iter().find(|c| c.id == id)
+    }
+
+    /// Add the thread in the threads queue of the CPU it is pinned to, or of the current CPU.
+    ///
+    /// Threads not pinned go to the least loaded online CPU if the current one is going offline.
     pub(in crate::scheduler) fn add_thread(&self, thread: ThreadRef) {
         assert_eq!(thread.state(), ThreadState::Runnable);
-        let cpu = Cpu::current();
+        let current_cpu = Cpu::current();
+        let cpu = match thread.affinity() {
+            Some(id) if id != current_cpu.id => self.get_cpu(id).expect("Invalid thread affinity"),
+            None if current_cpu.is_stopping() => self.least_loaded_cpu(),
+            _ => current_cpu,
+        };
+        let now = timer::uptime();
+        thread.counters().queued(now);
+        let is_deadline = deadline::queued(&thread, now);
         let mut threads = cpu.threads().lock();
         threads.push_back(thread);
+        drop(threads);
+
+        if !ptr::eq(cpu, current_cpu) {
+            // make the remote CPU reschedule in case it is idle
+            ipi::send_reschedule(cpu.id);
+        } else if is_deadline {
+            // a deadline thread preempts the normal threads
+            interrupts::chip().send_sgi(CoreSelection::Me, RESCHEDULE_SGI);
+        }
+    }
+
+    /// Move the threads of `cpu` not pinned to it to other CPUs.
+    fn migrate_threads(&self, cpu: &Cpu) {
+        let mut migrated = Vec::new();
+        cpu.threads().lock().retain(|t| {
+            let pinned = t.affinity() == Some(cpu.id);
+            if !pinned {
+                migrated.push(t.clone());
+            }
+            pinned
+        });
+        for thread in migrated {
+            trace!(target: "scheduler", "Migrate thread {} from CPU {}", thread.id(), cpu.id);
+            self.add_thread(thread);
+        }
+    }
+
+    /// Move `thread` to the CPU it is pinned to, if it is queued or running on another one.
+    pub(in crate::scheduler) fn move_to_affinity(&self, thread: &ThreadRef) {
+        let Some(target) = thread.affinity() else {
+            return;
+        };
+        for cpu in self.cpus().iter().filter(|cpu| cpu.id != target) {
+            let mut threads = cpu.threads().lock();
+            let Some(index) = threads
+                .iter()
+                .position(|t| t == thread && t.state() == ThreadState::Runnable)
+            else {
+                continue;
+            };
+            threads.remove(index);
+            drop(threads);
+            trace!(target: "scheduler", "Migrate thread {} from CPU {}", thread.id(), cpu.id);
+            self.add_thread(thread.clone());
+            return;
+        }
+        if thread.state() == ThreadState::Running {
+            // `schedule` moves it once preempted, on whichever CPU runs it
+            for cpu in self
+                .cpus()
+                .iter()
+                .filter(|cpu| cpu.id != target && cpu.is_online())
+            {
+                ipi::send_reschedule(cpu.id);
+            }
+        }
+    }
+
+    /// Return the online CPU with the fewest runnable threads, not going offline.
+    fn least_loaded_cpu(&self) -> &Cpu {
+        self.cpus()
+            .iter()
+            .filter(|cpu| cpu.is_online() && !cpu.is_stopping())
+            .min_by_key(|cpu| cpu.threads().lock().len())
+            .expect("No online CPU")
     }
 
     #[inline]
@@ -333,8 +596,13 @@ impl Scheduler {
             0,
             "Yielding with IRQs disabled"
         );
+        debug_assert_eq!(
+            Cpu::current().preempt_count.load(Ordering::Relaxed),
+            0,
+            "Yielding with preemption disabled"
+        );
         debug_assert_eq!(DAIF.get(), 0);
-        interrupts::chip().send_sgi(CoreSelection::Me, 0);
+        interrupts::chip().send_sgi(CoreSelection::Me, RESCHEDULE_SGI);
     }
 
     fn thread_destroyer_of_threads() -> ! {
@@ -351,10 +619,7 @@ impl Scheduler {
                     .unwrap()
                     .0;
                 process.threads.swap_remove(remove_index);
-
-                if process.threads.is_empty() {
-                    todo!("destroy process");
-                }
+                // the process and its address space are freed with its last reference
             }
             threads_to_destroy.clear(); // drop all threads
 
@@ -365,8 +630,21 @@ impl Scheduler {
     }
 }
 
+/// Called by the exception handlers once on the stack of the thread to run.
+#[unsafe(no_mangle)]
+extern "C" fn finish_switch() {
+    let cpu = Cpu::current();
+    if let Some(thread) = unsafe { (*cpu.switched_from.get()).take() } {
+        thread.set_on_cpu(false);
+    }
+}
+
 fn idle_thread() -> ! {
     loop {
+        // the reschedule IPI sent by `cpu_offline` wakes the CPU up
+        if Cpu::current().is_stopping() {
+            smp::cpu_die();
+        }
         asm::wfe();
     }
 }
@@ -375,27 +653,66 @@ fn idle_thread() -> ! {
 #[derive(Debug)]
 pub struct Cpu {
     pub id: u32,
+    /// Position of the CPU in the scheduler CPU list.
+    pub index: usize,
     pub is_main_cpu: bool,
     threads: Option<NoIrqMutex<VecDeque<ThreadRef>>>,
     idle_thread: Option<ThreadRef>,
     current_thread: SyncUnsafeCell<Option<ThreadRef>>,
+    /// The thread switched away from, until the CPU doesn't use its stack anymore.
+    switched_from: SyncUnsafeCell<Option<ThreadRef>>,
     pub irqs_depth: AtomicU32,
+    /// Count of `preempt_disable` calls, the current thread isn't switched away while it isn't 0.
+    preempt_count: AtomicU32,
+    /// A switch was requested while the preemption was disabled.
+    need_resched: AtomicBool,
+    /// The CPU is running the scheduler and can receive IPIs.
+    online: AtomicBool,
+    /// The CPU was asked to go offline, cleared once it is about to power off.
+    stopping: AtomicBool,
+    /// Bandwidth reserved by the deadline threads pinned to the CPU.
+    dl_bandwidth: AtomicU64,
+    counters: CpuCounters,
+    /// Top of the stack the fatal exceptions of the kernel run on, so a kernel stack overflow can be reported.
+    emergency_stack: VirtualAddress,
 }
 
+/// Used by the exception vectors, which load the emergency stack from `TPIDR_EL1`.
+pub(crate) const CPU_EMERGENCY_STACK_OFFSET: usize = offset_of!(Cpu, emergency_stack);
+
 const_assert!(AtomicCell::<Option<ThreadRef>>::is_lock_free());
 
 impl Cpu {
-    fn new(id: u32, is_main_cpu: bool) -> Self {
+    fn new(id: u32, index: usize, is_main_cpu: bool, emergency_stack: VirtualAddress) -> Self {
         Self {
             id,
+            index,
             is_main_cpu,
             threads: Some(Default::default()),
             idle_thread: None,
             current_thread: SyncUnsafeCell::new(None),
+            switched_from: SyncUnsafeCell::new(None),
             irqs_depth: 1.into(),
+            preempt_count: AtomicU32::new(0),
+            need_resched: AtomicBool::new(false),
+            online: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            dl_bandwidth: AtomicU64::new(0),
+            counters: CpuCounters::new(),
+            emergency_stack,
         }
     }
 
+    #[inline]
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
     #[inline(always)]
     pub fn threads(&self) -> &NoIrqMutex<VecDeque<ThreadRef>> {
         self.threads
@@ -419,6 +736,14 @@ impl Cpu {
         TPIDR_EL1.set(ptr.addr() as u64);
     }
 
+    /// Range of the emergency stack, none for the dummy CPU.
+    #[inline]
+    pub(crate) fn emergency_stack(&self) -> Option<Range<VirtualAddress>> {
+        (self.emergency_stack.addr() != 0).then(|| {
+            self.emergency_stack - EMERGENCY_STACK_PAGE_COUNT * PAGE_SIZE..self.emergency_stack
+        })
+    }
+
     #[inline]
     fn idle_thread(&self) -> &ThreadRef {
         self.idle_thread.as_ref().expect("No idle thread")
@@ -430,6 +755,11 @@ impl Cpu {
         unsafe { (*ptr).as_ref().expect("No current thread") }
     }
 
+    #[inline]
+    pub(crate) fn try_current_thread(&self) -> Option<&ThreadRef> {
+        unsafe { (*self.current_thread.get()).as_ref() }
+    }
+
     #[inline]
     fn set_current_thread(&self, thread: ThreadRef) {
         unsafe { *self.current_thread.get() = Some(thread) }
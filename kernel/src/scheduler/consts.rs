@@ -1,2 +1,3 @@
 pub const USER_STACK_PAGE_COUNT: usize = 64; // 256 KB
 pub const KERNEL_STACK_PAGE_COUNT: usize = 16; // 64 KB
+pub const EMERGENCY_STACK_PAGE_COUNT: usize = 4; // 16 KB
@@ -0,0 +1,254 @@
+use alloc::vec::Vec;
+use core::{sync::atomic::Ordering, time::Duration};
+
+use log::trace;
+
+use crate::{error::Error, timer};
+
+use super::{SCHEDULER, current_thread, sleep, thread::ThreadRef};
+
+/// Fixed point unit of the bandwidths, the bandwidth of a thread using a whole CPU.
+const BANDWIDTH_UNIT: u64 = 1 << 20;
+/// Share of each CPU usable by the deadline threads, the rest is left to the other threads.
+const MAX_BANDWIDTH: u64 = BANDWIDTH_UNIT * 95 / 100;
+
+/// Scheduling parameters of a periodic real-time thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    /// CPU time the thread can use in each period.
+    pub runtime: Duration,
+    /// Time between the starts of two jobs.
+    pub period: Duration,
+    /// Time after the start of a job by which its runtime should have run, at most `period`.
+    pub deadline: Duration,
+}
+
+impl DeadlineParams {
+    /// Parameters with a deadline equal to the period.
+    pub const fn implicit(runtime: Duration, period: Duration) -> Self {
+        Self {
+            runtime,
+            period,
+            deadline: period,
+        }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.runtime.is_zero() || self.runtime > self.deadline || self.deadline > self.period {
+            return Err(Error::CustomStr(
+                "Deadline params should be 0 < runtime <= deadline <= period",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Share of a CPU needed in the worst case, the density for constrained deadlines.
+    fn bandwidth(&self) -> u64 {
+        (self.runtime.as_nanos() * BANDWIDTH_UNIT as u128 / self.deadline.as_nanos()) as u64
+    }
+}
+
+/// State of a deadline thread, updated by the scheduler of the CPU the thread is pinned to.
+#[derive(Debug)]
+pub(super) struct DeadlineEntity {
+    params: DeadlineParams,
+    cpu_id: u32,
+    /// Bandwidth reserved on the CPU, released on drop.
+    bandwidth: u64,
+    /// Affinity of the thread before it became a deadline thread.
+    previous_affinity: Option<u32>,
+    /// Start of the current job.
+    period_start: Duration,
+    abs_deadline: Duration,
+    /// Runtime left to the current job.
+    remaining: Duration,
+    /// Uptime when the runtime used was last charged.
+    charged_at: Duration,
+}
+
+impl DeadlineEntity {
+    /// Start a new job at `start` with a full runtime.
+    fn replenish(&mut self, start: Duration) {
+        self.period_start = start;
+        self.abs_deadline = start + self.params.deadline;
+        self.remaining = self.params.runtime;
+    }
+}
+
+impl Drop for DeadlineEntity {
+    fn drop(&mut self) {
+        if let Some(cpu) = SCHEDULER.get_cpu(self.cpu_id) {
+            cpu.dl_bandwidth
+                .fetch_sub(self.bandwidth, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Make `thread` a deadline thread scheduled earliest deadline first, before all the other threads.
+///
+/// The thread is pinned to a CPU with enough bandwidth left, fail if there is none.
+/// `None` makes it a normal thread again.
+pub fn set_deadline(thread: &ThreadRef, params: Option<DeadlineParams>) -> Result<(), Error> {
+    let mut entity = thread.deadline().lock();
+    let Some(params) = params else {
+        if let Some(old) = entity.take() {
+            // queuing the thread locks its entity
+            drop(entity);
+            thread.set_affinity(old.previous_affinity);
+            SCHEDULER.move_to_affinity(thread);
+            trace!(target: "scheduler", "Thread {} isn't a deadline thread anymore", thread.id());
+        }
+        return Ok(());
+    };
+    params.check()?;
+
+    let bandwidth = params.bandwidth();
+    let (old_cpu, old_bandwidth) = entity
+        .as_ref()
+        .map_or((None, 0), |old| (Some(old.cpu_id), old.bandwidth));
+    let reserve = |cpu_id: u32| {
+        let cpu = SCHEDULER
+            .get_cpu(cpu_id)
+            .ok_or(Error::CustomStr("Unknown cpu"))?;
+        // the old bandwidth is released when the old entity is dropped
+        let old = if old_cpu == Some(cpu_id) {
+            old_bandwidth
+        } else {
+            0
+        };
+        cpu.dl_bandwidth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bw| {
+                (bw - old + bandwidth <= MAX_BANDWIDTH).then_some(bw + bandwidth)
+            })
+            .map(|_| ())
+            .map_err(|_| Error::CustomStr("Not enough CPU bandwidth for the deadline thread"))
+    };
+
+    let previous_affinity = match &*entity {
+        Some(old) => old.previous_affinity,
+        None => thread.affinity(),
+    };
+    let cpu_id = match old_cpu.or(previous_affinity) {
+        Some(cpu_id) => {
+            reserve(cpu_id)?;
+            cpu_id
+        }
+        None => {
+            // worst fit, spread the deadline threads
+            let mut cpus: Vec<_> = SCHEDULER
+                .cpus()
+                .iter()
+                .filter(|cpu| cpu.is_online())
+                .collect();
+            cpus.sort_by_key(|cpu| cpu.dl_bandwidth.load(Ordering::Relaxed));
+            cpus.iter()
+                .find(|cpu| reserve(cpu.id).is_ok())
+                .map(|cpu| cpu.id)
+                .ok_or(Error::CustomStr(
+                    "Not enough CPU bandwidth for the deadline thread",
+                ))?
+        }
+    };
+
+    let now = timer::uptime();
+    let mut new = DeadlineEntity {
+        params,
+        cpu_id,
+        bandwidth,
+        previous_affinity,
+        period_start: now,
+        abs_deadline: now,
+        remaining: Duration::ZERO,
+        charged_at: now,
+    };
+    new.replenish(now);
+    *entity = Some(new);
+    drop(entity);
+    thread.set_affinity(Some(cpu_id));
+    SCHEDULER.move_to_affinity(thread);
+
+    trace!(target: "scheduler", "Thread {} is a deadline thread on CPU {}: {:?}", thread.id(), cpu_id, params);
+    Ok(())
+}
+
+/// Return the deadline parameters of `thread`, `None` for a normal thread.
+pub fn deadline_params(thread: &ThreadRef) -> Option<DeadlineParams> {
+    thread
+        .deadline()
+        .lock()
+        .as_ref()
+        .map(|entity| entity.params)
+}
+
+/// End the job of the current deadline thread and sleep until the start of its next period.
+pub fn wait_next_period() {
+    let next_period = {
+        let mut entity = current_thread().deadline().lock();
+        let entity = entity.as_mut().expect("Not a deadline thread");
+        // replenished when queued again
+        entity.remaining = Duration::ZERO;
+        entity.period_start + entity.params.period
+    };
+    sleep(next_period.saturating_sub(timer::uptime()));
+}
+
+/// Called when `thread` becomes runnable, return true if it is a deadline thread.
+pub(super) fn queued(thread: &ThreadRef, now: Duration) -> bool {
+    let mut entity = thread.deadline().lock();
+    let Some(entity) = entity.as_mut() else {
+        return false;
+    };
+    // the job can't finish in time with the runtime left, start a new one
+    if now + entity.remaining >= entity.abs_deadline {
+        let next_period = entity.period_start + entity.params.period;
+        let slack = entity.params.deadline - entity.params.runtime;
+        // keep the periods aligned if the thread is late by less than the slack of its job
+        if now >= next_period && now <= next_period + slack {
+            entity.replenish(next_period);
+        } else {
+            entity.replenish(now);
+        }
+    }
+    true
+}
+
+pub(super) fn switched_in(thread: &ThreadRef, now: Duration) {
+    if let Some(entity) = thread.deadline().lock().as_mut() {
+        entity.charged_at = now;
+    }
+}
+
+/// Charge the runtime used by `thread` since it was switched in or last charged.
+///
+/// Return the start of the next period if its runtime is exhausted.
+pub(super) fn charge(thread: &ThreadRef, now: Duration) -> Option<Duration> {
+    let mut entity = thread.deadline().lock();
+    let entity = entity.as_mut()?;
+    let used = now.saturating_sub(entity.charged_at);
+    entity.remaining = entity.remaining.saturating_sub(used);
+    entity.charged_at = now;
+    entity
+        .remaining
+        .is_zero()
+        .then_some(entity.period_start + entity.params.period)
+}
+
+/// Return the absolute deadline of the current job of `thread`, `None` for a normal thread.
+#[inline]
+pub(super) fn abs_deadline(thread: &ThreadRef) -> Option<Duration> {
+    thread
+        .deadline()
+        .lock()
+        .as_ref()
+        .map(|entity| entity.abs_deadline)
+}
+
+/// Return when `thread` will have used its runtime if it runs from `now`.
+#[inline]
+pub(super) fn budget_end(thread: &ThreadRef, now: Duration) -> Option<Duration> {
+    thread
+        .deadline()
+        .lock()
+        .as_ref()
+        .map(|entity| now + entity.remaining)
+}
@@ -5,11 +5,14 @@ use log::trace;
 use crate::{scheduler::SCHEDULER, timer};
 
 use super::{
+    preempt::might_sleep,
     process::ProcessRef,
-    thread::{ThreadId, ThreadRef, ThreadState},
+    thread::{ParkState, ThreadId, ThreadRef, ThreadState},
     Cpu,
 };
 
+pub use super::thread::get_thread;
+
 #[inline]
 pub fn current_thread() -> &'static ThreadRef {
     let cpu = Cpu::current();
@@ -17,7 +20,6 @@ pub fn current_thread() -> &'static ThreadRef {
 }
 
 #[inline]
-#[allow(unused)]
 pub fn current_process() -> &'static ProcessRef {
     current_thread().process()
 }
@@ -28,6 +30,8 @@ pub fn exit(code: isize) -> ! {
         let thread = cpu.current_thread();
         debug_assert!(thread.state() == ThreadState::Running);
         thread.atomic_state().store(ThreadState::Exited);
+        // release its bandwidth now, the thread is destroyed later
+        *thread.deadline().lock() = None;
 
         trace!(target: "scheduler", "Thread {} of process {} exited with code {} on core {}", thread.id(), thread.process().id(), code, cpu.id);
 
@@ -50,25 +54,12 @@ pub fn yield_now() {
 }
 
 pub fn sleep(duration: Duration) {
+    might_sleep();
     {
         let time_point = timer::uptime() + duration;
-        let mut threads = SCHEDULER.waiting_threads().write();
-        let r = threads.binary_search_by(|e| {
-            let time = match e.state() {
-                ThreadState::Waiting(time) => time,
-                _ => unreachable!(),
-            };
-            time_point.cmp(&time)
-        });
         let current_thread = current_thread().clone();
         let id = current_thread.id();
-        current_thread
-            .atomic_state()
-            .store(ThreadState::Waiting(time_point));
-        match r {
-            Ok(i) => threads.insert(i, current_thread),
-            Err(i) => threads.insert(i, current_thread),
-        };
+        SCHEDULER.add_waiting_thread(current_thread, time_point);
 
         trace!(target: "scheduler", "Thread {} goes to sleep for {:?}", id, duration);
     }
@@ -76,63 +67,62 @@ pub fn sleep(duration: Duration) {
     yield_now();
 }
 
-/// Get a thread by its id.
-///
-/// **Warn**: This is O(n) with n as the thread count and may block the scheduler work so use carefully.
-pub fn get_thread(id: ThreadId) -> Option<ThreadRef> {
-    if current_thread().id() == id {
-        return Some(current_thread().clone());
-    }
-    for cpu in SCHEDULER.cpus() {
-        for thread in cpu.threads().lock().iter() {
-            if thread.id() == id {
-                return Some(thread.clone());
-            }
-        }
-    }
-
-    None
-}
-
 /// Set the current state as `Blocked` state and go to sleep.
+///
+/// Something should keep a reference to the thread to wake it up with `wake_thread`.
 pub fn block_thread() {
+    might_sleep();
     block_thread_drop(());
 }
 
 #[inline]
-/// Same as `block_thread` but also drop `val` while owning a lock that prevent others thread from unblocking it.
+/// Same as `block_thread` but also drop `val` once the thread is marked as blocked.
 ///
-/// May help to prevent race conditions if `val` is a lock guard.
+/// May help to prevent race conditions if `val` is a lock guard: a thread waking
+/// up the current one after taking the lock will see it blocked.
 pub fn block_thread_drop<T>(val: T) {
     let current_thread = current_thread();
     current_thread.atomic_state().store(ThreadState::Blocked);
 
     trace!(target: "scheduler", "Block thread {}", current_thread.id());
 
-    let mut threads = SCHEDULER.blocked_threads.lock();
-    threads.push(current_thread.clone());
-
     drop(val);
 
-    drop(threads);
-
     yield_now();
 }
 
-/// Unblock the thread. Return err if the thread isn't blocked.
-pub fn unblock_thread(id: ThreadId) -> Result<(), ()> {
-    let mut blocked_threads = SCHEDULER.blocked_threads.lock();
-    let (index, _) = blocked_threads
-        .iter()
-        .enumerate()
-        .find(|&(_, t)| t.id() == id)
-        .ok_or(())?;
-
-    trace!(target: "scheduler", "Unblock thread {}", id);
-
-    let thread = blocked_threads.swap_remove(index);
-    let r = thread.atomic_state().swap(ThreadState::Runnable);
-    debug_assert_eq!(r, ThreadState::Blocked);
-    SCHEDULER.add_thread(thread);
-    Ok(())
+/// Block the current thread until `unpark` is called on it.
+///
+/// Return immediately if `unpark` was called since the last `park`.
+pub fn park() {
+    might_sleep();
+    let current_thread = current_thread();
+    let mut state = current_thread.park_state().lock();
+    if *state == ParkState::Notified {
+        *state = ParkState::Empty;
+        return;
+    }
+    *state = ParkState::Parked;
+    block_thread_drop(state);
+}
+
+/// Wake up a blocked thread. Return false if the thread isn't blocked.
+pub fn wake_thread(thread: &ThreadRef) -> bool {
+    if thread
+        .atomic_state()
+        .compare_exchange(ThreadState::Blocked, ThreadState::Runnable)
+        .is_err()
+    {
+        return false;
+    }
+
+    trace!(target: "scheduler", "Unblock thread {}", thread.id());
+
+    SCHEDULER.add_thread(thread.clone());
+    true
+}
+
+/// Same as `wake_thread` but with the thread id. Return false if there is no such blocked thread.
+pub fn unblock_thread(id: ThreadId) -> bool {
+    get_thread(id).is_some_and(|thread| wake_thread(&thread))
 }
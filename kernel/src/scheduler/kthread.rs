@@ -0,0 +1,114 @@
+use alloc::{boxed::Box, string::String, sync::Arc};
+use spin::Mutex;
+
+use crate::{error::Error, sync::completion::Completion};
+
+use super::{
+    SCHEDULER, exit,
+    thread::{Thread, ThreadId, ThreadRef},
+};
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug)]
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    done: Completion,
+}
+
+/// An owned permission to join a kernel thread and get its return value.
+///
+/// The thread is detached if the handle is dropped.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    thread: ThreadRef,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Wait for the thread to finish and return its result.
+    pub fn join(self) -> T {
+        self.packet.done.wait();
+        self.packet
+            .result
+            .lock()
+            .take()
+            .expect("Kernel thread finished without result")
+    }
+
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.packet.done.is_done()
+    }
+
+    #[inline]
+    pub fn thread(&self) -> &ThreadRef {
+        &self.thread
+    }
+
+    #[inline]
+    pub fn id(&self) -> ThreadId {
+        self.thread.id()
+    }
+}
+
+/// Spawn a kernel thread named `name` running `f`.
+///
+/// The thread exits when `f` returns and its result can be retrieved through the returned handle.
+pub fn spawn<F, T>(name: impl Into<String>, f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_inner(name.into(), None, f)
+}
+
+/// Same as `spawn` but the thread is pinned to the CPU `cpu_id`.
+pub fn spawn_on<F, T>(cpu_id: u32, name: impl Into<String>, f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_inner(name.into(), Some(cpu_id), f)
+}
+
+fn spawn_inner<F, T>(name: String, affinity: Option<u32>, f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        done: Completion::new(),
+    });
+
+    let their_packet = Arc::clone(&packet);
+    let main: ThreadMain = Box::new(move || {
+        let result = f();
+        *their_packet.result.lock() = Some(result);
+        their_packet.done.complete_all();
+    });
+    // box again to pass a thin pointer in x0
+    let arg = Box::into_raw(Box::new(main));
+
+    let thread = Thread::new_with_arg(
+        SCHEDULER.get_kernel_process(),
+        name,
+        thread_main,
+        arg as usize,
+    )
+    .inspect_err(|_| drop(unsafe { Box::from_raw(arg) }))?;
+    thread.set_affinity(affinity);
+    thread.clone().start();
+
+    Ok(JoinHandle { thread, packet })
+}
+
+extern "C" fn thread_main(arg: usize) -> ! {
+    // use a scope here bc rust doesn't drop variables when calling a never return function
+    {
+        let main = unsafe { Box::from_raw(arg as *mut ThreadMain) };
+        main();
+    }
+    exit(0)
+}
@@ -0,0 +1,94 @@
+use core::{marker::PhantomData, sync::atomic::Ordering};
+
+use crate::interrupts::exceptions::{disable_exceptions, restore_exceptions};
+
+use super::{Cpu, yield_now};
+
+/// Disable the preemption of the current thread, it keeps running on its CPU until `preempt_enable`.
+///
+/// Can be nested, the thread shouldn't sleep before the matching `preempt_enable`.
+#[inline]
+pub fn preempt_disable() {
+    // the thread could move to another CPU between reading the current one and incrementing its counter
+    let state = disable_exceptions();
+    Cpu::current().preempt_count.fetch_add(1, Ordering::Relaxed);
+    restore_exceptions(state);
+}
+
+/// Enable again the preemption, and reschedule if it was requested while disabled.
+#[inline]
+pub fn preempt_enable() {
+    let state = disable_exceptions();
+    let cpu = Cpu::current();
+    let count = cpu.preempt_count.fetch_sub(1, Ordering::Relaxed);
+    debug_assert_ne!(count, 0, "preempt_enable without preempt_disable");
+    let reschedule = count == 1
+        && cpu.need_resched.load(Ordering::Relaxed)
+        && cpu.irqs_depth.load(Ordering::Relaxed) == 0;
+    restore_exceptions(state);
+    if reschedule {
+        yield_now();
+    }
+}
+
+/// Count of `preempt_disable` calls not matched by a `preempt_enable` on the current CPU.
+#[inline]
+pub fn preempt_count() -> u32 {
+    Cpu::current().preempt_count.load(Ordering::Relaxed)
+}
+
+/// Return true if the current thread can be preempted, so it is allowed to sleep.
+#[inline]
+pub fn preemptible() -> bool {
+    let cpu = Cpu::current();
+    cpu.preempt_count.load(Ordering::Relaxed) == 0 && cpu.irqs_depth.load(Ordering::Relaxed) == 0
+}
+
+/// Disable the preemption until dropped.
+#[derive(Debug)]
+pub struct PreemptGuard {
+    // enabled again on the same CPU
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    #[inline]
+    pub fn new() -> Self {
+        preempt_disable();
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    #[inline]
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// Called by the functions that may sleep, panic in debug builds if the preemption or the IRQs are disabled.
+#[inline]
+#[track_caller]
+pub fn might_sleep() {
+    if cfg!(debug_assertions) {
+        let cpu = Cpu::current();
+        // threads can't sleep before the scheduler starts, the locks are uncontended
+        if cpu.try_current_thread().is_none() {
+            return;
+        }
+        let irqs_depth = cpu.irqs_depth.load(Ordering::Relaxed);
+        let preempt_count = cpu.preempt_count.load(Ordering::Relaxed);
+        assert!(
+            irqs_depth == 0 && preempt_count == 0,
+            "Sleeping function called with IRQs or preemption disabled (irqs depth: {irqs_depth}, preempt count: {preempt_count})"
+        );
+    }
+}
@@ -4,16 +4,26 @@ use core::{
 };
 
 use alloc::vec::Vec;
+use hashbrown::HashMap;
+use spin::Lazy;
 
-use crate::memory::AddrSpaceLock;
+use crate::{memory::AddrSpaceLock, sync::no_irq_locks::NoIrqRwLock};
 
-use super::{sync_ref::SyncRef, thread::ThreadRef};
+use super::{
+    sync_ref::{SyncRef, WeakSyncRef},
+    thread::ThreadRef,
+};
 
 pub type ProcessId = usize;
 pub type ProcessRef = SyncRef<Process>;
+pub type WeakProcessRef = WeakSyncRef<Process>;
 
 static PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// All the processes by id, an entry is removed when its process is dropped.
+static PROCESSES: Lazy<NoIrqRwLock<HashMap<ProcessId, WeakProcessRef>>> =
+    Lazy::new(Default::default);
+
 #[inline]
 fn get_next_id() -> ProcessId {
     PROCESS_ID.fetch_add(1, Ordering::Relaxed) as ProcessId
@@ -45,7 +55,10 @@ impl Process {
     #[inline]
     // this alloc
     pub fn into_ref(self) -> ProcessRef {
-        ProcessRef::new(self)
+        let id = self.id;
+        let process = ProcessRef::new(self);
+        PROCESSES.write().insert(id, process.downgrade());
+        process
     }
 
     #[inline]
@@ -54,6 +67,12 @@ impl Process {
     }
 }
 
+impl Drop for Process {
+    fn drop(&mut self) {
+        PROCESSES.write().remove(&self.id);
+    }
+}
+
 impl ProcessRef {
     #[inline]
     pub fn id(&self) -> ProcessId {
@@ -61,9 +80,29 @@ impl ProcessRef {
         unsafe { (*ptr).id }
     }
 
+    /// Count of pages allocated in the address space of the process.
+    #[inline]
+    pub fn resident_pages(&self) -> usize {
+        self.get_addr_space().lock().resident_pages()
+    }
+
     // this is safe because we have a lock in a lock
     pub fn get_addr_space(&self) -> &AddrSpaceLock {
         let ptr = self.data_ptr();
         unsafe { &(*ptr).addr_space }
     }
 }
+
+/// Get a process by its id.
+pub fn get_process(id: ProcessId) -> Option<ProcessRef> {
+    PROCESSES.read().get(&id).and_then(WeakProcessRef::upgrade)
+}
+
+/// Return all the processes alive.
+pub fn all_processes() -> Vec<ProcessRef> {
+    PROCESSES
+        .read()
+        .values()
+        .filter_map(WeakProcessRef::upgrade)
+        .collect()
+}
@@ -1,25 +1,31 @@
 use core::{
     arch::global_asm,
     sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
 };
 
-use aarch64_cpu::registers::{MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR1_EL1, VBAR_EL1};
-use log::{info, trace};
-use tock_registers::interfaces::Readable;
+use aarch64_cpu::registers::{MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1, VBAR_EL1};
+use log::{info, trace, warn};
+use tock_registers::interfaces::{Readable, Writeable};
 
 use crate::{
     device_tree::{self, Node},
-    interrupts,
+    error::Error,
+    interrupts::{self, exceptions::disable_exceptions_depth, ipi},
     memory::{
         AddrSpaceSelector, MemoryUsage, PAGE_SIZE, PhysicalAddress, VirtualAddress,
-        VirtualAddressSpace,
+        VirtualAddressSpace, tlb,
         vmm::{MapFlags, MapOptions, MapSize, vmm},
     },
-    psci,
+    psci::{self, AffinityState, Function},
     scheduler::SCHEDULER,
+    sync::mutex::Mutex,
 };
 
-use super::exit;
+use super::{Cpu, current_thread, exit, sleep};
+
+/// Serializes the CPU hotplug operations.
+static HOTPLUG_LOCK: Mutex<()> = Mutex::new(());
 
 global_asm!(include_str!("ap_start.S"));
 
@@ -46,35 +52,44 @@ pub fn register_cpus() {
     });
 }
 
-pub fn start_cpus() {
+// identity map the low memory for the start code of the APs, executable since they start there
+fn create_start_addr_space() -> VirtualAddressSpace {
     let mut low_addr_space = VirtualAddressSpace::create_low().unwrap();
     for i in 0..4 {
         vmm()
             .map_page(
                 VirtualAddress::new(i * 1024 * 1024 * 1024),
                 PhysicalAddress::new(i * 1024 * 1024 * 1024),
-                MapOptions::default_size(MapSize::Size1GB),
+                MapOptions::new(MapSize::Size1GB, MapFlags::default_rw(true).with_exec()),
                 AddrSpaceSelector::Unlocked(&mut low_addr_space),
             )
             .unwrap();
     }
+    low_addr_space
+}
 
+pub fn start_cpus() {
+    let mut low_addr_space = create_start_addr_space();
     dt_iter_cpus(|id, cpu| {
         let is_main_cpu = id == device_tree::get_boot_cpu_id();
-        if !is_main_cpu {
-            start_cpu(id, &cpu, &mut low_addr_space);
+        if !is_main_cpu && let Err(err) = start_cpu(id, &cpu, &mut low_addr_space) {
+            warn!(target: "smp", "Failed to start cpu {id}: {err}");
         }
     })
 }
 
-fn start_cpu(id: u32, node: &device_tree::Node, low_addr_space: &mut VirtualAddressSpace) {
+fn start_cpu(
+    id: u32,
+    node: &device_tree::Node,
+    low_addr_space: &mut VirtualAddressSpace,
+) -> Result<(), Error> {
     let enable_method = node
         .get_property("enable-method")
         .expect("No 'enable-method' property in cpu node");
     let enable_method = enable_method.buff().consume_str().unwrap();
     match enable_method {
         "psci" => start_cpu_psci(id, low_addr_space),
-        _ => unimplemented!("Unknown enable method"),
+        _ => Err(Error::CustomStr("Unknown cpu enable method")),
     }
 }
 
@@ -95,14 +110,15 @@ struct StartInfos {
     tcr: u64,
     sctlr: u64,
     start_point: VirtualAddress,
+    infos: VirtualAddress,
 }
 
-fn start_cpu_psci(id: u32, low_addr_space: &mut VirtualAddressSpace) {
+fn start_cpu_psci(id: u32, low_addr_space: &mut VirtualAddressSpace) -> Result<(), Error> {
     trace!(target: "smp", "Starting cpu {id} with psci");
     let entry = ap_start as *const () as usize;
     let entry = VirtualAddress::new(entry).to_phys().unwrap();
 
-    let start_infos = StartInfos {
+    let mut start_infos = StartInfos {
         id,
         has_started: AtomicU32::new(0),
         ttbr0: VirtualAddress::new(low_addr_space.ptr as usize)
@@ -115,16 +131,18 @@ fn start_cpu_psci(id: u32, low_addr_space: &mut VirtualAddressSpace) {
         tcr: TCR_EL1.get(),
         sctlr: SCTLR_EL1.get(),
         start_point: VirtualAddress::from_ptr(ap_main as *const ()),
+        infos: VirtualAddress::new(0),
     };
+    let ptr = VirtualAddress::from_ref(&start_infos);
+    start_infos.infos = ptr;
+    let ptr = ptr.to_phys().unwrap();
 
-    let ptr = (&start_infos as *const StartInfos).addr();
-    let ptr = VirtualAddress::new(ptr).to_phys().unwrap();
-
-    unsafe { psci::cpu_on(id, entry, ptr.addr() as u64) };
+    unsafe { psci::cpu_on(id, entry, ptr.addr() as u64) }.map_err(Error::Psci)?;
 
-    while start_infos.has_started.load(Ordering::Relaxed) != 1 {
+    while start_infos.has_started.load(Ordering::Acquire) != 1 {
         core::hint::spin_loop();
     }
+    Ok(())
 }
 
 #[inline]
@@ -141,7 +159,14 @@ fn alloc_ap_stack() -> VirtualAddress {
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn ap_main(id: u32) -> ! {
+extern "C" fn ap_main(start_infos: &StartInfos) -> ! {
+    let id = start_infos.id;
+    // the start addr space is freed once all the CPUs started
+    TTBR0_EL1.set(0);
+    tlb::flush_all_local();
+    // the struct is invalid after here bc could have been dropped
+    start_infos.has_started.store(1, Ordering::Release);
+
     info!(target: "smp", "Core {id} online");
     interrupts::chip().init_ap();
     SCHEDULER.start(up);
@@ -150,3 +175,83 @@ extern "C" fn ap_main(id: u32) -> ! {
 fn up() -> ! {
     exit(0);
 }
+
+/// Take the CPU `id` offline and power it off, its threads are moved to the other CPUs.
+///
+/// Threads pinned to the CPU stay in its queue until it is back online.
+pub fn cpu_offline(id: u32) -> Result<(), Error> {
+    let _hotplug = HOTPLUG_LOCK.lock();
+    let cpu = SCHEDULER
+        .get_cpu(id)
+        .ok_or(Error::CustomStr("Unknown cpu"))?;
+    if cpu.is_main_cpu {
+        return Err(Error::CustomStr("The boot cpu can't go offline"));
+    }
+    if !cpu.is_online() {
+        return Err(Error::CustomStr("Cpu already offline"));
+    }
+    if current_thread().affinity() == Some(id) {
+        return Err(Error::CustomStr("Current thread pinned to the cpu"));
+    }
+    if cpu.dl_bandwidth.load(Ordering::Relaxed) != 0 {
+        return Err(Error::CustomStr("Deadline threads pinned to the cpu"));
+    }
+    psci::features(Function::CpuOff).map_err(Error::Psci)?;
+
+    trace!(target: "smp", "Stopping cpu {id}");
+    cpu.stopping.store(true, Ordering::Release);
+    ipi::send_reschedule(id);
+    while cpu.is_stopping() {
+        sleep(Duration::from_millis(1));
+    }
+    if psci::is_supported(Function::AffinityInfo) {
+        while psci::affinity_info(id).map_err(Error::Psci)? != AffinityState::Off {
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    info!(target: "smp", "Core {id} offline");
+    Ok(())
+}
+
+/// Start again the CPU `id` taken offline by `cpu_offline`.
+pub fn cpu_online(id: u32) -> Result<(), Error> {
+    let _hotplug = HOTPLUG_LOCK.lock();
+    let cpu = SCHEDULER
+        .get_cpu(id)
+        .ok_or(Error::CustomStr("Unknown cpu"))?;
+    if cpu.is_online() {
+        return Err(Error::CustomStr("Cpu already online"));
+    }
+
+    let mut node = None;
+    dt_iter_cpus(|cpu_id, cpu_node| {
+        if cpu_id == id {
+            node = Some(cpu_node);
+        }
+    });
+    let node = node.ok_or(Error::CustomStr("No cpu node in device tree"))?;
+
+    let mut low_addr_space = create_start_addr_space();
+    start_cpu(id, &node, &mut low_addr_space)?;
+    while !cpu.is_online() {
+        sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+/// Power off the current CPU, called by its idle thread once asked by `cpu_offline`.
+pub(super) fn cpu_die() -> ! {
+    disable_exceptions_depth();
+    let cpu = Cpu::current();
+    cpu.online.store(false, Ordering::SeqCst);
+    // the calls queued before the other CPUs saw the CPU offline
+    ipi::handle_calls();
+    // the threads woken up by the interrupt handlers since the last schedule
+    SCHEDULER.migrate_threads(cpu);
+
+    trace!(target: "smp", "Core {} powering off", cpu.id);
+    cpu.stopping.store(false, Ordering::Release);
+    let Err(err) = psci::cpu_off();
+    panic!("Failed to power off cpu {}: {err}", cpu.id);
+}
@@ -0,0 +1,222 @@
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::timer;
+
+use super::{
+    Cpu, SCHEDULER,
+    process::ProcessId,
+    thread::{self, Priority, ThreadId, ThreadRef, ThreadState},
+};
+
+const NO_CPU: u32 = u32::MAX;
+
+/// Counters of a thread updated by the scheduler.
+#[derive(Debug)]
+pub(super) struct ThreadCounters {
+    /// Uptime in ns when the thread started running or was queued.
+    since: AtomicU64,
+    run_time: AtomicU64,
+    wait_time: AtomicU64,
+    voluntary_switches: AtomicU64,
+    involuntary_switches: AtomicU64,
+    last_cpu: AtomicU32,
+}
+
+impl ThreadCounters {
+    pub(super) const fn new() -> Self {
+        Self {
+            since: AtomicU64::new(0),
+            run_time: AtomicU64::new(0),
+            wait_time: AtomicU64::new(0),
+            voluntary_switches: AtomicU64::new(0),
+            involuntary_switches: AtomicU64::new(0),
+            last_cpu: AtomicU32::new(NO_CPU),
+        }
+    }
+
+    /// The thread was queued, it waits for a CPU from now.
+    #[inline]
+    pub(super) fn queued(&self, now: Duration) {
+        self.since.store(now.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Time the queued thread has been waiting for a CPU at `now`.
+    #[inline]
+    pub(super) fn waited(&self, now: Duration) -> Duration {
+        let since = self.since.load(Ordering::Relaxed);
+        Duration::from_nanos((now.as_nanos() as u64).saturating_sub(since))
+    }
+
+    #[inline]
+    pub(super) fn switched_in(&self, cpu_id: u32, now: Duration) {
+        let now = now.as_nanos() as u64;
+        let since = self.since.swap(now, Ordering::Relaxed);
+        self.wait_time
+            .fetch_add(now.saturating_sub(since), Ordering::Relaxed);
+        self.last_cpu.store(cpu_id, Ordering::Relaxed);
+    }
+
+    /// The thread stopped running, `voluntary` if it blocked, slept or exited instead of being preempted.
+    #[inline]
+    pub(super) fn switched_out(&self, voluntary: bool, now: Duration) {
+        let now = now.as_nanos() as u64;
+        let since = self.since.swap(now, Ordering::Relaxed);
+        self.run_time
+            .fetch_add(now.saturating_sub(since), Ordering::Relaxed);
+        if voluntary {
+            self.voluntary_switches.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.involuntary_switches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counters of a CPU updated by the scheduler.
+#[derive(Debug)]
+pub(super) struct CpuCounters {
+    pub(super) context_switches: AtomicU64,
+}
+
+impl CpuCounters {
+    pub(super) const fn new() -> Self {
+        Self {
+            context_switches: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadStats {
+    pub id: ThreadId,
+    pub name: String,
+    pub process: ProcessId,
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// Time spent running.
+    pub run_time: Duration,
+    /// Time spent runnable waiting for a CPU.
+    pub wait_time: Duration,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    /// The CPU the thread last ran on.
+    pub last_cpu: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuStats {
+    pub id: u32,
+    pub online: bool,
+    /// Time spent running the idle thread since the CPU was last started.
+    pub idle_time: Duration,
+    pub context_switches: u64,
+    /// Count of threads in the run queue.
+    pub queue_len: usize,
+}
+
+/// Return the counters of `thread`, including the time of the current run or wait.
+pub fn thread_stats(thread: &ThreadRef) -> ThreadStats {
+    let counters = thread.counters();
+    let state = thread.state();
+    let now = timer::uptime().as_nanos() as u64;
+    let elapsed = now.saturating_sub(counters.since.load(Ordering::Relaxed));
+    let mut run_time = counters.run_time.load(Ordering::Relaxed);
+    let mut wait_time = counters.wait_time.load(Ordering::Relaxed);
+    match state {
+        ThreadState::Running => run_time += elapsed,
+        ThreadState::Runnable => wait_time += elapsed,
+        _ => {}
+    }
+
+    let last_cpu = counters.last_cpu.load(Ordering::Relaxed);
+    ThreadStats {
+        id: thread.id(),
+        name: thread.name().into(),
+        process: thread.process().id(),
+        state,
+        priority: thread.priority(),
+        run_time: Duration::from_nanos(run_time),
+        wait_time: Duration::from_nanos(wait_time),
+        voluntary_switches: counters.voluntary_switches.load(Ordering::Relaxed),
+        involuntary_switches: counters.involuntary_switches.load(Ordering::Relaxed),
+        last_cpu: (last_cpu != NO_CPU).then_some(last_cpu),
+    }
+}
+
+pub fn cpu_stats(cpu: &Cpu) -> CpuStats {
+    let idle_time = cpu
+        .idle_thread
+        .as_ref()
+        .map_or(Duration::ZERO, |idle| thread_stats(idle).run_time);
+    CpuStats {
+        id: cpu.id,
+        online: cpu.is_online(),
+        idle_time,
+        context_switches: cpu.counters.context_switches.load(Ordering::Relaxed),
+        queue_len: cpu.threads().lock().len(),
+    }
+}
+
+/// Return the counters of all the threads, sorted by id.
+pub fn all_threads_stats() -> Vec<ThreadStats> {
+    let mut stats: Vec<_> = thread::all_threads().iter().map(thread_stats).collect();
+    stats.sort_unstable_by_key(|s| s.id);
+    stats
+}
+
+pub fn all_cpus_stats() -> Vec<CpuStats> {
+    SCHEDULER.cpus().iter().map(cpu_stats).collect()
+}
+
+/// Content of `/proc/sched`.
+pub(super) fn proc_sched() -> String {
+    let mut s = String::new();
+    writeln!(s, "cpu online idle_ms switches queue").unwrap();
+    for cpu in all_cpus_stats() {
+        writeln!(
+            s,
+            "{} {} {} {} {}",
+            cpu.id,
+            cpu.online as u8,
+            cpu.idle_time.as_millis(),
+            cpu.context_switches,
+            cpu.queue_len
+        )
+        .unwrap();
+    }
+
+    writeln!(s).unwrap();
+    writeln!(s, "tid pid name state prio cpu run_ms wait_ms voluntary involuntary").unwrap();
+    for thread in all_threads_stats() {
+        let state = match thread.state {
+            ThreadState::Runnable => "R",
+            ThreadState::Running => "X",
+            ThreadState::Exited => "E",
+            ThreadState::Waiting(_) => "S",
+            ThreadState::Blocked => "B",
+        };
+        let cpu = thread
+            .last_cpu
+            .map_or_else(|| String::from("-"), |id| format!("{id}"));
+        writeln!(
+            s,
+            "{} {} {} {} {} {} {} {} {} {}",
+            thread.id,
+            thread.process,
+            thread.name,
+            state,
+            thread.priority,
+            cpu,
+            thread.run_time.as_millis(),
+            thread.wait_time.as_millis(),
+            thread.voluntary_switches,
+            thread.involuntary_switches
+        )
+        .unwrap();
+    }
+    s
+}
@@ -1,29 +1,58 @@
-use core::fmt::Debug;
+use core::{
+    alloc::Layout,
+    fmt::Debug,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering, fence},
+};
 
-use alloc::sync::Arc;
+use alloc::boxed::Box;
 
 use crate::sync::no_irq_locks::{NoIrqRwLock, RwLockReadGuard, RwLockWriteGuard};
 
-pub struct SyncRef<T>(Arc<NoIrqRwLock<T>>);
+/// The allocation of a `SyncRef`, a concrete type so its layout is known to the slab caches.
+pub struct SyncRefInner<T> {
+    strong: AtomicUsize,
+    /// The weak references, plus one held by all the strong ones.
+    weak: AtomicUsize,
+    data: NoIrqRwLock<T>,
+}
+
+pub struct SyncRef<T>(NonNull<SyncRefInner<T>>);
 
 impl<T> SyncRef<T> {
     pub fn new(data: T) -> Self {
-        Self(Arc::new(NoIrqRwLock::new(data)))
+        let inner = Box::new(SyncRefInner {
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            data: NoIrqRwLock::new(data),
+        });
+        Self(NonNull::from(Box::leak(inner)))
+    }
+
+    #[inline]
+    fn inner(&self) -> &SyncRefInner<T> {
+        unsafe { self.0.as_ref() }
     }
 
     #[inline]
     pub fn read(&self) -> RwLockReadGuard<'_, T> {
-        self.0.read()
+        self.inner().data.read()
     }
 
     #[inline]
     pub fn write(&self) -> RwLockWriteGuard<'_, T> {
-        self.0.write()
+        self.inner().data.write()
     }
 
     #[inline]
     pub fn data_ptr(&self) -> *mut T {
-        self.0.data_ptr()
+        self.inner().data.data_ptr()
+    }
+
+    #[inline]
+    pub fn downgrade(&self) -> WeakSyncRef<T> {
+        self.inner().weak.fetch_add(1, Ordering::Relaxed);
+        WeakSyncRef(self.0)
     }
 }
 
@@ -35,9 +64,76 @@ impl<T: Debug> Debug for SyncRef<T> {
 
 impl<T> Clone for SyncRef<T> {
     fn clone(&self) -> Self {
-        Self(Arc::clone(&self.0))
+        self.inner().strong.fetch_add(1, Ordering::Relaxed);
+        Self(self.0)
+    }
+}
+
+impl<T> Drop for SyncRef<T> {
+    fn drop(&mut self) {
+        if self.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        fence(Ordering::Acquire);
+        unsafe { ptr::drop_in_place(&raw mut (*self.0.as_ptr()).data) };
+        unsafe { release_weak(self.0) };
     }
 }
 
 unsafe impl<T> Send for SyncRef<T> {}
 unsafe impl<T> Sync for SyncRef<T> {}
+
+/// Drop a weak reference to `inner` and free it if it was the last one.
+///
+/// The data must already be dropped if it was the last one.
+unsafe fn release_weak<T>(inner: NonNull<SyncRefInner<T>>) {
+    let weak = unsafe { &inner.as_ref().weak };
+    if weak.fetch_sub(1, Ordering::Release) != 1 {
+        return;
+    }
+    fence(Ordering::Acquire);
+    // the data is already dropped, only free the memory
+    unsafe { alloc::alloc::dealloc(inner.as_ptr().cast(), Layout::new::<SyncRefInner<T>>()) };
+}
+
+/// A reference to a `SyncRef` value that doesn't keep it alive.
+pub struct WeakSyncRef<T>(NonNull<SyncRefInner<T>>);
+
+impl<T> WeakSyncRef<T> {
+    #[inline]
+    fn inner(&self) -> &SyncRefInner<T> {
+        unsafe { self.0.as_ref() }
+    }
+
+    pub fn upgrade(&self) -> Option<SyncRef<T>> {
+        self.inner()
+            .strong
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |strong| {
+                (strong != 0).then_some(strong + 1)
+            })
+            .ok()
+            .map(|_| SyncRef(self.0))
+    }
+}
+
+impl<T> Debug for WeakSyncRef<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "WeakSyncRef({:p})", self.0)
+    }
+}
+
+impl<T> Clone for WeakSyncRef<T> {
+    fn clone(&self) -> Self {
+        self.inner().weak.fetch_add(1, Ordering::Relaxed);
+        Self(self.0)
+    }
+}
+
+impl<T> Drop for WeakSyncRef<T> {
+    fn drop(&mut self) {
+        unsafe { release_weak(self.0) };
+    }
+}
+
+unsafe impl<T> Send for WeakSyncRef<T> {}
+unsafe impl<T> Sync for WeakSyncRef<T> {}
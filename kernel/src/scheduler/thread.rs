@@ -1,36 +1,81 @@
+#[cfg(feature = "lockdep")]
+use core::cell::SyncUnsafeCell;
 use core::{
+    alloc::Layout,
     fmt::Debug,
     mem::size_of,
-    sync::atomic::{AtomicUsize, Ordering},
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
     time::Duration,
 };
 
+use alloc::{string::String, vec::Vec};
 use crossbeam_utils::atomic::AtomicCell;
+use hashbrown::HashMap;
 use log::trace;
+use spin::Lazy;
 
+#[cfg(feature = "lockdep")]
+use crate::sync::lockdep::HeldLocks;
 use crate::{
     cpu::InterruptFrame,
     error::Error,
     memory::{
-        AddrSpaceSelector, PAGE_SHIFT, PAGE_SIZE, PhysicalAddress, VirtualAddress,
-        vmm::{MapFlags, MapOptions, MapSize, MemoryUsage, vmm},
+        AddrSpaceSelector, PAGE_SIZE, VirtualAddress,
+        slab::SlabCache,
+        vmm::{MapFlags, MemoryUsage, vmm},
     },
+    sync::no_irq_locks::{NoIrqMutex, NoIrqRwLock},
+    timer,
 };
 
 use super::{
     Cpu, SCHEDULER,
     consts::{KERNEL_STACK_PAGE_COUNT, USER_STACK_PAGE_COUNT},
+    deadline::{self, DeadlineEntity},
     process::ProcessRef,
-    sync_ref::SyncRef,
+    stats::ThreadCounters,
+    sync_ref::{SyncRef, SyncRefInner, WeakSyncRef},
 };
 
 pub type ThreadId = usize;
 pub type ThreadRef = SyncRef<Thread>;
+pub type WeakThreadRef = WeakSyncRef<Thread>;
 
 pub type ThreadEntry = fn() -> !;
 
+/// Entry of a thread taking an argument in x0.
+pub type ThreadEntryWithArg = extern "C" fn(usize) -> !;
+
+/// Scheduling priority of a thread, higher is more urgent.
+pub type Priority = u8;
+
+pub const DEFAULT_PRIORITY: Priority = 0;
+
+/// Count of owners boosted at most by a priority inheritance, a deadlock makes the chain a cycle.
+const MAX_BOOST_CHAIN: usize = 16;
+
+/// Address of a sleeping mutex, identifying it while it is locked.
+pub(crate) type MutexId = usize;
+
+/// A sleeping mutex owned by a thread.
+#[derive(Debug, Clone, Copy)]
+struct HeldMutex {
+    mutex: MutexId,
+    /// Priority inherited from the threads waiting for the mutex.
+    boost: Priority,
+}
+
 static THREAD_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// Named heap cache of the `ThreadRef` allocations.
+pub(super) static THREAD_CACHE: SlabCache =
+    SlabCache::new("thread", Layout::new::<SyncRefInner<Thread>>());
+
+/// All the threads by id, an entry is removed when its thread is dropped.
+static THREADS: Lazy<NoIrqRwLock<HashMap<ThreadId, WeakThreadRef>>> =
+    Lazy::new(Default::default);
+
 #[inline]
 fn get_next_id() -> ThreadId {
     THREAD_ID.fetch_add(1, Ordering::Relaxed) as ThreadId
@@ -48,11 +93,40 @@ pub enum ThreadState {
     Blocked,
 }
 
+/// State of the token used by `park` and `unpark`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ParkState {
+    Empty,
+    /// The thread is blocked in `park`.
+    Parked,
+    /// `unpark` was called while the thread wasn't parked, the next `park` returns immediately.
+    Notified,
+}
+
 pub struct Thread {
     process: ProcessRef,
     id: ThreadId,
+    name: String,
     state: AtomicCell<ThreadState>,
 
+    base_priority: AtomicU8,
+    /// Effective priority, may be boosted above `base_priority` by priority inheritance.
+    priority: AtomicU8,
+    /// Sleeping mutexes owned by the thread, the effective priority is the highest of their boosts.
+    held_mutexes: NoIrqMutex<Vec<HeldMutex>>,
+    /// Sleeping mutex the thread waits for and its owner, which inherits its boosts.
+    blocked_on: NoIrqMutex<Option<(MutexId, ThreadRef)>>,
+    /// Id of the CPU the thread is pinned to.
+    affinity: AtomicCell<Option<u32>>,
+    /// A CPU runs the thread or didn't finish switching away from it, so it can't run elsewhere.
+    on_cpu: AtomicBool,
+    park_state: NoIrqMutex<ParkState>,
+    /// Set for the threads of the deadline scheduling class.
+    deadline: NoIrqMutex<Option<DeadlineEntity>>,
+    #[cfg(feature = "lockdep")]
+    held_locks: SyncUnsafeCell<HeldLocks>,
+    counters: ThreadCounters,
+
     user_stack_base: VirtualAddress,
     kernel_stack_base: VirtualAddress,
     kernel_stack: VirtualAddress, // also a *mut InterruptFrame
@@ -69,8 +143,29 @@ impl Thread {
     /// add itself in the thread list of its parent process.
     pub fn new(
         process: &ProcessRef,
+        name: impl Into<String>,
         entry: ThreadEntry,
         is_idle_thread: bool,
+    ) -> Result<ThreadRef, Error> {
+        Self::create(process, name.into(), entry as usize, 0, is_idle_thread)
+    }
+
+    /// Same as `new` but `arg` is passed to `entry`.
+    pub fn new_with_arg(
+        process: &ProcessRef,
+        name: impl Into<String>,
+        entry: ThreadEntryWithArg,
+        arg: usize,
+    ) -> Result<ThreadRef, Error> {
+        Self::create(process, name.into(), entry as usize, arg, false)
+    }
+
+    fn create(
+        process: &ProcessRef,
+        name: String,
+        entry: usize,
+        arg: usize,
+        is_idle_thread: bool,
     ) -> Result<ThreadRef, Error> {
         let id = get_next_id();
         let mut process_lock = process.write();
@@ -79,11 +174,12 @@ impl Thread {
         let addr_space = &mut process_lock.addr_space;
 
         trace!(target: "scheduler",
-            "Create {} thread {} of process {} with entry {:p}",
+            "Create {} thread {} ({}) of process {} with entry {:#x}",
             if addr_space.is_low() { "user" } else { "kernel" },
             id,
+            name,
             process_id,
-            entry as *const ()
+            entry
         );
 
         let user_stack_base = {
@@ -92,22 +188,15 @@ impl Thread {
             } else {
                 MemoryUsage::KernelHeap
             };
-            let r = vmm().alloc_pages(
-                USER_STACK_PAGE_COUNT + 1,
+            vmm().alloc_guarded_pages(
+                USER_STACK_PAGE_COUNT,
                 usage,
                 MapFlags::default(),
                 AddrSpaceSelector::Locked(addr_space),
-            )?;
-            vmm().map_page(
-                r,
-                PhysicalAddress::new(usize::MAX & !((1 << PAGE_SHIFT) - 1)),
-                MapOptions::new(MapSize::Size4KB, MapFlags::new(false, false, 0b11, 1, true)),
-                AddrSpaceSelector::Locked(addr_space),
-            )?;
-            r + PAGE_SIZE
+            )?
         };
 
-        let kernel_stack_base = vmm().alloc_pages(
+        let kernel_stack_base = vmm().alloc_guarded_pages(
             KERNEL_STACK_PAGE_COUNT,
             MemoryUsage::KernelHeap,
             MapFlags::default(),
@@ -123,13 +212,27 @@ impl Thread {
         };
 
         regs.sp = (user_stack_base + USER_STACK_PAGE_COUNT * PAGE_SIZE).addr();
-        regs.pc = entry as usize;
+        regs.pc = entry;
+        regs.x0 = arg;
+        regs.x30 = 0; // entries never return
         regs.pstate = 4; // interrupts enabled, EL1t
 
         let thread = Self {
             process: process.clone(),
             id,
+            name,
             state: AtomicCell::new(ThreadState::Runnable),
+            base_priority: AtomicU8::new(DEFAULT_PRIORITY),
+            priority: AtomicU8::new(DEFAULT_PRIORITY),
+            held_mutexes: NoIrqMutex::new(Vec::new()),
+            blocked_on: NoIrqMutex::new(None),
+            affinity: AtomicCell::new(None),
+            on_cpu: AtomicBool::new(false),
+            park_state: NoIrqMutex::new(ParkState::Empty),
+            deadline: NoIrqMutex::new(None),
+            #[cfg(feature = "lockdep")]
+            held_locks: SyncUnsafeCell::new(HeldLocks::new()),
+            counters: ThreadCounters::new(),
             user_stack_base,
             kernel_stack_base,
             kernel_stack,
@@ -139,6 +242,7 @@ impl Thread {
 
         let thread_ref = ThreadRef::new(thread);
         process_lock.add_thread(thread_ref.clone());
+        THREADS.write().insert(id, thread_ref.downgrade());
 
         Ok(thread_ref)
     }
@@ -148,6 +252,13 @@ impl Thread {
         debug_assert_ne!(self.kernel_stack, 0);
         self.kernel_stack.as_ptr()
     }
+
+    /// Set the effective priority to the highest of the base priority and the boosts of the `held` mutexes.
+    fn update_priority(&self, held: &[HeldMutex]) {
+        let base = self.base_priority.load(Ordering::Relaxed);
+        let priority = held.iter().map(|m| m.boost).fold(base, Priority::max);
+        self.priority.store(priority, Ordering::Relaxed);
+    }
 }
 
 impl ThreadRef {
@@ -157,6 +268,39 @@ impl ThreadRef {
         unsafe { (*ptr).id }
     }
 
+    /// Range of the guard page below the kernel stack, the stack of the exception handlers.
+    #[inline]
+    pub fn kernel_stack_guard(&self) -> Range<VirtualAddress> {
+        let base = unsafe { (*self.data_ptr()).kernel_stack_base };
+        base - PAGE_SIZE..base
+    }
+
+    /// Range of the guard page below the stack the thread runs on.
+    #[inline]
+    pub fn stack_guard(&self) -> Range<VirtualAddress> {
+        let base = unsafe { (*self.data_ptr()).user_stack_base };
+        base - PAGE_SIZE..base
+    }
+
+    /// Range of the stack of the thread holding `addr`, if any.
+    pub fn stack_containing(&self, addr: VirtualAddress) -> Option<Range<VirtualAddress>> {
+        let ptr = self.data_ptr();
+        let (stack_base, kernel_stack_base) =
+            unsafe { ((*ptr).user_stack_base, (*ptr).kernel_stack_base) };
+        [
+            stack_base..stack_base + USER_STACK_PAGE_COUNT * PAGE_SIZE,
+            kernel_stack_base..kernel_stack_base + KERNEL_STACK_PAGE_COUNT * PAGE_SIZE,
+        ]
+        .into_iter()
+        .find(|stack| stack.contains(&addr))
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        let ptr = self.data_ptr();
+        unsafe { &(*ptr).name }
+    }
+
     #[inline]
     pub fn state(&self) -> ThreadState {
         let atomic_state = self.atomic_state();
@@ -169,6 +313,152 @@ impl ThreadRef {
         unsafe { &(*ptr).state }
     }
 
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).priority.load(Ordering::Relaxed) }
+    }
+
+    #[inline]
+    pub fn base_priority(&self) -> Priority {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).base_priority.load(Ordering::Relaxed) }
+    }
+
+    /// Set the base priority of the thread.
+    /// A boost from priority inheritance is kept until the thread releases the mutex it comes from.
+    pub fn set_priority(&self, priority: Priority) {
+        let thread = unsafe { &*self.data_ptr() };
+        let held = thread.held_mutexes.lock();
+        thread.base_priority.store(priority, Ordering::Relaxed);
+        thread.update_priority(&held);
+    }
+
+    /// Raise to at least `priority` the boost the thread inherits from `mutex`, and of the owners along its
+    /// chain of mutex waits.
+    pub(crate) fn boost_priority(&self, mutex: MutexId, priority: Priority) {
+        let mut thread = self.clone();
+        let mut mutex = mutex;
+        for _ in 0..MAX_BOOST_CHAIN {
+            let data = unsafe { &*thread.data_ptr() };
+            {
+                let mut held = data.held_mutexes.lock();
+                // the mutex was released since the owner was found
+                let Some(entry) = held.iter_mut().find(|m| m.mutex == mutex) else {
+                    break;
+                };
+                entry.boost = entry.boost.max(priority);
+                // the owners after an already boosted thread inherited its priority when it blocked
+                if data.priority.fetch_max(priority, Ordering::Relaxed) >= priority {
+                    break;
+                }
+            }
+            let Some((next_mutex, owner)) = thread.blocked_on() else {
+                break;
+            };
+            thread = owner;
+            mutex = next_mutex;
+        }
+    }
+
+    /// Sleeping mutex the thread waits for and its owner.
+    #[inline]
+    pub(crate) fn blocked_on(&self) -> Option<(MutexId, ThreadRef)> {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).blocked_on.lock().clone() }
+    }
+
+    /// Set the sleeping mutex the thread waits for and its owner, under the lock of the mutex.
+    #[inline]
+    pub(crate) fn set_blocked_on(&self, owner: Option<(MutexId, ThreadRef)>) {
+        let ptr = self.data_ptr();
+        unsafe { *(*ptr).blocked_on.lock() = owner };
+    }
+
+    /// Locks held by the thread with IRQs enabled, only accessed by the thread itself.
+    #[cfg(feature = "lockdep")]
+    #[inline]
+    pub(crate) fn held_locks(&self) -> *mut HeldLocks {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).held_locks.get() }
+    }
+
+    #[inline]
+    pub(super) fn counters(&self) -> &ThreadCounters {
+        let ptr = self.data_ptr();
+        unsafe { &(*ptr).counters }
+    }
+
+    /// The thread owns `mutex` from now, inheriting `boost` from its waiters.
+    pub(crate) fn mutex_acquired(&self, mutex: MutexId, boost: Priority) {
+        let thread = unsafe { &*self.data_ptr() };
+        let mut held = thread.held_mutexes.lock();
+        held.push(HeldMutex { mutex, boost });
+        thread.update_priority(&held);
+    }
+
+    /// Drop the priority inherited from the waiters of `mutex`.
+    pub(crate) fn mutex_released(&self, mutex: MutexId) {
+        let thread = unsafe { &*self.data_ptr() };
+        let mut held = thread.held_mutexes.lock();
+        let index = held
+            .iter()
+            .position(|m| m.mutex == mutex)
+            .expect("Releasing a mutex not owned");
+        held.swap_remove(index);
+        thread.update_priority(&held);
+    }
+
+    #[inline]
+    pub fn affinity(&self) -> Option<u32> {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).affinity.load() }
+    }
+
+    /// Pin the thread to the CPU `cpu_id`. It will be moved there the next time it is queued.
+    #[inline]
+    pub fn set_affinity(&self, cpu_id: Option<u32>) {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).affinity.store(cpu_id) };
+    }
+
+    #[inline]
+    pub(super) fn is_on_cpu(&self) -> bool {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).on_cpu.load(Ordering::Acquire) }
+    }
+
+    #[inline]
+    pub(super) fn set_on_cpu(&self, on_cpu: bool) {
+        let ptr = self.data_ptr();
+        unsafe { (*ptr).on_cpu.store(on_cpu, Ordering::Release) };
+    }
+
+    #[inline]
+    pub(super) fn park_state(&self) -> &NoIrqMutex<ParkState> {
+        let ptr = self.data_ptr();
+        unsafe { &(*ptr).park_state }
+    }
+
+    #[inline]
+    pub(super) fn deadline(&self) -> &NoIrqMutex<Option<DeadlineEntity>> {
+        let ptr = self.data_ptr();
+        unsafe { &(*ptr).deadline }
+    }
+
+    /// Wake up the thread if it is blocked in `park`, else make its next `park` call return immediately.
+    pub fn unpark(&self) {
+        let mut state = self.park_state().lock();
+        match *state {
+            ParkState::Parked => {
+                *state = ParkState::Empty;
+                // the thread is marked as blocked before releasing the lock in `park`
+                assert!(super::wake_thread(self));
+            }
+            ParkState::Empty | ParkState::Notified => *state = ParkState::Notified,
+        }
+    }
+
     #[inline]
     pub fn is_idle_thread(&self) -> bool {
         let ptr = self.data_ptr();
@@ -181,14 +471,33 @@ impl ThreadRef {
         unsafe { &(*ptr).process }
     }
 
+    /// Add the thread in the threads queue of its CPU.
     #[inline]
-    #[allow(unused)]
     pub fn start(self) {
         SCHEDULER.add_thread(self);
-        SCHEDULER.config_timer(Cpu::current().threads().lock().len());
+        let cpu = Cpu::current();
+        let budget_end = cpu
+            .try_current_thread()
+            .and_then(|current| deadline::budget_end(current, timer::uptime()));
+        SCHEDULER.config_timer(cpu.threads().lock().len(), budget_end);
     }
 }
 
+/// Get a thread by its id.
+#[inline]
+pub fn get_thread(id: ThreadId) -> Option<ThreadRef> {
+    THREADS.read().get(&id).and_then(WeakThreadRef::upgrade)
+}
+
+/// Return all the threads alive.
+pub fn all_threads() -> Vec<ThreadRef> {
+    THREADS
+        .read()
+        .values()
+        .filter_map(WeakThreadRef::upgrade)
+        .collect()
+}
+
 impl PartialEq for ThreadRef {
     #[inline(always)]
     fn eq(&self, other: &Self) -> bool {
@@ -207,15 +516,16 @@ impl Eq for Thread {}
 
 impl Drop for Thread {
     fn drop(&mut self) {
+        THREADS.write().remove(&self.id);
         vmm()
-            .dealloc_pages(
+            .dealloc_guarded_pages(
                 self.user_stack_base,
                 USER_STACK_PAGE_COUNT,
                 AddrSpaceSelector::Locked(self.process.get_addr_space()),
             )
             .unwrap();
         vmm()
-            .dealloc_pages(
+            .dealloc_guarded_pages(
                 self.kernel_stack_base,
                 KERNEL_STACK_PAGE_COUNT,
                 AddrSpaceSelector::kernel(),
@@ -229,7 +539,10 @@ impl Debug for Thread {
         f.debug_struct("Thread")
             .field("process", &self.process.id())
             .field("id", &self.id)
+            .field("name", &self.name)
             .field("state", &self.state)
+            .field("priority", &self.priority)
+            .field("affinity", &self.affinity)
             .field("user_stack_base", &self.user_stack_base)
             .field("kernel_stack_base", &self.kernel_stack_base)
             .field("kernel_stack", &self.kernel_stack)
@@ -1,15 +1,16 @@
 use core::ffi::CStr;
 
 use alloc::{collections::BTreeMap, string::String};
+use spin::Lazy;
 
-use crate::fs;
+use crate::{fs, sync::rcu::RcuCell};
 
-static mut SYMBOLS: BTreeMap<String, usize> = BTreeMap::new();
+static SYMBOLS: Lazy<RcuCell<BTreeMap<String, usize>>> = Lazy::new(Default::default);
 
 pub fn init() {
     let node = fs::get_node("/initrd/ksymbols").expect("ksymbols not found");
     let file = node.as_file().expect("Not a file");
-    let symbols = unsafe { &mut *&raw mut SYMBOLS };
+    let mut symbols = BTreeMap::new();
     let buff = file.read_to_end_vec(0).unwrap();
     let mut off = 0;
     while off + 10 < buff.len() {
@@ -19,9 +20,10 @@ pub fn init() {
         symbols.insert(str.into_owned(), addr);
         off += 8 + cstr.to_bytes_with_nul().len();
     }
+    SYMBOLS.replace(symbols);
 }
 
 #[inline]
 pub fn get(name: &str) -> Option<usize> {
-    unsafe { (&*&raw const SYMBOLS).get(name).copied() }
+    SYMBOLS.get().get(name).copied()
 }
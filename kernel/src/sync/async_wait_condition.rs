@@ -0,0 +1,124 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use super::no_irq_locks::NoIrqMutex;
+
+#[derive(Debug)]
+struct Waiter {
+    notified: AtomicBool,
+    waker: NoIrqMutex<Option<Waker>>,
+}
+
+impl Waiter {
+    fn notify(&self) {
+        self.notified.store(true, Ordering::Release);
+        // taken under the lock so a concurrent poll either sees `notified` or has stored its waker
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Same as `WaitCondition` but the waiters are futures instead of threads.
+///
+/// `notify_all` and `notify_one` can be called from interrupt context.
+#[derive(Debug)]
+pub struct AsyncWaitCondition {
+    waiters: NoIrqMutex<VecDeque<Arc<Waiter>>>,
+}
+
+impl AsyncWaitCondition {
+    pub const fn new() -> Self {
+        Self {
+            waiters: NoIrqMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Return a future completing once notified.
+    ///
+    /// The waiter is registered by this call and not by the first poll, so a lock guard protecting
+    /// the awaited condition can be dropped between the call and the await without losing a wake-up.
+    pub fn wait(&self) -> Wait<'_> {
+        let waiter = Arc::new(Waiter {
+            notified: AtomicBool::new(false),
+            waker: NoIrqMutex::new(None),
+        });
+        self.waiters.lock().push_back(Arc::clone(&waiter));
+        Wait {
+            cond: self,
+            waiter,
+            done: false,
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let waiters = mem::take(&mut *self.waiters.lock());
+        for waiter in waiters {
+            waiter.notify();
+        }
+    }
+
+    pub fn notify_one(&self) {
+        let waiter = self.waiters.lock().pop_front();
+        if let Some(waiter) = waiter {
+            waiter.notify();
+        }
+    }
+}
+
+impl Default for AsyncWaitCondition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `AsyncWaitCondition::wait`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless awaited"]
+pub struct Wait<'a> {
+    cond: &'a AsyncWaitCondition,
+    waiter: Arc<Waiter>,
+    done: bool,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let mut waker = this.waiter.waker.lock();
+        if this.waiter.notified.load(Ordering::Acquire) {
+            this.done = true;
+            return Poll::Ready(());
+        }
+        match waker.as_mut() {
+            Some(waker) => waker.clone_from(cx.waker()),
+            None => *waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut waiters = self.cond.waiters.lock();
+        let len = waiters.len();
+        waiters.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+        let removed = waiters.len() != len;
+        drop(waiters);
+        // notified but cancelled before seeing it, pass the notification to another waiter
+        if !removed {
+            self.cond.notify_one();
+        }
+    }
+}
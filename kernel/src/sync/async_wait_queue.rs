@@ -0,0 +1,52 @@
+use alloc::collections::VecDeque;
+
+use super::{async_wait_condition::AsyncWaitCondition, no_irq_locks::NoIrqMutex};
+
+/// Same as `WaitQueue` but received asynchronously. `send` can be called from interrupt context.
+#[derive(Debug)]
+pub struct AsyncWaitQueue<T> {
+    inner: NoIrqMutex<VecDeque<T>>,
+    waitcond: AsyncWaitCondition,
+}
+
+impl<T> AsyncWaitQueue<T> {
+    pub const fn new() -> Self {
+        Self {
+            inner: NoIrqMutex::new(VecDeque::new()),
+            waitcond: AsyncWaitCondition::new(),
+        }
+    }
+
+    pub fn send(&self, data: T) {
+        let mut queue = self.inner.lock();
+        queue.push_back(data);
+        drop(queue);
+        self.waitcond.notify_one();
+    }
+
+    pub async fn receive(&self) -> T {
+        loop {
+            // the guard isn't held across the await
+            let wait = {
+                let mut queue = self.inner.lock();
+                if let Some(data) = queue.pop_front() {
+                    return data;
+                }
+                self.waitcond.wait()
+            };
+            wait.await;
+        }
+    }
+
+    /// Same as `receive` but return `None` instead of waiting if the queue is empty.
+    #[inline]
+    pub fn try_receive(&self) -> Option<T> {
+        self.inner.lock().pop_front()
+    }
+}
+
+impl<T> Default for AsyncWaitQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,106 @@
+use alloc::collections::VecDeque;
+use core::mem;
+
+use crate::scheduler::{
+    block_thread_drop, current_thread, preempt::might_sleep, thread::ThreadRef, wake_thread,
+};
+
+use super::no_irq_locks::NoIrqMutex;
+
+/// `done` value once `complete_all` has been called.
+const COMPLETED_ALL: usize = usize::MAX;
+
+#[derive(Debug)]
+struct CompletionState {
+    done: usize,
+    waiters: VecDeque<ThreadRef>,
+}
+
+/// Let threads wait for an event signaled by another thread or by an interrupt handler.
+#[derive(Debug)]
+pub struct Completion {
+    state: NoIrqMutex<CompletionState>,
+}
+
+impl Completion {
+    pub const fn new() -> Self {
+        Self {
+            state: NoIrqMutex::new(CompletionState {
+                done: 0,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Sleep until the completion is signaled, consume one `complete` call.
+    pub fn wait(&self) {
+        might_sleep();
+        let mut state = self.state.lock();
+        if state.done > 0 {
+            if state.done != COMPLETED_ALL {
+                state.done -= 1;
+            }
+            return;
+        }
+        state.waiters.push_back(current_thread().clone());
+        block_thread_drop(state);
+    }
+
+    /// Consume one `complete` call without sleeping. Return false if there is none.
+    pub fn try_wait(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.done > 0 {
+            if state.done != COMPLETED_ALL {
+                state.done -= 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Wake up one waiter, or the next thread calling `wait` if there is none.
+    pub fn complete(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                drop(state);
+                assert!(wake_thread(&waiter));
+            }
+            None => {
+                if state.done != COMPLETED_ALL {
+                    state.done += 1;
+                }
+            }
+        }
+    }
+
+    /// Wake up all the waiters and let all future `wait` calls return immediately.
+    pub fn complete_all(&self) {
+        let mut state = self.state.lock();
+        state.done = COMPLETED_ALL;
+        let waiters = mem::take(&mut state.waiters);
+        drop(state);
+        for waiter in waiters {
+            assert!(wake_thread(&waiter));
+        }
+    }
+
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.state.lock().done > 0
+    }
+
+    /// Reset the completion to its initial state. There should be no waiters.
+    pub fn reinit(&self) {
+        let mut state = self.state.lock();
+        debug_assert!(state.waiters.is_empty());
+        state.done = 0;
+    }
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
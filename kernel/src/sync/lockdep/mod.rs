@@ -0,0 +1,78 @@
+#[cfg(feature = "lockdep")]
+mod tracker;
+
+#[cfg(feature = "lockdep")]
+pub use tracker::{HeldLocks, Tracked, irq_enter, irq_exit};
+
+use core::sync::atomic::AtomicU32;
+
+/// A lock class shared by many locks, for locks created dynamically.
+///
+/// Locks created without a class are each their own class.
+#[derive(Debug)]
+pub struct LockClass {
+    name: &'static str,
+    /// Slot of the class in the dependency graph, 0 if not registered yet.
+    #[cfg_attr(not(feature = "lockdep"), allow(unused))]
+    slot: AtomicU32,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            slot: AtomicU32::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[cfg(feature = "lockdep")]
+pub type RawTrackedMutex = Tracked<spin::Mutex<()>>;
+#[cfg(not(feature = "lockdep"))]
+pub type RawTrackedMutex = spin::Mutex<()>;
+
+#[cfg(feature = "lockdep")]
+pub type RawTrackedRwLock = Tracked<spin::RwLock<()>>;
+#[cfg(not(feature = "lockdep"))]
+pub type RawTrackedRwLock = spin::RwLock<()>;
+
+/// A spin mutex checked by the lock validator when the `lockdep` feature is enabled.
+pub type TrackedMutex<T> = lock_api::Mutex<RawTrackedMutex, T>;
+/// A spin rwlock checked by the lock validator when the `lockdep` feature is enabled.
+pub type TrackedRwLock<T> = lock_api::RwLock<RawTrackedRwLock, T>;
+pub type TrackedRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawTrackedRwLock, T>;
+
+/// Create a `TrackedMutex` belonging to `class`.
+#[inline]
+pub fn mutex_in_class<T>(class: &'static LockClass, val: T) -> TrackedMutex<T> {
+    #[cfg(feature = "lockdep")]
+    return TrackedMutex::from_raw(
+        Tracked::new(<spin::Mutex<()> as lock_api::RawMutex>::INIT, Some(class)),
+        val,
+    );
+    #[cfg(not(feature = "lockdep"))]
+    {
+        let _ = class;
+        TrackedMutex::new(val)
+    }
+}
+
+/// Create a `TrackedRwLock` belonging to `class`.
+#[inline]
+pub fn rwlock_in_class<T>(class: &'static LockClass, val: T) -> TrackedRwLock<T> {
+    #[cfg(feature = "lockdep")]
+    return TrackedRwLock::from_raw(
+        Tracked::new(<spin::RwLock<()> as lock_api::RawRwLock>::INIT, Some(class)),
+        val,
+    );
+    #[cfg(not(feature = "lockdep"))]
+    {
+        let _ = class;
+        TrackedRwLock::new(val)
+    }
+}
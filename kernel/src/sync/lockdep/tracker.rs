@@ -0,0 +1,774 @@
+use core::{
+    cell::SyncUnsafeCell,
+    fmt::Display,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use lock_api::{GuardSend, RawMutex, RawRwLock};
+use log::{error, warn};
+
+use crate::{
+    cpu,
+    interrupts::exceptions::{disable_exceptions, restore_exceptions},
+    scheduler::Cpu,
+};
+
+use super::LockClass;
+
+const MAX_CPUS: usize = 8;
+const MAX_HELD: usize = 32;
+const MAX_CLASSES: usize = 2048;
+const MAX_EDGES: usize = 8192;
+const MAX_CHAIN: usize = 8;
+const BACKTRACE_LEN: usize = 4;
+
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Backtrace([usize; BACKTRACE_LEN]);
+
+impl Backtrace {
+    #[inline(always)]
+    fn capture() -> Self {
+        let mut frames = [0; BACKTRACE_LEN];
+        cpu::backtrace(&mut frames);
+        Self(frames)
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for addr in self.0.iter().take_while(|a| **a != 0) {
+            write!(f, " {:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HeldLock {
+    slot: u32,
+    shared: bool,
+    backtrace: Backtrace,
+}
+
+/// Stack of the locks held by a thread or a CPU.
+#[derive(Debug)]
+pub struct HeldLocks {
+    locks: [HeldLock; MAX_HELD],
+    len: usize,
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            locks: [HeldLock {
+                slot: NONE,
+                shared: false,
+                backtrace: Backtrace([0; BACKTRACE_LEN]),
+            }; MAX_HELD],
+            len: 0,
+        }
+    }
+
+    #[inline]
+    fn iter(&self) -> impl Iterator<Item = &HeldLock> {
+        self.locks[..self.len].iter()
+    }
+
+    fn push(&mut self, lock: HeldLock) -> bool {
+        if self.len == MAX_HELD {
+            return false;
+        }
+        self.locks[self.len] = lock;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, slot: u32) -> bool {
+        let Some(i) = self.locks[..self.len].iter().rposition(|l| l.slot == slot) else {
+            return false;
+        };
+        self.locks.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+        true
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct CpuState {
+    /// Lockdep is running on this CPU, don't track the locks it takes.
+    busy: AtomicBool,
+    irq_nesting: AtomicU32,
+    /// Locks taken with IRQs disabled, they are released before the CPU switches thread.
+    held: SyncUnsafeCell<HeldLocks>,
+}
+
+static CPUS: [CpuState; MAX_CPUS] = [const {
+    CpuState {
+        busy: AtomicBool::new(false),
+        irq_nesting: AtomicU32::new(0),
+        held: SyncUnsafeCell::new(HeldLocks::new()),
+    }
+}; MAX_CPUS];
+
+/// Set when a table is full, lockdep stops tracking.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+fn cpu_state() -> &'static CpuState {
+    let index = Cpu::current().index;
+    assert!(index < MAX_CPUS, "Too many CPUs for lockdep");
+    &CPUS[index]
+}
+
+/// Called when entering an interrupt handler.
+#[inline]
+pub fn irq_enter() {
+    cpu_state().irq_nesting.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called when leaving an interrupt handler.
+#[inline]
+pub fn irq_exit() {
+    cpu_state().irq_nesting.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClassSlot {
+    generation: u32,
+    name: Option<&'static str>,
+    /// Head of the list of the dependencies to the classes acquired after this one.
+    edges: u32,
+    /// Where the class was acquired in interrupt context.
+    in_irq: Option<Backtrace>,
+    /// Where the class was acquired with IRQs enabled.
+    irqs_on: Option<Backtrace>,
+    irq_reported: bool,
+    next_free: u32,
+}
+
+const EMPTY_CLASS: ClassSlot = ClassSlot {
+    generation: 0,
+    name: None,
+    edges: NONE,
+    in_irq: None,
+    irqs_on: None,
+    irq_reported: false,
+    next_free: NONE,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    from: u32,
+    to: u32,
+    /// Generation of `to` when the edge was created, the edge is stale if it changed.
+    generation: u32,
+    next: u32,
+    /// Where the first lock was acquired.
+    first: Backtrace,
+    /// Where the second lock was acquired while holding the first one.
+    second: Backtrace,
+}
+
+const EMPTY_EDGE: Edge = Edge {
+    from: NONE,
+    to: NONE,
+    generation: 0,
+    next: NONE,
+    first: Backtrace([0; BACKTRACE_LEN]),
+    second: Backtrace([0; BACKTRACE_LEN]),
+};
+
+/// The dependency graph between the lock classes. Never allocates since the heap takes locks.
+struct Graph {
+    classes: [ClassSlot; MAX_CLASSES],
+    class_count: u32,
+    free_classes: u32,
+    edges: [Edge; MAX_EDGES],
+    edge_count: u32,
+    free_edges: u32,
+    // scratch space of `find_path`
+    visited: [u64; MAX_CLASSES / 64],
+    parent_edge: [u32; MAX_CLASSES],
+    stack: [u32; MAX_CLASSES],
+}
+
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph {
+    classes: [EMPTY_CLASS; MAX_CLASSES],
+    class_count: 0,
+    free_classes: NONE,
+    edges: [EMPTY_EDGE; MAX_EDGES],
+    edge_count: 0,
+    free_edges: NONE,
+    visited: [0; MAX_CLASSES / 64],
+    parent_edge: [NONE; MAX_CLASSES],
+    stack: [0; MAX_CLASSES],
+});
+
+impl Graph {
+    fn alloc_class(&mut self, name: Option<&'static str>) -> Option<u32> {
+        let slot = if self.free_classes != NONE {
+            let slot = self.free_classes;
+            self.free_classes = self.classes[slot as usize].next_free;
+            slot
+        } else if (self.class_count as usize) < MAX_CLASSES {
+            self.class_count += 1;
+            self.class_count - 1
+        } else {
+            return None;
+        };
+        let class = &mut self.classes[slot as usize];
+        *class = ClassSlot {
+            generation: class.generation,
+            name,
+            ..EMPTY_CLASS
+        };
+        Some(slot)
+    }
+
+    fn free_class(&mut self, slot: u32) {
+        let mut edge = self.classes[slot as usize].edges;
+        while edge != NONE {
+            let next = self.edges[edge as usize].next;
+            self.free_edge(edge);
+            edge = next;
+        }
+        let class = &mut self.classes[slot as usize];
+        *class = ClassSlot {
+            generation: class.generation.wrapping_add(1),
+            next_free: self.free_classes,
+            ..EMPTY_CLASS
+        };
+        self.free_classes = slot;
+    }
+
+    fn free_edge(&mut self, edge: u32) {
+        self.edges[edge as usize] = Edge {
+            next: self.free_edges,
+            ..EMPTY_EDGE
+        };
+        self.free_edges = edge;
+    }
+
+    /// Drop the dependencies of `from` to freed classes.
+    fn prune_edges(&mut self, from: u32) {
+        let mut prev = NONE;
+        let mut edge = self.classes[from as usize].edges;
+        while edge != NONE {
+            let e = self.edges[edge as usize];
+            if self.classes[e.to as usize].generation == e.generation {
+                prev = edge;
+            } else if prev == NONE {
+                self.classes[from as usize].edges = e.next;
+                self.free_edge(edge);
+            } else {
+                self.edges[prev as usize].next = e.next;
+                self.free_edge(edge);
+            }
+            edge = e.next;
+        }
+    }
+
+    fn has_edge(&mut self, from: u32, to: u32) -> bool {
+        self.prune_edges(from);
+        let mut edge = self.classes[from as usize].edges;
+        while edge != NONE {
+            if self.edges[edge as usize].to == to {
+                return true;
+            }
+            edge = self.edges[edge as usize].next;
+        }
+        false
+    }
+
+    fn add_edge(&mut self, from: u32, to: u32, first: Backtrace, second: Backtrace) -> bool {
+        let edge = if self.free_edges != NONE {
+            let edge = self.free_edges;
+            self.free_edges = self.edges[edge as usize].next;
+            edge
+        } else if (self.edge_count as usize) < MAX_EDGES {
+            self.edge_count += 1;
+            self.edge_count - 1
+        } else {
+            return false;
+        };
+        self.edges[edge as usize] = Edge {
+            from,
+            to,
+            generation: self.classes[to as usize].generation,
+            next: self.classes[from as usize].edges,
+            first,
+            second,
+        };
+        self.classes[from as usize].edges = edge;
+        true
+    }
+
+    #[inline]
+    fn visit(&mut self, class: u32) -> bool {
+        let (word, bit) = (class as usize / 64, 1 << (class % 64));
+        let visited = self.visited[word] & bit != 0;
+        self.visited[word] |= bit;
+        !visited
+    }
+
+    /// Search a path of dependencies from `from` to `to`, return its first edge and fill `chain`.
+    fn find_path(&mut self, from: u32, to: u32, chain: &mut Chain) -> Option<u32> {
+        self.visited.fill(0);
+        self.visit(from);
+        self.stack[0] = from;
+        let mut len = 1;
+
+        let mut found = false;
+        'search: while len > 0 {
+            len -= 1;
+            let class = self.stack[len];
+            self.prune_edges(class);
+            let mut edge = self.classes[class as usize].edges;
+            while edge != NONE {
+                let target = self.edges[edge as usize].to;
+                if self.visit(target) {
+                    self.parent_edge[target as usize] = edge;
+                    if target == to {
+                        found = true;
+                        break 'search;
+                    }
+                    self.stack[len] = target;
+                    len += 1;
+                }
+                edge = self.edges[edge as usize].next;
+            }
+        }
+        if !found {
+            return None;
+        }
+
+        // walk back from `to` to build the chain
+        let mut slots = [NONE; MAX_CHAIN];
+        let mut count = 0;
+        let mut class = to;
+        let mut first_edge = NONE;
+        while class != from {
+            if count < MAX_CHAIN - 1 {
+                slots[count] = class;
+                count += 1;
+            }
+            first_edge = self.parent_edge[class as usize];
+            class = self.edges[first_edge as usize].from;
+        }
+        chain.names[0] = self.class_name(from);
+        chain.len = 1;
+        for &slot in slots[..count].iter().rev() {
+            chain.names[chain.len] = self.class_name(slot);
+            chain.len += 1;
+        }
+        Some(first_edge)
+    }
+
+    #[inline]
+    fn class_name(&self, slot: u32) -> ClassName {
+        match self.classes[slot as usize].name {
+            Some(name) => ClassName::Named(name),
+            None => ClassName::Instance(slot),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ClassName {
+    Named(&'static str),
+    Instance(u32),
+}
+
+impl Display for ClassName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Named(name) => write!(f, "{}", name),
+            Self::Instance(slot) => write!(f, "lock#{}", slot),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Chain {
+    names: [ClassName; MAX_CHAIN],
+    len: usize,
+}
+
+impl Display for Chain {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, name) in self.names[..self.len].iter().enumerate() {
+            if i != 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Report {
+    Inversion {
+        held: ClassName,
+        held_at: Backtrace,
+        acquired: ClassName,
+        acquired_at: Backtrace,
+        chain: Chain,
+        reverse: Edge,
+    },
+    Recursion {
+        class: ClassName,
+        held_at: Backtrace,
+        acquired_at: Backtrace,
+    },
+    IrqUnsafe {
+        class: ClassName,
+        in_irq: Backtrace,
+        irqs_on: Backtrace,
+    },
+    Overflow(&'static str),
+}
+
+impl Report {
+    fn print(&self) {
+        match self {
+            Self::Inversion {
+                held,
+                held_at,
+                acquired,
+                acquired_at,
+                chain,
+                reverse,
+            } => {
+                error!(target: "lockdep", "Possible deadlock: acquiring {} while holding {}", acquired, held);
+                error!(target: "lockdep", "  {} was acquired at{}", held, held_at);
+                error!(target: "lockdep", "  {} is acquired at{}", acquired, acquired_at);
+                error!(target: "lockdep", "Existing dependency chain: {}", chain);
+                error!(target: "lockdep", "  {} was acquired at{}", chain.names[0], reverse.first);
+                error!(target: "lockdep", "  then {} at{}", chain.names[1], reverse.second);
+            }
+            Self::Recursion {
+                class,
+                held_at,
+                acquired_at,
+            } => {
+                error!(target: "lockdep", "Recursive locking of {}", class);
+                error!(target: "lockdep", "  first acquired at{}", held_at);
+                error!(target: "lockdep", "  acquired again at{}", acquired_at);
+            }
+            Self::IrqUnsafe {
+                class,
+                in_irq,
+                irqs_on,
+            } => {
+                error!(target: "lockdep", "{} is acquired both in interrupt context and with IRQs enabled", class);
+                error!(target: "lockdep", "  acquired in interrupt context at{}", in_irq);
+                error!(target: "lockdep", "  acquired with IRQs enabled at{}", irqs_on);
+            }
+            Self::Overflow(what) => {
+                warn!(target: "lockdep", "Too many {}, lockdep turned off", what);
+            }
+        }
+    }
+}
+
+/// Run `f` unless lockdep is already running on this CPU, with IRQs disabled.
+#[inline(always)]
+fn run(f: impl FnOnce(&CpuState, u32) -> Option<Report>) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // read before disabling the exceptions to know if the lock disables them
+    let irqs_depth = Cpu::current().irqs_depth.load(Ordering::Relaxed);
+    let daif = disable_exceptions();
+    let cpu = cpu_state();
+    if !cpu.busy.swap(true, Ordering::Acquire) {
+        if let Some(report) = f(cpu, irqs_depth) {
+            if let Report::Overflow(_) = report {
+                DISABLED.store(true, Ordering::Relaxed);
+            }
+            // still busy, the locks taken by the logger aren't tracked
+            report.print();
+        }
+        cpu.busy.store(false, Ordering::Release);
+    }
+    restore_exceptions(daif);
+}
+
+#[inline]
+fn get_slot(graph: &mut Graph, cell: &AtomicU32, name: Option<&'static str>) -> Option<u32> {
+    match cell.load(Ordering::Acquire) {
+        0 => {
+            let slot = graph.alloc_class(name)?;
+            cell.store(slot + 1, Ordering::Release);
+            Some(slot)
+        }
+        n => Some(n - 1),
+    }
+}
+
+/// Check the dependencies of the lock identified by `cell` then add it to the held locks.
+#[inline(always)]
+fn acquire(cell: &AtomicU32, name: Option<&'static str>, shared: bool, try_lock: bool) {
+    let backtrace = Backtrace::capture();
+    run(|cpu, irqs_depth| {
+        let thread = Cpu::current().try_current_thread();
+        let in_irq = cpu.irq_nesting.load(Ordering::Relaxed) > 0;
+        let cpu_held = unsafe { &mut *cpu.held.get() };
+        let thread_held = match thread {
+            Some(thread) if !in_irq => Some(unsafe { &mut *thread.held_locks() }),
+            _ => None,
+        };
+
+        let mut graph = GRAPH.lock();
+        let Some(slot) = get_slot(&mut graph, cell, name) else {
+            return Some(Report::Overflow("lock classes"));
+        };
+
+        let mut report = None;
+        let class = &mut graph.classes[slot as usize];
+        if in_irq {
+            class.in_irq.get_or_insert(backtrace);
+        } else if irqs_depth == 0 {
+            class.irqs_on.get_or_insert(backtrace);
+        }
+        if let (Some(in_irq), Some(irqs_on)) = (class.in_irq, class.irqs_on)
+            && !class.irq_reported
+        {
+            class.irq_reported = true;
+            report = Some(Report::IrqUnsafe {
+                class: graph.class_name(slot),
+                in_irq,
+                irqs_on,
+            });
+        }
+
+        if !try_lock {
+            let held = cpu_held
+                .iter()
+                .chain(thread_held.iter().flat_map(|h| h.iter()));
+            for held in held {
+                if held.slot == slot {
+                    // locks of a named class may be nested
+                    if name.is_none() && !(shared && held.shared) {
+                        report.get_or_insert(Report::Recursion {
+                            class: graph.class_name(slot),
+                            held_at: held.backtrace,
+                            acquired_at: backtrace,
+                        });
+                    }
+                    continue;
+                }
+                if graph.has_edge(held.slot, slot) {
+                    continue;
+                }
+                let mut chain = Chain {
+                    names: [ClassName::Instance(0); MAX_CHAIN],
+                    len: 0,
+                };
+                if let Some(edge) = graph.find_path(slot, held.slot, &mut chain) {
+                    // don't add the edge, so the inversion is reported each time it happens
+                    report.get_or_insert(Report::Inversion {
+                        held: graph.class_name(held.slot),
+                        held_at: held.backtrace,
+                        acquired: graph.class_name(slot),
+                        acquired_at: backtrace,
+                        chain,
+                        reverse: graph.edges[edge as usize],
+                    });
+                } else if !graph.add_edge(held.slot, slot, held.backtrace, backtrace) {
+                    return Some(Report::Overflow("lock dependencies"));
+                }
+            }
+        }
+        drop(graph);
+
+        let held = HeldLock {
+            slot,
+            shared,
+            backtrace,
+        };
+        let pushed = match thread_held {
+            Some(thread_held) if irqs_depth == 0 => thread_held.push(held),
+            _ => cpu_held.push(held),
+        };
+        if !pushed {
+            return Some(Report::Overflow("held locks"));
+        }
+        report
+    });
+}
+
+#[inline(always)]
+fn release(cell: &AtomicU32) {
+    let slot = match cell.load(Ordering::Acquire) {
+        0 => return,
+        n => n - 1,
+    };
+    run(|cpu, _| {
+        let cpu_held = unsafe { &mut *cpu.held.get() };
+        if !cpu_held.remove(slot)
+            && let Some(thread) = Cpu::current().try_current_thread()
+        {
+            // may not be found if released by another thread
+            unsafe { &mut *thread.held_locks() }.remove(slot);
+        }
+        None
+    });
+}
+
+fn forget_class(slot: u32) {
+    run(|_, _| {
+        GRAPH.lock().free_class(slot);
+        None
+    });
+}
+
+/// A raw lock checked by the lock validator.
+pub struct Tracked<R> {
+    raw: R,
+    class: Option<&'static LockClass>,
+    /// Slot of the lock in the dependency graph when it is its own class.
+    slot: AtomicU32,
+}
+
+impl<R> Tracked<R> {
+    pub const fn new(raw: R, class: Option<&'static LockClass>) -> Self {
+        Self {
+            raw,
+            class,
+            slot: AtomicU32::new(0),
+        }
+    }
+
+    #[inline(always)]
+    fn cell(&self) -> (&AtomicU32, Option<&'static str>) {
+        match self.class {
+            Some(class) => (&class.slot, Some(class.name)),
+            None => (&self.slot, None),
+        }
+    }
+
+    #[inline(always)]
+    fn acquire(&self, shared: bool, try_lock: bool) {
+        let (cell, name) = self.cell();
+        acquire(cell, name, shared, try_lock);
+    }
+
+    #[inline(always)]
+    fn release(&self) {
+        release(self.cell().0);
+    }
+}
+
+impl<R> Drop for Tracked<R> {
+    fn drop(&mut self) {
+        if self.class.is_none() {
+            let slot = *self.slot.get_mut();
+            if slot != 0 {
+                forget_class(slot - 1);
+            }
+        }
+    }
+}
+
+unsafe impl<R: RawMutex> RawMutex for Tracked<R> {
+    type GuardMarker = GuardSend;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self::new(R::INIT, None);
+
+    #[inline(always)]
+    fn lock(&self) {
+        // check before spinning, to report a deadlock instead of hanging
+        self.acquire(false, false);
+        self.raw.lock();
+    }
+
+    #[inline(always)]
+    fn try_lock(&self) -> bool {
+        let r = self.raw.try_lock();
+        if r {
+            self.acquire(false, true);
+        }
+        r
+    }
+
+    #[inline(always)]
+    unsafe fn unlock(&self) {
+        self.release();
+        unsafe { self.raw.unlock() };
+    }
+
+    #[inline(always)]
+    fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+}
+
+unsafe impl<R: RawRwLock> RawRwLock for Tracked<R> {
+    type GuardMarker = GuardSend;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self::new(R::INIT, None);
+
+    #[inline(always)]
+    fn lock_shared(&self) {
+        self.acquire(true, false);
+        self.raw.lock_shared();
+    }
+
+    #[inline(always)]
+    fn try_lock_shared(&self) -> bool {
+        let r = self.raw.try_lock_shared();
+        if r {
+            self.acquire(true, true);
+        }
+        r
+    }
+
+    #[inline(always)]
+    unsafe fn unlock_shared(&self) {
+        self.release();
+        unsafe { self.raw.unlock_shared() };
+    }
+
+    #[inline(always)]
+    fn lock_exclusive(&self) {
+        self.acquire(false, false);
+        self.raw.lock_exclusive();
+    }
+
+    #[inline(always)]
+    fn try_lock_exclusive(&self) -> bool {
+        let r = self.raw.try_lock_exclusive();
+        if r {
+            self.acquire(false, true);
+        }
+        r
+    }
+
+    #[inline(always)]
+    unsafe fn unlock_exclusive(&self) {
+        self.release();
+        unsafe { self.raw.unlock_exclusive() };
+    }
+
+    #[inline(always)]
+    fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    #[inline(always)]
+    fn is_locked_exclusive(&self) -> bool {
+        self.raw.is_locked_exclusive()
+    }
+}
@@ -1,4 +1,12 @@
+pub mod async_wait_condition;
+pub mod async_wait_queue;
+pub mod completion;
+pub mod lockdep;
+pub mod mutex;
 pub mod no_irq_locks;
+pub mod rcu;
+pub mod rwlock;
+pub mod semaphore;
 pub mod wait_condition;
 pub mod wait_map;
 pub mod wait_queue;
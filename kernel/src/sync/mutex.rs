@@ -0,0 +1,126 @@
+use alloc::collections::VecDeque;
+use lock_api::{GuardNoSend, RawMutex};
+
+use crate::scheduler::{
+    block_thread_drop, current_thread,
+    preempt::might_sleep,
+    thread::{MutexId, ThreadRef},
+    wake_thread,
+};
+
+use super::no_irq_locks::NoIrqMutex;
+
+/// A mutex that put the waiting threads to sleep instead of spinning.
+///
+/// The owner inherits the priority of the most urgent waiter until it releases the mutex.
+/// Shouldn't be used in interrupt context.
+pub type Mutex<T> = lock_api::Mutex<SleepMutexRaw, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, SleepMutexRaw, T>;
+
+#[derive(Debug)]
+struct MutexState {
+    owner: Option<ThreadRef>,
+    /// Sorted by decreasing priority when queued, the boosts may change it.
+    waiters: VecDeque<ThreadRef>,
+}
+
+pub struct SleepMutexRaw {
+    state: NoIrqMutex<MutexState>,
+}
+
+impl SleepMutexRaw {
+    #[inline]
+    fn id(&self) -> MutexId {
+        self as *const Self as MutexId
+    }
+
+    /// Return the thread owning the mutex.
+    pub fn owner(&self) -> Option<ThreadRef> {
+        self.state.lock().owner.clone()
+    }
+}
+
+unsafe impl RawMutex for SleepMutexRaw {
+    type GuardMarker = GuardNoSend;
+
+    const INIT: Self = Self {
+        state: NoIrqMutex::new(MutexState {
+            owner: None,
+            waiters: VecDeque::new(),
+        }),
+    };
+
+    fn lock(&self) {
+        might_sleep();
+        let current = current_thread().clone();
+        let mut state = self.state.lock();
+        let Some(owner) = &state.owner else {
+            state.owner = Some(current.clone());
+            current.mutex_acquired(self.id(), 0);
+            return;
+        };
+        assert!(
+            *owner != current,
+            "Thread {} tried to lock a mutex it already owns",
+            current.id()
+        );
+
+        let priority = current.priority();
+        owner.boost_priority(self.id(), priority);
+        current.set_blocked_on(Some((self.id(), owner.clone())));
+        let index = state
+            .waiters
+            .iter()
+            .position(|t| t.priority() < priority)
+            .unwrap_or(state.waiters.len());
+        state.waiters.insert(index, current.clone());
+
+        // the ownership is handed over by `unlock`
+        block_thread_drop(state);
+
+        debug_assert!(self.state.lock().owner.as_ref() == Some(&current));
+    }
+
+    fn try_lock(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.owner.is_some() {
+            return false;
+        }
+        let current = current_thread();
+        state.owner = Some(current.clone());
+        current.mutex_acquired(self.id(), 0);
+        true
+    }
+
+    unsafe fn unlock(&self) {
+        let mut state = self.state.lock();
+        let owner = state.owner.take().expect("Unlocking an unlocked mutex");
+        debug_assert!(&owner == current_thread());
+        owner.mutex_released(self.id());
+
+        // the waiters boosted after being queued may be out of order
+        let index = (0..state.waiters.len()).reduce(|best, i| {
+            if state.waiters[i].priority() > state.waiters[best].priority() {
+                i
+            } else {
+                best
+            }
+        });
+        if let Some(next) = index.and_then(|i| state.waiters.remove(i)) {
+            next.set_blocked_on(None);
+            for waiter in &state.waiters {
+                waiter.set_blocked_on(Some((self.id(), next.clone())));
+            }
+            let boost = state.waiters.iter().map(|t| t.priority()).max();
+            next.mutex_acquired(self.id(), boost.unwrap_or(0));
+            state.owner = Some(next.clone());
+            drop(state);
+            assert!(wake_thread(&next));
+        }
+    }
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.state.lock().owner.is_some()
+    }
+}
@@ -1,12 +1,13 @@
 use lock_api::{GuardSend, RawMutex, RawRwLock};
-use spin::{self, Mutex, RwLock};
 
 use crate::interrupts::exceptions::{disable_exceptions_depth, restore_exceptions_depth};
 
+use super::lockdep::{RawTrackedMutex, RawTrackedRwLock};
+
 pub type NoIrqMutex<T> = lock_api::Mutex<NoIrqMutexRaw, T>;
 pub type NoIrqMutexGuard<'a, T> = lock_api::MutexGuard<'a, NoIrqMutexRaw, T>;
 
-pub struct NoIrqMutexRaw<R: RawMutex = Mutex<()>>(R);
+pub struct NoIrqMutexRaw<R: RawMutex = RawTrackedMutex>(R);
 
 unsafe impl<R: RawMutex> RawMutex for NoIrqMutexRaw<R> {
     type GuardMarker = GuardSend;
@@ -42,7 +43,7 @@ pub type NoIrqRwLock<T> = lock_api::RwLock<NoIrqRwLockRaw, T>;
 pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, NoIrqRwLockRaw, T>;
 pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, NoIrqRwLockRaw, T>;
 
-pub struct NoIrqRwLockRaw<R: RawRwLock = RwLock<()>>(R);
+pub struct NoIrqRwLockRaw<R: RawRwLock = RawTrackedRwLock>(R);
 
 unsafe impl<R: RawRwLock> RawRwLock for NoIrqRwLockRaw<R> {
     const INIT: Self = Self(R::INIT);
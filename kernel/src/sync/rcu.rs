@@ -0,0 +1,235 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt::Debug,
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+    time::Duration,
+};
+use hashbrown::HashMap;
+use log::trace;
+
+use crate::{
+    interrupts::{
+        exceptions::{disable_exceptions_depth, restore_exceptions_depth},
+        ipi,
+    },
+    per_cpu,
+    scheduler::{Cpu, SCHEDULER, kthread, sleep},
+};
+
+use super::{mutex::Mutex, no_irq_locks::NoIrqMutex, wait_queue::WaitQueue};
+
+pub type RcuCallback = Box<dyn FnOnce() + Send>;
+pub type RcuList<T> = RcuCell<Vec<T>>;
+pub type RcuMap<K, V> = RcuCell<HashMap<K, V>>;
+
+/// Sequence number of the last started grace period.
+static GP_SEQ: AtomicUsize = AtomicUsize::new(0);
+/// Only one grace period at a time.
+static GP_LOCK: Mutex<()> = Mutex::new(());
+static CALLBACKS: WaitQueue<RcuCallback> = WaitQueue::new();
+
+per_cpu! {
+    /// Last grace period for which the CPU passed through a quiescent state.
+    static QS_SEQ: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Start the thread running the `call_rcu` callbacks, called once the boot CPU runs its first thread.
+pub(crate) fn init() {
+    kthread::spawn("rcu_gp", || {
+        loop {
+            let mut batch = Vec::new();
+            batch.push(CALLBACKS.receive());
+            while let Some(callback) = CALLBACKS.try_receive() {
+                batch.push(callback);
+            }
+
+            synchronize_rcu();
+            trace!(target: "rcu", "Run {} RCU callbacks", batch.len());
+            for callback in batch {
+                callback();
+            }
+        }
+    })
+    .expect("Failed to create the RCU thread");
+}
+
+/// Report that the current CPU isn't in a read-side critical section, called on context switch.
+#[inline]
+pub(crate) fn note_quiescent_state() {
+    let seq = GP_SEQ.load(Ordering::Acquire);
+    QS_SEQ.with(|qs| qs.store(seq, Ordering::Release));
+}
+
+/// A read-side critical section. Interrupts are disabled while it is held, so it shouldn't sleep.
+#[derive(Debug)]
+pub struct RcuReadGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for RcuReadGuard {
+    #[inline]
+    fn drop(&mut self) {
+        restore_exceptions_depth();
+    }
+}
+
+/// Enter a read-side critical section.
+#[inline]
+pub fn rcu_read_lock() -> RcuReadGuard {
+    // the CPU can't switch thread, hence can't report a quiescent state, until the guard is dropped
+    disable_exceptions_depth();
+    RcuReadGuard {
+        _not_send: PhantomData,
+    }
+}
+
+/// Wait until all the read-side critical sections running when called have ended.
+pub fn synchronize_rcu() {
+    debug_assert_eq!(
+        Cpu::current().irqs_depth.load(Ordering::Relaxed),
+        0,
+        "synchronize_rcu called with IRQs disabled or in a read-side critical section"
+    );
+
+    let _gp = GP_LOCK.lock();
+    let seq = GP_SEQ.fetch_add(1, Ordering::AcqRel) + 1;
+    // the current CPU isn't in a critical section
+    QS_SEQ.with(|qs| qs.store(seq, Ordering::Release));
+    trace!(target: "rcu", "Start grace period {}", seq);
+
+    loop {
+        let mut done = true;
+        for cpu in SCHEDULER.cpus().iter().filter(|cpu| cpu.is_online()) {
+            let qs = QS_SEQ.get(cpu.index).unwrap().load(Ordering::Acquire);
+            if qs < seq {
+                done = false;
+                // idle CPUs only report a quiescent state when they are woken up
+                if ipi::is_initialized() {
+                    ipi::send_reschedule(cpu.id);
+                }
+            }
+        }
+        if done {
+            break;
+        }
+        sleep(Duration::from_millis(1));
+    }
+    trace!(target: "rcu", "End grace period {}", seq);
+}
+
+/// Run `callback` once all the read-side critical sections running when called have ended.
+///
+/// Doesn't block, so can be called from interrupt context.
+pub fn call_rcu<F: FnOnce() + Send + 'static>(callback: F) {
+    CALLBACKS.send(Box::new(callback));
+}
+
+/// A pointer to a `T` that can be read without locking and is replaced on update.
+///
+/// The previous value is dropped after a grace period, so readers always see a valid value.
+pub struct RcuCell<T: Send + Sync + 'static> {
+    ptr: AtomicPtr<T>,
+    writer: NoIrqMutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: NoIrqMutex::new(()),
+        }
+    }
+
+    /// Return the current value, valid as long as the critical section.
+    #[inline]
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> &'a T {
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Return a reference to the current value holding a read-side critical section.
+    #[inline]
+    pub fn get(&self) -> RcuRef<'_, T> {
+        let guard = rcu_read_lock();
+        let value = unsafe { &*self.ptr.load(Ordering::Acquire) };
+        RcuRef {
+            value,
+            _guard: guard,
+        }
+    }
+
+    /// Replace the value and drop the previous one after a grace period.
+    pub fn replace(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.swap(value);
+    }
+
+    /// Replace the value by the one returned by `f` from a reference to the current value.
+    ///
+    /// Writers are serialized, and `f` is called with interrupts disabled.
+    pub fn update<R>(&self, f: impl FnOnce(&T) -> (T, R)) -> R {
+        let _writer = self.writer.lock();
+        let current = unsafe { &*self.ptr.load(Ordering::Acquire) };
+        let (value, r) = f(current);
+        self.swap(value);
+        r
+    }
+
+    fn swap(&self, value: T) {
+        let old = self
+            .ptr
+            .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        let old = unsafe { Box::from_raw(old) };
+        call_rcu(move || drop(old));
+    }
+}
+
+impl<T: Send + Sync + Clone + 'static> RcuCell<T> {
+    /// Update a copy of the value with `f` then replace the value by the copy.
+    #[inline]
+    pub fn modify<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.update(|current| {
+            let mut value = current.clone();
+            let r = f(&mut value);
+            (value, r)
+        })
+    }
+}
+
+impl<T: Send + Sync + Default + 'static> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Send + Sync + Debug + 'static> Debug for RcuCell<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("RcuCell").field(&*self.get()).finish()
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // no reader can hold a reference borrowing self
+        drop(unsafe { Box::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+/// A reference to the value of a `RcuCell`, holding a read-side critical section.
+pub struct RcuRef<'a, T> {
+    value: &'a T,
+    _guard: RcuReadGuard,
+}
+
+impl<T> Deref for RcuRef<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use lock_api::{GuardNoSend, RawRwLock};
+
+use crate::scheduler::{
+    block_thread_drop, current_thread, preempt::might_sleep, thread::ThreadRef, wake_thread,
+};
+
+use super::no_irq_locks::NoIrqMutex;
+
+/// A reader-writer lock that put the waiting threads to sleep instead of spinning.
+///
+/// Waiters are served in FIFO order so writers can't be starved by readers.
+/// Shouldn't be used in interrupt context.
+pub type RwLock<T> = lock_api::RwLock<SleepRwLockRaw, T>;
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, SleepRwLockRaw, T>;
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, SleepRwLockRaw, T>;
+
+#[derive(Debug)]
+struct RwLockState {
+    readers: usize,
+    writer: Option<ThreadRef>,
+    /// Waiting threads and if they want an exclusive access.
+    waiters: VecDeque<(ThreadRef, bool)>,
+}
+
+impl RwLockState {
+    /// Hand the lock over to the next waiters and return them.
+    fn wake_next(&mut self) -> Vec<ThreadRef> {
+        debug_assert!(self.readers == 0 && self.writer.is_none());
+        let mut woken = Vec::new();
+        match self.waiters.front() {
+            Some((_, true)) => {
+                let (thread, _) = self.waiters.pop_front().unwrap();
+                self.writer = Some(thread.clone());
+                woken.push(thread);
+            }
+            Some((_, false)) => {
+                while let Some((_, false)) = self.waiters.front() {
+                    let (thread, _) = self.waiters.pop_front().unwrap();
+                    self.readers += 1;
+                    woken.push(thread);
+                }
+            }
+            None => {}
+        }
+        woken
+    }
+}
+
+pub struct SleepRwLockRaw {
+    state: NoIrqMutex<RwLockState>,
+}
+
+impl SleepRwLockRaw {
+    fn unblock_all(woken: Vec<ThreadRef>) {
+        for thread in woken {
+            assert!(wake_thread(&thread));
+        }
+    }
+}
+
+unsafe impl RawRwLock for SleepRwLockRaw {
+    const INIT: Self = Self {
+        state: NoIrqMutex::new(RwLockState {
+            readers: 0,
+            writer: None,
+            waiters: VecDeque::new(),
+        }),
+    };
+    type GuardMarker = GuardNoSend;
+
+    fn lock_shared(&self) {
+        might_sleep();
+        let mut state = self.state.lock();
+        if state.writer.is_none() && state.waiters.is_empty() {
+            state.readers += 1;
+            return;
+        }
+        state.waiters.push_back((current_thread().clone(), false));
+
+        // the access is handed over by the unlocker
+        block_thread_drop(state);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer.is_none() && state.waiters.is_empty() {
+            state.readers += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    unsafe fn unlock_shared(&self) {
+        let mut state = self.state.lock();
+        debug_assert!(state.readers > 0);
+        state.readers -= 1;
+        if state.readers == 0 {
+            let woken = state.wake_next();
+            drop(state);
+            Self::unblock_all(woken);
+        }
+    }
+
+    fn lock_exclusive(&self) {
+        might_sleep();
+        let current = current_thread();
+        let mut state = self.state.lock();
+        if state.writer.is_none() && state.readers == 0 {
+            state.writer = Some(current.clone());
+            return;
+        }
+        assert!(
+            state.writer.as_ref() != Some(current),
+            "Thread {} tried to lock a RwLock it already owns",
+            current.id()
+        );
+        state.waiters.push_back((current.clone(), true));
+
+        // the access is handed over by the unlocker
+        block_thread_drop(state);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer.is_none() && state.readers == 0 {
+            state.writer = Some(current_thread().clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        let mut state = self.state.lock();
+        let writer = state.writer.take();
+        debug_assert!(writer.as_ref() == Some(current_thread()));
+        let woken = state.wake_next();
+        drop(state);
+        Self::unblock_all(woken);
+    }
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        let state = self.state.lock();
+        state.readers > 0 || state.writer.is_some()
+    }
+
+    #[inline]
+    fn is_locked_exclusive(&self) -> bool {
+        self.state.lock().writer.is_some()
+    }
+}
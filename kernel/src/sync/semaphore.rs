@@ -0,0 +1,72 @@
+use alloc::collections::VecDeque;
+
+use crate::scheduler::{
+    block_thread_drop, current_thread, preempt::might_sleep, thread::ThreadRef, wake_thread,
+};
+
+use super::no_irq_locks::NoIrqMutex;
+
+#[derive(Debug)]
+struct SemaphoreState {
+    count: usize,
+    waiters: VecDeque<ThreadRef>,
+}
+
+/// A counting semaphore. `up` can be called from interrupt context.
+#[derive(Debug)]
+pub struct Semaphore {
+    state: NoIrqMutex<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            state: NoIrqMutex::new(SemaphoreState {
+                count,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Take a unit, sleep until one is available.
+    pub fn down(&self) {
+        might_sleep();
+        let mut state = self.state.lock();
+        if state.count > 0 {
+            state.count -= 1;
+            return;
+        }
+        state.waiters.push_back(current_thread().clone());
+
+        // the unit is handed over by `up`
+        block_thread_drop(state);
+    }
+
+    /// Take a unit if one is available.
+    pub fn try_down(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.count > 0 {
+            state.count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Release a unit, waking up the first waiter if any.
+    pub fn up(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                drop(state);
+                assert!(wake_thread(&waiter));
+            }
+            None => state.count += 1,
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.state.lock().count
+    }
+}
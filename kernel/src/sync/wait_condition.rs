@@ -1,28 +1,39 @@
 use core::{mem, ops::DerefMut};
 
 use alloc::vec::Vec;
-use spin::lock_api::Mutex;
 
-use crate::scheduler::{block_thread_drop, current_thread, thread::ThreadRef, unblock_thread};
+use crate::scheduler::{
+    block_thread_drop, current_thread, preempt::might_sleep, thread::ThreadRef, wake_thread,
+};
+
+use super::no_irq_locks::NoIrqMutex;
 
 #[derive(Debug)]
 pub struct WaitCondition {
-    waiters: Mutex<Vec<ThreadRef>>,
+    waiters: NoIrqMutex<Vec<ThreadRef>>,
 }
 
 impl WaitCondition {
     pub const fn new() -> Self {
         Self {
-            waiters: Mutex::new(Vec::new()),
+            waiters: NoIrqMutex::new(Vec::new()),
         }
     }
 
     pub fn wait(&self) {
+        might_sleep();
+        self.wait_drop(());
+    }
+
+    /// Same as `wait` except that `val` is dropped once the thread is registered as a waiter.
+    ///
+    /// May help to prevent lost wake-ups if `val` is a lock guard protecting the awaited condition.
+    pub fn wait_drop<T>(&self, val: T) {
         let current_thread = current_thread().clone();
         let mut waiters = self.waiters.lock();
         waiters.push(current_thread);
 
-        block_thread_drop(waiters);
+        block_thread_drop((waiters, val));
     }
 
     pub fn notify_all(&self) {
@@ -31,16 +42,22 @@ impl WaitCondition {
         mem::swap(waiters_lock.deref_mut(), &mut waiters);
         drop(waiters_lock);
         for waiter in waiters {
-            unblock_thread(waiter.id()).unwrap();
+            assert!(wake_thread(&waiter));
         }
     }
 
     pub fn notify_one(&self) {
         let mut waiters = self.waiters.lock();
-        if waiters.first().is_some() {
+        if !waiters.is_empty() {
             let waiter = waiters.swap_remove(0);
             drop(waiters);
-            unblock_thread(waiter.id()).unwrap();
+            assert!(wake_thread(&waiter));
         }
     }
 }
+
+impl Default for WaitCondition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,17 +1,20 @@
 use alloc::{collections::BTreeMap, vec, vec::Vec};
-use spin::lock_api::Mutex;
 
-use crate::scheduler::{block_thread_drop, current_thread, thread::ThreadId, unblock_thread};
+use crate::scheduler::{
+    block_thread_drop, current_thread, preempt::might_sleep, thread::ThreadRef, wake_thread,
+};
+
+use super::no_irq_locks::NoIrqMutex;
 
 #[derive(Debug)]
 pub struct WaitMap<T: Ord> {
-    tree: Mutex<BTreeMap<Option<T>, Vec<ThreadId>>>,
+    tree: NoIrqMutex<BTreeMap<Option<T>, Vec<ThreadRef>>>,
 }
 
 impl<T: Ord> WaitMap<T> {
     pub fn new() -> Self {
         Self {
-            tree: Mutex::new(BTreeMap::new()),
+            tree: NoIrqMutex::new(BTreeMap::new()),
         }
     }
 
@@ -24,23 +27,23 @@ impl<T: Ord> WaitMap<T> {
 
         if let Some(threads) = val_threads {
             for thread in threads {
-                unblock_thread(thread).unwrap();
+                assert!(wake_thread(&thread));
             }
         }
         if let Some(threads) = any_threads {
             for thread in threads {
-                unblock_thread(thread).unwrap();
+                assert!(wake_thread(&thread));
             }
         }
     }
 
     fn wait_key<D>(&self, key: Option<T>, drop: D) {
-        let current_id = current_thread().id();
+        let current = current_thread().clone();
         let mut tree = self.tree.lock();
         if let Some(threads) = tree.get_mut(&key) {
-            threads.push(current_id);
+            threads.push(current);
         } else {
-            tree.insert(key, vec![current_id]);
+            tree.insert(key, vec![current]);
         }
 
         block_thread_drop((tree, drop));
@@ -49,12 +52,14 @@ impl<T: Ord> WaitMap<T> {
     #[inline]
     /// Pause the current thread while waiting for another thread to send an equal `val`.
     pub fn wait(&self, val: T) {
+        might_sleep();
         self.wait_key(Some(val), ());
     }
 
     #[inline]
     /// Pause the current thread while waiting for another thread to send something.
     pub fn wait_any(&self) {
+        might_sleep();
         self.wait_key(None, ());
     }
 
@@ -1,18 +1,20 @@
 use alloc::collections::VecDeque;
-use spin::lock_api::Mutex;
 
-use super::wait_condition::WaitCondition;
+use crate::scheduler::preempt::might_sleep;
 
+use super::{no_irq_locks::NoIrqMutex, wait_condition::WaitCondition};
+
+/// A queue where receivers sleep until data is available. `send` can be called from interrupt context.
 #[derive(Debug)]
 pub struct WaitQueue<T> {
-    inner: Mutex<VecDeque<T>>,
+    inner: NoIrqMutex<VecDeque<T>>,
     waitcond: WaitCondition,
 }
 
 impl<T> WaitQueue<T> {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            inner: Mutex::new(VecDeque::new()),
+            inner: NoIrqMutex::new(VecDeque::new()),
             waitcond: WaitCondition::new(),
         }
     }
@@ -25,15 +27,21 @@ impl<T> WaitQueue<T> {
     }
 
     pub fn receive(&self) -> T {
+        might_sleep();
         loop {
             let mut queue = self.inner.lock();
             if let Some(data) = queue.pop_front() {
                 return data;
             }
-            drop(queue);
-            self.waitcond.wait();
+            self.waitcond.wait_drop(queue);
         }
     }
+
+    /// Same as `receive` but return `None` instead of sleeping if the queue is empty.
+    #[inline]
+    pub fn try_receive(&self) -> Option<T> {
+        self.inner.lock().pop_front()
+    }
 }
 
 impl<T> Default for WaitQueue<T> {
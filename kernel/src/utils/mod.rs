@@ -1,5 +1,6 @@
 pub mod buffer;
 pub mod byte_size;
+pub mod per_cpu;
 pub mod sizes;
 pub mod smart_ptr;
 pub mod sync_once_cell;
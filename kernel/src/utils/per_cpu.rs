@@ -0,0 +1,87 @@
+use alloc::boxed::Box;
+use core::{fmt::Debug, slice};
+use spin::Once;
+
+use crate::{
+    interrupts::exceptions::{disable_exceptions_depth, restore_exceptions_depth},
+    scheduler::{Cpu, SCHEDULER},
+};
+
+/// A variable with one instance for each CPU, declared with `per_cpu!`.
+///
+/// The instances are created on first use, so it shouldn't be used before the CPUs are registered.
+/// Use `Cell` or `RefCell` to mutate the instance of the current CPU.
+pub struct PerCpu<T> {
+    values: Once<Box<[T]>>,
+    init: fn() -> T,
+}
+
+// the instance of a CPU is only accessed by this CPU, unless T is Sync
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            values: Once::new(),
+            init,
+        }
+    }
+
+    fn values(&self) -> &[T] {
+        self.values.call_once(|| {
+            let count = SCHEDULER.cpus().len();
+            assert!(count > 0, "Per-CPU variable used before the CPUs were registered");
+            (0..count).map(|_| (self.init)()).collect()
+        })
+    }
+
+    /// Call `f` with the instance of the current CPU.
+    ///
+    /// Interrupts are disabled during the call, so the thread can't be moved to another CPU.
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        disable_exceptions_depth();
+        let r = f(&self.values()[Cpu::current().index]);
+        restore_exceptions_depth();
+        r
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// Return the instance of the CPU at `index` in the scheduler CPU list.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.values().get(index)
+    }
+
+    /// Iterate over the instances of all the CPUs, in the order of the scheduler CPU list.
+    #[inline]
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.values().iter()
+    }
+}
+
+impl<T: Debug + Sync> Debug for PerCpu<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("PerCpu").field(&self.values.get()).finish()
+    }
+}
+
+/// Declare per-CPU variables, each CPU getting its own instance initialized with the expression.
+///
+/// ```ignore
+/// per_cpu! {
+///     static COUNTER: Cell<usize> = Cell::new(0);
+/// }
+/// COUNTER.with(|c| c.set(c.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::utils::per_cpu::PerCpu<$t> =
+                $crate::utils::per_cpu::PerCpu::new(|| $init);
+        )+
+    };
+}
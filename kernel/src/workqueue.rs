@@ -0,0 +1,144 @@
+use alloc::{boxed::Box, format, vec::Vec};
+use core::fmt::{self, Debug};
+use log::trace;
+
+use crate::{
+    error::Error,
+    scheduler::{Cpu, SCHEDULER, kthread},
+    sync::wait_queue::WaitQueue,
+    utils::sync_once_cell::SyncOnceCell,
+};
+
+/// A work item, run later by a kernel thread with interrupts enabled.
+pub type Work = Box<dyn FnOnce() + Send>;
+
+enum Queues {
+    /// One queue per CPU, each one served by a worker pinned to its CPU.
+    PerCpu(Vec<(u32, WaitQueue<Work>)>),
+    /// A single queue served by workers that can run on any CPU.
+    Unbound(WaitQueue<Work>),
+}
+
+pub struct WorkQueue {
+    name: &'static str,
+    queues: Queues,
+}
+
+impl Debug for WorkQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus = match &self.queues {
+            Queues::PerCpu(queues) => Some(queues.iter().map(|(id, _)| *id).collect::<Vec<_>>()),
+            Queues::Unbound(_) => None,
+        };
+        f.debug_struct("WorkQueue")
+            .field("name", &self.name)
+            .field("cpus", &cpus)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WorkQueue {
+    /// Create a workqueue with a worker pinned to each CPU.
+    pub fn new_per_cpu(name: &'static str) -> Result<&'static Self, Error> {
+        let queues = SCHEDULER
+            .cpus()
+            .iter()
+            .map(|cpu| (cpu.id, WaitQueue::new()))
+            .collect();
+        let wq = Self::leak(name, Queues::PerCpu(queues));
+        if let Queues::PerCpu(queues) = &wq.queues {
+            for (cpu_id, queue) in queues {
+                kthread::spawn_on(*cpu_id, format!("{}/{}", name, cpu_id), || {
+                    Self::worker(queue)
+                })?;
+            }
+        }
+        Ok(wq)
+    }
+
+    /// Create a workqueue with `workers` threads not bound to any CPU.
+    pub fn new_unbound(name: &'static str, workers: usize) -> Result<&'static Self, Error> {
+        assert!(workers > 0);
+        let wq = Self::leak(name, Queues::Unbound(WaitQueue::new()));
+        if let Queues::Unbound(queue) = &wq.queues {
+            for i in 0..workers {
+                kthread::spawn(format!("{}/u{}", name, i), || Self::worker(queue))?;
+            }
+        }
+        Ok(wq)
+    }
+
+    // workers keep a reference to the workqueue, so it is never freed
+    fn leak(name: &'static str, queues: Queues) -> &'static Self {
+        Box::leak(Box::new(Self { name, queues }))
+    }
+
+    fn worker(queue: &'static WaitQueue<Work>) -> ! {
+        loop {
+            let work = queue.receive();
+            work();
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Queue `work` on the current CPU, or on any CPU for an unbound workqueue.
+    ///
+    /// Can be called from interrupt context.
+    pub fn queue<F: FnOnce() + Send + 'static>(&self, work: F) {
+        self.queue_on(Cpu::current().id, work);
+    }
+
+    /// Queue `work` on the CPU `cpu_id`. The CPU is ignored for an unbound workqueue.
+    ///
+    /// Can be called from interrupt context.
+    pub fn queue_on<F: FnOnce() + Send + 'static>(&self, cpu_id: u32, work: F) {
+        trace!(target: "workqueue", "Queue work on {} (CPU {})", self.name, cpu_id);
+        let queue = match &self.queues {
+            Queues::PerCpu(queues) => {
+                &queues
+                    .iter()
+                    .find(|(id, _)| *id == cpu_id)
+                    .expect("Invalid CPU id")
+                    .1
+            }
+            Queues::Unbound(queue) => queue,
+        };
+        queue.send(Box::new(work));
+    }
+}
+
+static SYSTEM_WQ: SyncOnceCell<&'static WorkQueue> = SyncOnceCell::new();
+static SYSTEM_UNBOUND_WQ: SyncOnceCell<&'static WorkQueue> = SyncOnceCell::new();
+
+/// Create the system workqueues, called once the boot CPU runs its first thread.
+pub(crate) fn init() {
+    let system = WorkQueue::new_per_cpu("events").expect("Failed to create the system workqueue");
+    let unbound = WorkQueue::new_unbound("events_unbound", SCHEDULER.cpus().len())
+        .expect("Failed to create the system unbound workqueue");
+    let set = unsafe { (SYSTEM_WQ.set(system), SYSTEM_UNBOUND_WQ.set(unbound)) };
+    if !matches!(set, (Ok(()), Ok(()))) {
+        panic!("Workqueues initialized twice");
+    }
+}
+
+/// The system per-CPU workqueue.
+#[inline]
+pub fn system() -> &'static WorkQueue {
+    SYSTEM_WQ.get().expect("Workqueues not initialized")
+}
+
+/// The system unbound workqueue.
+#[inline]
+pub fn system_unbound() -> &'static WorkQueue {
+    SYSTEM_UNBOUND_WQ.get().expect("Workqueues not initialized")
+}
+
+/// Queue `work` on the system workqueue for the current CPU.
+#[inline]
+pub fn queue_work<F: FnOnce() + Send + 'static>(work: F) {
+    system().queue(work);
+}
@@ -12,27 +12,10 @@ fn main() {
         .truncate(true)
         .open(dest_path)
         .unwrap();
-    let page_arr: [String; 512] = (0..512)
-        .map(|i| {
-            let v = 0x10000000000705u64; // contigous, attrIndex: 1, NS, AP: rw EL1, SH: inner, AF: 1;
-            let addr: u64 = i * 0x40000000; // 1GB
-            let r = v | addr;
-            let str = format!("TableEntry {{ bits: {} }}", r);
-            str
-        })
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
-    let mut page_arr_str = String::new();
-    page_arr_str.push_str("[");
-    for (i, entry) in page_arr.iter().enumerate() {
-        use std::fmt::Write;
-        write!(page_arr_str, "{entry}").unwrap();
-        if i != 511 {
-            write!(page_arr_str, ", ").unwrap();
-        }
-    }
-    page_arr_str.push_str("]");
+    let v = 0x10000000000705u64; // contigous, attrIndex: 1, NS, AP: rw EL1, SH: inner, AF: 1;
+    // the loader runs from the identity map, the linear map is never executed
+    let page_arr_str = table_str(v);
+    let linear_arr_str = table_str(v | 1 << 53 | 1 << 54); // PXN, UXN
     writeln!(
         file,
         "static mut TABLE_LOW: Table = Table({});",
@@ -47,7 +30,7 @@ fn main() {
     writeln!(
         file,
         "static mut TABLE_HIGH_L1_0: Table = Table({});",
-        page_arr_str
+        linear_arr_str
     )
     .unwrap();
     writeln!(
@@ -57,3 +40,28 @@ fn main() {
     .unwrap();
     println!("cargo:rerun-if-changed=build.rs");
 }
+
+// 512 1GB blocks mapping the start of the physical memory with the attributes `v`
+fn table_str(v: u64) -> String {
+    let page_arr: [String; 512] = (0..512)
+        .map(|i| {
+            let addr: u64 = i * 0x40000000; // 1GB
+            let r = v | addr;
+            let str = format!("TableEntry {{ bits: {} }}", r);
+            str
+        })
+        .collect::<Vec<_>>()
+        .try_into()
+        .unwrap();
+    let mut page_arr_str = String::new();
+    page_arr_str.push_str("[");
+    for (i, entry) in page_arr.iter().enumerate() {
+        use std::fmt::Write;
+        write!(page_arr_str, "{entry}").unwrap();
+        if i != 511 {
+            write!(page_arr_str, ", ").unwrap();
+        }
+    }
+    page_arr_str.push_str("]");
+    page_arr_str
+}
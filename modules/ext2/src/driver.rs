@@ -2,21 +2,21 @@ use alloc::{sync::Arc, vec::Vec};
 use kernel::{
     error::Error,
     fs::{self, node::FsNodeRef},
+    sync::lockdep::TrackedMutex,
 };
-use spin::lock_api::Mutex;
 
 use crate::filesystem::FileSystem;
 
 pub static DRIVER: Driver = Driver {
-    filesystems: Mutex::new(Vec::new()),
+    filesystems: TrackedMutex::new(Vec::new()),
 };
 
 #[derive(Debug)]
-pub struct Driver<'a> {
-    filesystems: Mutex<Vec<Arc<FileSystem<'a>>>>,
+pub struct Driver {
+    filesystems: TrackedMutex<Vec<Arc<FileSystem>>>,
 }
 
-impl<'a> fs::Driver for Driver<'a> {
+impl fs::Driver for Driver {
     fn fs_type(&self) -> &str {
         "ext2"
     }
@@ -1,15 +1,18 @@
-use core::{assert_matches::debug_assert_matches, ops::Deref};
+use core::{assert_matches::debug_assert_matches, mem::MaybeUninit, ops::Deref};
 
 use alloc::{
     boxed::Box,
     sync::{Arc, Weak},
 };
+use hashbrown::HashMap;
 use kernel::{
     error::{Error, FsError::*},
     fs::{
         self,
         node::{File, FsNode, FsNodeRef},
+        page_cache::FileCache,
     },
+    sync::lockdep::{LockClass, TrackedMutex, mutex_in_class},
     utils::{
         buffer::Buffer,
         smart_ptr::{SmartPtrDeref, SmartPtrResizableBuff},
@@ -20,46 +23,54 @@ use log::{info, warn};
 use crate::{
     consts::{FILE_NODE_BUFF_SIZE, ROOT_INODE, SIGNATURE},
     icache::InodeCache,
-    nodes::{DirNode, FileNode},
+    nodes::{DirNode, FileNode, InodeIo},
     structs::{BlockGroupDescriptor, DirEntry, Inode, InodeRef, SuperBlock, Type},
 };
 
 pub type BlockIndex = usize;
 pub type InodeIndex = usize;
 
+static FILE_CACHES_CLASS: LockClass = LockClass::new("ext2_file_caches");
+
 #[derive(Debug)]
-pub struct FileSystem<'a> {
-    device: SmartPtrDeref<'a, FsNode<()>, dyn File>,
+pub struct FileSystem {
+    device: SmartPtrDeref<'static, FsNode<()>, dyn File>,
+    /// The node of the device, identifying it to the page cache.
+    device_node: FsNodeRef,
     pub superblock: SuperBlock,
     block_group_table: Box<[BlockGroupDescriptor]>,
     weak: Weak<Self>,
-    files: SmartPtrResizableBuff<FsNode<FileNode<'a>>, FILE_NODE_BUFF_SIZE>,
-    dirs: SmartPtrResizableBuff<FsNode<DirNode<'a>>, FILE_NODE_BUFF_SIZE>,
+    files: SmartPtrResizableBuff<FsNode<FileNode>, FILE_NODE_BUFF_SIZE>,
+    dirs: SmartPtrResizableBuff<FsNode<DirNode>, FILE_NODE_BUFF_SIZE>,
     inode_cache: InodeCache,
+    /// The page caches of the files, shared by all their nodes.
+    file_caches: TrackedMutex<HashMap<InodeIndex, FileCache>>,
 }
 
-impl<'a> FileSystem<'a> {
-    pub fn new(device: FsNodeRef) -> Result<Arc<Self>, Error> {
-        let device = FsNodeRef::clone(&device)
+impl FileSystem {
+    pub fn new(device_node: FsNodeRef) -> Result<Arc<Self>, Error> {
+        let device = FsNodeRef::clone(&device_node)
             .into_file()
             .ok_or(Error::Fs(NotAFile))?;
         let superblock = Self::read_superblock(&*device)?;
         let block_group_table = Self::read_block_group_descriptor_table(&*device, &superblock)?;
         let s = Arc::new_cyclic(|weak| Self {
             device,
+            device_node,
             superblock,
             block_group_table,
             weak: weak.clone(),
             files: SmartPtrResizableBuff::new(),
             dirs: SmartPtrResizableBuff::new(),
             inode_cache: InodeCache::new(),
+            file_caches: mutex_in_class(&FILE_CACHES_CLASS, HashMap::new()),
         });
         Ok(s)
     }
 
     pub fn get_root_node(&self) -> Result<FsNodeRef, Error> {
         let inode = self.read_inode(ROOT_INODE)?;
-        self.file_from_inode(inode)
+        self.file_from_inode(ROOT_INODE, inode)
     }
 
     fn read_superblock(device: &dyn File) -> Result<SuperBlock, Error> {
@@ -131,25 +142,52 @@ impl<'a> FileSystem<'a> {
         off: usize,
     ) -> Result<(), Error> {
         assert!(off < self.superblock.block_size());
+        let block = self.inode_block_index(inode, block)?;
+        self.read_block(block, buff, off)
+    }
+
+    /// Read the whole block `block` of the data of `inode` straight from the device.
+    ///
+    /// The page cache keeps the file data, so it bypasses the block cache when the device has one.
+    pub fn read_data_block(
+        &self,
+        inode: &Inode,
+        block: BlockIndex,
+        buff: &mut Buffer,
+    ) -> Result<(), Error> {
+        let block_size = self.superblock.block_size();
+        debug_assert_eq!(buff.len(), block_size);
+        let device = match self.device_node.as_block() {
+            Some(device) if block_size % device.block_size() == 0 => device,
+            _ => return self.read_inode_block(inode, block, buff, 0),
+        };
+        let block = self.inode_block_index(inode, block)?;
+        if block == 0 {
+            // a hole in a sparse file
+            buff.inner_mut().fill(MaybeUninit::new(0));
+            return Ok(());
+        }
+        device.read_direct(
+            fs::block::BlockIndex(block * block_size / device.block_size()),
+            buff,
+        )
+    }
+
+    /// Return the index on the device of the block `block` of the data of `inode`.
+    fn inode_block_index(&self, inode: &Inode, block: BlockIndex) -> Result<BlockIndex, Error> {
         let max_block_indirect = (self.superblock.block_size() / 4) + 12;
         let max_block_double_indirect =
             (max_block_indirect - 12) * (self.superblock.block_size() / 4) + 12;
         let max_block_triple_indirect =
             (max_block_double_indirect - 12) * (self.superblock.block_size() / 4) + 12;
         if block < 12 {
-            self.read_block(inode.ptrs[block] as BlockIndex, buff, off)
+            Ok(inode.ptrs[block] as BlockIndex)
         } else if block < max_block_indirect {
-            let block =
-                self.read_indirect_block(inode.indirect_ptr as BlockIndex, block - 12, 1)?;
-            self.read_block(block, buff, off)
+            self.read_indirect_block(inode.indirect_ptr as BlockIndex, block - 12, 1)
         } else if block < max_block_double_indirect {
-            let block =
-                self.read_indirect_block(inode.double_indirect_ptr as BlockIndex, block - 12, 2)?;
-            self.read_block(block, buff, off)
+            self.read_indirect_block(inode.double_indirect_ptr as BlockIndex, block - 12, 2)
         } else if block < max_block_triple_indirect {
-            let block =
-                self.read_indirect_block(inode.triple_indirect_ptr as BlockIndex, block - 12, 3)?;
-            self.read_block(block, buff, off)
+            self.read_indirect_block(inode.triple_indirect_ptr as BlockIndex, block - 12, 3)
         } else {
             // Block index out of range.
             Err(Error::Fs(InvalidFS))
@@ -230,16 +268,32 @@ impl<'a> FileSystem<'a> {
 
     #[inline]
     pub fn file_from_dir_entry(&self, dir_entry: &DirEntry) -> Result<FsNodeRef, Error> {
-        let inode = self.read_inode(dir_entry.inode as InodeIndex)?;
-        self.file_from_inode(inode)
+        let index = dir_entry.inode as InodeIndex;
+        let inode = self.read_inode(index)?;
+        self.file_from_inode(index, inode)
+    }
+
+    /// Return the page cache of the file `index`, created on its first use.
+    ///
+    /// The caches of the files without any node left are dropped when a new one is created.
+    fn file_cache(&self, index: InodeIndex, inode: InodeRef) -> FileCache {
+        if let Some(cache) = self.file_caches.lock().get(&index) {
+            return cache.clone();
+        }
+        let size = inode.size();
+        let io = InodeIo::new(self.weak.clone(), self.device_node.clone(), inode);
+        let cache = FileCache::new(Box::new(io), size);
+        let mut caches = self.file_caches.lock();
+        caches.retain(|_, cache| !cache.is_unique());
+        caches.entry(index).or_insert(cache).clone()
     }
 
-    pub fn file_from_inode(&self, inode: InodeRef) -> Result<FsNodeRef, Error> {
+    pub fn file_from_inode(&self, index: InodeIndex, inode: InodeRef) -> Result<FsNodeRef, Error> {
         let inode_type =
             Type::try_from(inode.type_and_permissions).map_err(|_| Error::Fs(InvalidFS))?;
         match inode_type {
             Type::File => {
-                let file = FileNode::new(self.weak.upgrade().expect("Arc destroyed"), inode);
+                let file = FileNode::new(self.file_cache(index, inode));
                 let node = self.files.insert(file);
                 let node = FsNodeRef::new(node);
                 Ok(node)
@@ -1,6 +1,9 @@
 use hashbrown::HashMap;
-use kernel::{error::Error, utils::smart_ptr::SmartPtrResizableBuff};
-use spin::lock_api::RwLock;
+use kernel::{
+    error::Error,
+    sync::lockdep::{LockClass, TrackedRwLock, rwlock_in_class},
+    utils::smart_ptr::SmartPtrResizableBuff,
+};
 
 use crate::{
     consts::INODE_CACHE_GROUP_SIZE,
@@ -8,17 +11,19 @@ use crate::{
     structs::{Inode, InodeRef},
 };
 
+static INODE_CACHE_CLASS: LockClass = LockClass::new("ext2_inode_cache");
+
 #[derive(Debug)]
 pub struct InodeCache {
     data: SmartPtrResizableBuff<Inode, INODE_CACHE_GROUP_SIZE>,
-    hash: RwLock<HashMap<InodeIndex, InodeRef>>,
+    hash: TrackedRwLock<HashMap<InodeIndex, InodeRef>>,
 }
 
 impl InodeCache {
     pub fn new() -> Self {
         Self {
             data: SmartPtrResizableBuff::new(),
-            hash: RwLock::new(HashMap::new()),
+            hash: rwlock_in_class(&INODE_CACHE_CLASS, HashMap::new()),
         }
     }
 
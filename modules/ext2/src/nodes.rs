@@ -1,82 +1,103 @@
+use core::mem::MaybeUninit;
+
 use alloc::{
     string::{String, ToString},
-    sync::Arc,
+    sync::{Arc, Weak},
     vec::Vec,
 };
 use kernel::{
     create_fs_node,
-    error::Error,
-    fs::node::{Directory, File, FsNode, FsNodeInfos, FsNodeRef},
+    error::{Error, FsError},
+    fs::{
+        node::{Directory, File, FsNode, FsNodeInfos, FsNodeRef},
+        page_cache::{FileCache, PageIo},
+    },
+    memory::PAGE_SIZE,
     utils::buffer::Buffer,
 };
 
 use crate::{filesystem::FileSystem, structs::InodeRef};
 
 #[derive(Debug)]
-pub struct FileNode<'a> {
-    fs: Arc<FileSystem<'a>>,
-    inode: InodeRef,
+pub struct FileNode {
+    cache: FileCache,
 }
 
-impl<'a> FileNode<'a> {
+impl FileNode {
     #[inline(always)]
-    pub fn new(fs: Arc<FileSystem<'a>>, inode: InodeRef) -> FsNode<Self> {
-        let size = inode.size();
-        let file = Self { fs, inode };
+    pub fn new(cache: FileCache) -> FsNode<Self> {
+        let size = cache.size();
+        let file = Self { cache };
         create_fs_node!(file, FsNodeInfos { size }, file: dyn File)
     }
 }
 
-unsafe impl<'a> File for FileNode<'a> {
+unsafe impl File for FileNode {
+    #[inline]
     fn read(&self, offset: usize, buff: &mut Buffer) -> Result<usize, Error> {
-        let block_size = self.fs.superblock.block_size();
-        let size = buff.len();
+        self.cache.read(offset, buff)
+    }
+}
+
+/// Read the pages of a file for the page cache, straight from the device.
+///
+/// The filesystem owns the caches, so it is only weakly referenced.
+#[derive(Debug)]
+pub struct InodeIo {
+    fs: Weak<FileSystem>,
+    device: FsNodeRef,
+    inode: InodeRef,
+}
+
+impl InodeIo {
+    #[inline]
+    pub fn new(fs: Weak<FileSystem>, device: FsNodeRef, inode: InodeRef) -> Self {
+        Self { fs, device, inode }
+    }
+}
 
-        let start_block = offset / block_size;
-        let start_block_off = offset % block_size;
-        let end_block = (offset + size) / block_size;
-        let end_block_off = (offset + size) % block_size;
+impl PageIo for InodeIo {
+    fn read_page(&self, index: usize, buff: &mut Buffer) -> Result<(), Error> {
+        let fs = self
+            .fs
+            .upgrade()
+            .ok_or(Error::Fs(FsError::CustomStr("Filesystem destroyed")))?;
+        let block_size = fs.superblock.block_size();
+        let start = index * PAGE_SIZE;
+        let len = self.inode.size().saturating_sub(start).min(PAGE_SIZE);
 
+        // a page holds whole blocks, the one past the end of the file is zeroed below
         let mut offset = 0;
-        for block_idx in start_block..=end_block {
-            if block_idx == start_block {
-                let buff = buff.slice_mut(start_block_off..block_size.min(size));
-                self.fs
-                    .read_inode_block(&self.inode, block_idx, buff, start_block_off)?;
-                offset += buff.len(); // Should be the same as `block_size.min(size) - start_block_off`.
-            } else if block_idx == end_block {
-                let buff = buff.slice_mut(offset..offset + end_block_off);
-                self.fs.read_inode_block(&self.inode, block_idx, buff, 0)?;
-                offset += buff.len();
-            } else {
-                let old_offset = offset;
-                offset += block_size;
-                let buff = buff.slice_mut(old_offset..offset);
-                self.fs.read_inode_block(&self.inode, block_idx, buff, 0)?;
-            }
+        while offset < len {
+            let buff = buff.slice_mut(offset..offset + block_size);
+            fs.read_data_block(&self.inode, (start + offset) / block_size, buff)?;
+            offset += block_size;
         }
+        buff.inner_mut()[len..].fill(MaybeUninit::new(0));
+        Ok(())
+    }
 
-        debug_assert_eq!(offset, buff.len());
-        Ok(buff.len())
+    #[inline]
+    fn device(&self) -> Option<&FsNodeRef> {
+        Some(&self.device)
     }
 }
-
 #[derive(Debug)]
-pub struct DirNode<'a> {
-    fs: Arc<FileSystem<'a>>,
+pub struct DirNode {
+    fs: Arc<FileSystem>,
     inode: InodeRef,
 }
 
-impl<'a> DirNode<'a> {
+impl DirNode {
     #[inline(always)]
-    pub fn new(fs: Arc<FileSystem<'a>>, inode: InodeRef) -> FsNode<Self> {
+    pub fn new(fs: Arc<FileSystem>, inode: InodeRef) -> FsNode<Self> {
         let size = inode.size();
         let dir = Self { fs, inode };
         create_fs_node!(dir, FsNodeInfos { size }, directory: dyn Directory)
     }
 }
 
-unsafe impl<'a> Directory for DirNode<'a> {
+unsafe impl Directory for DirNode {
     fn find(&self, name: &str) -> Result<Option<FsNodeRef>, Error> {
         let mut file = None;
         self.fs.read_dir(&self.inode, |entry| {
@@ -11,15 +11,19 @@ use hashbrown::HashMap;
 use kernel::{
     bus::pcie::{Capability, MsixCapability, MsixTableEntry, PciDevice},
     error::Error,
+    executor,
     interrupts::{self, InterruptMode, MsiVector},
     memory::{
         AddrSpaceSelector, MemoryUsage, PAGE_SIZE,
         vmm::{MapFlags, vmm},
     },
+    sync::lockdep::{
+        LockClass, TrackedMutex, TrackedRwLock, TrackedRwLockReadGuard, mutex_in_class,
+        rwlock_in_class,
+    },
     utils::sync_once_cell::SyncOnceCell,
 };
 use log::trace;
-use spin::lock_api::{Mutex, RwLock, RwLockReadGuard};
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 use crate::{
@@ -33,14 +37,19 @@ use crate::{
     set_interrupt_handler,
 };
 
+static SUBMISSION_QUEUES_CLASS: LockClass = LockClass::new("nvme_submission_queues");
+static COMPLETION_QUEUES_CLASS: LockClass = LockClass::new("nvme_completion_queues");
+static MSIX_TABLE_CLASS: LockClass = LockClass::new("nvme_msix_table");
+static INTERRUPTS_MAP_CLASS: LockClass = LockClass::new("nvme_interrupts_map");
+
 #[derive(Debug)]
 pub struct Device {
     pub regs: &'static Registers,
-    pub submission_queues: RwLock<Vec<SubmissionQueue>>,
-    pub completion_queues: RwLock<Vec<CompletionQueue>>,
+    pub submission_queues: TrackedRwLock<Vec<SubmissionQueue>>,
+    pub completion_queues: TrackedRwLock<Vec<CompletionQueue>>,
     pub doorbell_stride: usize,
-    pub msix_table: Mutex<&'static mut [MsixTableEntry]>,
-    pub interrupts_map: RwLock<HashMap<u32, CompletionQueueId>>,
+    pub msix_table: TrackedMutex<&'static mut [MsixTableEntry]>,
+    pub interrupts_map: TrackedRwLock<HashMap<u32, CompletionQueueId>>,
     pub controller_infos: SyncOnceCell<IndentifyControllerData>,
 }
 
@@ -121,11 +130,11 @@ impl Device {
 
         Self {
             regs,
-            submission_queues: RwLock::new(vec![submission]),
-            completion_queues: RwLock::new(vec![completion]),
+            submission_queues: rwlock_in_class(&SUBMISSION_QUEUES_CLASS, vec![submission]),
+            completion_queues: rwlock_in_class(&COMPLETION_QUEUES_CLASS, vec![completion]),
             doorbell_stride,
-            msix_table: Mutex::new(msix_table),
-            interrupts_map: RwLock::new(HashMap::new()),
+            msix_table: mutex_in_class(&MSIX_TABLE_CLASS, msix_table),
+            interrupts_map: rwlock_in_class(&INTERRUPTS_MAP_CLASS, HashMap::new()),
             controller_infos: SyncOnceCell::new(),
         }
     }
@@ -203,7 +212,7 @@ impl Device {
         id: SubmissionQueueId,
     ) -> impl Deref<Target = SubmissionQueue> + '_ {
         let queues = self.submission_queues.read();
-        RwLockReadGuard::map(queues, |q| &q[id.get() as usize])
+        TrackedRwLockReadGuard::map(queues, |q| &q[id.get() as usize])
     }
 
     #[inline]
@@ -212,7 +221,7 @@ impl Device {
         id: CompletionQueueId,
     ) -> impl Deref<Target = CompletionQueue> + '_ {
         let queues = self.completion_queues.read();
-        RwLockReadGuard::map(queues, |q| &q[id.get() as usize])
+        TrackedRwLockReadGuard::map(queues, |q| &q[id.get() as usize])
     }
 
     #[inline]
@@ -274,6 +283,35 @@ impl Device {
         response
     }
 
+    /// Same as `wait_cmd` but wait asynchronously, the queue isn't borrowed while waiting.
+    pub async fn wait_cmd_async(
+        &self,
+        queue_id: CompletionQueueId,
+        command_id: u16,
+    ) -> CompletionEntry {
+        let waiters = self.get_completion_queue(queue_id).async_waiters();
+        let response = loop {
+            let wait = {
+                let queue = self.get_completion_queue(queue_id);
+                match queue.get_or_wait(self, command_id, &waiters) {
+                    Ok(entry) => break entry,
+                    Err(wait) => wait,
+                }
+            };
+            match wait {
+                Some(wait) => wait.await,
+                None => executor::yield_now().await,
+            }
+        };
+        trace!(
+            "Receive response {:?} for cmd {} in queue {}",
+            response,
+            command_id,
+            queue_id.get()
+        );
+        response
+    }
+
     pub fn create_submission_queue(
         &self,
         completion_id: CompletionQueueId,
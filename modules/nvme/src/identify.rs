@@ -1,5 +1,8 @@
 use alloc::vec::Vec;
-use kernel::{error::Error, memory::Dma};
+use kernel::{
+    error::Error,
+    memory::{Dma, DmaMask, PAGE_SIZE},
+};
 use static_assertions::assert_eq_size;
 
 use crate::{cmd::Command, device::Device, queues::SubmissionQueueId};
@@ -121,7 +124,7 @@ pub struct NamespaceInfos {
 
 impl Device {
     pub fn identify_controller(&self) -> Result<(), Error> {
-        let buff: Dma<IndentifyControllerData> = unsafe { Dma::new()? };
+        let buff: Dma<IndentifyControllerData> = unsafe { Dma::new_in(DmaMask::ALL, PAGE_SIZE)? };
         let cmd = Command::identify_controller(buff.phys());
         let r = unsafe { self.submit_and_wait_cmd(SubmissionQueueId::admin(), cmd) };
         assert!(r.status().success());
@@ -132,7 +135,7 @@ impl Device {
     }
 
     pub fn identify_namespace_list(&self) -> Result<Vec<u32>, Error> {
-        let buff: Dma<[u32; 1024]> = unsafe { Dma::new()? };
+        let buff: Dma<[u32; 1024]> = unsafe { Dma::new_in(DmaMask::ALL, PAGE_SIZE)? };
         let cmd = Command::identify_namespace_list(buff.phys());
         let r = unsafe { self.submit_and_wait_cmd(SubmissionQueueId::admin(), cmd) };
         assert!(r.status().success());
@@ -141,7 +144,7 @@ impl Device {
     }
 
     pub fn identify_namespace(&self, namespace: u32) -> Result<NamespaceInfos, Error> {
-        let buff: Dma<IdentifyNamespaceData> = unsafe { Dma::new()? };
+        let buff: Dma<IdentifyNamespaceData> = unsafe { Dma::new_in(DmaMask::ALL, PAGE_SIZE)? };
         let cmd = Command::identify_namespace(buff.phys(), namespace);
         let r = unsafe { self.submit_and_wait_cmd(SubmissionQueueId::admin(), cmd) };
         assert!(r.status().success());
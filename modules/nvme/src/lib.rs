@@ -5,10 +5,16 @@ extern crate alloc;
 
 use core::ptr;
 
-use alloc::{sync::Arc, vec::Vec};
+use alloc::sync::Arc;
 use device::Device;
-use kernel::{bus::pcie::PciDevice, devices, error::Error, interrupts};
-use spin::lock_api::RwLock;
+use kernel::{
+    bus::pcie::PciDevice,
+    devices,
+    error::Error,
+    interrupts::{self, softirq},
+    sync::rcu::RcuList,
+};
+use spin::Lazy;
 
 mod cmd;
 mod device;
@@ -24,23 +30,27 @@ pub fn init() -> Result<(), Error> {
     Ok(())
 }
 
-static DEVICES: RwLock<Vec<Arc<Device>>> = RwLock::new(Vec::new());
+static DEVICES: Lazy<RcuList<Arc<Device>>> = Lazy::new(Default::default);
 
 fn device_handler(device: &PciDevice) {
     let device = Arc::new(Device::new(device.clone()));
-    let mut devices = DEVICES.write();
-    devices.push(Arc::clone(&device));
-    drop(devices);
+    DEVICES.modify(|devices| devices.push(Arc::clone(&device)));
     device.init().unwrap();
 }
 
 fn interrupt_handler(id: u32, dev_index: usize) {
-    let device = &DEVICES.read()[dev_index];
+    // waking up the waiting threads is deferred out of the interrupt context
+    softirq::raise(bottom_half, dev_index << 32 | id as usize);
+}
+
+fn bottom_half(data: usize) {
+    let (dev_index, id) = (data >> 32, data as u32);
+    let device = Arc::clone(&DEVICES.get()[dev_index]);
     device.interrupt_handler(id);
 }
 
 fn set_interrupt_handler(id: u32, device: &Device) {
-    let devices = DEVICES.read();
+    let devices = DEVICES.get();
     let (device_id, _) = devices
         .iter()
         .enumerate()
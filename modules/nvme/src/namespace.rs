@@ -1,13 +1,15 @@
 use core::{
     fmt::Debug,
+    iter,
+    mem::size_of,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-use alloc::{format, string::String, sync::Arc};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
 use kernel::{
     error::Error,
-    fs::block::{BlockDev, BlockDevInfos, BlockIndex},
-    memory::PhysicalAddress,
+    fs::block::{AsyncBlockDev, BlockDev, BlockDevInfos, BlockFuture, BlockIndex},
+    memory::{Dma, DmaDirection, DmaMapping, DmaMask, PAGE_SIZE, PhysicalAddress},
     utils::buffer::Buffer,
 };
 use log::warn;
@@ -16,7 +18,7 @@ use crate::{
     cmd::Command,
     device::Device,
     identify::NamespaceInfos,
-    queues::{CompletionQueueId, SubmissionQueueId},
+    queues::{CompletionEntry, CompletionQueueId, SubmissionQueueId},
 };
 
 pub struct Namespace {
@@ -63,6 +65,54 @@ impl Namespace {
         let id = COUNTER.fetch_add(1, Ordering::Relaxed);
         format!("nvme{}n{}", id, namespace_id)
     }
+
+    /// Submit `cmd` and wait asynchronously for its completion.
+    async fn run_cmd_async(&self, cmd: Command) -> CompletionEntry {
+        let cmd_id = {
+            let squeue = self.device.get_submission_queue(self.sq);
+            unsafe { self.device.submit_cmd(&squeue, cmd) }
+        };
+        self.device.wait_cmd_async(self.cq, cmd_id).await
+    }
+}
+
+/// The data pointers of a command and the PRP list they may point to, kept until the command completes.
+struct DataPtrs {
+    prp1: PhysicalAddress,
+    prp2: PhysicalAddress,
+    _list: Option<Dma<[u64]>>,
+}
+
+impl DataPtrs {
+    /// Describe the segments of `mapping` with one PRP entry per page.
+    fn new(mapping: &DmaMapping) -> Result<Self, Error> {
+        // the segments after the first one start on a page
+        let mut pages = mapping.segments().iter().flat_map(|segment| {
+            let start = segment.addr.addr();
+            let end = start + segment.len;
+            iter::once(start).chain(
+                (start / PAGE_SIZE + 1..end.div_ceil(PAGE_SIZE)).map(|page| page * PAGE_SIZE),
+            )
+        });
+        let prp1 = pages.next().unwrap_or(0);
+        let others: Vec<u64> = pages.map(|addr| addr as u64).collect();
+        let (prp2, list) = match others.len() {
+            0 => (0, None),
+            1 => (others[0] as usize, None),
+            // a list in a single page
+            len if len <= PAGE_SIZE / size_of::<u64>() => {
+                let mut list = unsafe { Dma::<[u64]>::new_slice(len)? };
+                list.copy_from_slice(&others);
+                (list.phys().addr(), Some(list))
+            }
+            _ => return Err(Error::CustomStr("Transfer too big for a PRP list")),
+        };
+        Ok(Self {
+            prp1: PhysicalAddress::new(prp1),
+            prp2: PhysicalAddress::new(prp2),
+            _list: list,
+        })
+    }
 }
 
 impl BlockDev for Namespace {
@@ -71,15 +121,11 @@ impl BlockDev for Namespace {
     }
 
     fn read(&self, block: BlockIndex, buff: &mut Buffer) -> Result<(), Error> {
+        let mapping = DmaMapping::new(buff, DmaDirection::FromDevice, DmaMask::ALL)?;
+        let ptrs = DataPtrs::new(&mapping)?;
         let squeue = self.device.get_submission_queue(self.sq);
         let cqueue = self.device.get_completion_queue(self.cq);
-        let cmd = Command::read(
-            buff.phys(),
-            PhysicalAddress::new(0),
-            self.infos.id,
-            block.0 as u64,
-            0,
-        );
+        let cmd = Command::read(ptrs.prp1, ptrs.prp2, self.infos.id, block.0 as u64, 0);
         let cmd_id = unsafe { self.device.submit_cmd(&squeue, cmd) };
         let r = self.device.wait_cmd(&cqueue, cmd_id);
         if r.status().success() {
@@ -91,15 +137,11 @@ impl BlockDev for Namespace {
     }
 
     fn write(&self, block: BlockIndex, buff: &Buffer) -> Result<(), Error> {
+        let mapping = DmaMapping::to_device(buff, DmaMask::ALL)?;
+        let ptrs = DataPtrs::new(&mapping)?;
         let squeue = self.device.get_submission_queue(self.sq);
         let cqueue = self.device.get_completion_queue(self.cq);
-        let cmd = Command::write(
-            buff.phys(),
-            PhysicalAddress::new(0),
-            self.infos.id,
-            block.0 as u64,
-            0,
-        );
+        let cmd = Command::write(ptrs.prp1, ptrs.prp2, self.infos.id, block.0 as u64, 0);
         let cmd_id = unsafe { self.device.submit_cmd(&squeue, cmd) };
         let r = self.device.wait_cmd(&cqueue, cmd_id);
         if r.status().success() {
@@ -109,4 +151,40 @@ impl BlockDev for Namespace {
             Err(Error::IoError)
         }
     }
+
+    fn as_async(&self) -> Option<&dyn AsyncBlockDev> {
+        Some(self)
+    }
+}
+
+impl AsyncBlockDev for Namespace {
+    fn read_async<'a>(&'a self, block: BlockIndex, buff: &'a mut Buffer) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let mapping = DmaMapping::new(buff, DmaDirection::FromDevice, DmaMask::ALL)?;
+            let ptrs = DataPtrs::new(&mapping)?;
+            let cmd = Command::read(ptrs.prp1, ptrs.prp2, self.infos.id, block.0 as u64, 0);
+            let r = self.run_cmd_async(cmd).await;
+            if r.status().success() {
+                Ok(())
+            } else {
+                warn!("Reading block {} failed: {:?}", block.0, r);
+                Err(Error::IoError)
+            }
+        })
+    }
+
+    fn write_async<'a>(&'a self, block: BlockIndex, buff: &'a Buffer) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let mapping = DmaMapping::to_device(buff, DmaMask::ALL)?;
+            let ptrs = DataPtrs::new(&mapping)?;
+            let cmd = Command::write(ptrs.prp1, ptrs.prp2, self.infos.id, block.0 as u64, 0);
+            let r = self.run_cmd_async(cmd).await;
+            if r.status().success() {
+                Ok(())
+            } else {
+                warn!("Writing block {} failed: {:?}", block.0, r);
+                Err(Error::IoError)
+            }
+        })
+    }
 }
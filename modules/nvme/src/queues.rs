@@ -6,13 +6,18 @@ use core::{
     ptr,
 };
 
+use alloc::sync::Arc;
 use kernel::{
     error::Error,
-    memory::{Dma, PhysicalAddress},
+    memory::{Dma, DmaMask, PAGE_SIZE, PhysicalAddress},
     scheduler::yield_now,
-    sync::{no_irq_locks::NoIrqMutex, wait_map::WaitMap},
+    sync::{
+        async_wait_condition::{AsyncWaitCondition, Wait},
+        lockdep::{LockClass, TrackedMutex, mutex_in_class},
+        no_irq_locks::NoIrqMutex,
+        wait_map::WaitMap,
+    },
 };
-use spin::lock_api::Mutex;
 
 use crate::device::Device;
 
@@ -35,11 +40,13 @@ pub struct SubmissionEntry {
     pub dword15: u32,
 }
 
+static SQ_CLASS: LockClass = LockClass::new("nvme_sq");
+
 #[derive(Debug)]
 pub struct SubmissionQueue {
     pub id: SubmissionQueueId,
     pub completion_id: CompletionQueueId,
-    inner: Mutex<SqInner>,
+    inner: TrackedMutex<SqInner>,
 }
 
 #[derive(Debug)]
@@ -55,12 +62,15 @@ impl SubmissionQueue {
         len: usize,
         completion_id: CompletionQueueId,
     ) -> Result<Self, Error> {
-        let buff = unsafe { Dma::new_slice(len)? };
-        let inner = Mutex::new(SqInner {
-            buff,
-            tail: 0,
-            head: 0,
-        });
+        let buff = unsafe { Dma::new_slice_in(len, DmaMask::ALL, PAGE_SIZE)? };
+        let inner = mutex_in_class(
+            &SQ_CLASS,
+            SqInner {
+                buff,
+                tail: 0,
+                head: 0,
+            },
+        );
         Ok(Self {
             id,
             completion_id,
@@ -135,6 +145,8 @@ pub struct CompletionQueue {
     head: NoIrqMutex<u16>,
     pub interrupt_vector: Option<u16>,
     wait_map: WaitMap<u16>,
+    /// Futures waiting for an entry, notified on each interrupt.
+    async_waiters: Arc<AsyncWaitCondition>,
     interrupt_lock: NoIrqMutex<()>,
 }
 
@@ -144,13 +156,14 @@ impl CompletionQueue {
         len: usize,
         interrupt_vector: Option<u16>,
     ) -> Result<Self, Error> {
-        let buff = unsafe { Dma::new_slice(len)? };
+        let buff = unsafe { Dma::new_slice_in(len, DmaMask::ALL, PAGE_SIZE)? };
         let q = Self {
             id,
             buff,
             head: NoIrqMutex::new(0),
             interrupt_vector,
             wait_map: WaitMap::new(),
+            async_waiters: Arc::new(AsyncWaitCondition::new()),
             interrupt_lock: NoIrqMutex::new(()),
         };
         Ok(q)
@@ -217,6 +230,37 @@ impl CompletionQueue {
         }
     }
 
+    /// Condition to pass to `get_or_wait`, kept outside of the queue so it can be awaited without holding the queue.
+    #[inline]
+    pub fn async_waiters(&self) -> Arc<AsyncWaitCondition> {
+        Arc::clone(&self.async_waiters)
+    }
+
+    /// Return the entry `id` if it is ready, else register a waiter on `waiters` which is notified by the next interrupt.
+    ///
+    /// The waiter is `None` if the queue has no interrupt and must be polled.
+    pub fn get_or_wait<'a>(
+        &self,
+        device: &Device,
+        id: u16,
+        waiters: &'a AsyncWaitCondition,
+    ) -> Result<CompletionEntry, Option<Wait<'a>>> {
+        if self.interrupt_vector.is_none() {
+            return self.get(device, Some(id)).ok_or(None);
+        }
+
+        let lock = self.interrupt_lock.lock(); // same as in `wait_entry`
+        match self.get(device, Some(id)) {
+            Some(entry) => {
+                drop(lock);
+                // the next entry may have completed with the same interrupt
+                self.interrupt_handler();
+                Ok(entry)
+            }
+            None => Err(Some(waiters.wait())),
+        }
+    }
+
     pub fn interrupt_handler(&self) {
         // TODO: It may be better to properly read the entry here and send it to the threads that are waitings instead of reading it 2 times.
         let entry = {
@@ -232,6 +276,7 @@ impl CompletionQueue {
                 drop(lock);
             }
             self.wait_map.send(entry.command_id);
+            self.async_waiters.notify_all();
         }
     }
 }
@@ -2,6 +2,8 @@ use std::process::Command;
 
 use super::{Action, ActionRef, CommandAction};
 
+const RUSTFLAGS: &str = "-C symbol-mangling-version=v0";
+
 #[derive(Debug)]
 pub struct CargoCmdAction {
     inner: CommandAction,
@@ -31,7 +33,7 @@ impl CargoCmdAction {
             ]);
         }
         command.args(args);
-        command.env("RUSTFLAGS", "-C symbol-mangling-version=v0");
+        command.env("RUSTFLAGS", RUSTFLAGS);
         if release {
             command.arg("-r");
         }
@@ -39,6 +41,13 @@ impl CargoCmdAction {
             inner: CommandAction::new(command, name, true, dependencies),
         }
     }
+
+    /// Pass `flags` to rustc in addition to the default ones.
+    pub fn with_rustflags(mut self, flags: &[&str]) -> Self {
+        let flags = format!("{} {}", RUSTFLAGS, flags.join(" "));
+        self.inner.command_mut().env("RUSTFLAGS", flags);
+        self
+    }
 }
 
 impl Action for CargoCmdAction {
@@ -34,6 +34,10 @@ impl CommandAction {
             progress_report,
         }
     }
+
+    pub fn command_mut(&mut self) -> &mut Command {
+        &mut self.cmd
+    }
 }
 
 impl Action for CommandAction {
@@ -183,6 +183,18 @@ fn action_build(args: Args) -> Result<ActionRef, Box<dyn Error>> {
     if args.no_default_features {
         kernel_args.push("--no-default-features");
     }
+    // the lock validator and the heap debugging walk the frame pointers to record where the locks
+    // are taken and the blocks allocated
+    let frame_pointers = args.features.iter().any(|features| {
+        features
+            .split(',')
+            .any(|feature| feature == "lockdep" || feature == "heap_debug")
+    });
+    let kernel_rustflags: &[&str] = if frame_pointers {
+        &["-C force-frame-pointers=yes"]
+    } else {
+        &[]
+    };
     let kernel_build: ActionRef = CargoCmdAction::new(
         "kernel/Cargo.toml",
         Some("Kernel".into()),
@@ -198,6 +210,7 @@ fn action_build(args: Args) -> Result<ActionRef, Box<dyn Error>> {
         &kernel_args,
         vec![],
     )
+    .with_rustflags(kernel_rustflags)
     .into();
     let clear_kernel_objs =
         ClearDirAction::new("build/kernel_objs".into(), vec![create_dirs]).into();